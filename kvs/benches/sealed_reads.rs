@@ -0,0 +1,46 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use kvs::KvStore;
+use tempfile::TempDir;
+
+const KEYS: usize = 10_000;
+// Small enough that nearly every key ends up in a sealed epoch
+const MAX_LOG_SIZE: u64 = 64 * 1024;
+
+fn populate() -> TempDir {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())
+        .expect("failed to open store")
+        .with_max_size(MAX_LOG_SIZE);
+    let value = "v".repeat(100);
+    for i in 0..KEYS {
+        store
+            .set(format!("key{}", i), value.clone())
+            .expect("failed to set");
+    }
+    temp_dir
+}
+
+fn sealed_reads(c: &mut Criterion) {
+    let temp_dir = populate();
+    let mut group = c.benchmark_group("sealed_get");
+    for &mmap in &[false, true] {
+        let name = if mmap { "mmap" } else { "file" };
+        let mut store = KvStore::open(temp_dir.path())
+            .expect("failed to open store")
+            .with_max_size(MAX_LOG_SIZE)
+            .with_mmap(mmap);
+        group.bench_function(BenchmarkId::from_parameter(name), |b| {
+            let mut i = 0;
+            b.iter(|| {
+                // Skip the tail of the keyspace, which lives in the current epoch
+                let key = format!("key{}", i % (KEYS / 2));
+                i += 7;
+                store.get(key).expect("failed to get")
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, sealed_reads);
+criterion_main!(benches);
@@ -0,0 +1,85 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use kvs::{AsyncClient, AsyncRespServer, KvStore, RespServer};
+use std::net::TcpListener;
+use std::thread;
+use tempfile::TempDir;
+use tokio::runtime::Runtime;
+
+// Each client sets and then gets this many keys per iteration
+const OPS_PER_CLIENT: usize = 10;
+
+fn open(dir: &TempDir) -> KvStore {
+    KvStore::open(dir.path()).expect("failed to open store")
+}
+
+/// Serve a store from the thread-per-connection server, returning its address.
+fn start_sync(dir: &TempDir) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("failed to find a free port");
+    let addr = listener.local_addr().unwrap().to_string();
+    let store = open(dir);
+    thread::spawn(move || RespServer::new(store).serve(listener));
+    addr
+}
+
+/// Serve a store from the tokio server on `runtime`, returning its address.
+fn start_async(dir: &TempDir, runtime: &Runtime) -> String {
+    let listener = runtime
+        .block_on(tokio::net::TcpListener::bind("127.0.0.1:0"))
+        .expect("failed to find a free port");
+    let addr = listener.local_addr().unwrap().to_string();
+    runtime.spawn(AsyncRespServer::new(open(dir)).serve(listener));
+    addr
+}
+
+/// Sets and gets from many clients at once, each on a connection of its own.
+fn concurrent_clients(c: &mut Criterion) {
+    let runtime = Runtime::new().expect("failed to start tokio");
+    let (sync_dir, async_dir) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+    let servers = [
+        ("sync", start_sync(&sync_dir)),
+        ("async", start_async(&async_dir, &runtime)),
+    ];
+
+    let mut group = c.benchmark_group("server");
+    for &clients in &[1, 16, 256] {
+        group.throughput(Throughput::Elements((clients * OPS_PER_CLIENT * 2) as u64));
+        for (name, addr) in &servers {
+            let mut connected: Vec<AsyncClient> = runtime.block_on(async {
+                let mut connected = Vec::with_capacity(clients);
+                for _ in 0..clients {
+                    connected.push(AsyncClient::connect(addr).await.expect("failed to connect"));
+                }
+                connected
+            });
+            group.bench_function(BenchmarkId::new(*name, clients), |b| {
+                b.iter(|| {
+                    connected = runtime.block_on(async {
+                        let tasks: Vec<_> = connected
+                            .drain(..)
+                            .enumerate()
+                            .map(|(id, mut client)| {
+                                tokio::spawn(async move {
+                                    for i in 0..OPS_PER_CLIENT {
+                                        let key = format!("client{}-key{}", id, i);
+                                        client.set(key.clone(), "value".to_owned()).await.unwrap();
+                                        client.get(key).await.unwrap().expect("just set");
+                                    }
+                                    client
+                                })
+                            })
+                            .collect();
+                        let mut returned = Vec::with_capacity(tasks.len());
+                        for task in tasks {
+                            returned.push(task.await.expect("client panicked"));
+                        }
+                        returned
+                    });
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, concurrent_clients);
+criterion_main!(benches);
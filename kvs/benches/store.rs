@@ -0,0 +1,156 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use kvs::KvStore;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+// Small enough that a populated store spans many epochs
+const MAX_LOG_SIZE: u64 = 64 * 1024;
+const KEYS: usize = 10_000;
+
+fn temp_dir() -> TempDir {
+    TempDir::new().expect("unable to create temporary working directory")
+}
+
+fn open(dir: &TempDir) -> KvStore {
+    KvStore::open(dir.path())
+        .expect("failed to open store")
+        .with_max_size(MAX_LOG_SIZE)
+}
+
+fn populate(store: &mut KvStore, keys: usize, value_size: usize) {
+    let value = "v".repeat(value_size);
+    for i in 0..keys {
+        store
+            .set(format!("key{}", i), value.clone())
+            .expect("failed to set");
+    }
+}
+
+fn set(c: &mut Criterion) {
+    let mut group = c.benchmark_group("set");
+    for &value_size in &[16, 256, 4096] {
+        let dir = temp_dir();
+        let mut store = open(&dir);
+        let value = "v".repeat(value_size);
+        let mut i = 0u64;
+        group.throughput(Throughput::Bytes(value_size as u64));
+        group.bench_function(BenchmarkId::from_parameter(value_size), |b| {
+            b.iter(|| {
+                // Fresh keys only, so the benchmark never triggers compaction
+                i += 1;
+                store
+                    .set(format!("key{}", i), value.clone())
+                    .expect("failed to set")
+            })
+        });
+    }
+    group.finish();
+}
+
+fn get(c: &mut Criterion) {
+    let dir = temp_dir();
+    let mut store = open(&dir);
+    populate(&mut store, KEYS, 100);
+    drop(store);
+
+    // The first keys written live in sealed epochs, the last in the current one
+    let ranges = [("sealed", 0..KEYS / 2), ("current", KEYS - 10..KEYS)];
+    let mut group = c.benchmark_group("get");
+    for (epoch, range) in ranges.iter() {
+        // Hot reads repeat a handful of keys through the cache, cold reads sweep the range without one
+        for &hot in &[true, false] {
+            let mut store = open(&dir);
+            let keys: Vec<String> = if hot {
+                store = store.with_cache_capacity(1024 * 1024);
+                range
+                    .clone()
+                    .take(10)
+                    .map(|i| format!("key{}", i))
+                    .collect()
+            } else {
+                range.clone().map(|i| format!("key{}", i)).collect()
+            };
+            let name = format!("{}/{}", epoch, if hot { "hot" } else { "cold" });
+            let mut i = 0;
+            group.bench_function(name, |b| {
+                b.iter(|| {
+                    i = (i + 7) % keys.len();
+                    store.get(keys[i].clone()).expect("failed to get")
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+fn remove(c: &mut Criterion) {
+    let dir = temp_dir();
+    let mut store = open(&dir);
+    let mut next = 0u64;
+    c.bench_function("remove", |b| {
+        b.iter_custom(|iters| {
+            let keys: Vec<String> = (next..next + iters).map(|i| format!("key{}", i)).collect();
+            next += iters;
+            for key in &keys {
+                store
+                    .set(key.clone(), "value".to_owned())
+                    .expect("failed to set");
+            }
+
+            let start = Instant::now();
+            for key in keys {
+                store.remove(key).expect("failed to remove");
+            }
+            start.elapsed()
+        })
+    });
+}
+
+fn open_replay(c: &mut Criterion) {
+    let mut group = c.benchmark_group("open");
+    group.sample_size(10);
+    for &keys in &[10_000, 100_000] {
+        let dir = temp_dir();
+        let mut store = open(&dir);
+        populate(&mut store, keys, 100);
+        drop(store);
+
+        group.throughput(Throughput::Elements(keys as u64));
+        group.bench_function(BenchmarkId::from_parameter(keys), |b| {
+            b.iter(|| KvStore::open(dir.path()).expect("failed to open store"))
+        });
+    }
+    group.finish();
+}
+
+fn compact(c: &mut Criterion) {
+    const LIVE_KEYS: usize = 1_000;
+    let mut group = c.benchmark_group("compact");
+    group
+        .sample_size(10)
+        .measurement_time(Duration::from_secs(10));
+    for &value_size in &[16, 256, 4096] {
+        let dir = temp_dir();
+        let mut store = open(&dir);
+        populate(&mut store, LIVE_KEYS, value_size);
+
+        group.throughput(Throughput::Bytes((LIVE_KEYS * value_size) as u64));
+        group.bench_function(BenchmarkId::from_parameter(value_size), |b| {
+            b.iter_custom(|iters| {
+                let mut elapsed = Duration::default();
+                for _ in 0..iters {
+                    // Leave some garbage behind for each compaction to clear out
+                    populate(&mut store, LIVE_KEYS / 10, value_size);
+                    let start = Instant::now();
+                    store.compact().expect("failed to compact");
+                    elapsed += start.elapsed();
+                }
+                elapsed
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, set, get, remove, open_replay, compact);
+criterion_main!(benches);
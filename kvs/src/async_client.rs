@@ -0,0 +1,92 @@
+use crate::resp::{parse_frame, Frame, READ_SIZE};
+use crate::{BadReply, Connect, Disconnected, Error, Result};
+use snafu::ResultExt;
+use std::io;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// A client for [`AsyncRespServer`](crate::AsyncRespServer) or [`RespServer`](crate::RespServer), for use on tokio.
+///
+/// Each client holds one connection and sends one command at a time, so open a client per task to have many commands
+/// in flight at once.
+pub struct AsyncClient {
+    addr: String,
+    stream: TcpStream,
+    // Bytes read past the end of the last reply
+    buf: Vec<u8>,
+}
+
+impl AsyncClient {
+    pub async fn connect(addr: &str) -> Result<AsyncClient> {
+        let stream = TcpStream::connect(addr).await.context(Connect { addr })?;
+        let _ = stream.set_nodelay(true);
+        Ok(AsyncClient {
+            addr: addr.to_owned(),
+            stream,
+            buf: Vec::new(),
+        })
+    }
+
+    pub async fn get(&mut self, key: String) -> Result<Option<String>> {
+        match self.call(vec!["GET".to_owned(), key]).await? {
+            Frame::Bulk(val) => Ok(val),
+            found => Err(self.unexpected(found)),
+        }
+    }
+
+    pub async fn set(&mut self, key: String, val: String) -> Result<()> {
+        match self.call(vec!["SET".to_owned(), key, val]).await? {
+            Frame::Simple(ok) if ok == "OK" => Ok(()),
+            found => Err(self.unexpected(found)),
+        }
+    }
+
+    pub async fn remove(&mut self, key: String) -> Result<()> {
+        match self.call(vec!["DEL".to_owned(), key]).await? {
+            Frame::Integer(1) => Ok(()),
+            Frame::Integer(0) => Err(Error::NotFound),
+            found => Err(self.unexpected(found)),
+        }
+    }
+
+    /// Send a command and wait for its reply.
+    async fn call(&mut self, args: Vec<String>) -> Result<Frame> {
+        let mut out = Vec::new();
+        Frame::Array(args.into_iter().map(|arg| Frame::Bulk(Some(arg))).collect())
+            .write_to(&mut out);
+        let addr = &self.addr;
+        self.stream
+            .write_all(&out)
+            .await
+            .context(Disconnected { addr })?;
+
+        loop {
+            if let Some((frame, len)) = parse_frame(&self.buf, 0).context(Disconnected { addr })? {
+                self.buf.drain(..len);
+                return Ok(frame);
+            }
+            self.buf.reserve(READ_SIZE);
+            let read = self
+                .stream
+                .read_buf(&mut self.buf)
+                .await
+                .context(Disconnected { addr })?;
+            if read == 0 {
+                let e = io::Error::from(io::ErrorKind::UnexpectedEof);
+                return Err(e).context(Disconnected { addr });
+            }
+        }
+    }
+
+    fn unexpected(&self, found: Frame) -> Error {
+        let reply = match found {
+            Frame::Error(message) => message,
+            found => format!("{:?}", found),
+        };
+        BadReply {
+            addr: &self.addr,
+            reply,
+        }
+        .build()
+    }
+}
@@ -0,0 +1,106 @@
+use crate::resp::{answer, sweep, take_commands, Session, READ_SIZE, SWEEP_INTERVAL};
+use crate::{KvStore, Listen, Result, Tokens};
+use snafu::ResultExt;
+use std::io;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task;
+use tokio::time;
+
+/// Serves a store to Redis clients just like [`RespServer`](crate::RespServer), but on tokio, so that thousands of
+/// connections, idle ones especially, can share a few threads.
+///
+/// The store reads and writes files as it carries out commands, so they are run on tokio's blocking pool rather
+/// than holding up the connections sharing a thread. Must be run from within a tokio runtime.
+pub struct AsyncRespServer {
+    store: Arc<Mutex<KvStore>>,
+    tokens: Option<Arc<Tokens>>,
+}
+
+impl AsyncRespServer {
+    pub fn new(store: KvStore) -> AsyncRespServer {
+        AsyncRespServer {
+            store: Arc::new(Mutex::new(store)),
+            tokens: None,
+        }
+    }
+
+    /// Require clients to authenticate, as with [`RespServer::with_tokens`](crate::RespServer::with_tokens).
+    pub fn with_tokens(mut self, tokens: Tokens) -> Self {
+        self.tokens = Some(Arc::new(tokens));
+        self
+    }
+
+    /// Bind to `addr` and serve clients there.
+    pub async fn run(self, addr: &str) -> Result<()> {
+        let listener = TcpListener::bind(addr).await.context(Listen { addr })?;
+        info!("serving RESP on {}", addr);
+        self.serve(listener).await;
+        Ok(())
+    }
+
+    /// Serve clients connecting on `listener`, each in a task of its own. Never returns.
+    pub async fn serve(self, listener: TcpListener) {
+        let store = self.store.clone();
+        tokio::spawn(async move {
+            let mut interval = time::interval(SWEEP_INTERVAL);
+            loop {
+                interval.tick().await;
+                let store = store.clone();
+                let _ = task::spawn_blocking(move || sweep(&store)).await;
+            }
+        });
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    let store = self.store.clone();
+                    let session = Session::new(self.tokens.clone());
+                    tokio::spawn(async move {
+                        if let Err(e) = serve(store, session, stream).await {
+                            debug!("RESP connection closed: {}", e);
+                        }
+                    });
+                }
+                Err(e) => warn!("failed to accept RESP connection: {}", e),
+            }
+        }
+    }
+}
+
+/// Carry out one client's commands until it hangs up or breaks the protocol.
+async fn serve(
+    store: Arc<Mutex<KvStore>>,
+    mut session: Session,
+    mut stream: TcpStream,
+) -> io::Result<()> {
+    let _ = stream.set_nodelay(true);
+    let (mut buf, mut out) = (Vec::new(), Vec::new());
+    loop {
+        buf.reserve(READ_SIZE);
+        if stream.read_buf(&mut buf).await? == 0 {
+            return Ok(());
+        }
+        let (commands, error) = take_commands(&mut buf, session.max_bulk());
+        let done = if commands.is_empty() {
+            // Nothing for the store to do, so nothing to block on
+            answer(&store, &mut session, commands, error, &mut out)
+        } else {
+            let store = store.clone();
+            let (done, answered, authenticated) = task::spawn_blocking(move || {
+                let done = answer(&store, &mut session, commands, error, &mut out);
+                (done, out, session)
+            })
+            .await
+            .map_err(io::Error::other)?;
+            out = answered;
+            session = authenticated;
+            done
+        };
+        stream.write_all(&out).await?;
+        out.clear();
+        if done {
+            return Ok(());
+        }
+    }
+}
@@ -0,0 +1,90 @@
+use crate::{BadConfig, Open, Result};
+use ring::digest::{digest, SHA256};
+use serde::Deserialize;
+use snafu::ResultExt;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+/// What a token lets its holder do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Permission {
+    /// Get and scan keys.
+    Read,
+    /// Anything, including writes.
+    ReadWrite,
+}
+
+/// The tokens a server accepts, each with what it permits. Usually read from a TOML file like this:
+///
+/// ```toml
+/// [tokens]
+/// "a-long-random-secret" = "read-write"
+/// "another-secret" = "read"
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Tokens {
+    tokens: HashMap<String, Permission>,
+}
+
+impl Tokens {
+    /// Read tokens from a TOML file.
+    pub fn load(path: impl AsRef<Path>) -> Result<Tokens> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path).with_context(|| Open { path })?;
+        toml::from_str(&text).context(BadConfig { path })
+    }
+
+    /// Accept `token`, permitting `permission`.
+    pub fn with_token(mut self, token: impl Into<String>, permission: Permission) -> Self {
+        self.tokens.insert(token.into(), permission);
+        self
+    }
+
+    /// What `token` permits, if it's one of these.
+    pub fn check(&self, token: &str) -> Option<Permission> {
+        // Look at every token, comparing every byte of digests that are all the same length, so how long this takes
+        // says nothing about how close a guess was or how long the tokens are
+        let guess = digest(&SHA256, token.as_bytes());
+        self.tokens.iter().fold(None, |found, (known, permission)| {
+            let known = digest(&SHA256, known.as_bytes());
+            if constant_time_eq(known.as_ref(), guess.as_ref()) {
+                Some(*permission)
+            } else {
+                found
+            }
+        })
+    }
+}
+
+/// Only constant time for slices of the same length, such as digests.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_from_toml() {
+        let tokens: Tokens =
+            toml::from_str("[tokens]\nwriter = \"read-write\"\nreader = \"read\"\n").unwrap();
+        assert_eq!(tokens.check("writer"), Some(Permission::ReadWrite));
+        assert_eq!(tokens.check("reader"), Some(Permission::Read));
+        assert_eq!(tokens.check("read"), None);
+        assert_eq!(tokens.check(""), None);
+        assert!(toml::from_str::<Tokens>("[tokens]\nx = \"admin\"\n").is_err());
+    }
+
+    #[test]
+    fn tokens_only_match_exactly() {
+        let tokens = Tokens::default().with_token("secret", Permission::Read);
+        assert_eq!(tokens.check("secret"), Some(Permission::Read));
+        assert_eq!(tokens.check("secre"), None);
+        assert_eq!(tokens.check("secrets"), None);
+        assert_eq!(tokens.check("Secret"), None);
+    }
+}
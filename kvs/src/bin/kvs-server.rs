@@ -0,0 +1,198 @@
+extern crate structopt;
+use human_panic::setup_panic;
+use std::env;
+use std::net::ToSocketAddrs;
+use std::path::PathBuf;
+use structopt::StructOpt;
+use tokio::runtime::Runtime;
+
+use kvs::{
+    AsyncRespServer, Config, Error, HttpServer, KvStore, KvsServer, RaftNode, RespServer, Result,
+    Tokens,
+};
+
+// Exit codes are those of kvs::ErrorCode, the same as kvs uses. Keep EXIT_CODES in step.
+const EXIT_CODES: &str = "EXIT CODES:
+    1    Any failure not covered below
+    6    The logs are corrupt
+    7    Reading or writing the logs failed
+    8    Listening on an address or talking to the rest of the cluster failed
+    9    The config file, TLS certificate or key, tokens file or node id is invalid, or the address would be
+         reachable from other hosts without --insecure";
+
+#[derive(StructOpt, Debug)]
+#[structopt(
+    name = "kvs-server",
+    about = "one node of a replicated kvs cluster, or a single store serving kvs, Redis or HTTP clients",
+    author,
+    after_help = EXIT_CODES
+)]
+struct Opts {
+    /// This node's position in the list of nodes
+    #[structopt(long = "id", required_unless_one = &["LISTEN_ADDR", "RESP_ADDR", "HTTP_ADDR"])]
+    id: Option<u64>,
+
+    /// The address of every node in the cluster, including this one, separated by commas
+    #[structopt(
+        long = "nodes",
+        name = "ADDRS",
+        use_delimiter = true,
+        required_unless_one = &["LISTEN_ADDR", "RESP_ADDR", "HTTP_ADDR"]
+    )]
+    nodes: Vec<String>,
+
+    /// Instead of joining a cluster, serve the store to kvs clients on this address
+    #[structopt(
+        long = "listen",
+        name = "LISTEN_ADDR",
+        conflicts_with_all = &["id", "ADDRS", "snapshot-threshold", "RESP_ADDR", "HTTP_ADDR"]
+    )]
+    listen: Option<String>,
+
+    /// Instead of joining a cluster, serve the store to Redis clients on this address
+    #[structopt(
+        long = "resp",
+        name = "RESP_ADDR",
+        conflicts_with_all = &["id", "ADDRS", "snapshot-threshold", "HTTP_ADDR"]
+    )]
+    resp: Option<String>,
+
+    /// Instead of joining a cluster, serve the store over HTTP on this address
+    #[structopt(
+        long = "http",
+        name = "HTTP_ADDR",
+        conflicts_with_all = &["id", "ADDRS", "snapshot-threshold"]
+    )]
+    http: Option<String>,
+
+    /// Talk TLS to kvs clients, presenting the certificate chain in this PEM file
+    #[structopt(long = "tls-cert", requires_all = &["LISTEN_ADDR", "tls-key"])]
+    tls_cert: Option<PathBuf>,
+
+    /// The private key for --tls-cert, in a PEM file
+    #[structopt(long = "tls-key", requires = "tls-cert")]
+    tls_key: Option<PathBuf>,
+
+    /// Only serve clients that authenticate with one of the tokens in this TOML file, which Redis clients send with
+    /// AUTH and HTTP clients in an Authorization: Bearer header. Cluster nodes can't check tokens, so it can't be
+    /// given with --id
+    #[structopt(long = "tokens", conflicts_with = "id")]
+    tokens: Option<PathBuf>,
+
+    /// Serve clients on an address other hosts can reach without requiring TLS and tokens, which only --listen
+    /// supports, trusting the network to keep others out
+    #[structopt(long = "insecure")]
+    insecure: bool,
+
+    /// Serve Redis clients from a few threads on tokio, rather than a thread per connection
+    #[structopt(long = "async", requires = "RESP_ADDR")]
+    asynchronous: bool,
+
+    /// Keep the node's logs here, rather than wherever the config file says or the current directory
+    #[structopt(short = "f", long = "file", env = "LOG_DIR")]
+    logfile: Option<PathBuf>,
+
+    /// Read the store's settings from this TOML file
+    #[structopt(long = "config", name = "CONFIG", env = "KVS_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// How many entries to apply between snapshots of the store
+    #[structopt(long = "snapshot-threshold")]
+    snapshot_threshold: Option<u64>,
+}
+
+/// Refuse to serve clients on `addr` if other hosts could reach it, unless they must authenticate over TLS or we've
+/// been told to trust the network.
+fn guard(addr: &str, protected: bool, insecure: bool) -> Result<()> {
+    if protected || insecure {
+        return Ok(());
+    }
+    // An address that doesn't resolve is left to fail when bound, which says why
+    let exposed = addr
+        .to_socket_addrs()
+        .is_ok_and(|mut addrs| addrs.any(|resolved| !resolved.ip().is_loopback()));
+    if exposed {
+        return Err(Error::Exposed {
+            addr: addr.to_owned(),
+        });
+    }
+    Ok(())
+}
+
+fn run(opts: Opts) -> Result<()> {
+    let config = match &opts.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    let dir = match opts.logfile.as_ref().or(config.data_dir.as_ref()) {
+        Some(dir) => dir.clone(),
+        None => env::current_dir().expect("invalid cwd"),
+    };
+    if let Some(addr) = opts.listen {
+        let protected = opts.tls_cert.is_some() && opts.tokens.is_some();
+        guard(&addr, protected, opts.insecure)?;
+        let store = KvStore::open(dir)?.with_config(&config);
+        let mut server = KvsServer::new(store);
+        if let (Some(cert), Some(key)) = (&opts.tls_cert, &opts.tls_key) {
+            server = server.with_tls(cert, key)?;
+        }
+        if let Some(path) = &opts.tokens {
+            server = server.with_tokens(Tokens::load(path)?);
+        }
+        let runtime = Runtime::new().expect("failed to start tokio");
+        return runtime.block_on(server.run(&addr));
+    }
+    let tokens = opts.tokens.as_ref().map(Tokens::load).transpose()?;
+    // Tokens alone protect nothing here, as without TLS they cross the network for anyone to read
+    if let Some(addr) = opts.resp {
+        guard(&addr, false, opts.insecure)?;
+        let store = KvStore::open(dir)?.with_config(&config);
+        if opts.asynchronous {
+            let mut server = AsyncRespServer::new(store);
+            if let Some(tokens) = tokens {
+                server = server.with_tokens(tokens);
+            }
+            let runtime = Runtime::new().expect("failed to start tokio");
+            return runtime.block_on(server.run(&addr));
+        }
+        let mut server = RespServer::new(store);
+        if let Some(tokens) = tokens {
+            server = server.with_tokens(tokens);
+        }
+        return server.run(&addr);
+    }
+    if let Some(addr) = opts.http {
+        guard(&addr, false, opts.insecure)?;
+        let mut server = HttpServer::new(KvStore::open(dir)?.with_config(&config));
+        if let Some(tokens) = tokens {
+            server = server.with_tokens(tokens);
+        }
+        return server.run(&addr);
+    }
+
+    let id = opts
+        .id
+        .expect("required without --listen, --resp or --http");
+    // Nodes take requests from clients as well as each other, on the address the rest of the cluster knows them by
+    if let Some(addr) = opts.nodes.get(id as usize) {
+        guard(addr, false, opts.insecure)?;
+    }
+    let mut node = RaftNode::open(id, opts.nodes, dir)?.with_store_config(&config);
+    if let Some(threshold) = opts.snapshot_threshold {
+        node = node.with_snapshot_threshold(threshold);
+    }
+    node.run()
+}
+
+fn main() {
+    setup_panic!();
+    // Settings, RUST_LOG included, may come from a .env file, but it's fine not to have one
+    dotenv::dotenv().ok();
+    env_logger::init();
+
+    let opts = Opts::from_args();
+    if let Err(e) = run(opts) {
+        eprintln!("{}", e);
+        std::process::exit(e.code() as i32);
+    }
+}
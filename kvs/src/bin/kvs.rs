@@ -1,19 +1,307 @@
 extern crate structopt;
 use human_panic::setup_panic;
+use rustyline::completion::{Completer, Pair};
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
+use serde::Deserialize;
+use std::collections::BTreeSet;
 use std::env;
+use std::fs::File;
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::net::TcpListener;
 use std::path::PathBuf;
+use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
 use structopt::StructOpt;
 
-use kvs::{KvStore, Result};
+use kvs::{ClusterClient, Config, Durability, Error, ErrorCode, Follower, KvStore, Report, Result};
+use serde_json::{json, Value};
+
+// How long a follower waits before reconnecting to its leader
+const RECONNECT_DELAY: Duration = Duration::from_millis(500);
+
+// Exit codes, so that scripts can tell failures apart, are those of kvs::ErrorCode. Keep EXIT_CODES in step.
+const EXIT_FAILED: i32 = ErrorCode::Failed as i32;
+const EXIT_CORRUPT: i32 = ErrorCode::Corrupt as i32;
+const EXIT_INVALID: i32 = ErrorCode::Invalid as i32;
+
+const EXIT_CODES: &str = "EXIT CODES, with the kind of error that --output json reports:
+    0                     Success, including `get` of a key that isn't there
+    1    failed           Any other failure
+    2    not_found        The key, namespace or index wasn't found
+    3    key_too_large    The key is longer than --max-key-size
+    4    value_too_large  The value is longer than --max-value-size
+    5    quota_exceeded   The write would go past --max-live-bytes or --max-disk-bytes
+    6    corrupt          The logs are corrupt
+    7    io               Reading or writing the logs failed
+    8    network          Talking to the cluster or a leader failed
+    9    invalid          The request can't be carried out, such as merging without a merge operator, or the config
+                          file is invalid
+
+`batch` carries on past lines that fail, then exits with the code for the first of them. `verify` exits with 6 if it
+finds any problems.";
 
 #[derive(StructOpt, Debug)]
-#[structopt(name = "kvs", about, author)]
+#[structopt(name = "kvs", about, author, after_help = EXIT_CODES)]
 struct Opts {
     #[structopt(subcommand)]
     commands: Option<Kv>,
 
+    /// Keep the logs here, rather than wherever the config file says or the current directory
     #[structopt(short = "f", long = "file", env = "LOG_DIR")]
     logfile: Option<PathBuf>,
+
+    /// Read settings from this TOML file. Flags and environment variables take precedence over it
+    #[structopt(long = "config", name = "CONFIG", env = "KVS_CONFIG")]
+    config: Option<PathBuf>,
+
+    #[structopt(flatten)]
+    tuning: TuningOpts,
+
+    /// Send `set`, `get`, `rm` and `watch` to a kvs-server cluster with these comma separated addresses instead
+    #[structopt(
+        long = "cluster",
+        name = "ADDRS",
+        use_delimiter = true,
+        number_of_values = 1
+    )]
+    cluster: Vec<String>,
+
+    #[structopt(flatten)]
+    limits: LimitOpts,
+
+    /// Print results and errors as `text` or `json`. Errors go to stderr either way
+    #[structopt(long = "output", name = "FORMAT", default_value = "text")]
+    output: Output,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Output {
+    Text,
+    Json,
+}
+
+impl FromStr for Output {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Output, String> {
+        match s {
+            "text" => Ok(Output::Text),
+            "json" => Ok(Output::Json),
+            _ => Err(format!(
+                "unknown output format {}, expected text or json",
+                s
+            )),
+        }
+    }
+}
+
+/// What a command did, to be printed in whichever format was asked for.
+enum Reply {
+    Set { key: String },
+    Got { key: String, value: Option<String> },
+    Removed { key: String },
+    Indexed { name: String },
+    Found { keys: Vec<String> },
+    // Disk usage before and after
+    Compacted { before: u64, after: u64 },
+    Verified(Report),
+    // Already printed as it happened, along with the exit code of the first thing that failed, if any did
+    Streamed { failed: Option<i32> },
+}
+
+impl Reply {
+    fn into_json(self) -> Option<Value> {
+        Some(match self {
+            Reply::Set { key } => json!({ "ok": true, "key": key }),
+            Reply::Got { key, value } => json!({ "ok": true, "key": key, "value": value }),
+            Reply::Removed { key } => json!({ "ok": true, "key": key }),
+            Reply::Indexed { name } => json!({ "ok": true, "index": name }),
+            Reply::Found { keys } => json!({ "ok": true, "keys": keys }),
+            Reply::Compacted { before, after } => {
+                json!({ "ok": true, "before_bytes": before, "after_bytes": after })
+            }
+            Reply::Verified(report) => json!({
+                "ok": report.is_ok(),
+                "logs": report.logs,
+                "records": report.records,
+                "keys": report.keys,
+                "problems": report.problems.iter().map(ToString::to_string).collect::<Vec<_>>(),
+            }),
+            Reply::Streamed { .. } => return None,
+        })
+    }
+
+    /// The exit code for a command that ran but found something wrong.
+    fn failed(&self) -> Option<i32> {
+        match self {
+            Reply::Verified(report) if !report.is_ok() => Some(EXIT_CORRUPT),
+            Reply::Streamed { failed } => *failed,
+            _ => None,
+        }
+    }
+
+    fn print(self, output: Output) {
+        if output == Output::Json {
+            if let Some(printed) = self.into_json() {
+                println!("{}", printed);
+            }
+            return;
+        }
+        match self {
+            Reply::Got { value: Some(v), .. } => print!("{}", v),
+            Reply::Got { value: None, .. } => print!("Key not found"),
+            Reply::Found { keys } => {
+                for key in keys {
+                    println!("{}", key);
+                }
+            }
+            Reply::Compacted { before, after } => {
+                println!("compacted {} bytes of logs to {}", before, after)
+            }
+            Reply::Verified(report) => {
+                for problem in &report.problems {
+                    println!("{}", problem);
+                }
+                println!(
+                    "checked {} records in {} logs and {} keys: {} problems",
+                    report.records,
+                    report.logs,
+                    report.keys,
+                    report.problems.len()
+                );
+            }
+            Reply::Set { .. }
+            | Reply::Removed { .. }
+            | Reply::Indexed { .. }
+            | Reply::Streamed { .. } => {}
+        }
+    }
+}
+
+/// Why a command failed, as reported to whoever ran it.
+struct Failure {
+    code: i32,
+    kind: &'static str,
+    message: String,
+}
+
+impl Failure {
+    fn of(e: &Error) -> Failure {
+        let (code, kind) = classify(e);
+        Failure {
+            code,
+            kind,
+            message: e.to_string(),
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "ok": false,
+            "error": { "kind": self.kind, "code": self.code, "message": self.message },
+        })
+    }
+}
+
+/// How the store is run, overriding the config file.
+#[derive(StructOpt, Debug)]
+struct TuningOpts {
+    /// Start a new log once the current one reaches this many bytes
+    #[structopt(long = "max-log-size", name = "LOG_BYTES", env = "KVS_MAX_LOG_SIZE")]
+    max_log_size: Option<u64>,
+
+    /// Keep up to this many bytes of recently used keys and values in memory
+    #[structopt(long = "cache-size", name = "CACHE_BYTES", env = "KVS_CACHE_SIZE")]
+    cache_size: Option<usize>,
+
+    /// Compact after this many removals or overwrites, or never if zero
+    #[structopt(
+        long = "compaction-threshold",
+        name = "MUTATIONS",
+        env = "KVS_COMPACTION_THRESHOLD"
+    )]
+    compaction_threshold: Option<u64>,
+
+    /// `flush` each write to the operating system, or also `sync` it to disk
+    #[structopt(long = "durability", name = "DURABILITY", env = "KVS_DURABILITY")]
+    durability: Option<Durability>,
+}
+
+impl Opts {
+    /// Open the store, set up by the config file and then by flags and the environment.
+    fn open_store(&self) -> Result<KvStore> {
+        let config = match &self.config {
+            Some(path) => Config::load(path)?,
+            None => Config::default(),
+        };
+        let config = config.overridden_by(Config {
+            data_dir: self.logfile.clone(),
+            max_log_size: self.tuning.max_log_size,
+            cache_size: self.tuning.cache_size,
+            compaction_threshold: self.tuning.compaction_threshold,
+            durability: self.tuning.durability,
+        });
+        let dir = match &config.data_dir {
+            Some(dir) => dir.clone(),
+            None => env::current_dir().expect("invalid cwd"),
+        };
+        let store = KvStore::open(dir)?.with_config(&config);
+        Ok(self.limits.apply(store))
+    }
+}
+
+/// The most the store may hold, in bytes. Writes past any of them fail with their own exit code.
+#[derive(StructOpt, Debug)]
+struct LimitOpts {
+    #[structopt(long = "max-key-size", name = "KEY_BYTES", env = "KVS_MAX_KEY_SIZE")]
+    key_size: Option<u64>,
+
+    #[structopt(
+        long = "max-value-size",
+        name = "VALUE_BYTES",
+        env = "KVS_MAX_VALUE_SIZE"
+    )]
+    value_size: Option<u64>,
+
+    /// Limit the keys and values of every live key, together
+    #[structopt(
+        long = "max-live-bytes",
+        name = "LIVE_BYTES",
+        env = "KVS_MAX_LIVE_BYTES"
+    )]
+    live_bytes: Option<u64>,
+
+    /// Limit the logs kept on disk, together
+    #[structopt(
+        long = "max-disk-bytes",
+        name = "DISK_BYTES",
+        env = "KVS_MAX_DISK_BYTES"
+    )]
+    disk_bytes: Option<u64>,
+}
+
+impl LimitOpts {
+    fn apply(&self, mut store: KvStore) -> KvStore {
+        if let Some(bytes) = self.key_size {
+            store = store.with_max_key_size(bytes);
+        }
+        if let Some(bytes) = self.value_size {
+            store = store.with_max_value_size(bytes);
+        }
+        if let Some(bytes) = self.live_bytes {
+            store = store.with_max_live_bytes(bytes);
+        }
+        if let Some(bytes) = self.disk_bytes {
+            store = store.with_max_disk_bytes(bytes);
+        }
+        store
+    }
 }
 
 #[derive(StructOpt, Debug)]
@@ -24,6 +312,31 @@ enum Kv {
     Get(GetOpts),
     #[structopt(name = "rm")]
     Rm(RmOpts),
+    /// Serve followers while applying `set KEY VALUE`, `get KEY` and `rm KEY` commands read from stdin
+    #[structopt(name = "leader")]
+    Leader(LeaderOpts),
+    /// Keep the store in sync with a leader, reconnecting whenever the connection drops
+    #[structopt(name = "follow")]
+    Follow(FollowOpts),
+    /// Print `set KEY VALUE` or `rm KEY` for each change to keys starting with PREFIX, until the connection drops
+    #[structopt(name = "watch")]
+    Watch(WatchOpts),
+    /// Index keys starting with PREFIX by the JSON field at PATH in their values, such as `$.email`
+    #[structopt(name = "index")]
+    Index(IndexOpts),
+    /// Print the keys whose values hold VALUE at the path an index was declared with, one per line
+    #[structopt(name = "find")]
+    Find(FindOpts),
+    /// Apply `set KEY VALUE`, `get KEY` and `rm KEY` lines, or JSON lines like `{"op": "set", "key": "k", "value":
+    /// "v"}`, from FILE or stdin, printing a result for each
+    #[structopt(name = "batch")]
+    Batch(BatchOpts),
+    /// Rewrite the logs to hold only live keys, printing how much space they took up before and after
+    #[structopt(name = "compact")]
+    Compact,
+    /// Read every record in every log and check the index against them, printing each problem found
+    #[structopt(name = "verify")]
+    Verify,
 }
 
 #[derive(StructOpt, Debug)]
@@ -47,40 +360,496 @@ struct RmOpts {
     key: String,
 }
 
-fn run(cmd: Kv, logf: impl Into<PathBuf>) -> Result<()> {
-    let mut store = KvStore::open(logf)?;
+#[derive(StructOpt, Debug)]
+struct LeaderOpts {
+    #[structopt(long = "listen", name = "ADDR", default_value = "127.0.0.1:4001")]
+    listen: String,
+}
+
+#[derive(StructOpt, Debug)]
+struct FollowOpts {
+    #[structopt(name = "LEADER")]
+    leader: String,
+}
+
+#[derive(StructOpt, Debug)]
+struct WatchOpts {
+    #[structopt(name = "PREFIX", default_value = "")]
+    prefix: String,
+}
+
+#[derive(StructOpt, Debug)]
+struct IndexOpts {
+    #[structopt(name = "NAME")]
+    name: String,
+
+    #[structopt(name = "PREFIX")]
+    prefix: String,
+
+    #[structopt(name = "PATH")]
+    path: String,
+}
+
+#[derive(StructOpt, Debug)]
+struct FindOpts {
+    #[structopt(name = "INDEX")]
+    index: String,
 
-    match cmd {
+    #[structopt(name = "VALUE")]
+    value: String,
+}
+
+#[derive(StructOpt, Debug)]
+struct BatchOpts {
+    /// Read from this file rather than stdin
+    #[structopt(name = "FILE")]
+    file: Option<PathBuf>,
+}
+
+/// One line of a batch.
+#[derive(Deserialize, Debug)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum BatchOp {
+    Set { key: String, value: String },
+    Get { key: String },
+    Rm { key: String },
+}
+
+impl FromStr for BatchOp {
+    type Err = String;
+
+    fn from_str(line: &str) -> std::result::Result<BatchOp, String> {
+        if line.starts_with('{') {
+            return serde_json::from_str(line).map_err(|e| format!("bad JSON command: {}", e));
+        }
+        match line.splitn(3, ' ').collect::<Vec<_>>().as_slice() {
+            ["set", key, value] => Ok(BatchOp::Set {
+                key: key.to_string(),
+                value: value.to_string(),
+            }),
+            ["get", key] => Ok(BatchOp::Get {
+                key: key.to_string(),
+            }),
+            ["rm", key] => Ok(BatchOp::Rm {
+                key: key.to_string(),
+            }),
+            _ => Err(format!("unknown command: {}", line)),
+        }
+    }
+}
+
+/// Apply each line of a batch to the store in turn, printing what became of it. Blank lines and lines starting with
+/// `#` are skipped.
+fn batch(store: &mut KvStore, opts: BatchOpts, output: Output) -> Result<Reply> {
+    let input: Box<dyn BufRead> = match opts.file {
+        Some(path) => Box::new(io::BufReader::new(
+            File::open(&path).map_err(|source| Error::Open { source, path })?,
+        )),
+        None => Box::new(io::stdin().lock()),
+    };
+
+    let mut failed = None;
+    for (n, line) in input.lines().map_while(io::Result::ok).enumerate() {
+        let n = n + 1;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let result = line
+            .parse()
+            .map_err(|message| Failure {
+                code: EXIT_INVALID,
+                kind: "invalid",
+                message,
+            })
+            .and_then(|op| {
+                let reply = match op {
+                    BatchOp::Set { key, value } => {
+                        store.set(key.clone(), value).map(|()| Reply::Set { key })
+                    }
+                    BatchOp::Get { key } => store
+                        .get(key.clone())
+                        .map(|value| Reply::Got { key, value }),
+                    BatchOp::Rm { key } => {
+                        store.remove(key.clone()).map(|()| Reply::Removed { key })
+                    }
+                };
+                reply.map_err(|e| Failure::of(&e))
+            });
+        if let (None, Err(failure)) = (failed, &result) {
+            failed = Some(failure.code);
+        }
+
+        match (output, result) {
+            (Output::Json, Ok(reply)) => {
+                let mut printed = reply
+                    .into_json()
+                    .expect("batch commands reply with something");
+                printed["line"] = json!(n);
+                println!("{}", printed);
+            }
+            (Output::Json, Err(failure)) => {
+                let mut printed = failure.to_json();
+                printed["line"] = json!(n);
+                println!("{}", printed);
+            }
+            (Output::Text, Ok(Reply::Got { value: Some(v), .. })) => println!("{}", v),
+            (Output::Text, Ok(Reply::Got { value: None, .. })) => println!("Key not found"),
+            (Output::Text, Ok(_)) => println!("ok"),
+            (Output::Text, Err(failure)) => eprintln!("line {}: {}", n, failure.message),
+        }
+    }
+    Ok(Reply::Streamed { failed })
+}
+
+/// Apply commands from stdin one line at a time, printing the results. Serves followers until killed.
+fn lead(mut store: KvStore, opts: LeaderOpts) -> Result<()> {
+    let listener = TcpListener::bind(&opts.listen).map_err(|source| Error::Listen {
+        source,
+        addr: opts.listen.clone(),
+    })?;
+    store.serve_followers(listener);
+
+    for line in io::stdin().lock().lines().map_while(io::Result::ok) {
+        let words: Vec<&str> = line.splitn(3, ' ').collect();
+        let result = match words.as_slice() {
+            ["set", key, value] => store.set(key.to_string(), value.to_string()),
+            ["get", key] => store.get(key.to_string()).map(|v| match v {
+                Some(v) => println!("{}", v),
+                None => println!("Key not found"),
+            }),
+            ["rm", key] => store.remove(key.to_string()),
+            [""] => Ok(()),
+            _ => {
+                eprintln!("unknown command: {}", line);
+                Ok(())
+            }
+        };
+        if let Err(e) = result {
+            eprintln!("{}", e);
+        }
+    }
+    loop {
+        thread::park();
+    }
+}
+
+/// Follow a leader forever, announcing each time the follower catches up.
+fn follow(store: KvStore, opts: FollowOpts) -> Result<()> {
+    let mut follower = Follower::new(store)?;
+    loop {
+        match follower.connect(&opts.leader) {
+            Ok(()) => loop {
+                let caught_up = follower.caught_up();
+                if let Err(e) = follower.step() {
+                    eprintln!("{}", e);
+                    break;
+                }
+                if !caught_up && follower.caught_up() {
+                    match follower.position() {
+                        Some(position) => println!("caught up at {}", position),
+                        None => println!("caught up"),
+                    }
+                }
+            },
+            Err(e) => eprintln!("{}", e),
+        }
+        thread::sleep(RECONNECT_DELAY);
+    }
+}
+
+/// Carry out a command against the cluster's leader, wherever it is.
+fn run_remote(cmd: Kv, nodes: Vec<String>, output: Output) -> Result<Reply> {
+    let mut client = ClusterClient::new(nodes);
+    let reply = match cmd {
         Kv::Set(opts) => {
-            store.set(opts.key, opts.value)?;
+            client.set(opts.key.clone(), opts.value)?;
+            Reply::Set { key: opts.key }
         }
-        Kv::Get(opts) => {
-            match store.get(opts.key)? {
-                Some(v) => print!("{}", v),
-                None => print!("Key not found"),
-            };
+        Kv::Get(opts) => Reply::Got {
+            value: client.get(opts.key.clone())?,
+            key: opts.key,
+        },
+        Kv::Rm(opts) => {
+            client.remove(opts.key.clone())?;
+            Reply::Removed { key: opts.key }
+        }
+        Kv::Watch(opts) => {
+            let mut out = io::stdout();
+            for change in client.watch(opts.prefix)? {
+                let printed = match (output, change.val) {
+                    (Output::Json, val) => {
+                        writeln!(out, "{}", json!({ "key": change.key, "value": val }))
+                    }
+                    (Output::Text, Some(val)) => writeln!(out, "set {} {}", change.key, val),
+                    (Output::Text, None) => writeln!(out, "rm {}", change.key),
+                };
+                // Whoever was reading has gone away
+                if printed.is_err() {
+                    return Ok(Reply::Streamed { failed: None });
+                }
+            }
+            return Err(Error::WatchLost);
         }
+        Kv::Leader(_)
+        | Kv::Follow(_)
+        | Kv::Index(_)
+        | Kv::Find(_)
+        | Kv::Batch(_)
+        | Kv::Compact
+        | Kv::Verify => {
+            eprintln!("--cluster only applies to set, get, rm and watch");
+            std::process::exit(EXIT_FAILED);
+        }
+    };
+    Ok(reply)
+}
+
+fn run(cmd: Kv, mut store: KvStore, output: Output) -> Result<Reply> {
+    let reply = match cmd {
+        Kv::Set(opts) => {
+            store.set(opts.key.clone(), opts.value)?;
+            Reply::Set { key: opts.key }
+        }
+        Kv::Get(opts) => Reply::Got {
+            value: store.get(opts.key.clone())?,
+            key: opts.key,
+        },
         Kv::Rm(opts) => {
-            store.remove(opts.key)?;
+            store.remove(opts.key.clone())?;
+            Reply::Removed { key: opts.key }
+        }
+        Kv::Index(opts) => {
+            store.create_index(&opts.name, &opts.prefix, &opts.path)?;
+            Reply::Indexed { name: opts.name }
+        }
+        Kv::Find(opts) => Reply::Found {
+            keys: store.find_by_index(&opts.index, &opts.value)?,
+        },
+        Kv::Batch(opts) => batch(&mut store, opts, output)?,
+        Kv::Compact => {
+            let before = store.stats().disk_bytes;
+            store.compact()?;
+            Reply::Compacted {
+                before,
+                after: store.stats().disk_bytes,
+            }
+        }
+        Kv::Verify => Reply::Verified(store.verify()?),
+        Kv::Leader(opts) => {
+            lead(store, opts)?;
+            Reply::Streamed { failed: None }
+        }
+        Kv::Follow(opts) => {
+            follow(store, opts)?;
+            Reply::Streamed { failed: None }
+        }
+        Kv::Watch(_) => {
+            eprintln!("watch needs a kvs-server cluster to watch, given with --cluster");
+            std::process::exit(EXIT_FAILED);
+        }
+    };
+    Ok(reply)
+}
+
+// What the interactive shell understands, for completion and `help`
+const SHELL_COMMANDS: &[(&str, &str)] = &[
+    ("set", "set KEY VALUE"),
+    ("get", "get KEY"),
+    ("rm", "rm KEY"),
+    ("scan", "scan [PREFIX]"),
+    ("stats", "stats"),
+    ("compact", "compact"),
+    ("help", "help"),
+    ("exit", "exit"),
+];
+
+/// Completes the shell's commands, and then the store's keys.
+struct ShellHelper {
+    keys: BTreeSet<String>,
+}
+
+impl Completer for ShellHelper {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let line = &line[..pos];
+        let start = line.rfind(' ').map_or(0, |space| space + 1);
+        let word = &line[start..];
+        let pair = |s: &str| Pair {
+            display: s.to_owned(),
+            replacement: s.to_owned(),
+        };
+        let candidates = if start == 0 {
+            SHELL_COMMANDS
+                .iter()
+                .map(|(cmd, _)| *cmd)
+                .filter(|cmd| cmd.starts_with(word))
+                .map(pair)
+                .collect()
+        } else if line[..start].trim_end().contains(' ') {
+            // Values aren't keys
+            Vec::new()
+        } else {
+            self.keys
+                .range(word.to_owned()..)
+                .take_while(|key| key.starts_with(word))
+                .map(|key| pair(key))
+                .collect()
+        };
+        Ok((start, candidates))
+    }
+}
+
+impl Hinter for ShellHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ShellHelper {}
+
+impl Validator for ShellHelper {}
+
+impl Helper for ShellHelper {}
+
+/// Read commands from the terminal and apply them to a store opened once, until told to exit.
+fn shell(mut store: KvStore) -> Result<()> {
+    let keys = store.namespace("").keys().into_iter().collect();
+    // Reported like any other failure to open a file, so it gets the same exit code
+    let mut editor: Editor<ShellHelper, DefaultHistory> =
+        Editor::new().map_err(|e| Error::Open {
+            source: match e {
+                ReadlineError::Io(source) => source,
+                e => io::Error::other(e),
+            },
+            path: PathBuf::from("stdin"),
+        })?;
+    editor.set_helper(Some(ShellHelper { keys }));
+    let history = env::var_os("HOME").map(|home| PathBuf::from(home).join(".kvs_history"));
+    if let Some(history) = &history {
+        // There's no history the first time round
+        let _ = editor.load_history(history);
+    }
+
+    loop {
+        let line = match editor.readline("kvs> ") {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(_) => break,
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let _ = editor.add_history_entry(line.as_str());
+
+        let words: Vec<&str> = line.trim().splitn(3, ' ').collect();
+        let keys = &mut editor.helper_mut().expect("set above").keys;
+        let result =
+            match words.as_slice() {
+                ["set", key, value] => store.set(key.to_string(), value.to_string()).map(|()| {
+                    keys.insert(key.to_string());
+                }),
+                ["get", key] => store.get(key.to_string()).map(|v| match v {
+                    Some(v) => println!("{}", v),
+                    None => println!("Key not found"),
+                }),
+                ["rm", key] => store.remove(key.to_string()).map(|()| {
+                    keys.remove(*key);
+                }),
+                ["scan"] | ["scan", _] => store
+                    .snapshot_read()
+                    .scan(words.get(1).unwrap_or(&""))
+                    .map(|pairs| {
+                        for (key, val) in pairs {
+                            println!("{} {}", key, val);
+                        }
+                    }),
+                ["stats"] => {
+                    let stats = store.stats();
+                    println!("keys: {}", stats.keys);
+                    println!("epoch: {}", stats.epoch);
+                    println!("live bytes: {}", stats.live_bytes);
+                    println!("disk bytes: {}", stats.disk_bytes);
+                    println!(
+                        "cache: {} bytes, {} hits, {} misses",
+                        stats.cache_size, stats.cache_hits, stats.cache_misses
+                    );
+                    Ok(())
+                }
+                ["compact"] => store.compact(),
+                ["help"] => {
+                    for (_, usage) in SHELL_COMMANDS {
+                        println!("{}", usage);
+                    }
+                    Ok(())
+                }
+                ["exit"] | ["quit"] => break,
+                _ => {
+                    eprintln!("unknown command: {}, try help", line.trim());
+                    Ok(())
+                }
+            };
+        if let Err(e) = result {
+            eprintln!("{}", e);
+        }
+    }
+
+    if let Some(history) = &history {
+        if let Err(e) = editor.save_history(history) {
+            eprintln!("couldn't save history to {}: {}", history.display(), e);
         }
     }
     Ok(())
 }
 
+/// Sort an error into one of the documented exit codes, along with a name for it in JSON output.
+fn classify(e: &Error) -> (i32, &'static str) {
+    let code = e.code();
+    (code as i32, code.name())
+}
+
 fn main() {
     setup_panic!();
 
-    let opts = Opts::from_args();
-    let logf = opts
-        .logfile
-        .unwrap_or(env::current_dir().expect("invalid cwd"));
-    if let Some(cmd) = opts.commands {
-        if let Err(e) = run(cmd, logf) {
-            println!("{}", e);
-            std::process::exit(1);
+    // Settings may come from a .env file, but it's fine not to have one
+    dotenv::dotenv().ok();
+    let mut opts = Opts::from_args();
+    if let Some(cmd) = opts.commands.take() {
+        let result = if opts.cluster.is_empty() {
+            opts.open_store()
+                .and_then(|store| run(cmd, store, opts.output))
+        } else {
+            run_remote(cmd, opts.cluster, opts.output)
+        };
+        match result {
+            Ok(reply) => {
+                let failed = reply.failed();
+                reply.print(opts.output);
+                if let Some(code) = failed {
+                    std::process::exit(code);
+                }
+            }
+            Err(e) => {
+                let failure = Failure::of(&e);
+                match opts.output {
+                    Output::Text => eprintln!("{}", failure.message),
+                    Output::Json => eprintln!("{}", failure.to_json()),
+                }
+                std::process::exit(failure.code);
+            }
+        }
+    } else if io::stdin().is_terminal() && opts.cluster.is_empty() {
+        let result = opts.open_store().and_then(shell);
+        if let Err(e) = result {
+            eprintln!("{}", e);
+            std::process::exit(classify(&e).0);
         }
     } else {
         eprintln!("missing command!");
-        std::process::exit(1);
+        std::process::exit(EXIT_FAILED);
     }
 }
@@ -0,0 +1,115 @@
+use std::collections::{BTreeMap, HashMap};
+
+struct CacheEntry {
+    val: String,
+    // When this entry was last touched. Doubles as its key in the recency list.
+    tick: u64,
+}
+
+/// A least-recently-used cache of values, bounded by the total size of the keys and values it holds.
+///
+/// Keys are cached separately for each namespace.
+pub(crate) struct ValueCache {
+    capacity: usize,
+    size: usize,
+    tick: u64,
+    entries: HashMap<String, HashMap<String, CacheEntry>>,
+    // Namespaces and keys ordered from least to most recently used
+    recency: BTreeMap<u64, (String, String)>,
+    pub(crate) hits: u64,
+    pub(crate) misses: u64,
+}
+
+impl ValueCache {
+    pub(crate) fn new(capacity: usize) -> Self {
+        ValueCache {
+            capacity,
+            size: 0,
+            tick: 0,
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// The number of bytes currently held by the cache.
+    pub(crate) fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Look up a value, marking it as recently used and counting the hit or miss.
+    pub(crate) fn get(&mut self, ns: &str, key: &str) -> Option<&String> {
+        self.tick += 1;
+        match self.entries.get_mut(ns).and_then(|keys| keys.get_mut(key)) {
+            Some(entry) => {
+                let key = self
+                    .recency
+                    .remove(&entry.tick)
+                    .expect("cache entry missing from recency list");
+                entry.tick = self.tick;
+                self.recency.insert(self.tick, key);
+                self.hits += 1;
+                Some(&entry.val)
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Cache a value, evicting the least recently used entries until it fits.
+    ///
+    /// Values larger than the whole cache are not stored, but still evict any stale entry for the key.
+    pub(crate) fn insert(&mut self, ns: &str, key: String, val: String) {
+        self.remove(ns, &key);
+        let cost = key.len() + val.len();
+        if cost > self.capacity {
+            return;
+        }
+        while self.size + cost > self.capacity {
+            let (_, (oldest_ns, oldest)) = self
+                .recency
+                .iter()
+                .next()
+                .expect("cache over capacity while empty");
+            let (oldest_ns, oldest) = (oldest_ns.clone(), oldest.clone());
+            self.remove(&oldest_ns, &oldest);
+        }
+
+        self.tick += 1;
+        self.size += cost;
+        self.recency.insert(self.tick, (ns.to_owned(), key.clone()));
+        self.entries.entry(ns.to_owned()).or_default().insert(
+            key,
+            CacheEntry {
+                val,
+                tick: self.tick,
+            },
+        );
+    }
+
+    /// Drop the value cached for a key, if any.
+    pub(crate) fn remove(&mut self, ns: &str, key: &str) {
+        let keys = match self.entries.get_mut(ns) {
+            Some(keys) => keys,
+            None => return,
+        };
+        if let Some(entry) = keys.remove(key) {
+            self.recency.remove(&entry.tick);
+            self.size -= key.len() + entry.val.len();
+        }
+        if keys.is_empty() {
+            self.entries.remove(ns);
+        }
+    }
+
+    /// Drop every value cached for a namespace.
+    pub(crate) fn remove_namespace(&mut self, ns: &str) {
+        for (key, entry) in self.entries.remove(ns).unwrap_or_default() {
+            self.recency.remove(&entry.tick);
+            self.size -= key.len() + entry.val.len();
+        }
+    }
+}
@@ -0,0 +1,157 @@
+use crate::logfile::LogFile;
+use crate::storage::Storage;
+use crate::{ChangesExpired, Command, Deser, ListDir, Position, ReadChanges, Result};
+use bson::{Bson, Document};
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+use std::collections::VecDeque;
+use std::io;
+use std::sync::Arc;
+
+/// The name of the file recording which logs the last compaction wrote.
+pub(crate) const COMPACTED: &str = "compacted";
+
+/// The epochs holding the output of a compaction, which are copies of values rather than changes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Compacted {
+    pub(crate) from: u64,
+    pub(crate) until: u64,
+}
+
+impl Compacted {
+    pub(crate) fn holds(&self, epoch: u64) -> bool {
+        self.from <= epoch && epoch < self.until
+    }
+
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        match bson::to_bson(self) {
+            Ok(Bson::Document(doc)) => doc.to_writer(&mut bytes),
+            other => unreachable!("compacted epochs encoded as {:?}", other),
+        }
+        .expect("writing to memory can't fail");
+        bytes
+    }
+
+    pub(crate) fn decode(mut bytes: &[u8]) -> Result<Compacted> {
+        let doc = Document::from_reader(&mut bytes).context(Deser { offset: 0u64 })?;
+        bson::from_bson(Bson::Document(doc)).context(Deser { offset: 0u64 })
+    }
+}
+
+/// A log still to be read for changes.
+struct Source {
+    epoch: u64,
+    retired: bool,
+    // Where to stop reading, since the store may still be writing to it
+    until: u64,
+}
+
+/// The commands recorded in a store's logs after some position, oldest first. See
+/// [`KvStore::changes_since`](crate::KvStore::changes_since).
+///
+/// Logs are read one at a time as the iterator reaches them. Iteration stops after the first error.
+pub struct Changes {
+    storage: Arc<dyn Storage>,
+    after: Option<Position>,
+    sources: VecDeque<Source>,
+    read: VecDeque<(Position, Command)>,
+}
+
+impl Changes {
+    /// Plan to read everything after `after` and before `end`, from whichever logs still hold changes.
+    pub(crate) fn new(
+        storage: Arc<dyn Storage>,
+        after: Option<Position>,
+        compacted: Option<Compacted>,
+        end: Position,
+    ) -> Result<Changes> {
+        let retired = storage.retired().with_context(|| ListDir {
+            path: storage.path(),
+        })?;
+        let live = storage.epochs().with_context(|| ListDir {
+            path: storage.path(),
+        })?;
+        let retired = retired.into_iter().map(|epoch| (epoch, true));
+        let live = live
+            .into_iter()
+            .filter(|epoch| !compacted.is_some_and(|c| c.holds(*epoch)))
+            .map(|epoch| (epoch, false));
+        let mut sources: Vec<Source> = retired
+            .chain(live)
+            .filter(|(epoch, _)| *epoch <= end.epoch)
+            .map(|(epoch, retired)| Source {
+                epoch,
+                retired,
+                until: if epoch == end.epoch {
+                    end.offset
+                } else {
+                    u64::MAX
+                },
+            })
+            .collect();
+        sources.sort_unstable_by_key(|source| source.epoch);
+
+        // Changes from before the oldest log we have are gone, unless all that's missing is compacted copies
+        let from = after.map_or(0, |after| after.epoch);
+        let oldest = sources.first().map_or(end.epoch, |source| source.epoch);
+        if from < oldest && !compacted.is_some_and(|c| c.holds(from)) {
+            return ChangesExpired { epoch: from }.fail();
+        }
+        sources.retain(|source| source.epoch >= from);
+
+        Ok(Changes {
+            storage,
+            after,
+            sources: sources.into(),
+            read: VecDeque::new(),
+        })
+    }
+
+    /// Read the next log's changes.
+    fn read_next(&mut self, source: Source) -> Result<()> {
+        let storage = self.storage.as_ref();
+        let opened = if source.retired {
+            LogFile::open_retired(source.epoch, storage)
+        } else {
+            // Compaction may have set it aside since we started
+            match LogFile::open(source.epoch, storage) {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    LogFile::open_retired(source.epoch, storage)
+                }
+                opened => opened,
+            }
+        };
+        let mut log = opened.context(ReadChanges {
+            epoch: source.epoch,
+        })?;
+
+        let after = self.after;
+        let read = &mut self.read;
+        log.read_until(source.until, |cmd, offset| {
+            let position = Position {
+                epoch: source.epoch,
+                offset,
+            };
+            if after.is_none_or(|after| position > after) {
+                read.push_back((position, cmd));
+            }
+            Ok(())
+        })
+    }
+}
+
+impl Iterator for Changes {
+    type Item = Result<(Position, Command)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.read.is_empty() {
+            let source = self.sources.pop_front()?;
+            if let Err(e) = self.read_next(source) {
+                self.sources.clear();
+                return Some(Err(e));
+            }
+        }
+        self.read.pop_front().map(Ok)
+    }
+}
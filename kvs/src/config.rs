@@ -0,0 +1,51 @@
+use crate::{BadConfig, Durability, Open, Result};
+use serde::Deserialize;
+use snafu::ResultExt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Settings for a store, usually read from a TOML file like this:
+///
+/// ```toml
+/// data_dir = "/var/lib/kvs"
+/// max_log_size = 10000000
+/// cache_size = 1048576
+/// compaction_threshold = 1000
+/// durability = "sync"
+/// ```
+///
+/// Anything left out keeps the store's default. See [`KvStore::with_config`](crate::KvStore::with_config).
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Where to keep the logs.
+    pub data_dir: Option<PathBuf>,
+    /// See [`KvStore::with_max_size`](crate::KvStore::with_max_size).
+    pub max_log_size: Option<u64>,
+    /// See [`KvStore::with_cache_capacity`](crate::KvStore::with_cache_capacity).
+    pub cache_size: Option<usize>,
+    /// See [`KvStore::with_compaction_threshold`](crate::KvStore::with_compaction_threshold).
+    pub compaction_threshold: Option<u64>,
+    /// See [`KvStore::with_durability`](crate::KvStore::with_durability).
+    pub durability: Option<Durability>,
+}
+
+impl Config {
+    /// Read settings from a TOML file.
+    pub fn load(path: impl AsRef<Path>) -> Result<Config> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path).with_context(|| Open { path })?;
+        toml::from_str(&text).context(BadConfig { path })
+    }
+
+    /// Take each setting from `overrides` where it has one, and from this config otherwise.
+    pub fn overridden_by(self, overrides: Config) -> Config {
+        Config {
+            data_dir: overrides.data_dir.or(self.data_dir),
+            max_log_size: overrides.max_log_size.or(self.max_log_size),
+            cache_size: overrides.cache_size.or(self.cache_size),
+            compaction_threshold: overrides.compaction_threshold.or(self.compaction_threshold),
+            durability: overrides.durability.or(self.durability),
+        }
+    }
+}
@@ -0,0 +1,284 @@
+use crate::{Error, KvStore, Listen, Permission, Result, Tokens};
+use percent_encoding::percent_decode_str;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use snafu::ResultExt;
+use std::io::Read;
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+// Refuse request bodies longer than this rather than reading them into memory
+const MAX_BODY: u64 = 64 * 1024 * 1024;
+
+/// Serves a store over HTTP, with JSON bodies:
+///
+/// - `GET /keys/{key}` answers `{"key": ..., "value": ...}`, or 404 if there is no such key.
+/// - `PUT /keys/{key}` sets the key to the `value` in a body like `{"value": ...}`.
+/// - `DELETE /keys/{key}` removes the key, or answers 404 if there is no such key.
+/// - `GET /keys?prefix={prefix}` answers `{"keys": [{"key": ..., "value": ...}, ...]}` in order of key, for every key
+///   starting with the prefix, or every key without one.
+/// - `GET /stats` answers with the store's [`Stats`](crate::Stats).
+/// - `POST /compact` compacts the store and answers `{"before": ..., "after": ...}`, its size on disk before and after.
+///
+/// Keys in paths and queries are percent-decoded. Failures answer `{"error": message}` with a fitting status code.
+///
+/// By default anyone who can connect may do anything. See [`with_tokens`](HttpServer::with_tokens).
+pub struct HttpServer {
+    store: Arc<Mutex<KvStore>>,
+    tokens: Option<Arc<Tokens>>,
+}
+
+impl HttpServer {
+    pub fn new(store: KvStore) -> HttpServer {
+        HttpServer {
+            store: Arc::new(Mutex::new(store)),
+            tokens: None,
+        }
+    }
+
+    /// Answer 401 to every request without an `Authorization: Bearer` header holding one of `tokens`, and 403 to
+    /// writes whose token only permits `GET`s.
+    pub fn with_tokens(mut self, tokens: Tokens) -> Self {
+        self.tokens = Some(Arc::new(tokens));
+        self
+    }
+
+    /// Bind to `addr` and serve clients there.
+    pub fn run(self, addr: &str) -> Result<()> {
+        let listener = TcpListener::bind(addr).context(Listen { addr })?;
+        info!("serving HTTP on {}", addr);
+        self.serve(listener);
+        Ok(())
+    }
+
+    /// Serve requests arriving on `listener`, each on its own thread. Only returns once the listener stops accepting
+    /// connections.
+    pub fn serve(self, listener: TcpListener) {
+        let server = match Server::from_listener(listener, None) {
+            Ok(server) => server,
+            Err(e) => {
+                error!("failed to serve HTTP: {}", e);
+                return;
+            }
+        };
+        for request in server.incoming_requests() {
+            let (store, tokens) = (self.store.clone(), self.tokens.clone());
+            thread::spawn(move || {
+                let mut request = request;
+                // Nothing reaches the store without a token permitting it
+                let (status, body) = match authorize(tokens.as_deref(), &request) {
+                    Ok(()) => handle(&store, &mut request),
+                    Err(e) => failure(&e),
+                };
+                // Nothing to say is said with an empty body, not `null`
+                let body = match body {
+                    Value::Null => String::new(),
+                    body => body.to_string(),
+                };
+                let mut response = Response::from_string(body)
+                    .with_status_code(status)
+                    .with_header(json_content());
+                if status == 401 {
+                    response = response.with_header(
+                        Header::from_bytes(&b"WWW-Authenticate"[..], &b"Bearer"[..])
+                            .expect("a valid header"),
+                    );
+                }
+                if let Err(e) = request.respond(response) {
+                    debug!("failed to answer HTTP request: {}", e);
+                }
+            });
+        }
+    }
+}
+
+/// Check the request's bearer token permits what it asks for, if tokens are required.
+fn authorize(tokens: Option<&Tokens>, request: &Request) -> Result<()> {
+    let tokens = match tokens {
+        Some(tokens) => tokens,
+        None => return Ok(()),
+    };
+    let needed = match request.method() {
+        Method::Get | Method::Head => Permission::Read,
+        _ => Permission::ReadWrite,
+    };
+    let permission = request
+        .headers()
+        .iter()
+        .find(|header| header.field.equiv("Authorization"))
+        .and_then(|header| header.value.as_str().split_once(' '))
+        .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("Bearer"))
+        .and_then(|(_, token)| tokens.check(token.trim()));
+    match permission {
+        None => Err(Error::Unauthorized),
+        Some(permission) if permission < needed => Err(Error::ReadOnly),
+        Some(_) => Ok(()),
+    }
+}
+
+/// The body a value is set with.
+#[derive(Deserialize)]
+struct Put {
+    value: String,
+}
+
+/// Carry out a request, returning the status and body to answer it with.
+fn handle(store: &Mutex<KvStore>, request: &mut Request) -> (u16, Value) {
+    let url = request.url().to_owned();
+    let (path, query) = match url.split_once('?') {
+        Some((path, query)) => (path, query),
+        None => (url.as_str(), ""),
+    };
+    let method = request.method().clone();
+
+    if path == "/keys" {
+        return match method {
+            Method::Get => {
+                let prefix = query_param(query, "prefix").unwrap_or_default();
+                let scanned = store.lock().unwrap().snapshot_read().scan(&prefix);
+                respond(scanned.map(|pairs| {
+                    let keys: Vec<Value> = pairs
+                        .into_iter()
+                        .map(|(key, value)| json!({ "key": key, "value": value }))
+                        .collect();
+                    (200, json!({ "keys": keys }))
+                }))
+            }
+            _ => not_allowed(),
+        };
+    }
+    if let Some(key) = path.strip_prefix("/keys/") {
+        let key = match percent_decode_str(key).decode_utf8() {
+            Ok(key) if !key.is_empty() => key.into_owned(),
+            Ok(_) => return not_found(),
+            Err(_) => return bad_request("keys must be UTF-8"),
+        };
+        return match method {
+            Method::Get => match store.lock().unwrap().get(key.clone()) {
+                Ok(Some(value)) => (200, json!({ "key": key, "value": value })),
+                Ok(None) => failure(&Error::NotFound),
+                Err(e) => failure(&e),
+            },
+            Method::Put => {
+                let mut body = Vec::new();
+                let read = request
+                    .as_reader()
+                    .take(MAX_BODY + 1)
+                    .read_to_end(&mut body);
+                if let Err(e) = read {
+                    return bad_request(&e.to_string());
+                }
+                if body.len() as u64 > MAX_BODY {
+                    return (413, json!({ "error": "request body is too large" }));
+                }
+                match serde_json::from_slice::<Put>(&body) {
+                    Ok(put) => respond(
+                        store
+                            .lock()
+                            .unwrap()
+                            .set(key, put.value)
+                            .map(|()| (204, Value::Null)),
+                    ),
+                    Err(e) => {
+                        bad_request(&format!("expected a body like {{\"value\": ...}}: {}", e))
+                    }
+                }
+            }
+            Method::Delete => respond(
+                store
+                    .lock()
+                    .unwrap()
+                    .remove(key)
+                    .map(|()| (204, Value::Null)),
+            ),
+            _ => not_allowed(),
+        };
+    }
+    match (path, method) {
+        ("/stats", Method::Get) => {
+            let stats = store.lock().unwrap().stats();
+            (
+                200,
+                serde_json::to_value(stats).expect("stats are plain data"),
+            )
+        }
+        ("/compact", Method::Post) => {
+            let mut store = store.lock().unwrap();
+            let before = store.stats().disk_bytes;
+            respond(store.compact().map(|()| {
+                let after = store.stats().disk_bytes;
+                (200, json!({ "before": before, "after": after }))
+            }))
+        }
+        ("/stats", _) | ("/compact", _) => not_allowed(),
+        _ => not_found(),
+    }
+}
+
+/// The decoded value of the first `name` parameter in a query string, if it has one.
+fn query_param(query: &str, name: &str) -> Option<String> {
+    query.split('&').find_map(|pair| {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        if key != name {
+            return None;
+        }
+        // Forms encode spaces as pluses
+        let value = value.replace('+', " ");
+        Some(percent_decode_str(&value).decode_utf8_lossy().into_owned())
+    })
+}
+
+fn respond(result: Result<(u16, Value)>) -> (u16, Value) {
+    result.unwrap_or_else(|e| failure(&e))
+}
+
+/// The status and body to answer with when the store fails.
+fn failure(e: &Error) -> (u16, Value) {
+    let status = match e {
+        Error::Unauthorized => 401,
+        Error::ReadOnly => 403,
+        Error::NotFound => 404,
+        Error::KeyTooLarge { .. } | Error::ValueTooLarge { .. } => 413,
+        Error::QuotaExceeded { .. } => 507,
+        _ => 500,
+    };
+    (status, json!({ "error": e.to_string() }))
+}
+
+fn bad_request(message: &str) -> (u16, Value) {
+    (400, json!({ "error": message }))
+}
+
+fn not_found() -> (u16, Value) {
+    (404, json!({ "error": "no such resource" }))
+}
+
+fn not_allowed() -> (u16, Value) {
+    (405, json!({ "error": "method not allowed" }))
+}
+
+fn json_content() -> Header {
+    Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).expect("a valid header")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn query_params() {
+        assert_eq!(
+            query_param("prefix=a%2Fb", "prefix"),
+            Some("a/b".to_owned())
+        );
+        assert_eq!(
+            query_param("x=1&prefix=a+b", "prefix"),
+            Some("a b".to_owned())
+        );
+        assert_eq!(query_param("prefix", "prefix"), Some(String::new()));
+        assert_eq!(query_param("prefixes=a", "prefix"), None);
+        assert_eq!(query_param("", "prefix"), None);
+    }
+}
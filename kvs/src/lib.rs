@@ -3,15 +3,64 @@ extern crate log;
 
 use bson::de::Error as BsonDeError;
 use bson::ser::Error as BsonSerError;
-use bson::{Bson, Document};
 use serde::{Deserialize, Serialize};
-use snafu::{ResultExt, Snafu};
-use std::collections::HashMap;
-use std::fs;
-use std::fs::{File, OpenOptions};
+use snafu::{ensure, OptionExt, ResultExt, Snafu};
+use std::collections::{hash_map, HashMap};
+use std::fmt;
 use std::io;
-use std::io::{Seek, SeekFrom, Write};
+use std::net::TcpListener;
 use std::path::PathBuf;
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Weak};
+
+mod async_client;
+mod async_resp;
+mod auth;
+mod cache;
+mod changes;
+mod config;
+mod http;
+mod logfile;
+mod memory;
+mod merge;
+mod namespace;
+mod protocol;
+mod raft;
+mod raftlog;
+mod replication;
+mod resp;
+mod secondary;
+mod snapshot;
+mod storage;
+mod verify;
+mod watch;
+mod wire;
+
+pub use async_client::AsyncClient;
+pub use async_resp::AsyncRespServer;
+pub use auth::{Permission, Tokens};
+use cache::ValueCache;
+pub use changes::Changes;
+use changes::{Compacted, COMPACTED};
+pub use config::Config;
+pub use http::HttpServer;
+use logfile::{Format, LogFile, SealedLog};
+use memory::MemStorage;
+use merge::resolve;
+pub use merge::{Add, Append, JsonMergePatch, Max, MergeOperator};
+pub use namespace::Namespace;
+pub use protocol::{ErrorCode, KvsClient, KvsServer, Request, Response};
+pub use raft::{Awaited, ClusterClient, NodeStatus, RaftNode, Role};
+pub use replication::Follower;
+use replication::Leader;
+pub use resp::RespServer;
+use secondary::{Definition, Definitions, SecondaryIndex, INDEXES};
+use snapshot::Pin;
+pub use snapshot::Snapshot;
+use storage::{DirStorage, Storage};
+pub use verify::{Problem, Report};
+pub use watch::Change;
+use watch::Watchers;
 
 #[derive(Debug, Snafu)]
 pub enum Error {
@@ -29,9 +78,23 @@ pub enum Error {
         source: Box<Error>,
     },
     #[snafu(display("failed to roll back to epoch {}: {}", epoch, source))]
-    RollBack { source: io::Error, epoch: u64 },
+    RollBack {
+        #[snafu(source(from(Error, Box::new)))]
+        source: Box<Error>,
+        epoch: u64,
+    },
     #[snafu(display("failed to remove outdated log {}: {}", epoch, source))]
     RemoveLog { source: io::Error, epoch: u64 },
+    #[snafu(display("failed to move log {} into the changelog: {}", epoch, source))]
+    RetireLog { source: io::Error, epoch: u64 },
+    #[snafu(display("failed to record which logs were compacted: {}", source))]
+    RecordCompaction { source: io::Error },
+    #[snafu(display("failed to record the last record applied from the leader: {}", source))]
+    RecordProgress { source: io::Error },
+    #[snafu(display("failed to read changes from epoch {}: {}", epoch, source))]
+    ReadChanges { source: io::Error, epoch: u64 },
+    #[snafu(display("changes from epoch {} onwards are no longer retained", epoch))]
+    ChangesExpired { epoch: u64 },
     #[snafu(display("failed to open {}: {}", path.display(), source))]
     Open { source: io::Error, path: PathBuf },
 
@@ -42,17 +105,125 @@ pub enum Error {
     #[snafu(display("error deserializing command at offset {}: {}", offset, source))]
     Deser { source: BsonDeError, offset: u64 },
     #[snafu(display("error serializing command {:?}: {}", cmd, source))]
-    Ser { source: BsonSerError, cmd: Command },
+    Ser {
+        #[snafu(source(from(BsonSerError, Box::new)))]
+        source: Box<BsonSerError>,
+        cmd: Command,
+    },
     #[snafu(display("error writing command to offset {}: {}", offset, source))]
-    LogWrite { source: BsonSerError, offset: u64 },
+    LogWrite {
+        #[snafu(source(from(BsonSerError, Box::new)))]
+        source: Box<BsonSerError>,
+        offset: u64,
+    },
     #[snafu(display("failed to {} at offset {}: {}", action, offset, source))]
     Io {
         action: String,
         source: io::Error,
         offset: u64,
     },
+    #[snafu(display("record at offset {} is truncated", offset))]
+    Truncated { offset: u64 },
+    #[snafu(display(
+        "record at offset {} is corrupt: expected checksum {:#010x}, found {:#010x}",
+        offset,
+        expected,
+        found
+    ))]
+    Checksum {
+        offset: u64,
+        expected: u32,
+        found: u32,
+    },
+    #[snafu(display("unexpected end of log marker at offset {}", offset))]
+    UnexpectedSeal { offset: u64 },
+    #[snafu(display("log starts with an unrecognised header {:02x?}", header))]
+    BadHeader { header: [u8; 8] },
+    #[snafu(display("epoch {} is in a format that can't be written to", epoch))]
+    ReadOnlyFormat { epoch: u64 },
+    #[snafu(display("failed to connect to {}: {}", addr, source))]
+    Connect { source: io::Error, addr: String },
+    #[snafu(display("not connected to a leader"))]
+    NotConnected,
+    #[snafu(display("failed to send replication message: {}", source))]
+    SendMessage { source: io::Error },
+    #[snafu(display("failed to encode replication message: {}", source))]
+    EncodeMessage {
+        #[snafu(source(from(BsonSerError, Box::new)))]
+        source: Box<BsonSerError>,
+    },
+    #[snafu(display("failed to receive replication message: {}", source))]
+    ReceiveMessage { source: BsonDeError },
+    #[snafu(display("unexpected replication message {}", found))]
+    UnexpectedMessage { found: String },
+    #[snafu(display("failed to listen on {}: {}", addr, source))]
+    Listen { source: io::Error, addr: String },
+    #[snafu(display("lost connection to {}: {}", addr, source))]
+    Disconnected { source: io::Error, addr: String },
+    #[snafu(display("unexpected reply from {}: {}", addr, reply))]
+    BadReply { addr: String, reply: String },
+    #[snafu(display("message is {} bytes, more than the {} a frame may carry", size, max))]
+    FrameTooLarge { size: u64, max: u64 },
+    #[snafu(display("{}", message))]
+    Remote { code: ErrorCode, message: String },
+    #[snafu(display("not authorized: authenticate with a token the server accepts"))]
+    Unauthorized,
+    #[snafu(display("this token may only read"))]
+    ReadOnly,
+    #[snafu(display("invalid TLS certificate or key {}: {}", path.display(), message))]
+    BadTls { path: PathBuf, message: String },
+    #[snafu(display(
+        "refusing to serve {} without TLS and tokens, as it isn't a loopback address",
+        addr
+    ))]
+    Exposed { addr: String },
+    #[snafu(display("failed to persist {}: {}", path.display(), source))]
+    Persist { source: io::Error, path: PathBuf },
+    #[snafu(display("failed to load {}: {}", path.display(), source))]
+    LoadRaftLog {
+        #[snafu(source(from(Error, Box::new)))]
+        source: Box<Error>,
+        path: PathBuf,
+    },
+    #[snafu(display("raft log is missing entry {}", index))]
+    MissingEntry { index: u64 },
+    #[snafu(display("no leader available"))]
+    NoLeader,
+    #[snafu(display("{} is not leading and doesn't know which node is", addr))]
+    NotLeader { addr: String },
+    #[snafu(display("{} timed out waiting for {}", addr, awaited))]
+    Unavailable { addr: String, awaited: Awaited },
+    #[snafu(display("node {} is not one of the {} nodes in the cluster", id, nodes))]
+    UnknownNode { id: u64, nodes: usize },
+    #[snafu(display("lost the connection to the cluster while watching for changes"))]
+    WatchLost,
     #[snafu(display("Key not found"))]
     NotFound,
+    #[snafu(display("key is {} bytes, more than the limit of {}", size, max))]
+    KeyTooLarge { size: u64, max: u64 },
+    #[snafu(display("value is {} bytes, more than the limit of {}", size, max))]
+    ValueTooLarge { size: u64, max: u64 },
+    #[snafu(display("{} quota of {} bytes exceeded", quota, max))]
+    QuotaExceeded { quota: Quota, max: u64 },
+    #[snafu(display("no merge operator has been set up"))]
+    NoMergeOperator,
+    #[snafu(display("failed to merge into {}: {}", key, message))]
+    MergeFailed { key: String, message: String },
+    #[snafu(display("Namespace {} not found", name))]
+    NoNamespace { name: String },
+    #[snafu(display("Index {} not found", name))]
+    NoIndex { name: String },
+    #[snafu(display("Index {} already exists", name))]
+    IndexExists { name: String },
+    #[snafu(display("invalid index path {}: expected $.field or $.field.nested", path))]
+    BadIndexPath { path: String },
+    #[snafu(display("failed to record index definitions: {}", source))]
+    RecordIndexes { source: io::Error },
+    #[snafu(display("invalid config file {}: {}", path.display(), source))]
+    BadConfig {
+        source: toml::de::Error,
+        path: PathBuf,
+    },
     #[snafu(display("Expected command {} at offset {}, found {:?}", cmd, offset, found))]
     BadIndex {
         cmd: String,
@@ -63,133 +234,216 @@ pub enum Error {
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
-/// Log alteration commands.
-#[derive(Debug, Serialize, Deserialize)]
-pub enum Command {
-    Set { key: String, val: String },
-    Rm(String),
+/// Which of a store's quotas a write would have taken it past.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quota {
+    /// The keys and values of every live key.
+    LiveData,
+    /// Every log kept on disk, including the changelog.
+    Disk,
 }
 
-struct LogFile {
-    epoch: u64,
-    handle: File,
-    pos: u64,
+impl fmt::Display for Quota {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Quota::LiveData => write!(f, "live data"),
+            Quota::Disk => write!(f, "disk"),
+        }
+    }
 }
 
-impl io::Read for LogFile {
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        let bytes_read = self.handle.read(buf)?;
-        self.pos += bytes_read as u64;
-        Ok(bytes_read)
-    }
+/// How sure a store makes that a write has reached the disk before it returns.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Durability {
+    /// Hand each record to the operating system. Writes survive the process crashing, but not the machine. The
+    /// default.
+    #[default]
+    Flush,
+    /// Also wait for the disk to have each record, so that writes survive losing power. Much slower.
+    Sync,
 }
 
-impl io::Write for LogFile {
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        let wrote = self.handle.write(buf)?;
-        self.pos += wrote as u64;
-        Ok(wrote)
-    }
+impl std::str::FromStr for Durability {
+    type Err = String;
 
-    fn flush(&mut self) -> io::Result<()> {
-        self.handle.flush()
+    fn from_str(s: &str) -> std::result::Result<Durability, String> {
+        match s {
+            "flush" => Ok(Durability::Flush),
+            "sync" => Ok(Durability::Sync),
+            _ => Err(format!("unknown durability {}, expected flush or sync", s)),
+        }
     }
 }
 
-impl io::Seek for LogFile {
-    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
-        let pos = self.handle.seek(pos)?;
-        self.pos = pos;
-        Ok(pos)
-    }
+/// The most a store will hold, in bytes. Nothing is limited by default.
+#[derive(Debug, Clone, Copy, Default)]
+struct Limits {
+    key_size: Option<u64>,
+    value_size: Option<u64>,
+    live_bytes: Option<u64>,
+    disk_bytes: Option<u64>,
 }
 
-impl LogFile {
-    /// Open a new, empty log file.
-    /// Truncates the file if it already exists.
-    fn new(epoch: u64, path: impl Into<PathBuf>) -> io::Result<LogFile> {
-        let mut path = path.into();
-        path.push(epoch.to_string());
-        let mut handle = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .truncate(true)
-            .create(true)
-            .open(path)?;
-        let length = handle.seek(SeekFrom::End(0))?;
-
-        Ok(LogFile {
-            epoch,
-            handle,
-            pos: length,
-        })
-    }
-
-    /// Open an existing log file.
-    fn open(epoch: u64, path: impl Into<PathBuf>) -> io::Result<LogFile> {
-        let mut path = path.into();
-        path.push(epoch.to_string());
-        let mut handle = OpenOptions::new().read(true).append(true).open(path)?;
-        let length = handle.seek(SeekFrom::End(0))?;
-
-        Ok(LogFile {
-            epoch,
-            handle,
-            pos: length,
-        })
-    }
-
-    /// Read the command, if any, stored at the provided offset.
-    fn retrieve(&mut self, offset: u64) -> Result<Command> {
-        self.seek(SeekFrom::Start(offset))
-            .with_context(|| LogSeek {})?;
-        let doc = Document::from_reader(self).context(Deser { offset })?;
-        let found: Command = bson::from_bson(Bson::Document(doc)).context(Deser { offset })?;
+/// Log alteration commands.
+///
+/// Every command applies to a namespace. The default namespace is named by the empty string, which is left out when
+/// the command is recorded.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "Recorded")]
+pub enum Command {
+    Set {
+        #[serde(default, skip_serializing_if = "String::is_empty")]
+        ns: String,
+        key: String,
+        val: String,
+    },
+    Rm {
+        #[serde(default, skip_serializing_if = "String::is_empty")]
+        ns: String,
+        key: String,
+    },
+    /// Remove every key in a namespace.
+    DropNamespace { ns: String },
+    /// Combine an operand with a key's value using the store's merge operator, whenever the value is next needed.
+    Merge {
+        #[serde(default, skip_serializing_if = "String::is_empty")]
+        ns: String,
+        key: String,
+        operand: String,
+    },
+}
 
-        debug!("read {:?} in epoch {}@{}", &found, self.epoch, offset);
-        Ok(found)
-    }
+/// Every form commands have been recorded in, so that logs from before namespaces still replay.
+#[derive(Deserialize)]
+enum Recorded {
+    Set {
+        #[serde(default)]
+        ns: String,
+        key: String,
+        val: String,
+    },
+    Rm(Removal),
+    DropNamespace {
+        ns: String,
+    },
+    Merge {
+        #[serde(default)]
+        ns: String,
+        key: String,
+        operand: String,
+    },
+}
 
-    /// Record a command to the log file.
-    ///
-    /// Returns the offset from the start of the file the command was written to.
-    fn record(&mut self, cmd: Command) -> Result<u64> {
-        debug!("recording {:?} in epoch {}@{}", &cmd, self.epoch, self.pos);
-        let bs = bson::to_bson(&cmd).context(Ser { cmd })?;
-        // We know its a document
-        let doc = bs.as_document().unwrap();
-        let offset = self.seek(SeekFrom::End(0)).with_context(|| LogSeek {})?;
-        doc.to_writer(self).context(LogWrite { offset })?;
-        self.flush().context(Io {
-            action: "flush".to_owned(),
-            offset,
-        })?;
-        debug!("recorded command in epoch {}@{}", self.epoch, offset);
-        Ok(offset)
-    }
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Removal {
+    Key(String),
+    InNamespace {
+        #[serde(default)]
+        ns: String,
+        key: String,
+    },
+}
 
-    // Replay the log, applying a callback function to every recorded event.
-    fn replay<F: FnMut(Command, u64)>(&mut self, mut callback: F) -> Result<()> {
-        debug!("replaying epoch {}", self.epoch);
-        let length = self.seek(SeekFrom::End(0)).with_context(|| LogSeek {})?;
-        self.seek(SeekFrom::Start(0)).with_context(|| LogSeek {})?;
-        while self.pos < length {
-            let offset = self.pos;
-            let cmd = self.retrieve(self.pos)?;
-            callback(cmd, offset);
+impl From<Recorded> for Command {
+    fn from(recorded: Recorded) -> Command {
+        match recorded {
+            Recorded::Set { ns, key, val } => Command::Set { ns, key, val },
+            Recorded::Rm(Removal::Key(key)) => Command::Rm {
+                ns: String::new(),
+                key,
+            },
+            Recorded::Rm(Removal::InNamespace { ns, key }) => Command::Rm { ns, key },
+            Recorded::DropNamespace { ns } => Command::DropNamespace { ns },
+            Recorded::Merge { ns, key, operand } => Command::Merge { ns, key, operand },
         }
-        Ok(())
     }
 }
 
+#[derive(Clone, Copy)]
 struct KeyEntry {
     epoch: u64,
     offset: u64,
+    // Bytes of key and value or operand, which count towards the live data quota
+    size: u64,
 }
 
 type KeyDir = HashMap<String, KeyEntry>;
 
+/// The key directory of each namespace that has any keys.
+type Index = HashMap<String, KeyDir>;
+
+/// Where the operands merged into each key since it was last set were recorded, oldest first, by namespace.
+type Merges = HashMap<String, HashMap<String, Vec<KeyEntry>>>;
+
+/// Forget the operands merged into a key, returning how many bytes they took up.
+fn forget_operands(merges: &mut Merges, ns: &str, key: &str) -> u64 {
+    let keys = match merges.get_mut(ns) {
+        Some(keys) => keys,
+        None => return 0,
+    };
+    let size = keys
+        .remove(key)
+        .map_or(0, |operands| operands.iter().map(|entry| entry.size).sum());
+    if keys.is_empty() {
+        merges.remove(ns);
+    }
+    size
+}
+
+/// Add up the sizes of every live key's value and operands.
+fn live_bytes(index: &Index, merges: &Merges) -> u64 {
+    let values: u64 = index
+        .values()
+        .flat_map(KeyDir::values)
+        .map(|entry| entry.size)
+        .sum();
+    let operands: u64 = merges
+        .values()
+        .flat_map(HashMap::values)
+        .flatten()
+        .map(|entry| entry.size)
+        .sum();
+    values + operands
+}
+
+/// Where a record lives in a store's logs. Later records always have greater positions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Position {
+    pub epoch: u64,
+    pub offset: u64,
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}@{}", self.epoch, self.offset)
+    }
+}
+
+/// A point-in-time summary of a store's state.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Stats {
+    /// The number of live keys.
+    pub keys: usize,
+    /// The epoch currently being written to.
+    pub epoch: u64,
+    /// Reads served from the value cache.
+    pub cache_hits: u64,
+    /// Reads that missed the value cache and went to the log.
+    pub cache_misses: u64,
+    /// Bytes of keys and values currently held by the value cache.
+    pub cache_size: usize,
+    /// Bytes of the keys and values of every live key.
+    pub live_bytes: u64,
+    /// Bytes of every log kept on disk, including the changelog.
+    pub disk_bytes: u64,
+}
+
 const DEFAULT_MAX_LOG_SIZE: u64 = 10_000_000; // 10MB
+const DEFAULT_COMPACTION_THRESHOLD: u64 = 1000;
+// More than a record adds to a log beyond its namespace, key and value, including a seal if the log then fills up
+const RECORD_OVERHEAD: u64 = 64;
 
 /// A string to string key-value store
 ///
@@ -198,91 +452,229 @@ const DEFAULT_MAX_LOG_SIZE: u64 = 10_000_000; // 10MB
 /// Example usage:
 /// ```rust
 /// # use kvs::KvStore;
-/// let mut store = KvStore::open("/tmp/logd").expect("should work");
+/// # let dir = tempfile::TempDir::new().unwrap();
+/// let mut store = KvStore::open(dir.path()).expect("should work");
 /// store.set("my key".to_owned(), "my value".to_owned());
 /// let val = store.get("my key".to_owned()).expect("should exist");
 /// assert_eq!(val, Some("my value".to_owned()));
 ///```
 pub struct KvStore {
     // TODO: keep multiple log files?
-    index: KeyDir,
-    path: PathBuf,
+    index: Index,
+    storage: Arc<dyn Storage>,
     // Writer for the current epoch
     log: LogFile,
-    // TODO: keep an LRU cache of file handles, keyed by epoch?
-    // readers: HashMap<u64, File>
+    // Memory-mapped readers for sealed epochs, opened on first use.
+    readers: HashMap<u64, SealedLog>,
+    mmap: bool,
+    // Recently read or written values, if enabled.
+    cache: Option<ValueCache>,
     epoch: u64,
     max_log_size: u64,
-    // These tests require us to trigger compaction. I'd rather push that up to another layer, but to get it over with
-    // we'll trigger compaction after every 100 removals or overwrites.
+    // Removals and overwrites since the last compaction, which is triggered once there are more than the threshold
     mutations: u64,
+    compaction_threshold: u64,
+    durability: Durability,
+    // Followers to stream appended records to, once serving them.
+    leader: Option<Arc<Leader>>,
+    watchers: Watchers,
+    // Which logs the last compaction wrote, if there has been one
+    compacted: Option<Compacted>,
+    // How many logs of changes to keep in the changelog after compaction
+    changelog_retention: usize,
+    last: Option<Position>,
+    // Open snapshots, which compaction mustn't remove logs out from under
+    pins: Vec<Weak<Pin>>,
+    // Secondary indexes over JSON values, rebuilt from the logs on open
+    indexes: Vec<SecondaryIndex>,
+    limits: Limits,
+    // Bytes of the keys and values of every live key
+    live: u64,
+    // Bytes of every log but the current one
+    sealed_bytes: u64,
+    merges: Merges,
+    merge_operator: Option<Arc<dyn MergeOperator>>,
 }
 
 impl KvStore {
     pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
         let path = path.into();
+        let storage = DirStorage::new(path.clone()).context(MkDir { path })?;
+        KvStore::open_storage(Arc::new(storage))
+    }
 
-        if let Err(e) = fs::create_dir_all(&path) {
-            if e.kind() != io::ErrorKind::AlreadyExists {
-                return Err(e).context(MkDir { path });
-            }
-        };
+    /// Create an empty store that keeps its logs in memory instead of on disk.
+    ///
+    /// It rotates epochs and compacts exactly like a store on disk, but everything is gone once it is dropped. Handy
+    /// for tests that would otherwise need a temporary directory.
+    ///
+    /// ```rust
+    /// # use kvs::KvStore;
+    /// let mut store = KvStore::in_memory();
+    /// store.set("my key".to_owned(), "my value".to_owned());
+    /// assert_eq!(store.get("my key".to_owned()).unwrap(), Some("my value".to_owned()));
+    /// ```
+    pub fn in_memory() -> Self {
+        KvStore::open_storage(Arc::new(MemStorage::default()))
+            .expect("failed to open an empty in-memory store")
+    }
 
-        let mut index = KeyDir::new();
+    /// Open a store whose logs are kept in `storage`, replaying whatever is already there.
+    fn open_storage(storage: Arc<dyn Storage>) -> Result<Self> {
+        let mut index = Index::new();
+        let mut merges = Merges::new();
         let mut logs = Vec::<LogFile>::new();
 
-        for entry in fs::read_dir(&path).context(ListDir { path: path.clone() })? {
-            let f = entry.expect("failed to list direntry");
-
-            // There is probably an easier way to do this...
-            let e: u64 = f
-                .file_name()
-                .to_string_lossy()
-                .parse()
-                .expect("expected file to have a u64 name");
-            let lf = LogFile::open(e, path.clone()).with_context(|| Open { path: f.path() })?;
+        for e in storage.epochs().with_context(|| ListDir {
+            path: storage.path(),
+        })? {
+            let lf = LogFile::open(e, storage.as_ref()).with_context(|| Open {
+                path: storage.locate(e),
+            })?;
             logs.push(lf);
         }
 
         // Sort from lowest to highest epoch
         logs.sort_unstable_by(|a, b| a.epoch.partial_cmp(&b.epoch).unwrap());
 
+        let compacted = match storage.read_meta(COMPACTED).with_context(|| Open {
+            path: storage.path().join(COMPACTED),
+        })? {
+            Some(bytes) => Some(Compacted::decode(&bytes)?),
+            None => None,
+        };
+
         let mut epoch: u64 = 0;
-        for log in &mut logs {
+        let mut last = None;
+        let newest = logs.len().saturating_sub(1);
+        let mut newest_sealed = false;
+        for (i, log) in logs.iter_mut().enumerate() {
             epoch = log.epoch;
-            log.replay(|cmd: Command, offset: u64| {
+            let replayed = log.replay(|cmd: Command, offset: u64| {
+                last = Some(Position { epoch, offset });
                 match cmd {
-                    Command::Set { key, val: _ } => {
-                        index.insert(key, KeyEntry { epoch, offset });
+                    Command::Set { ns, key, val } => {
+                        let size = (key.len() + val.len()) as u64;
+                        forget_operands(&mut merges, &ns, &key);
+                        index.entry(ns).or_default().insert(
+                            key,
+                            KeyEntry {
+                                epoch,
+                                offset,
+                                size,
+                            },
+                        );
                     }
-                    Command::Rm(key) => {
-                        index.remove(&key);
+                    Command::Rm { ns, key } => {
+                        forget_operands(&mut merges, &ns, &key);
+                        if let Some(keys) = index.get_mut(&ns) {
+                            keys.remove(&key);
+                            if keys.is_empty() {
+                                index.remove(&ns);
+                            }
+                        }
+                    }
+                    Command::DropNamespace { ns } => {
+                        index.remove(&ns);
+                        merges.remove(&ns);
+                    }
+                    Command::Merge { ns, key, operand } => {
+                        let entry = KeyEntry {
+                            epoch,
+                            offset,
+                            size: (key.len() + operand.len()) as u64,
+                        };
+                        match index.entry(ns.clone()).or_default().entry(key) {
+                            hash_map::Entry::Occupied(merged) => merges
+                                .entry(ns)
+                                .or_default()
+                                .entry(merged.key().clone())
+                                .or_default()
+                                .push(entry),
+                            hash_map::Entry::Vacant(first) => {
+                                first.insert(entry);
+                            }
+                        }
                     }
                 };
-            })
-            .context(Replay { epoch })?;
+            });
+            match replayed {
+                Ok(sealed) if i == newest => newest_sealed = sealed,
+                Ok(true) => {}
+                // Every log but the newest must have been sealed, otherwise it has lost its tail
+                Ok(false) => {
+                    return Err(Error::Truncated { offset: log.len() }).context(Replay { epoch });
+                }
+                // A record cut short at the very end of the newest log is a write that never completed
+                Err(Error::Truncated { offset }) if i == newest => {
+                    warn!("discarding incomplete record at {}@{}", epoch, offset);
+                    log.truncate(offset).context(Io {
+                        action: "truncate".to_owned(),
+                        offset,
+                    })?;
+                }
+                Err(e) => return Err(e).context(Replay { epoch }),
+            }
         }
 
         // Grab file for the current epoch
-        let log: LogFile = if logs.len() == 0 {
-            LogFile::new(epoch, path.clone()).with_context(|| Open { path: path.clone() })?
-        } else {
-            logs.pop().unwrap()
+        let log: LogFile = match logs.pop() {
+            Some(log) if !newest_sealed && log.format == Format::Checksummed => log,
+            // We stopped after sealing the newest log but before starting the next, or it is in an older format
+            Some(_) => {
+                epoch += 1;
+                LogFile::new(epoch, storage.as_ref()).with_context(|| Open {
+                    path: storage.locate(epoch),
+                })?
+            }
+            None => LogFile::new(epoch, storage.as_ref()).with_context(|| Open {
+                path: storage.locate(epoch),
+            })?,
+        };
+
+        let definitions = match storage.read_meta(INDEXES).with_context(|| Open {
+            path: storage.path().join(INDEXES),
+        })? {
+            Some(bytes) => Definitions::decode(&bytes)?,
+            None => Definitions::default(),
         };
 
-        Ok(KvStore {
+        let live = live_bytes(&index, &merges);
+        let mut store = KvStore {
             index,
-            path,
+            storage,
             log,
+            readers: HashMap::new(),
+            mmap: true,
+            cache: None,
             epoch,
             max_log_size: DEFAULT_MAX_LOG_SIZE,
             mutations: 0,
-        })
+            compaction_threshold: DEFAULT_COMPACTION_THRESHOLD,
+            durability: Durability::default(),
+            leader: None,
+            watchers: Watchers::default(),
+            compacted,
+            changelog_retention: 0,
+            last,
+            pins: Vec::new(),
+            indexes: Vec::new(),
+            limits: Limits::default(),
+            live,
+            sealed_bytes: 0,
+            merges,
+            merge_operator: None,
+        };
+        store.sealed_bytes = store.measure_sealed()?;
+        for definition in definitions.indexes {
+            let index = store.build_index(definition)?;
+            store.indexes.push(index);
+        }
+        Ok(store)
     }
 
-    // TODO: the KvStore should either take a callback that defines when to compact, or should only compact manually.
     fn should_compact(&self) -> bool {
-        return self.mutations > 1000;
+        self.compaction_threshold > 0 && self.mutations > self.compaction_threshold
     }
 
     /// Set the size after which the store will rotate to a new log file.
@@ -291,46 +683,633 @@ impl KvStore {
         self
     }
 
-    /// Retrieve the value stored at the specified key
+    /// Compact once more than `mutations` keys have been removed or overwritten since the last compaction, rather than
+    /// the default of 1000. A threshold of zero leaves compaction to calls to [`compact`](KvStore::compact).
+    pub fn with_compaction_threshold(mut self, mutations: u64) -> Self {
+        self.compaction_threshold = mutations;
+        self
+    }
+
+    /// Set how sure writes are to have reached the disk before they return. See [`Durability`].
+    pub fn with_durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    /// Apply every setting given in `config`, besides where the logs are kept.
+    pub fn with_config(mut self, config: &Config) -> Self {
+        self.configure(config);
+        self
+    }
+
+    pub(crate) fn configure(&mut self, config: &Config) {
+        if let Some(bytes) = config.max_log_size {
+            self.max_log_size = bytes;
+        }
+        if let Some(bytes) = config.cache_size {
+            self.cache = (bytes > 0).then(|| ValueCache::new(bytes));
+        }
+        if let Some(mutations) = config.compaction_threshold {
+            self.compaction_threshold = mutations;
+        }
+        if let Some(durability) = config.durability {
+            self.durability = durability;
+        }
+    }
+
+    /// Set whether reads from sealed epochs are served from memory-mapped log files (the default) or by seeking a
+    /// freshly opened file handle.
+    pub fn with_mmap(mut self, enabled: bool) -> Self {
+        self.mmap = enabled;
+        self
+    }
+
+    /// Keep up to `capacity` bytes of recently used keys and values in memory, serving repeated reads without
+    /// touching the log. A capacity of zero disables the cache.
+    pub fn with_cache_capacity(mut self, capacity: usize) -> Self {
+        self.cache = if capacity > 0 {
+            Some(ValueCache::new(capacity))
+        } else {
+            None
+        };
+        self
+    }
+
+    /// Refuse to set keys longer than `bytes`, failing with [`Error::KeyTooLarge`].
+    pub fn with_max_key_size(mut self, bytes: u64) -> Self {
+        self.limits.key_size = Some(bytes);
+        self
+    }
+
+    /// Refuse to set values longer than `bytes`, failing with [`Error::ValueTooLarge`].
+    pub fn with_max_value_size(mut self, bytes: u64) -> Self {
+        self.limits.value_size = Some(bytes);
+        self
+    }
+
+    /// Refuse writes that would take the keys and values of every live key past `bytes` in total, failing with
+    /// [`Error::QuotaExceeded`]. Overwriting a key only counts the difference in size.
+    pub fn with_max_live_bytes(mut self, bytes: u64) -> Self {
+        self.limits.live_bytes = Some(bytes);
+        self
+    }
+
+    /// Refuse writes that would take the logs on disk past `bytes` in total, failing with [`Error::QuotaExceeded`].
     ///
-    /// Returns `None` if the key does not exist.
-    pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        if !self.index.contains_key(&key) {
-            return Ok(None);
+    /// Overwritten and removed values take up space until the next compaction, so compacting can make room again.
+    /// Compaction itself may go past the limit while it copies values.
+    pub fn with_max_disk_bytes(mut self, bytes: u64) -> Self {
+        self.limits.disk_bytes = Some(bytes);
+        self
+    }
+
+    /// Combine operands passed to [`merge`](KvStore::merge) with values using `operator`. Stores have none by
+    /// default, so merging fails with [`Error::NoMergeOperator`].
+    ///
+    /// Operands are recorded as they are and only combined when needed, so a store must always be opened with the
+    /// same operator.
+    pub fn with_merge_operator(mut self, operator: impl MergeOperator + 'static) -> Self {
+        self.merge_operator = Some(Arc::new(operator));
+        // Secondary indexes were built before there was any way to work out merged values
+        let merged: Vec<String> = self
+            .merges
+            .get("")
+            .map(|keys| keys.keys().cloned().collect())
+            .unwrap_or_default();
+        for key in merged {
+            if self.indexes.iter().any(|index| index.covers("", &key)) {
+                let val = self.read("", &key).unwrap_or_else(|e| {
+                    warn!("failed to index {}: {}", key, e);
+                    None
+                });
+                self.reindex("", &key, val.as_deref());
+            }
         }
-        // Otherwise seek and get the key
-        let entry = self.index.get(&key).unwrap().clone();
+        self
+    }
 
-        debug!("getting {} from {}@{}", &key, entry.epoch, entry.offset);
-        if entry.epoch == self.epoch {
-            return match self.log.retrieve(entry.offset)? {
-                Command::Set { key: k2, val } => {
-                    debug_assert!(key == k2, "found a set for the wrong key");
-                    Ok(Some(val))
+    /// Keep the logs of up to `epochs` epochs of changes in a changelog after compaction, rather than deleting them,
+    /// so that [`changes_since`](KvStore::changes_since) can reach back past it. None are kept by default.
+    pub fn with_changelog_retention(mut self, epochs: usize) -> Self {
+        self.changelog_retention = epochs;
+        self
+    }
+
+    /// Stream every record this store appends to followers connecting on `listener`, after catching each of them up
+    /// on what they missed. See [`Follower`].
+    ///
+    /// Connections are served on background threads until the store is dropped. May be called again to accept
+    /// followers on more than one listener.
+    pub fn serve_followers(&mut self, listener: TcpListener) {
+        let end = Position {
+            epoch: self.epoch,
+            offset: self.log.len(),
+        };
+        let storage = &self.storage;
+        self.leader
+            .get_or_insert_with(|| Arc::new(Leader::new(storage.clone(), end)))
+            .listen(listener);
+    }
+
+    /// Hear about every key starting with `prefix` that is set or removed from now on. Pass a whole key to watch just
+    /// that key, or rather every key it is a prefix of, and an empty prefix to watch everything.
+    ///
+    /// Changes are sent once they have been recorded, in the order they were made. Compaction rewrites values without
+    /// changing them, so it doesn't send any. Dropping the receiver ends the watch.
+    ///
+    /// ```rust
+    /// # use kvs::{Change, KvStore};
+    /// let mut store = KvStore::in_memory();
+    /// let changes = store.watch("user/");
+    /// store.set("user/1".to_owned(), "ann".to_owned()).unwrap();
+    /// store.set("group/1".to_owned(), "admins".to_owned()).unwrap();
+    /// store.remove("user/1".to_owned()).unwrap();
+    /// assert_eq!(changes.try_recv().unwrap(), Change { key: "user/1".to_owned(), val: Some("ann".to_owned()) });
+    /// assert_eq!(changes.try_recv().unwrap(), Change { key: "user/1".to_owned(), val: None });
+    /// assert!(changes.try_recv().is_err());
+    /// ```
+    pub fn watch(&mut self, prefix: impl Into<String>) -> Receiver<Change> {
+        self.watchers.add(String::new(), prefix.into())
+    }
+
+    /// Work with the keys in a namespace, which are kept apart from those in every other namespace.
+    ///
+    /// A namespace comes into being when its first key is set, and goes away along with its last key.
+    ///
+    /// ```rust
+    /// # use kvs::KvStore;
+    /// let mut store = KvStore::in_memory();
+    /// store.namespace("users").set("1".to_owned(), "ann".to_owned()).unwrap();
+    /// assert_eq!(store.get("1".to_owned()).unwrap(), None);
+    /// assert_eq!(store.namespaces(), vec!["users".to_owned()]);
+    /// store.drop_namespace("users").unwrap();
+    /// assert_eq!(store.namespace("users").get("1".to_owned()).unwrap(), None);
+    /// ```
+    pub fn namespace(&mut self, name: impl Into<String>) -> Namespace<'_> {
+        Namespace::new(self, name.into())
+    }
+
+    /// List the namespaces with any keys in them, in order. The default namespace isn't included.
+    pub fn namespaces(&self) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .index
+            .keys()
+            .filter(|ns| !ns.is_empty())
+            .cloned()
+            .collect();
+        names.sort_unstable();
+        names
+    }
+
+    /// Remove every key in a namespace at once.
+    pub fn drop_namespace(&mut self, name: &str) -> Result<()> {
+        let keys = match self.index.get(name) {
+            Some(keys) => keys.len() as u64,
+            None => {
+                return NoNamespace {
+                    name: name.to_owned(),
                 }
-                Command::Rm(k) => Err(Error::BadIndex {
-                    cmd: "Set".to_owned(),
-                    offset: entry.offset,
-                    found: Command::Rm(k),
-                }),
+                .fail()
+            }
+        };
+        self.append(Command::DropNamespace {
+            ns: name.to_owned(),
+        })?;
+        if let Some(operands) = self.merges.remove(name) {
+            self.live -= operands
+                .values()
+                .flatten()
+                .map(|entry| entry.size)
+                .sum::<u64>();
+        }
+        if let Some(dropped) = self.index.remove(name) {
+            self.live -= dropped.values().map(|entry| entry.size).sum::<u64>();
+            for key in dropped.keys() {
+                self.watchers.notify(name, key, None);
+            }
+        }
+        if let Some(cache) = self.cache.as_mut() {
+            cache.remove_namespace(name);
+        }
+        if name.is_empty() {
+            self.indexes.iter_mut().for_each(SecondaryIndex::clear);
+        }
+
+        self.mutations += keys;
+        if self.should_compact() {
+            return self.compact().context(Compact);
+        }
+        Ok(())
+    }
+
+    /// Apply a command recorded by another store, such as a leader.
+    pub(crate) fn apply(&mut self, cmd: Command) -> Result<()> {
+        match cmd {
+            Command::Set { ns, key, val } => self.set_in(&ns, key, val),
+            Command::Rm { ns, key } => self.remove_in(&ns, key),
+            Command::DropNamespace { ns } => self.drop_namespace(&ns),
+            Command::Merge { ns, key, operand } => self.merge_in(&ns, key, operand),
+        }
+    }
+
+    /// Every key in the store, along with its namespace.
+    pub(crate) fn keys(&self) -> impl Iterator<Item = (&String, &String)> {
+        self.index
+            .iter()
+            .flat_map(|(ns, keys)| keys.keys().map(move |key| (ns, key)))
+    }
+
+    /// Wait until every log, and which logs there are, has reached the disk, whatever the store's durability.
+    pub(crate) fn sync_all(&mut self) -> Result<()> {
+        self.sync_from(0)
+    }
+
+    /// Like [`sync_all`](KvStore::sync_all), but only for the logs of `epoch` onwards, for callers that know the
+    /// older ones already reached the disk.
+    pub(crate) fn sync_from(&mut self, epoch: u64) -> Result<()> {
+        let storage = self.storage.as_ref();
+        for e in storage.epochs().with_context(|| ListDir {
+            path: storage.path(),
+        })? {
+            if e < epoch {
+                continue;
+            }
+            let synced = if e == self.epoch {
+                self.log.sync()
+            } else {
+                storage.open(e).and_then(|mut handle| handle.sync())
             };
+            synced.with_context(|| Persist {
+                path: storage.locate(e),
+            })?;
         }
+        storage.sync_dir().with_context(|| Persist {
+            path: storage.path(),
+        })
+    }
+
+    /// Read the commands recorded after `after`, or from the very beginning if it's `None`, along with where each
+    /// was recorded.
+    ///
+    /// Positions only ever increase, so a reader can remember the last one it saw and resume from it later, even
+    /// from another process. Compaction doesn't count as a change: the copies of values it writes are skipped.
+    /// Changes from before a compaction are only kept for as long as the changelog retains them. Asking for changes
+    /// that are gone fails with [`Error::ChangesExpired`].
+    ///
+    /// Only what has been recorded by the time this is called is read.
+    ///
+    /// ```rust
+    /// # use kvs::{Command, KvStore};
+    /// let mut store = KvStore::in_memory();
+    /// store.set("a".to_owned(), "1".to_owned()).unwrap();
+    /// let seen = store.last_position();
+    /// store.remove("a".to_owned()).unwrap();
+    /// let changes: Vec<_> = store.changes_since(seen).unwrap().collect::<kvs::Result<_>>().unwrap();
+    /// assert!(matches!(&changes[..], [(_, Command::Rm { key, .. })] if key == "a"));
+    /// ```
+    pub fn changes_since(&self, after: Option<Position>) -> Result<Changes> {
+        let end = Position {
+            epoch: self.epoch,
+            offset: self.log.len(),
+        };
+        Changes::new(self.storage.clone(), after, self.compacted, end)
+    }
 
-        // TODO cache log handles?
-        let mut log = LogFile::open(entry.epoch, self.path.clone()).with_context(|| Open {
-            path: self.path.clone(),
+    /// Where the most recent record was written, if there is one. Reading changes since then only finds new ones.
+    pub fn last_position(&self) -> Option<Position> {
+        self.last
+    }
+
+    /// Take a read-only view of the default namespace as it is now, which later writes don't show up in.
+    ///
+    /// Compaction keeps the logs an open snapshot reads from, so hold on to one only as long as needed: they are
+    /// removed by the first compaction after it is dropped.
+    ///
+    /// ```rust
+    /// # use kvs::KvStore;
+    /// let mut store = KvStore::in_memory();
+    /// store.set("user/1".to_owned(), "ann".to_owned()).unwrap();
+    /// let mut snapshot = store.snapshot_read();
+    /// store.set("user/1".to_owned(), "bob".to_owned()).unwrap();
+    /// store.set("user/2".to_owned(), "cat".to_owned()).unwrap();
+    /// store.compact().unwrap();
+    /// assert_eq!(snapshot.get("user/1".to_owned()).unwrap(), Some("ann".to_owned()));
+    /// assert_eq!(snapshot.scan("user/").unwrap(), vec![("user/1".to_owned(), "ann".to_owned())]);
+    /// ```
+    pub fn snapshot_read(&mut self) -> Snapshot {
+        let keys = self.index.get("").cloned().unwrap_or_default();
+        let operands = self.merges.get("").cloned().unwrap_or_default();
+        let epoch = keys
+            .values()
+            .chain(operands.values().flatten())
+            .map(|entry| entry.epoch)
+            .min()
+            .unwrap_or(self.epoch);
+        let pin = Arc::new(Pin { epoch });
+        self.pins.push(Arc::downgrade(&pin));
+        Snapshot::new(
+            self.storage.clone(),
+            keys,
+            operands,
+            self.merge_operator.clone(),
+            self.last,
+            pin,
+        )
+    }
+
+    /// Declare a secondary index named `name` over keys in the default namespace starting with `prefix`, whose
+    /// values are looked up by the JSON field at `path`, such as `$.email` or `$.address.city`.
+    ///
+    /// Values that aren't JSON objects with a string, number or boolean at that path are left out of the index. The
+    /// index is kept up to date as keys are set and removed, and is rebuilt whenever the store is opened.
+    ///
+    /// ```rust
+    /// # use kvs::KvStore;
+    /// let mut store = KvStore::in_memory();
+    /// store.create_index("email", "user/", "$.email").unwrap();
+    /// store.set("user/1".to_owned(), r#"{"email": "ann@example.com"}"#.to_owned()).unwrap();
+    /// store.set("user/2".to_owned(), r#"{"email": "bob@example.com"}"#.to_owned()).unwrap();
+    /// assert_eq!(store.find_by_index("email", "bob@example.com").unwrap(), vec!["user/2".to_owned()]);
+    /// ```
+    pub fn create_index(&mut self, name: &str, prefix: &str, path: &str) -> Result<()> {
+        ensure!(
+            !self
+                .indexes
+                .iter()
+                .any(|index| index.definition.name == name),
+            IndexExists { name }
+        );
+        let index = self.build_index(Definition {
+            name: name.to_owned(),
+            prefix: prefix.to_owned(),
+            path: path.to_owned(),
         })?;
+        self.indexes.push(index);
+        if let Err(e) = self.record_indexes() {
+            self.indexes.pop();
+            return Err(e);
+        }
+        Ok(())
+    }
 
-        match log.retrieve(entry.offset)? {
-            Command::Set { key: k2, val } => {
-                debug_assert!(key == k2, "found a set for the wrong key");
-                Ok(Some(val))
+    /// Forget a secondary index. The keys it covered are left alone.
+    pub fn drop_index(&mut self, name: &str) -> Result<()> {
+        let position = self
+            .indexes
+            .iter()
+            .position(|index| index.definition.name == name)
+            .context(NoIndex { name })?;
+        let dropped = self.indexes.remove(position);
+        if let Err(e) = self.record_indexes() {
+            self.indexes.insert(position, dropped);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Find the keys whose values hold `value` at the path indexed by `index`, in order.
+    ///
+    /// Numbers and booleans are found by how they are written in JSON, so `42` or `true`.
+    pub fn find_by_index(&self, index: &str, value: &str) -> Result<Vec<String>> {
+        let found = self
+            .indexes
+            .iter()
+            .find(|found| found.definition.name == index)
+            .context(NoIndex { name: index })?;
+        Ok(found.find(value))
+    }
+
+    /// Index the keys a new secondary index covers by their current values.
+    fn build_index(&mut self, definition: Definition) -> Result<SecondaryIndex> {
+        let mut index = SecondaryIndex::new(definition)?;
+        let keys: Vec<String> = self
+            .index
+            .get("")
+            .map(|keys| {
+                keys.keys()
+                    .filter(|key| index.covers("", key))
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default();
+        for key in keys {
+            let val = match self.read("", &key) {
+                Ok(val) => val,
+                // Left out until it can be worked out
+                Err(Error::NoMergeOperator) | Err(Error::MergeFailed { .. }) => None,
+                Err(e) => return Err(e),
+            };
+            index.update(&key, val.as_deref());
+        }
+        Ok(index)
+    }
+
+    /// Bring the secondary indexes covering a key up to date with its new value, or its removal.
+    fn reindex(&mut self, ns: &str, key: &str, val: Option<&str>) {
+        for index in self
+            .indexes
+            .iter_mut()
+            .filter(|index| index.covers(ns, key))
+        {
+            index.update(key, val);
+        }
+    }
+
+    fn record_indexes(&self) -> Result<()> {
+        let definitions = Definitions {
+            indexes: self
+                .indexes
+                .iter()
+                .map(|index| index.definition.clone())
+                .collect(),
+        };
+        self.storage
+            .write_meta(INDEXES, &definitions.encode())
+            .context(RecordIndexes)
+    }
+
+    /// Report the store's current state.
+    pub fn stats(&self) -> Stats {
+        let mut stats = Stats {
+            keys: self.index.values().map(KeyDir::len).sum(),
+            epoch: self.epoch,
+            ..Stats::default()
+        };
+        if let Some(cache) = &self.cache {
+            stats.cache_hits = cache.hits;
+            stats.cache_misses = cache.misses;
+            stats.cache_size = cache.size();
+        }
+        stats.live_bytes = self.live;
+        stats.disk_bytes = self.sealed_bytes + self.log.len();
+        stats
+    }
+
+    /// Add up the size of every log but the current one, including those in the changelog.
+    fn measure_sealed(&self) -> Result<u64> {
+        let storage = self.storage.as_ref();
+        let mut bytes = 0;
+        for e in storage.epochs().with_context(|| ListDir {
+            path: storage.path(),
+        })? {
+            if e != self.epoch {
+                let log = LogFile::open(e, storage).with_context(|| Open {
+                    path: storage.locate(e),
+                })?;
+                bytes += log.pos;
             }
-            Command::Rm(k) => Err(Error::BadIndex {
-                cmd: "Set".to_owned(),
-                offset: entry.offset,
-                found: Command::Rm(k),
-            }),
+        }
+        for e in storage.retired().with_context(|| ListDir {
+            path: storage.path(),
+        })? {
+            let log = LogFile::open_retired(e, storage).with_context(|| Open {
+                path: storage.locate(e),
+            })?;
+            bytes += log.pos;
+        }
+        Ok(bytes)
+    }
+
+    /// Check that writing `value` to `key` stays within the store's limits, where `replaces` says whether it replaces
+    /// what is there or is merged into it.
+    fn check_limits(&self, ns: &str, key: &str, value: &str, replaces: bool) -> Result<()> {
+        let limits = self.limits;
+        let (key_size, value_size) = (key.len() as u64, value.len() as u64);
+        if let Some(max) = limits.key_size {
+            ensure!(
+                key_size <= max,
+                KeyTooLarge {
+                    size: key_size,
+                    max
+                }
+            );
+        }
+        if let Some(max) = limits.value_size {
+            ensure!(
+                value_size <= max,
+                ValueTooLarge {
+                    size: value_size,
+                    max
+                }
+            );
+        }
+        if let Some(max) = limits.live_bytes {
+            let operands = self
+                .merges
+                .get(ns)
+                .and_then(|keys| keys.get(key))
+                .map_or(0, |operands| operands.iter().map(|entry| entry.size).sum());
+            let previous = self
+                .index
+                .get(ns)
+                .and_then(|keys| keys.get(key))
+                .map_or(0, |entry| entry.size + operands);
+            let previous = if replaces { previous } else { 0 };
+            let live = self.live - previous + key_size + value_size;
+            ensure!(
+                live <= max,
+                QuotaExceeded {
+                    quota: Quota::LiveData,
+                    max
+                }
+            );
+        }
+        if let Some(max) = limits.disk_bytes {
+            let record = RECORD_OVERHEAD + (ns.len() + key.len() + value.len()) as u64;
+            let disk = self.sealed_bytes + self.log.len() + record;
+            ensure!(
+                disk <= max,
+                QuotaExceeded {
+                    quota: Quota::Disk,
+                    max
+                }
+            );
+        }
+        Ok(())
+    }
+
+    /// Grab the reader for a sealed epoch, mapping its log file if this is the first read from it.
+    fn sealed(&mut self, epoch: u64) -> Result<&SealedLog> {
+        if !self.readers.contains_key(&epoch) {
+            let log = SealedLog::open(epoch, self.storage.as_ref()).with_context(|| Open {
+                path: self.storage.locate(epoch),
+            })?;
+            self.readers.insert(epoch, log);
+        }
+        Ok(&self.readers[&epoch])
+    }
+
+    /// Retrieve the value stored at the specified key
+    ///
+    /// Returns `None` if the key does not exist.
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.get_in("", key)
+    }
+
+    fn get_in(&mut self, ns: &str, key: String) -> Result<Option<String>> {
+        if !self
+            .index
+            .get(ns)
+            .is_some_and(|keys| keys.contains_key(&key))
+        {
+            return Ok(None);
+        }
+        if let Some(cache) = self.cache.as_mut() {
+            if let Some(val) = cache.get(ns, &key) {
+                return Ok(Some(val.clone()));
+            }
+        }
+
+        let found = self.read(ns, &key)?;
+        if let (Some(cache), Some(val)) = (self.cache.as_mut(), &found) {
+            cache.insert(ns, key, val.clone());
+        }
+        Ok(found)
+    }
+
+    /// Forget any cached value for a key that is about to change.
+    ///
+    /// This happens before the change is written, so that if writing it or anything after fails the next read goes
+    /// back to the log instead of trusting a value the key directory may no longer point at.
+    fn uncache(&mut self, ns: &str, key: &str) {
+        if let Some(cache) = self.cache.as_mut() {
+            cache.remove(ns, key);
+        }
+    }
+
+    /// Read the value stored at the specified key from the log, bypassing the cache.
+    fn read(&mut self, ns: &str, key: &str) -> Result<Option<String>> {
+        let entry = match self.index.get(ns).and_then(|keys| keys.get(key)) {
+            Some(entry) => *entry,
+            None => return Ok(None),
+        };
+
+        debug!("getting {} from {}@{}", &key, entry.epoch, entry.offset);
+        let operands = self
+            .merges
+            .get(ns)
+            .and_then(|keys| keys.get(key))
+            .cloned()
+            .unwrap_or_default();
+        let mut records = Vec::with_capacity(operands.len() + 1);
+        for entry in Some(entry).into_iter().chain(operands) {
+            records.push((entry.offset, self.retrieve(entry)?));
+        }
+        resolve(self.merge_operator.as_deref(), key, records).map(Some)
+    }
+
+    /// Read the record an entry points at.
+    fn retrieve(&mut self, entry: KeyEntry) -> Result<Command> {
+        if entry.epoch == self.epoch {
+            self.log.retrieve(entry.offset)
+        } else if self.mmap {
+            self.sealed(entry.epoch)?.retrieve(entry.offset)
+        } else {
+            let mut log =
+                LogFile::open(entry.epoch, self.storage.as_ref()).with_context(|| Open {
+                    path: self.storage.locate(entry.epoch),
+                })?;
+            log.retrieve(entry.offset)
         }
     }
 
@@ -338,19 +1317,43 @@ impl KvStore {
     ///
     /// If a value is already stored at this key it is unceremoniously overwritten.
     pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        let cmd = Command::Set {
+        self.set_in("", key, value)
+    }
+
+    fn set_in(&mut self, ns: &str, key: String, value: String) -> Result<()> {
+        self.check_limits(ns, &key, &value, true)?;
+        self.uncache(ns, &key);
+        let cached = self.cache.as_ref().map(|_| (key.clone(), value.clone()));
+        let watched = self.watchers.watching(ns, &key).then(|| value.clone());
+        let indexed = self
+            .indexes
+            .iter()
+            .any(|index| index.covers(ns, &key))
+            .then(|| value.clone());
+        let entry = self.append(Command::Set {
+            ns: ns.to_owned(),
             key: key.clone(),
             val: value,
-        };
-
-        let offset = self.log.record(cmd)?;
-        let previous = self.index.insert(
-            key,
-            KeyEntry {
-                epoch: self.epoch,
-                offset,
-            },
-        );
+        })?;
+        if let Some(value) = watched {
+            self.watchers.notify(ns, &key, Some(&value));
+        }
+        if let Some(value) = indexed {
+            self.reindex(ns, &key, Some(&value));
+        }
+        self.live -= forget_operands(&mut self.merges, ns, &key);
+        let previous = self
+            .index
+            .entry(ns.to_owned())
+            .or_default()
+            .insert(key, entry);
+        if let (Some(cache), Some((key, value))) = (self.cache.as_mut(), cached) {
+            cache.insert(ns, key, value);
+        }
+        self.live += entry.size;
+        if let Some(previous) = previous {
+            self.live -= previous.size;
+        }
 
         if previous.is_some() {
             self.mutations += 1;
@@ -358,38 +1361,179 @@ impl KvStore {
                 return self.compact().context(Compact);
             }
         }
+        Ok(())
+    }
 
-        if self.log.pos < self.max_log_size {
-            return Ok(());
-        }
+    /// Combine `operand` with the value stored at the specified key using the store's merge operator, without reading
+    /// the value first. See [`with_merge_operator`](KvStore::with_merge_operator).
+    ///
+    /// The operand is only recorded here. It is combined with the value whenever that is next read, and for good
+    /// when the store is compacted, so a bad operand fails those with [`Error::MergeFailed`] instead.
+    ///
+    /// ```rust
+    /// # use kvs::{Add, KvStore};
+    /// let mut store = KvStore::in_memory().with_merge_operator(Add);
+    /// store.merge("visits".to_owned(), "1".to_owned()).unwrap();
+    /// store.merge("visits".to_owned(), "2".to_owned()).unwrap();
+    /// assert_eq!(store.get("visits".to_owned()).unwrap(), Some("3".to_owned()));
+    /// ```
+    pub fn merge(&mut self, key: String, operand: String) -> Result<()> {
+        self.merge_checked("", key, operand)
+    }
 
-        // New epoch
-        self.epoch += 1;
-        debug!("beginning epoch {}", self.epoch);
-        self.log = LogFile::new(self.epoch, self.path.clone()).with_context(|| Open {
-            path: self.path.clone(),
+    /// Record a merge, checking there is an operator to work out the result with first.
+    fn merge_checked(&mut self, ns: &str, key: String, operand: String) -> Result<()> {
+        ensure!(self.merge_operator.is_some(), NoMergeOperator);
+        self.merge_in(ns, key, operand)
+    }
+
+    /// Record a merge. Followers use this directly, as they record whatever their leader did even if they can't
+    /// work out the result themselves.
+    fn merge_in(&mut self, ns: &str, key: String, operand: String) -> Result<()> {
+        self.check_limits(ns, &key, &operand, false)?;
+        self.uncache(ns, &key);
+        let entry = self.append(Command::Merge {
+            ns: ns.to_owned(),
+            key: key.clone(),
+            operand,
         })?;
+        self.live += entry.size;
+        match self
+            .index
+            .entry(ns.to_owned())
+            .or_default()
+            .entry(key.clone())
+        {
+            hash_map::Entry::Occupied(_) => self
+                .merges
+                .entry(ns.to_owned())
+                .or_default()
+                .entry(key.clone())
+                .or_default()
+                .push(entry),
+            hash_map::Entry::Vacant(first) => {
+                first.insert(entry);
+            }
+        }
 
+        let watched = self.watchers.watching(ns, &key);
+        let indexed = self.indexes.iter().any(|index| index.covers(ns, &key));
+        if watched || indexed {
+            match self.read(ns, &key) {
+                Ok(val) => {
+                    self.watchers.notify(ns, &key, val.as_deref());
+                    self.reindex(ns, &key, val.as_deref());
+                }
+                Err(e) => {
+                    warn!("failed to work out {} after merging into it: {}", key, e);
+                    self.reindex(ns, &key, None);
+                }
+            }
+        }
+
+        self.mutations += 1;
+        if self.should_compact() {
+            return self.compact().context(Compact);
+        }
         Ok(())
     }
 
-    fn roll_back(&mut self, epoch: u64) -> io::Result<()> {
-        self.epoch = epoch;
-        self.log = LogFile::open(self.epoch, self.path.clone())?;
-
-        // For safety's sake remove all logs files after this epoch
-        for entry in fs::read_dir(&self.path)? {
-            let f = entry?;
-            let e: u64 = f
-                .file_name()
-                .to_string_lossy()
-                .parse()
-                .expect("expected file to have a u64 name");
+    /// Record a command in the current log, rotating to a new epoch if the log is now full.
+    ///
+    /// Returns where the command was recorded.
+    fn append(&mut self, cmd: Command) -> Result<KeyEntry> {
+        if self.log.sealed_at().is_some() {
+            // An earlier rotation sealed the log but could neither start the next one nor unseal it again. Nothing
+            // more can go in this log, so writes fail until a rotation gets through.
+            self.rotate()?;
+        }
+        let published = self.leader.as_ref().map(|_| cmd.clone());
+        let size = match &cmd {
+            Command::Set { key, val, .. } => (key.len() + val.len()) as u64,
+            Command::Merge { key, operand, .. } => (key.len() + operand.len()) as u64,
+            _ => 0,
+        };
+        let entry = KeyEntry {
+            epoch: self.epoch,
+            offset: self.log.record(cmd)?,
+            size,
+        };
+        if self.durability == Durability::Sync {
+            // The record may still turn up after a failure here, just like one whose write failed part way
+            self.log.sync().context(Io {
+                action: "sync".to_owned(),
+                offset: entry.offset,
+            })?;
+        }
+        self.last = Some(Position {
+            epoch: entry.epoch,
+            offset: entry.offset,
+        });
+        if let (Some(leader), Some(cmd)) = (&self.leader, published) {
+            let end = Position {
+                epoch: self.epoch,
+                offset: self.log.len(),
+            };
+            let position = Position {
+                epoch: entry.epoch,
+                offset: entry.offset,
+            };
+            leader.publish(position, cmd, end);
+        }
+        if self.log.len() >= self.max_log_size {
+            // The command is safely recorded either way, so try again on the next write rather than failing this one
+            if let Err(e) = self.rotate() {
+                warn!("failed to rotate past epoch {}: {}", self.epoch, e);
+            }
+        }
+        Ok(entry)
+    }
 
-            if e > epoch {
-                fs::remove_file(f.path())?;
+    /// Seal the current log and start writing to a new epoch.
+    fn rotate(&mut self) -> Result<()> {
+        // A rotation that failed part way may have left the log sealed already
+        let sealed_at = match self.log.sealed_at() {
+            Some(offset) => offset,
+            None => {
+                let offset = self.log.len();
+                self.log.seal()?;
+                offset
+            }
+        };
+        let next = self.epoch + 1;
+        match LogFile::new(next, self.storage.as_ref()) {
+            Ok(log) => {
+                self.sealed_bytes += self.log.len();
+                self.epoch = next;
+                self.log = log;
+                debug!("beginning epoch {}", self.epoch);
+                Ok(())
+            }
+            Err(e) => {
+                // Carry on writing to the current log
+                self.log.truncate(sealed_at).context(Io {
+                    action: "unseal".to_owned(),
+                    offset: sealed_at,
+                })?;
+                Err(e).context(Open {
+                    path: self.storage.locate(next),
+                })
             }
         }
+    }
+
+    /// Abandon a failed compaction that began after `epoch`, moving on to a fresh epoch and removing the logs it wrote.
+    ///
+    /// Nothing is unsealed or reused, so failing part way through leaves the store writing to a log that replays
+    /// after any compacted logs left behind. Those only repeat values already found in older logs.
+    fn roll_back(&mut self, epoch: u64) -> Result<()> {
+        let compacted_until = self.epoch;
+        self.rotate()?;
+        for e in epoch + 1..=compacted_until {
+            self.readers.remove(&e);
+            self.storage.remove(e).context(RemoveLog { epoch: e })?;
+        }
+        self.sealed_bytes = self.measure_sealed()?;
         Ok(())
     }
 
@@ -403,64 +1547,217 @@ impl KvStore {
     ///    It would allow us to compact from later epochs into earlier ones, however.
     pub fn compact(&mut self) -> Result<()> {
         let start_epoch = self.epoch;
+        self.mutations = 0;
 
-        self.epoch += 1;
+        self.rotate()?;
         let rm_until = self.epoch;
-        self.log = match LogFile::new(self.epoch, self.path.clone()) {
-            Ok(lf) => lf,
-            Err(e) => {
-                self.epoch = start_epoch;
-                return Err(e).context(Open {
-                    path: self.path.clone(),
-                });
-            }
-        };
 
-        let keys: Vec<String> = self.index.keys().map(|k| k.clone()).collect();
+        let keys: Vec<(String, String)> = self
+            .keys()
+            .map(|(ns, key)| (ns.clone(), key.clone()))
+            .collect();
 
-        for key in keys {
-            let maybe_val = match self.get(key.clone()) {
-                Ok(v) => v,
-                Err(e) => {
-                    self.roll_back(start_epoch)
-                        .context(RollBack { epoch: start_epoch })?;
-                    return Err(e);
-                }
-            };
-            if let Some(value) = maybe_val {
+        // Index the compacted records separately so that rolling back leaves the index untouched
+        let mut compacted = Index::with_capacity(self.index.len());
+        let mut unmerged = Merges::new();
+        for (ns, key) in keys {
+            // Go straight to the log so compaction doesn't churn the cache
+            let copied = match self.read(&ns, &key) {
                 // May rotate to a new log file. That's fine!
-                // prevent nested compaction.
-                self.mutations = 0;
-                if let Err(e) = self.set(key, value) {
-                    self.roll_back(start_epoch)
-                        .context(RollBack { epoch: start_epoch })?;
-                    return Err(e);
+                Ok(Some(val)) => {
+                    let cmd = Command::Set {
+                        ns: ns.clone(),
+                        key: key.clone(),
+                        val,
+                    };
+                    self.append(cmd).map(|entry| {
+                        compacted.entry(ns).or_default().insert(key, entry);
+                    })
+                }
+                Ok(None) => Ok(()),
+                // Keep what can't be merged as it is, rather than losing it or failing every compaction from now on
+                Err(Error::NoMergeOperator) | Err(Error::MergeFailed { .. }) => {
+                    warn!("copying {} without merging its operands", key);
+                    self.copy_unmerged(&ns, &key, &mut compacted, &mut unmerged)
                 }
+                Err(e) => Err(e),
             };
+            if let Err(e) = copied {
+                self.roll_back(start_epoch)
+                    .context(RollBack { epoch: start_epoch })?;
+                return Err(e);
+            }
         }
 
-        // Remove old log files. We don't need to roll back on failure after this point
-        for entry in fs::read_dir(&self.path).with_context(|| ListDir {
-            path: self.path.clone(),
-        })? {
-            let f = entry.with_context(|| ListDir {
-                path: self.path.clone(),
-            })?;
+        // Start a fresh epoch so that new writes never share a log with compacted records. Otherwise a torn write
+        // at the end of that log could only be discarded along with part of the compacted state.
+        if !self.log.is_empty() {
+            if let Err(e) = self.rotate() {
+                self.roll_back(start_epoch)
+                    .context(RollBack { epoch: start_epoch })?;
+                return Err(e);
+            }
+        }
+
+        self.index = compacted;
+        self.merges = unmerged;
+        self.live = live_bytes(&self.index, &self.merges);
 
-            // There is probably an easier way to do this...
-            let e: u64 = f
-                .file_name()
-                .to_string_lossy()
-                .parse()
-                .expect("expected file to have a u64 name");
-            if e < rm_until {
-                // remove the file
-                fs::remove_file(f.path()).context(RemoveLog { epoch: e })?;
+        // Note which logs hold copies rather than changes before removing the copies the last compaction wrote
+        let copies = Compacted {
+            from: rm_until,
+            until: self.epoch,
+        };
+        self.storage
+            .write_meta(COMPACTED, &copies.encode())
+            .context(RecordCompaction)?;
+        let previous = self.compacted.replace(copies);
+
+        // Remove old log files. We don't need to roll back on failure after this point, but must go from oldest to
+        // newest: any that are left behind still replay correctly as long as they are the newest of the old logs.
+        // That also lets us keep whichever old logs open snapshots still read from until a later compaction.
+        self.pins.retain(|pin| pin.strong_count() > 0);
+        let pinned = self
+            .pins
+            .iter()
+            .filter_map(|pin| pin.upgrade())
+            .map(|pin| pin.epoch)
+            .min();
+        let mut old: Vec<u64> = self
+            .storage
+            .epochs()
+            .with_context(|| ListDir {
+                path: self.storage.path(),
+            })?
+            .into_iter()
+            .filter(|e| *e < rm_until && pinned.is_none_or(|pinned| *e < pinned))
+            .collect();
+        old.sort_unstable();
+        for e in old {
+            self.readers.remove(&e);
+            if self.changelog_retention > 0 && !previous.is_some_and(|c| c.holds(e)) {
+                self.storage.retire(e).context(RetireLog { epoch: e })?;
+            } else {
+                self.storage.remove(e).context(RemoveLog { epoch: e })?;
             }
         }
+
+        let mut retired = self.storage.retired().with_context(|| ListDir {
+            path: self.storage.path(),
+        })?;
+        retired.sort_unstable();
+        let excess = retired.len().saturating_sub(self.changelog_retention);
+        for e in retired.into_iter().take(excess) {
+            self.storage
+                .remove_retired(e)
+                .context(RemoveLog { epoch: e })?;
+        }
+        self.sealed_bytes = self.measure_sealed()?;
+        Ok(())
+    }
+
+    /// Copy the records a key's value would be worked out from into the current log.
+    fn copy_unmerged(
+        &mut self,
+        ns: &str,
+        key: &str,
+        compacted: &mut Index,
+        unmerged: &mut Merges,
+    ) -> Result<()> {
+        let base = self.index[ns][key];
+        let operands = self
+            .merges
+            .get(ns)
+            .and_then(|keys| keys.get(key))
+            .cloned()
+            .unwrap_or_default();
+
+        let cmd = self.retrieve(base)?;
+        let entry = self.append(cmd)?;
+        compacted
+            .entry(ns.to_owned())
+            .or_default()
+            .insert(key.to_owned(), entry);
+        for operand in operands {
+            let cmd = self.retrieve(operand)?;
+            let entry = self.append(cmd)?;
+            unmerged
+                .entry(ns.to_owned())
+                .or_default()
+                .entry(key.to_owned())
+                .or_default()
+                .push(entry);
+        }
         Ok(())
     }
 
+    /// Read every record in every log, and check that each key's index entries point at its value.
+    ///
+    /// Reads otherwise only come across a bad record or index entry when they happen to need it. This looks at all
+    /// of them, reporting every problem it finds instead of stopping at the first. Only fails if the logs can't be
+    /// listed.
+    pub fn verify(&mut self) -> Result<Report> {
+        let mut report = Report::default();
+        let storage = self.storage.clone();
+        for epoch in storage.epochs().with_context(|| ListDir {
+            path: storage.path(),
+        })? {
+            report.logs += 1;
+            let mut log = match LogFile::open(epoch, storage.as_ref()) {
+                Ok(log) => log,
+                Err(source) => {
+                    let error = Error::Open {
+                        source,
+                        path: storage.locate(epoch),
+                    };
+                    report.problems.push(Problem::Unreadable { epoch, error });
+                    continue;
+                }
+            };
+            let mut records = 0;
+            match log.replay(|_, _| records += 1) {
+                Ok(false) if epoch != self.epoch => report.problems.push(Problem::Unsealed {
+                    epoch,
+                    len: log.len(),
+                }),
+                Ok(_) => {}
+                Err(error) => report.problems.push(Problem::Unreadable { epoch, error }),
+            }
+            report.records += records;
+        }
+
+        let mut entries = Vec::new();
+        for (ns, keys) in &self.index {
+            for (key, entry) in keys {
+                let operands = self.merges.get(ns).and_then(|keys| keys.get(key));
+                entries.push((ns.clone(), key.clone(), *entry, false));
+                for operand in operands.into_iter().flatten() {
+                    entries.push((ns.clone(), key.clone(), *operand, true));
+                }
+            }
+        }
+        report.keys = self.keys().count();
+        for (ns, key, entry, operand) in entries {
+            let found = self.retrieve(entry);
+            if found
+                .as_ref()
+                .is_ok_and(|cmd| verify::belongs_to(cmd, &ns, &key, operand))
+            {
+                continue;
+            }
+            report.problems.push(Problem::Misindexed {
+                ns,
+                key,
+                at: Position {
+                    epoch: entry.epoch,
+                    offset: entry.offset,
+                },
+                found,
+            });
+        }
+        Ok(report)
+    }
+
     /// Remove the value stored under the specified key.
     ///
     /// If nothing is stored at that key nothing happens.
@@ -468,19 +1765,41 @@ impl KvStore {
     /// # Example
     /// ```rust
     /// # use kvs::KvStore;
-    /// let mut store = KvStore::open("/tmp/logd").expect("should open");
+    /// # let dir = tempfile::TempDir::new().unwrap();
+    /// let mut store = KvStore::open(dir.path()).expect("should open");
     /// store.set("my key".to_owned(), "my value".to_owned());
     /// store.remove("my key".to_owned());
     /// let val = store.get("my key".to_owned()).expect("shouldn't error");
     /// assert_eq!(val, None);
     /// ```
     pub fn remove(&mut self, key: String) -> Result<()> {
-        if !self.index.contains_key(&key) {
+        self.remove_in("", key)
+    }
+
+    fn remove_in(&mut self, ns: &str, key: String) -> Result<()> {
+        if !self
+            .index
+            .get(ns)
+            .is_some_and(|keys| keys.contains_key(&key))
+        {
             return Err(Error::NotFound);
         }
-        let cmd = Command::Rm(key.clone());
-        self.log.record(cmd)?;
-        self.index.remove(&key);
+        self.uncache(ns, &key);
+        self.append(Command::Rm {
+            ns: ns.to_owned(),
+            key: key.clone(),
+        })?;
+        self.watchers.notify(ns, &key, None);
+        self.reindex(ns, &key, None);
+        self.live -= forget_operands(&mut self.merges, ns, &key);
+        if let Some(keys) = self.index.get_mut(ns) {
+            if let Some(removed) = keys.remove(&key) {
+                self.live -= removed.size;
+            }
+            if keys.is_empty() {
+                self.index.remove(ns);
+            }
+        }
 
         self.mutations += 1;
         if self.should_compact() {
@@ -489,3 +1808,12 @@ impl KvStore {
         Ok(())
     }
 }
+
+impl Drop for KvStore {
+    fn drop(&mut self) {
+        // Hang up on followers rather than leaving them waiting for records that will never come
+        if let Some(leader) = &self.leader {
+            leader.shutdown();
+        }
+    }
+}
@@ -0,0 +1,431 @@
+use crate::storage::{Handle, Mapped, Storage};
+use crate::{
+    BadHeader, Checksum, Command, Deser, Io, LogSeek, LogWrite, ReadOnlyFormat, Result, Ser,
+    Truncated, UnexpectedSeal,
+};
+use bson::{Bson, Document};
+use snafu::{ensure, OptionExt, ResultExt};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+/// Fill `buf` from the reader, treating running out of input as a truncated record.
+fn read_record_bytes<R: io::Read>(reader: &mut R, buf: &mut [u8], offset: u64) -> Result<()> {
+    match reader.read_exact(buf) {
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Truncated { offset }.fail(),
+        r => r.context(Io {
+            action: "read".to_owned(),
+            offset,
+        }),
+    }
+}
+
+/// Logs start with these bytes followed by their format's version, as a little-endian u32.
+///
+/// Read as the length of a BSON document they are far more than BSON allows, so a log from before the header existed
+/// can't be mistaken for one that has it.
+const MAGIC: [u8; 4] = [0xff, b'k', b'v', b's'];
+const HEADER_LEN: u64 = 8;
+/// The version of the format logs are written in.
+const VERSION: u32 = 2;
+/// The largest document BSON allows, which no record in a log without a header can be longer than.
+const MAX_DOCUMENT: i32 = 16 * 1024 * 1024;
+
+/// How the records in a log are laid out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Format {
+    /// Bare BSON documents, as written before logs had a header. These logs have no checksums and are never sealed,
+    /// so they are only ever read.
+    Bare,
+    /// Checksummed records following the header, ending with a seal once the log is complete. See `decode`.
+    Checksummed,
+    /// A header that is damaged or from a later version, holding the log's first bytes.
+    Unrecognised([u8; HEADER_LEN as usize]),
+}
+
+impl Format {
+    /// Work out a log's format from its first bytes, returning it with the offset its first record is at.
+    fn detect(head: &[u8]) -> (Format, u64) {
+        let header = Format::header();
+        // A log that is empty, or stops part way through its header, has had nothing written to it yet
+        if header.starts_with(head) {
+            return (Format::Checksummed, HEADER_LEN);
+        }
+        if head.len() < 4 {
+            // Too short to hold any header, so all there is is the start of a record
+            return (Format::Bare, 0);
+        }
+        let length = i32::from_le_bytes([head[0], head[1], head[2], head[3]]);
+        if (5..=MAX_DOCUMENT).contains(&length) {
+            return (Format::Bare, 0);
+        }
+        let mut found = [0u8; HEADER_LEN as usize];
+        found[..head.len()].copy_from_slice(head);
+        (Format::Unrecognised(found), HEADER_LEN)
+    }
+
+    fn header() -> [u8; HEADER_LEN as usize] {
+        let mut header = [0u8; HEADER_LEN as usize];
+        header[..4].copy_from_slice(&MAGIC);
+        header[4..].copy_from_slice(&VERSION.to_le_bytes());
+        header
+    }
+}
+
+/// Decode the record starting at the reader's current position, which is `offset` bytes into a log in `format`.
+///
+/// Records are a BSON document followed by the little-endian CRC32 of the document's bytes, so that torn writes
+/// and corruption are caught rather than decoded into the wrong command. An empty document marks the end of a
+/// sealed log and decodes to `None`.
+fn decode<R: io::Read>(reader: &mut R, offset: u64, format: Format) -> Result<Option<Command>> {
+    let doc = match format {
+        Format::Bare => Some(read_document(reader, offset)?),
+        Format::Checksummed => decode_document(reader, offset)?,
+        Format::Unrecognised(header) => return BadHeader { header }.fail(),
+    };
+    let doc = match doc {
+        Some(doc) => doc,
+        None => return Ok(None),
+    };
+    bson::from_bson(Bson::Document(doc))
+        .map(Some)
+        .context(Deser { offset })
+}
+
+/// Decode a record's document without interpreting it. See `decode` for the layout.
+pub(crate) fn decode_document<R: io::Read>(
+    reader: &mut R,
+    offset: u64,
+) -> Result<Option<Document>> {
+    let bytes = read_document_bytes(reader, offset)?;
+
+    let mut checksum = [0u8; 4];
+    read_record_bytes(reader, &mut checksum, offset)?;
+    let expected = u32::from_le_bytes(checksum);
+    let found = crc32fast::hash(&bytes);
+    if expected != found {
+        return Checksum {
+            offset,
+            expected,
+            found,
+        }
+        .fail();
+    }
+
+    let doc = Document::from_reader(&mut bytes.as_slice()).context(Deser { offset })?;
+    if doc.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(doc))
+}
+
+/// Read a bare BSON document with nothing after it.
+fn read_document<R: io::Read>(reader: &mut R, offset: u64) -> Result<Document> {
+    let bytes = read_document_bytes(reader, offset)?;
+    Document::from_reader(&mut bytes.as_slice()).context(Deser { offset })
+}
+
+/// Read the bytes of the BSON document at the reader's position, without decoding them.
+fn read_document_bytes<R: io::Read>(reader: &mut R, offset: u64) -> Result<Vec<u8>> {
+    let mut bytes = vec![0u8; 4];
+    read_record_bytes(reader, &mut bytes, offset)?;
+    // The document's length includes the length itself
+    let length = i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]).max(4) as u64;
+    let read = reader
+        .by_ref()
+        .take(length - 4)
+        .read_to_end(&mut bytes)
+        .context(Io {
+            action: "read".to_owned(),
+            offset,
+        })?;
+    if (read as u64) < length - 4 {
+        return Truncated { offset }.fail();
+    }
+    Ok(bytes)
+}
+
+/// Encode a document as a checksummed record. See `decode` for the layout.
+pub(crate) fn encode(doc: &Document, offset: u64) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    doc.to_writer(&mut bytes).context(LogWrite { offset })?;
+    let checksum = crc32fast::hash(&bytes);
+    bytes.extend_from_slice(&checksum.to_le_bytes());
+    Ok(bytes)
+}
+
+pub(crate) struct LogFile {
+    pub(crate) epoch: u64,
+    handle: Box<dyn Handle>,
+    pub(crate) pos: u64,
+    // Where the log ends, which reads seeking back to earlier records don't change
+    len: u64,
+    pub(crate) format: Format,
+    // Where the first record is, after the header
+    start: u64,
+    // Where the seal is, once the log has been sealed
+    sealed: Option<u64>,
+}
+
+impl io::Read for LogFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let bytes_read = self.handle.read(buf)?;
+        self.pos += bytes_read as u64;
+        Ok(bytes_read)
+    }
+}
+
+impl io::Write for LogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let wrote = self.handle.write(buf)?;
+        self.pos += wrote as u64;
+        self.len = self.len.max(self.pos);
+        Ok(wrote)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.handle.flush()
+    }
+}
+
+impl io::Seek for LogFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = self.handle.seek(pos)?;
+        self.pos = pos;
+        Ok(pos)
+    }
+}
+
+impl LogFile {
+    /// Open a new, empty log file.
+    /// Truncates the file if it already exists.
+    pub(crate) fn new(epoch: u64, storage: &dyn Storage) -> io::Result<LogFile> {
+        let handle = storage.create(epoch)?;
+        LogFile::from_handle(epoch, handle)
+    }
+
+    /// Open an existing log file.
+    pub(crate) fn open(epoch: u64, storage: &dyn Storage) -> io::Result<LogFile> {
+        let handle = storage.open(epoch)?;
+        LogFile::from_handle(epoch, handle)
+    }
+
+    /// Open a log that has been set aside in the changelog.
+    pub(crate) fn open_retired(epoch: u64, storage: &dyn Storage) -> io::Result<LogFile> {
+        let handle = storage.open_retired(epoch)?;
+        LogFile::from_handle(epoch, handle)
+    }
+
+    fn from_handle(epoch: u64, mut handle: Box<dyn Handle>) -> io::Result<LogFile> {
+        let mut head = Vec::with_capacity(HEADER_LEN as usize);
+        handle.seek(SeekFrom::Start(0))?;
+        Read::by_ref(&mut handle)
+            .take(HEADER_LEN)
+            .read_to_end(&mut head)?;
+        let (format, start) = Format::detect(&head);
+        let length = handle.seek(SeekFrom::End(0))?;
+
+        Ok(LogFile {
+            epoch,
+            handle,
+            pos: length,
+            len: length,
+            format,
+            start,
+            sealed: None,
+        })
+    }
+
+    /// The length of the log, up to the end of the last record written to it.
+    pub(crate) fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Whether nothing has been recorded in the log.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len <= self.start
+    }
+
+    /// Where the log's seal is, if it has one. Nothing more may be recorded in a sealed log.
+    pub(crate) fn sealed_at(&self) -> Option<u64> {
+        self.sealed
+    }
+
+    /// Read the command, if any, stored at the provided offset.
+    pub(crate) fn retrieve(&mut self, offset: u64) -> Result<Command> {
+        self.seek(SeekFrom::Start(offset))
+            .with_context(|| LogSeek {})?;
+        let format = self.format;
+        let found = decode(self, offset, format)?.context(UnexpectedSeal { offset })?;
+
+        debug!("read {:?} in epoch {}@{}", &found, self.epoch, offset);
+        Ok(found)
+    }
+
+    /// Record a command to the log file.
+    ///
+    /// Returns the offset from the start of the file the command was written to.
+    pub(crate) fn record(&mut self, cmd: Command) -> Result<u64> {
+        debug!("recording {:?} in epoch {}@{}", &cmd, self.epoch, self.pos);
+        let bs = bson::to_bson(&cmd).context(Ser { cmd })?;
+        // We know its a document
+        let doc = bs.as_document().unwrap();
+        let offset = self.append(doc)?;
+        debug!("recorded command in epoch {}@{}", self.epoch, offset);
+        Ok(offset)
+    }
+
+    /// Mark the log as complete. Nothing may be recorded after this.
+    pub(crate) fn seal(&mut self) -> Result<()> {
+        let offset = self.append(&Document::new())?;
+        self.sealed = Some(offset);
+        debug!("sealed epoch {}@{}", self.epoch, offset);
+        Ok(())
+    }
+
+    /// Append a document to the end of the log as a single record.
+    ///
+    /// If the write fails whatever part of the record made it to disk is cut off again, so that later records
+    /// aren't stranded behind garbage.
+    fn append(&mut self, doc: &Document) -> Result<u64> {
+        ensure!(
+            self.format == Format::Checksummed,
+            ReadOnlyFormat { epoch: self.epoch }
+        );
+        if self.len < self.start {
+            self.write_header()?;
+        }
+        let offset = self.seek(SeekFrom::End(0)).with_context(|| LogSeek {})?;
+        let record = encode(doc, offset)?;
+        let written = self.write_all(&record).and_then(|_| self.flush());
+        if let Err(source) = written {
+            if let Err(e) = self.truncate(offset) {
+                warn!(
+                    "failed to discard partial record at {}@{}: {}",
+                    self.epoch, offset, e
+                );
+            }
+            return Err(source).context(Io {
+                action: "write".to_owned(),
+                offset,
+            });
+        }
+        Ok(offset)
+    }
+
+    /// Write the header to a log that doesn't have all of it yet, replacing whatever part of it made it to disk.
+    fn write_header(&mut self) -> Result<()> {
+        let torn = if self.len > 0 {
+            self.truncate(0)
+        } else {
+            Ok(())
+        };
+        let written = torn
+            .and_then(|_| self.write_all(&Format::header()))
+            .and_then(|_| self.flush());
+        if let Err(source) = written {
+            if let Err(e) = self.truncate(0) {
+                warn!(
+                    "failed to discard partial header of epoch {}: {}",
+                    self.epoch, e
+                );
+            }
+            return Err(source).context(Io {
+                action: "write".to_owned(),
+                offset: 0u64,
+            });
+        }
+        Ok(())
+    }
+
+    /// Replay the log, applying a callback function to every recorded event.
+    ///
+    /// Returns whether the log has been sealed. Logs in the bare format are never written to again, so they count as
+    /// sealed once they have been read to the end.
+    pub(crate) fn replay<F: FnMut(Command, u64)>(&mut self, mut callback: F) -> Result<bool> {
+        debug!("replaying epoch {}", self.epoch);
+        let length = self.seek(SeekFrom::End(0)).with_context(|| LogSeek {})?;
+        self.seek(SeekFrom::Start(self.start))
+            .with_context(|| LogSeek {})?;
+        while self.pos < length {
+            let (offset, format) = (self.pos, self.format);
+            match decode(self, offset, format)? {
+                Some(cmd) => callback(cmd, offset),
+                None if self.pos == length => {
+                    self.sealed = Some(offset);
+                    return Ok(true);
+                }
+                None => return UnexpectedSeal { offset }.fail(),
+            }
+        }
+        Ok(self.format == Format::Bare)
+    }
+
+    /// Apply a callback to every record before `until`, stopping early at the end of the log or its seal.
+    ///
+    /// Unlike `replay` this is safe to use while the log is still being written to, as long as `until` is no further
+    /// than the end of the last complete record.
+    pub(crate) fn read_until<F>(&mut self, until: u64, mut callback: F) -> Result<()>
+    where
+        F: FnMut(Command, u64) -> Result<()>,
+    {
+        let until = until.min(self.seek(SeekFrom::End(0)).with_context(|| LogSeek {})?);
+        self.seek(SeekFrom::Start(self.start))
+            .with_context(|| LogSeek {})?;
+        while self.pos < until {
+            let (offset, format) = (self.pos, self.format);
+            match decode(self, offset, format)? {
+                Some(cmd) => callback(cmd, offset)?,
+                None => break,
+            }
+        }
+        Ok(())
+    }
+
+    /// Wait until every record appended so far has reached the disk.
+    pub(crate) fn sync(&mut self) -> io::Result<()> {
+        self.handle.sync()
+    }
+
+    /// Discard everything in the log from `offset` onwards.
+    pub(crate) fn truncate(&mut self, offset: u64) -> io::Result<()> {
+        self.handle.set_len(offset)?;
+        self.len = offset;
+        if self.sealed.is_some_and(|at| at >= offset) {
+            self.sealed = None;
+        }
+        self.seek(SeekFrom::Start(offset))?;
+        Ok(())
+    }
+}
+
+/// A read-only view of a sealed epoch.
+///
+/// Once the store rotates away from an epoch its log file is never written to again, so it can be mapped into
+/// memory once and records decoded straight out of the mapping instead of seeking a file handle on every read.
+pub(crate) struct SealedLog {
+    epoch: u64,
+    map: Mapped,
+    format: Format,
+}
+
+impl SealedLog {
+    pub(crate) fn open(epoch: u64, storage: &dyn Storage) -> io::Result<SealedLog> {
+        let map = storage.map(epoch)?;
+        let head = map.as_ref().as_ref();
+        let (format, _) = Format::detect(&head[..head.len().min(HEADER_LEN as usize)]);
+        Ok(SealedLog { epoch, map, format })
+    }
+
+    /// Read the command, if any, stored at the provided offset.
+    pub(crate) fn retrieve(&self, offset: u64) -> Result<Command> {
+        let map = self.map.as_ref().as_ref();
+        let start = (offset as usize).min(map.len());
+        let mut buf = &map[start..];
+        let found = decode(&mut buf, offset, self.format)?.context(UnexpectedSeal { offset })?;
+
+        debug!(
+            "read {:?} in sealed epoch {}@{}",
+            &found, self.epoch, offset
+        );
+        Ok(found)
+    }
+}
@@ -0,0 +1,557 @@
+use crate::storage::{Handle, Mapped, Storage};
+use std::collections::HashMap;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// Failures for `MemStorage` to inject.
+#[derive(Default)]
+pub(crate) struct Faults {
+    /// How many more bytes may be written before writes fail as if the disk were full. `None` means no limit.
+    pub(crate) space: Option<u64>,
+    /// Whether creating logs fails.
+    pub(crate) create: bool,
+    /// Whether removing logs fails.
+    pub(crate) remove: bool,
+    /// How many more renames may succeed before they fail. Moving logs into the changelog and replacing files kept
+    /// alongside them both rename files. `None` means no limit.
+    pub(crate) renames: Option<usize>,
+    /// Whether cutting logs short fails.
+    pub(crate) truncate: bool,
+    /// Whether waiting for writes to reach the disk fails.
+    pub(crate) sync: bool,
+}
+
+#[derive(Default)]
+struct MemInner {
+    logs: HashMap<u64, Arc<Mutex<Vec<u8>>>>,
+    retired: HashMap<u64, Arc<Mutex<Vec<u8>>>>,
+    meta: HashMap<String, Vec<u8>>,
+    faults: Faults,
+}
+
+/// Logs kept in memory, for stores that don't need to outlive the process.
+///
+/// Clones share the same logs, so a test can keep a copy to inject faults into or reopen a store from.
+#[derive(Clone, Default)]
+pub(crate) struct MemStorage {
+    inner: Arc<Mutex<MemInner>>,
+}
+
+impl MemStorage {
+    /// Change the failures to inject from now on.
+    #[cfg(test)]
+    pub(crate) fn inject(&self, faults: Faults) {
+        self.inner.lock().unwrap().faults = faults;
+    }
+
+    /// Copy the logs as they are now into separate storage, without any faults.
+    #[cfg(test)]
+    pub(crate) fn fork(&self) -> MemStorage {
+        let inner = self.inner.lock().unwrap();
+        let copy = |logs: &HashMap<u64, Arc<Mutex<Vec<u8>>>>| {
+            logs.iter()
+                .map(|(epoch, data)| (*epoch, Arc::new(Mutex::new(data.lock().unwrap().clone()))))
+                .collect()
+        };
+        MemStorage {
+            inner: Arc::new(Mutex::new(MemInner {
+                logs: copy(&inner.logs),
+                retired: copy(&inner.retired),
+                meta: inner.meta.clone(),
+                faults: Faults::default(),
+            })),
+        }
+    }
+
+    fn injected(what: &str) -> io::Error {
+        io::Error::other(format!("injected {} failure", what))
+    }
+}
+
+impl MemInner {
+    /// Use up one of the renames allowed to succeed, failing if there are none left.
+    fn rename(&mut self) -> io::Result<()> {
+        match self.faults.renames {
+            Some(0) => Err(MemStorage::injected("rename")),
+            Some(left) => {
+                self.faults.renames = Some(left - 1);
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+impl Storage for MemStorage {
+    fn path(&self) -> &Path {
+        Path::new(":memory:")
+    }
+
+    fn epochs(&self) -> io::Result<Vec<u64>> {
+        Ok(self.inner.lock().unwrap().logs.keys().cloned().collect())
+    }
+
+    fn create(&self, epoch: u64) -> io::Result<Box<dyn Handle>> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.faults.create {
+            return Err(MemStorage::injected("create"));
+        }
+        let data = Arc::new(Mutex::new(Vec::new()));
+        inner.logs.insert(epoch, data.clone());
+        Ok(Box::new(MemHandle {
+            storage: self.clone(),
+            data,
+            pos: 0,
+        }))
+    }
+
+    fn open(&self, epoch: u64) -> io::Result<Box<dyn Handle>> {
+        let inner = self.inner.lock().unwrap();
+        match inner.logs.get(&epoch) {
+            Some(data) => Ok(Box::new(MemHandle {
+                storage: self.clone(),
+                data: data.clone(),
+                pos: 0,
+            })),
+            None => Err(io::ErrorKind::NotFound.into()),
+        }
+    }
+
+    fn map(&self, epoch: u64) -> io::Result<Mapped> {
+        let inner = self.inner.lock().unwrap();
+        match inner.logs.get(&epoch) {
+            Some(data) => Ok(Box::new(data.lock().unwrap().clone())),
+            None => Err(io::ErrorKind::NotFound.into()),
+        }
+    }
+
+    fn remove(&self, epoch: u64) -> io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.faults.remove {
+            return Err(MemStorage::injected("remove"));
+        }
+        match inner.logs.remove(&epoch) {
+            Some(_) => Ok(()),
+            None => Err(io::ErrorKind::NotFound.into()),
+        }
+    }
+
+    fn retire(&self, epoch: u64) -> io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        if !inner.logs.contains_key(&epoch) {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+        inner.rename()?;
+        match inner.logs.remove(&epoch) {
+            Some(data) => {
+                inner.retired.insert(epoch, data);
+                Ok(())
+            }
+            None => Err(io::ErrorKind::NotFound.into()),
+        }
+    }
+
+    fn retired(&self) -> io::Result<Vec<u64>> {
+        Ok(self.inner.lock().unwrap().retired.keys().cloned().collect())
+    }
+
+    fn open_retired(&self, epoch: u64) -> io::Result<Box<dyn Handle>> {
+        let inner = self.inner.lock().unwrap();
+        match inner.retired.get(&epoch) {
+            Some(data) => Ok(Box::new(MemHandle {
+                storage: self.clone(),
+                data: data.clone(),
+                pos: 0,
+            })),
+            None => Err(io::ErrorKind::NotFound.into()),
+        }
+    }
+
+    fn remove_retired(&self, epoch: u64) -> io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.faults.remove {
+            return Err(MemStorage::injected("remove"));
+        }
+        match inner.retired.remove(&epoch) {
+            Some(_) => Ok(()),
+            None => Err(io::ErrorKind::NotFound.into()),
+        }
+    }
+
+    fn read_meta(&self, name: &str) -> io::Result<Option<Vec<u8>>> {
+        Ok(self.inner.lock().unwrap().meta.get(name).cloned())
+    }
+
+    fn write_meta(&self, name: &str, contents: &[u8]) -> io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.faults.create {
+            return Err(MemStorage::injected("create"));
+        }
+        // The new contents are staged in a file of their own, then renamed over the old
+        inner.rename()?;
+        inner.meta.insert(name.to_owned(), contents.to_vec());
+        Ok(())
+    }
+
+    fn sync_dir(&self) -> io::Result<()> {
+        if self.inner.lock().unwrap().faults.sync {
+            return Err(MemStorage::injected("sync"));
+        }
+        Ok(())
+    }
+}
+
+/// An open in-memory log. Writes always append, like a file opened in append mode.
+struct MemHandle {
+    storage: MemStorage,
+    data: Arc<Mutex<Vec<u8>>>,
+    pos: u64,
+}
+
+impl Read for MemHandle {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let data = self.data.lock().unwrap();
+        let start = (self.pos as usize).min(data.len());
+        let read = (&data[start..]).read(buf)?;
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+
+impl Write for MemHandle {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut inner = self.storage.inner.lock().unwrap();
+        let mut data = self.data.lock().unwrap();
+        let wrote = match inner.faults.space {
+            Some(0) if !buf.is_empty() => return Err(io::ErrorKind::StorageFull.into()),
+            // Write as much as fits, leaving the caller to find out the disk is full on its next write
+            Some(space) => {
+                let wrote = buf.len().min(space as usize);
+                inner.faults.space = Some(space - wrote as u64);
+                wrote
+            }
+            None => buf.len(),
+        };
+        data.extend_from_slice(&buf[..wrote]);
+        self.pos = data.len() as u64;
+        Ok(wrote)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for MemHandle {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let len = self.data.lock().unwrap().len() as i64;
+        let pos = match pos {
+            SeekFrom::Start(offset) => offset as i64,
+            SeekFrom::End(offset) => len + offset,
+            SeekFrom::Current(offset) => self.pos as i64 + offset,
+        };
+        if pos < 0 {
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+        self.pos = pos as u64;
+        Ok(self.pos)
+    }
+}
+
+impl Handle for MemHandle {
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        let mut inner = self.storage.inner.lock().unwrap();
+        if inner.faults.truncate {
+            return Err(MemStorage::injected("truncate"));
+        }
+        let mut data = self.data.lock().unwrap();
+        // Truncating gives space back, so a failed write can make room for a smaller one
+        if let Some(space) = inner.faults.space.as_mut() {
+            *space += (data.len() as u64).saturating_sub(len);
+        }
+        data.resize(len as usize, 0);
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        if self.storage.inner.lock().unwrap().faults.sync {
+            return Err(MemStorage::injected("sync"));
+        }
+        // There's no disk to wait for
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Faults, MemStorage};
+    use crate::storage::Storage;
+    use crate::{Error, KvStore};
+    use std::collections::HashMap;
+    use std::io;
+    use std::sync::Arc;
+
+    const KEYS: usize = 30;
+    // Small enough that every test spans several epochs
+    const MAX_LOG_SIZE: u64 = 512;
+
+    fn key(id: usize) -> String {
+        format!("key{}", id)
+    }
+
+    fn open(storage: &MemStorage) -> KvStore {
+        KvStore::open_storage(Arc::new(storage.clone()))
+            .expect("failed to open store")
+            .with_max_size(MAX_LOG_SIZE)
+    }
+
+    /// Fill a store with a mix of sets, overwrites and removals, returning what it should now hold.
+    fn populate(store: &mut KvStore) -> HashMap<String, String> {
+        let mut expected = HashMap::new();
+        for i in 0..100 {
+            let (key, val) = (key(i % KEYS), format!("value{}", i));
+            store.set(key.clone(), val.clone()).expect("failed to set");
+            expected.insert(key.clone(), val);
+            if i % 7 == 0 {
+                store.remove(key.clone()).expect("failed to remove");
+                expected.remove(&key);
+            }
+        }
+        expected
+    }
+
+    fn assert_holds(store: &mut KvStore, expected: &HashMap<String, String>) {
+        for k in 0..KEYS {
+            assert_eq!(store.get(key(k)).unwrap(), expected.get(&key(k)).cloned());
+        }
+    }
+
+    /// Check the store holds exactly `expected`, both as it is and when reopened from a copy of its logs.
+    fn check(store: &mut KvStore, storage: &MemStorage, expected: &HashMap<String, String>) {
+        assert_holds(store, expected);
+        assert_holds(&mut open(&storage.fork()), expected);
+    }
+
+    #[test]
+    fn full_disk_loses_no_acknowledged_writes() {
+        for space in (0..600).step_by(11) {
+            let storage = MemStorage::default();
+            let mut store = open(&storage);
+            let mut expected = populate(&mut store);
+
+            storage.inject(Faults {
+                space: Some(space),
+                ..Faults::default()
+            });
+            for i in 0..50 {
+                let (key, val) = (key(i % KEYS), format!("full{}", i));
+                match store.set(key.clone(), val.clone()) {
+                    Ok(()) => {
+                        expected.insert(key, val);
+                    }
+                    Err(Error::Io { source, .. }) => {
+                        assert_eq!(source.kind(), io::ErrorKind::StorageFull)
+                    }
+                    Err(e) => panic!("unexpected error: {}", e),
+                }
+            }
+            check(&mut store, &storage, &expected);
+
+            // Rejected writes must not leave anything behind to trip over once there is room again
+            storage.inject(Faults::default());
+            store
+                .set(key(0), "roomy".to_owned())
+                .expect("failed to set");
+            expected.insert(key(0), "roomy".to_owned());
+            check(&mut store, &storage, &expected);
+        }
+    }
+
+    #[test]
+    fn failed_compaction_loses_no_acknowledged_writes() {
+        for space in (0..3000).step_by(97) {
+            for &(create, remove) in &[(false, false), (true, false), (false, true)] {
+                let storage = MemStorage::default();
+                let mut store = open(&storage);
+                let expected = populate(&mut store);
+
+                storage.inject(Faults {
+                    space: Some(space),
+                    create,
+                    remove,
+                    ..Faults::default()
+                });
+                // Whether or not this fails, nothing may go missing
+                let _ = store.compact();
+                check(&mut store, &storage, &expected);
+
+                storage.inject(Faults::default());
+                store.compact().expect("failed to compact");
+                check(&mut store, &storage, &expected);
+            }
+        }
+    }
+
+    #[test]
+    fn failed_syncs_are_reported() {
+        let storage = MemStorage::default();
+        let mut store = open(&storage);
+        let expected = populate(&mut store);
+        store.compact().expect("failed to compact");
+
+        storage.inject(Faults {
+            sync: true,
+            ..Faults::default()
+        });
+        match store.sync_all() {
+            Err(Error::Persist { .. }) => {}
+            r => panic!("expected a sync failure, got {:?}", r),
+        }
+        check(&mut store, &storage, &expected);
+
+        storage.inject(Faults::default());
+        store.sync_all().expect("failed to sync");
+    }
+
+    #[test]
+    fn failed_log_removal_is_reported() {
+        let storage = MemStorage::default();
+        let mut store = open(&storage);
+        let expected = populate(&mut store);
+
+        storage.inject(Faults {
+            remove: true,
+            ..Faults::default()
+        });
+        match store.compact() {
+            Err(Error::RemoveLog { .. }) => {}
+            r => panic!("expected a removal failure, got {:?}", r),
+        }
+        check(&mut store, &storage, &expected);
+
+        // The next compaction cleans up what this one left behind
+        storage.inject(Faults::default());
+        store.compact().expect("failed to compact");
+        check(&mut store, &storage, &expected);
+
+        let control = MemStorage::default();
+        let mut store = open(&control);
+        populate(&mut store);
+        store.compact().expect("failed to compact");
+        assert_eq!(
+            storage.epochs().unwrap().len(),
+            control.epochs().unwrap().len()
+        );
+    }
+
+    #[test]
+    fn failed_writes_leave_no_stale_cache_entries() {
+        for space in (0..200).step_by(7) {
+            let storage = MemStorage::default();
+            let mut store = open(&storage).with_cache_capacity(4096);
+            let mut expected = populate(&mut store);
+            assert_holds(&mut store, &expected);
+
+            storage.inject(Faults {
+                space: Some(space),
+                ..Faults::default()
+            });
+            for i in 0..KEYS {
+                let (key, val) = (key(i), format!("cached{}", i));
+                let result = if i % 3 == 0 {
+                    store.remove(key.clone()).map(|_| expected.remove(&key))
+                } else {
+                    store
+                        .set(key.clone(), val.clone())
+                        .map(|_| expected.insert(key, val))
+                };
+                match result {
+                    Ok(_) | Err(Error::NotFound) | Err(Error::Io { .. }) => {}
+                    Err(e) => panic!("unexpected error: {}", e),
+                }
+            }
+            check(&mut store, &storage, &expected);
+        }
+    }
+
+    #[test]
+    fn failed_renames_lose_no_acknowledged_writes() {
+        for renames in 0..6 {
+            let storage = MemStorage::default();
+            let mut store = open(&storage).with_changelog_retention(2);
+            let expected = populate(&mut store);
+            store.compact().expect("failed to compact");
+            populate(&mut store);
+
+            storage.inject(Faults {
+                renames: Some(renames),
+                ..Faults::default()
+            });
+            match store.compact() {
+                Ok(()) => {}
+                Err(Error::RecordCompaction { .. }) | Err(Error::RetireLog { .. }) => {}
+                Err(e) => panic!("unexpected error: {}", e),
+            }
+            check(&mut store, &storage, &expected);
+            storage.inject(Faults {
+                renames: Some(0),
+                ..Faults::default()
+            });
+            match store.create_index("all", "", "$.a") {
+                Err(Error::RecordIndexes { .. }) => {}
+                r => panic!("expected a failure to record the index, got {:?}", r),
+            }
+
+            storage.inject(Faults::default());
+            store.compact().expect("failed to compact");
+            check(&mut store, &storage, &expected);
+        }
+    }
+
+    #[test]
+    fn failed_unseal_refuses_writes_until_rotation_succeeds() {
+        let storage = MemStorage::default();
+        let mut store = open(&storage);
+        storage.inject(Faults {
+            create: true,
+            truncate: true,
+            ..Faults::default()
+        });
+        // The write that fills the first log is kept even though rotating away from it fails, leaving it sealed
+        let mut expected = HashMap::new();
+        for i in 0.. {
+            let (key, val) = (key(i % KEYS), format!("value{}", i));
+            match store.set(key.clone(), val.clone()) {
+                Ok(()) => {
+                    expected.insert(key, val);
+                }
+                Err(Error::Io { action, .. }) if action == "unseal" => break,
+                Err(e) => panic!("unexpected error: {}", e),
+            }
+        }
+        assert_eq!(storage.epochs().unwrap(), vec![0]);
+        check(&mut store, &storage, &expected);
+
+        storage.inject(Faults::default());
+        store
+            .set(key(0), "rotated".to_owned())
+            .expect("failed to set");
+        expected.insert(key(0), "rotated".to_owned());
+        assert_eq!(storage.epochs().unwrap().len(), 2);
+        check(&mut store, &storage, &expected);
+    }
+
+    #[test]
+    fn failed_rotation_keeps_writing_to_the_current_log() {
+        let storage = MemStorage::default();
+        let mut store = open(&storage);
+        storage.inject(Faults {
+            create: true,
+            ..Faults::default()
+        });
+        let expected = populate(&mut store);
+        assert_eq!(storage.epochs().unwrap(), vec![0]);
+        check(&mut store, &storage, &expected);
+    }
+}
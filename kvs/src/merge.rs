@@ -0,0 +1,132 @@
+use crate::{Command, Error, NoMergeOperator, Result};
+use serde_json::{Map, Value};
+use snafu::OptionExt;
+
+/// Combines values with operands passed to [`KvStore::merge`](crate::KvStore::merge).
+///
+/// Operands are only combined when a value is read or compacted, so this must give the same answer every time for
+/// the same inputs.
+pub trait MergeOperator: Send + Sync {
+    /// Combine a key's value, or `None` if it hasn't got one, with an operand. Fails with a description of the problem
+    /// if the two can't be combined.
+    fn merge(&self, key: &str, existing: Option<&str>, operand: &str) -> Result<String, String>;
+}
+
+/// Appends operands to the end of the value.
+pub struct Append;
+
+impl MergeOperator for Append {
+    fn merge(&self, _: &str, existing: Option<&str>, operand: &str) -> Result<String, String> {
+        Ok(format!("{}{}", existing.unwrap_or_default(), operand))
+    }
+}
+
+/// Adds operands to the value, treating both as 64-bit integers. A key without a value starts from zero.
+pub struct Add;
+
+impl MergeOperator for Add {
+    fn merge(&self, _: &str, existing: Option<&str>, operand: &str) -> Result<String, String> {
+        let existing = existing.map_or(Ok(0), parse_int)?;
+        existing
+            .checked_add(parse_int(operand)?)
+            .map(|sum| sum.to_string())
+            .ok_or_else(|| format!("{} + {} overflows", existing, operand))
+    }
+}
+
+fn parse_int(s: &str) -> Result<i64, String> {
+    s.trim()
+        .parse()
+        .map_err(|e| format!("{:?} is not an integer: {}", s, e))
+}
+
+/// Keeps whichever of the value and operand is the larger number, as it was written.
+pub struct Max;
+
+impl MergeOperator for Max {
+    fn merge(&self, _: &str, existing: Option<&str>, operand: &str) -> Result<String, String> {
+        let larger = match existing {
+            Some(existing) if parse_number(existing)? >= parse_number(operand)? => existing,
+            _ => {
+                parse_number(operand)?;
+                operand
+            }
+        };
+        Ok(larger.to_owned())
+    }
+}
+
+fn parse_number(s: &str) -> Result<f64, String> {
+    s.trim()
+        .parse()
+        .map_err(|e| format!("{:?} is not a number: {}", s, e))
+}
+
+/// Applies operands to the value as JSON merge patches ([RFC 7396](https://tools.ietf.org/html/rfc7396)).
+pub struct JsonMergePatch;
+
+impl MergeOperator for JsonMergePatch {
+    fn merge(&self, _: &str, existing: Option<&str>, operand: &str) -> Result<String, String> {
+        let mut target = match existing {
+            Some(existing) => parse_json(existing)?,
+            None => Value::Null,
+        };
+        apply_patch(&mut target, parse_json(operand)?);
+        Ok(target.to_string())
+    }
+}
+
+fn parse_json(s: &str) -> Result<Value, String> {
+    serde_json::from_str(s).map_err(|e| format!("{:?} is not JSON: {}", s, e))
+}
+
+fn apply_patch(target: &mut Value, patch: Value) {
+    let patch = match patch {
+        Value::Object(patch) => patch,
+        patch => {
+            *target = patch;
+            return;
+        }
+    };
+    if !target.is_object() {
+        *target = Value::Object(Map::new());
+    }
+    let fields = target.as_object_mut().expect("just made an object");
+    for (field, patch) in patch {
+        if patch.is_null() {
+            fields.remove(&field);
+        } else {
+            apply_patch(fields.entry(field).or_insert(Value::Null), patch);
+        }
+    }
+}
+
+/// Work out a key's value from the record that last set it, or its first operand if it has never been set, followed
+/// by every operand merged into it since. Each record comes with the offset it was read from.
+pub(crate) fn resolve(
+    operator: Option<&dyn MergeOperator>,
+    key: &str,
+    records: Vec<(u64, Command)>,
+) -> Result<String> {
+    let mut val: Option<String> = None;
+    for (offset, record) in records {
+        val = Some(match record {
+            Command::Set { val: set, .. } if val.is_none() => set,
+            Command::Merge { operand, .. } => operator
+                .context(NoMergeOperator)?
+                .merge(key, val.as_deref(), &operand)
+                .map_err(|message| Error::MergeFailed {
+                    key: key.to_owned(),
+                    message,
+                })?,
+            found => {
+                return Err(Error::BadIndex {
+                    cmd: "Merge".to_owned(),
+                    offset,
+                    found,
+                })
+            }
+        });
+    }
+    Ok(val.expect("a key always has a record"))
+}
@@ -0,0 +1,71 @@
+use crate::{Change, KvStore, Result};
+use std::sync::mpsc::Receiver;
+
+/// The keys in one namespace of a store. See [`KvStore::namespace`].
+///
+/// Namespaces share the store's logs, so writes to any of them are recorded and compacted together.
+pub struct Namespace<'a> {
+    store: &'a mut KvStore,
+    name: String,
+}
+
+impl<'a> Namespace<'a> {
+    pub(crate) fn new(store: &'a mut KvStore, name: String) -> Namespace<'a> {
+        Namespace { store, name }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Retrieve the value stored at the specified key in this namespace. See [`KvStore::get`].
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.store.get_in(&self.name, key)
+    }
+
+    /// Set the value for the specified key in this namespace. See [`KvStore::set`].
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.store.set_in(&self.name, key, value)
+    }
+
+    /// Combine an operand with the value stored at the specified key in this namespace. See [`KvStore::merge`].
+    pub fn merge(&mut self, key: String, operand: String) -> Result<()> {
+        self.store.merge_checked(&self.name, key, operand)
+    }
+
+    /// Remove the value stored under the specified key in this namespace. See [`KvStore::remove`].
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.store.remove_in(&self.name, key)
+    }
+
+    /// Hear about keys in this namespace starting with `prefix` being set or removed. See [`KvStore::watch`].
+    ///
+    /// Dropping the namespace counts as removing each of its keys.
+    pub fn watch(&mut self, prefix: impl Into<String>) -> Receiver<Change> {
+        self.store.watchers.add(self.name.clone(), prefix.into())
+    }
+
+    /// List the keys in this namespace, in order.
+    pub fn keys(&self) -> Vec<String> {
+        let mut keys: Vec<String> = self
+            .store
+            .index
+            .get(&self.name)
+            .map(|keys| keys.keys().cloned().collect())
+            .unwrap_or_default();
+        keys.sort_unstable();
+        keys
+    }
+
+    /// Read out every key in this namespace along with its value, in order of key.
+    pub fn export(&mut self) -> Result<Vec<(String, String)>> {
+        let mut pairs = Vec::new();
+        for key in self.keys() {
+            // Reading doesn't change what's in the namespace, so every key is still there
+            if let Some(val) = self.store.read(&self.name, &key)? {
+                pairs.push((key, val));
+            }
+        }
+        Ok(pairs)
+    }
+}
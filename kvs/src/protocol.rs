@@ -0,0 +1,792 @@
+//! The kvs protocol, over which [`KvsClient`]s send requests to a [`KvsServer`].
+//!
+//! Every frame is the length of the rest of it as a big-endian `u32`, then a request id as a big-endian `u64`, then
+//! a [`Request`] or [`Response`] as a BSON document. Responses carry the id of the request they answer. Clients may
+//! send many requests without waiting, and the server carries them out concurrently, so responses can come back in
+//! any order. Documents may be up to 16 MiB, as in BSON itself, but servers requiring tokens take only a few KiB
+//! from clients that haven't yet authenticated.
+//!
+//! Servers may require TLS, and may require clients to authenticate with a token before anything else. Tokens are
+//! checked in the order requests arrive, so requests sent after [`Request::Auth`] are allowed whatever it allows.
+
+use crate::wire::{encode, receive};
+use crate::{
+    BadTls, Command, Connect, Disconnected, Error, KvStore, Listen, Namespace, Open, Permission,
+    Result, Tokens,
+};
+use serde::{Deserialize, Serialize};
+use snafu::{OptionExt, ResultExt};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, oneshot, Semaphore};
+use tokio::task;
+use tokio_rustls::rustls::crypto::{ring, CryptoProvider};
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use tokio_rustls::rustls::{ClientConfig, RootCertStore, ServerConfig};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+// Refuse frames longer than these rather than reading them into memory: an id and the largest BSON document, or
+// until a client has authenticated, an id and a token
+const MAX_FRAME: u32 = 8 + 16 * 1024 * 1024;
+const MAX_UNAUTHENTICATED_FRAME: u32 = 4 * 1024;
+/// How many requests a connection may have in flight before the server stops reading more from it.
+const MAX_IN_FLIGHT: usize = 1024;
+
+/// Something for a [`KvsServer`] to do.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Request {
+    /// Authenticate with a token, answered with [`Response::Done`] if the server accepts it. Servers that don't
+    /// require tokens accept any.
+    Auth { token: String },
+    /// Carry out a command, answered with [`Response::Done`].
+    Apply { command: Command },
+    /// Read a key, answered with [`Response::Value`].
+    Get { ns: String, key: String },
+    /// Read every key in a namespace starting with `prefix`, answered with [`Response::Pairs`] in order of key.
+    Scan { ns: String, prefix: String },
+}
+
+/// A [`KvsServer`]'s answer to a [`Request`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Response {
+    Done,
+    Value { value: Option<String> },
+    Pairs { pairs: Vec<(String, String)> },
+    Failed { code: ErrorCode, message: String },
+}
+
+impl Response {
+    fn failed(e: &Error) -> Response {
+        Response::Failed {
+            code: e.code(),
+            message: e.to_string(),
+        }
+    }
+}
+
+/// The kind of an [`Error`], as sent to clients and used by `kvs` as its exit code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(into = "u16", from = "u16")]
+#[repr(u16)]
+pub enum ErrorCode {
+    /// Anything not covered by another code.
+    Failed = 1,
+    /// The key, namespace or index wasn't found.
+    NotFound = 2,
+    KeyTooLarge = 3,
+    ValueTooLarge = 4,
+    /// The write would go past a limit on the store's size.
+    QuotaExceeded = 5,
+    /// The logs are corrupt.
+    Corrupt = 6,
+    /// Reading or writing the logs failed.
+    Io = 7,
+    /// Talking to another process failed.
+    Network = 8,
+    /// The request can't be carried out as asked.
+    Invalid = 9,
+    /// The client hasn't authenticated with a token the server accepts.
+    Unauthorized = 10,
+    /// The client's token doesn't permit the request.
+    Forbidden = 11,
+}
+
+impl ErrorCode {
+    /// A short name for the code, like `not_found`.
+    pub fn name(self) -> &'static str {
+        match self {
+            ErrorCode::Failed => "failed",
+            ErrorCode::NotFound => "not_found",
+            ErrorCode::KeyTooLarge => "key_too_large",
+            ErrorCode::ValueTooLarge => "value_too_large",
+            ErrorCode::QuotaExceeded => "quota_exceeded",
+            ErrorCode::Corrupt => "corrupt",
+            ErrorCode::Io => "io",
+            ErrorCode::Network => "network",
+            ErrorCode::Invalid => "invalid",
+            ErrorCode::Unauthorized => "unauthorized",
+            ErrorCode::Forbidden => "forbidden",
+        }
+    }
+
+    /// The error a client reports for a request that failed with this code.
+    fn into_error(self, message: String) -> Error {
+        match self {
+            ErrorCode::NotFound => Error::NotFound,
+            code => Error::Remote { code, message },
+        }
+    }
+}
+
+impl From<ErrorCode> for u16 {
+    fn from(code: ErrorCode) -> u16 {
+        code as u16
+    }
+}
+
+impl From<u16> for ErrorCode {
+    /// Codes from newer servers that this one doesn't know of are taken as [`ErrorCode::Failed`].
+    fn from(code: u16) -> ErrorCode {
+        [
+            ErrorCode::NotFound,
+            ErrorCode::KeyTooLarge,
+            ErrorCode::ValueTooLarge,
+            ErrorCode::QuotaExceeded,
+            ErrorCode::Corrupt,
+            ErrorCode::Io,
+            ErrorCode::Network,
+            ErrorCode::Invalid,
+            ErrorCode::Unauthorized,
+            ErrorCode::Forbidden,
+        ]
+        .iter()
+        .copied()
+        .find(|known| *known as u16 == code)
+        .unwrap_or(ErrorCode::Failed)
+    }
+}
+
+impl Error {
+    /// What kind of error this is.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Error::Replay { source, .. }
+            | Error::Compact { source }
+            | Error::RollBack { source, .. }
+            | Error::LoadRaftLog { source, .. } => source.code(),
+            Error::Remote { code, .. } => *code,
+            Error::NotFound | Error::NoNamespace { .. } | Error::NoIndex { .. } => {
+                ErrorCode::NotFound
+            }
+            Error::KeyTooLarge { .. } => ErrorCode::KeyTooLarge,
+            Error::ValueTooLarge { .. } => ErrorCode::ValueTooLarge,
+            Error::QuotaExceeded { .. } => ErrorCode::QuotaExceeded,
+            Error::Deser { .. }
+            | Error::Truncated { .. }
+            | Error::Checksum { .. }
+            | Error::UnexpectedSeal { .. }
+            | Error::BadHeader { .. }
+            | Error::BadIndex { .. }
+            | Error::MissingEntry { .. } => ErrorCode::Corrupt,
+            Error::MkDir { .. }
+            | Error::RemoveLog { .. }
+            | Error::RetireLog { .. }
+            | Error::RecordCompaction { .. }
+            | Error::RecordProgress { .. }
+            | Error::ReadChanges { .. }
+            | Error::Open { .. }
+            | Error::ListDir { .. }
+            | Error::LogSeek { .. }
+            | Error::Io { .. }
+            | Error::Persist { .. }
+            | Error::RecordIndexes { .. } => ErrorCode::Io,
+            Error::Connect { .. }
+            | Error::NotConnected
+            | Error::SendMessage { .. }
+            | Error::EncodeMessage { .. }
+            | Error::ReceiveMessage { .. }
+            | Error::UnexpectedMessage { .. }
+            | Error::Listen { .. }
+            | Error::Disconnected { .. }
+            | Error::BadReply { .. }
+            | Error::NoLeader
+            | Error::NotLeader { .. }
+            | Error::Unavailable { .. }
+            | Error::WatchLost => ErrorCode::Network,
+            Error::ChangesExpired { .. }
+            | Error::NoMergeOperator
+            | Error::MergeFailed { .. }
+            | Error::IndexExists { .. }
+            | Error::BadIndexPath { .. }
+            | Error::BadConfig { .. }
+            | Error::BadTls { .. }
+            | Error::Exposed { .. }
+            | Error::FrameTooLarge { .. }
+            | Error::UnknownNode { .. } => ErrorCode::Invalid,
+            Error::Unauthorized => ErrorCode::Unauthorized,
+            Error::ReadOnly => ErrorCode::Forbidden,
+            Error::Ser { .. } | Error::LogWrite { .. } | Error::ReadOnlyFormat { .. } => {
+                ErrorCode::Failed
+            }
+        }
+    }
+}
+
+/// Everything rustls needs, using ring for cryptography.
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+fn bad_tls(path: &Path, e: impl ToString) -> Error {
+    BadTls {
+        path,
+        message: e.to_string(),
+    }
+    .build()
+}
+
+/// Read every certificate in a PEM file.
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let file = File::open(path).with_context(|| Open { path })?;
+    let certs = rustls_pemfile::certs(&mut io::BufReader::new(file))
+        .collect::<io::Result<Vec<_>>>()
+        .map_err(|e| bad_tls(path, e))?;
+    if certs.is_empty() {
+        return Err(bad_tls(path, "no certificates found"));
+    }
+    Ok(certs)
+}
+
+/// Read the first private key in a PEM file.
+fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    let file = File::open(path).with_context(|| Open { path })?;
+    rustls_pemfile::private_key(&mut io::BufReader::new(file))
+        .map_err(|e| bad_tls(path, e))?
+        .with_context(|| BadTls {
+            path,
+            message: "no private key found",
+        })
+}
+
+fn encode_frame<T: Serialize>(id: u64, msg: &T) -> Result<Vec<u8>> {
+    let body = encode(msg)?;
+    if body.len() + 8 > MAX_FRAME as usize {
+        return Err(Error::FrameTooLarge {
+            size: body.len() as u64,
+            max: u64::from(MAX_FRAME - 8),
+        });
+    }
+    let mut frame = Vec::with_capacity(12 + body.len());
+    frame.extend_from_slice(&(body.len() as u32 + 8).to_be_bytes());
+    frame.extend_from_slice(&id.to_be_bytes());
+    frame.extend_from_slice(&body);
+    Ok(frame)
+}
+
+/// Read the next frame, no longer than `max`, returning its id and body. `None` if the other end hung up between
+/// frames.
+async fn read_frame(
+    reader: &mut (impl AsyncRead + Unpin),
+    max: u32,
+) -> io::Result<Option<(u64, Vec<u8>)>> {
+    let mut len = [0; 4];
+    match reader.read_exact(&mut len).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let len = u32::from_be_bytes(len);
+    if !(8..=max).contains(&len) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid frame length {}", len),
+        ));
+    }
+    let mut id = [0; 8];
+    reader.read_exact(&mut id).await?;
+    // Only grow the body as it arrives, so claiming a long frame costs nothing until it's sent
+    let mut body = Vec::new();
+    let expected = u64::from(len - 8);
+    if (&mut *reader).take(expected).read_to_end(&mut body).await? as u64 != expected {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(Some((u64::from_be_bytes(id), body)))
+}
+
+/// Write out frames as they come, flushing whenever there are no more waiting.
+async fn write_frames(
+    writer: impl AsyncWrite + Unpin,
+    mut frames: mpsc::Receiver<Vec<u8>>,
+) -> io::Result<()> {
+    let mut writer = BufWriter::new(writer);
+    while let Some(frame) = frames.recv().await {
+        writer.write_all(&frame).await?;
+        while let Ok(frame) = frames.try_recv() {
+            writer.write_all(&frame).await?;
+        }
+        writer.flush().await?;
+    }
+    Ok(())
+}
+
+/// Serves a store to [`KvsClient`]s on tokio. See the [module docs](self) for the protocol.
+///
+/// Requests are carried out on tokio's blocking pool, as the store reads and writes files. Must be run from within a
+/// tokio runtime.
+///
+/// By default anyone who can connect may do anything, in plain text. See [`with_tls`](KvsServer::with_tls) and
+/// [`with_tokens`](KvsServer::with_tokens).
+pub struct KvsServer {
+    store: Arc<Mutex<KvStore>>,
+    tls: Option<TlsAcceptor>,
+    tokens: Option<Arc<Tokens>>,
+}
+
+impl KvsServer {
+    pub fn new(store: KvStore) -> KvsServer {
+        KvsServer {
+            store: Arc::new(Mutex::new(store)),
+            tls: None,
+            tokens: None,
+        }
+    }
+
+    /// Only talk TLS, presenting the certificate chain in the PEM file `cert`, whose private key is in the PEM file
+    /// `key`.
+    pub fn with_tls(mut self, cert: impl AsRef<Path>, key: impl AsRef<Path>) -> Result<Self> {
+        let (cert, key) = (cert.as_ref(), key.as_ref());
+        let config = ServerConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .map_err(|e| bad_tls(cert, e))?
+            .with_no_client_auth()
+            .with_single_cert(load_certs(cert)?, load_key(key)?)
+            .map_err(|e| bad_tls(key, e))?;
+        self.tls = Some(TlsAcceptor::from(Arc::new(config)));
+        Ok(self)
+    }
+
+    /// Refuse every request from a client until it authenticates with one of `tokens`, and any its token doesn't
+    /// permit after that.
+    pub fn with_tokens(mut self, tokens: Tokens) -> Self {
+        self.tokens = Some(Arc::new(tokens));
+        self
+    }
+
+    /// Bind to `addr` and serve clients there.
+    pub async fn run(self, addr: &str) -> Result<()> {
+        let listener = TcpListener::bind(addr).await.context(Listen { addr })?;
+        info!("serving kvs clients on {}", addr);
+        self.serve(listener).await;
+        Ok(())
+    }
+
+    /// Serve clients connecting on `listener`, each in a task of its own. Never returns.
+    pub async fn serve(self, listener: TcpListener) {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    let _ = stream.set_nodelay(true);
+                    let (store, tokens, tls) =
+                        (self.store.clone(), self.tokens.clone(), self.tls.clone());
+                    tokio::spawn(async move {
+                        let served = match tls {
+                            Some(tls) => match tls.accept(stream).await {
+                                Ok(stream) => serve(store, tokens, stream).await,
+                                Err(e) => Err(e),
+                            },
+                            None => serve(store, tokens, stream).await,
+                        };
+                        if let Err(e) = served {
+                            debug!("kvs connection closed: {}", e);
+                        }
+                    });
+                }
+                Err(e) => warn!("failed to accept kvs connection: {}", e),
+            }
+        }
+    }
+}
+
+fn response_frame(id: u64, response: &Response) -> Vec<u8> {
+    encode_frame(id, response)
+        .or_else(|e| encode_frame(id, &Response::failed(&e)))
+        .expect("failures can always be encoded")
+}
+
+/// Carry out one client's requests until it hangs up or breaks the framing.
+async fn serve(
+    store: Arc<Mutex<KvStore>>,
+    tokens: Option<Arc<Tokens>>,
+    stream: impl AsyncRead + AsyncWrite + Send + 'static,
+) -> io::Result<()> {
+    let (reader, writer) = tokio::io::split(stream);
+    let mut reader = BufReader::new(reader);
+    let (frames, pending) = mpsc::channel(MAX_IN_FLIGHT);
+    let writing = tokio::spawn(write_frames(writer, pending));
+    let in_flight = Arc::new(Semaphore::new(MAX_IN_FLIGHT));
+    let mut permission = match tokens {
+        Some(_) => None,
+        None => Some(Permission::ReadWrite),
+    };
+
+    loop {
+        let max = match permission {
+            Some(_) => MAX_FRAME,
+            None => MAX_UNAUTHENTICATED_FRAME,
+        };
+        let (id, body) = match read_frame(&mut reader, max).await? {
+            Some(frame) => frame,
+            None => break,
+        };
+        let request = match receive::<Request, _>(&mut body.as_slice()) {
+            Ok(request) => request,
+            Err(e) => {
+                let response = Response::Failed {
+                    code: ErrorCode::Invalid,
+                    message: e.to_string(),
+                };
+                let _ = frames.send(response_frame(id, &response)).await;
+                continue;
+            }
+        };
+        // Nothing reaches the store without a token permitting it
+        let needed = match &request {
+            Request::Auth { token } => {
+                permission = match &tokens {
+                    Some(tokens) => tokens.check(token),
+                    None => Some(Permission::ReadWrite),
+                };
+                let response = match permission {
+                    Some(_) => Response::Done,
+                    None => Response::failed(&Error::Unauthorized),
+                };
+                let _ = frames.send(response_frame(id, &response)).await;
+                continue;
+            }
+            Request::Get { .. } | Request::Scan { .. } => Permission::Read,
+            Request::Apply { .. } => Permission::ReadWrite,
+        };
+        let refused = match permission {
+            None => Some(Error::Unauthorized),
+            Some(permission) if permission < needed => Some(Error::ReadOnly),
+            Some(_) => None,
+        };
+        if let Some(e) = refused {
+            let _ = frames.send(response_frame(id, &Response::failed(&e))).await;
+            continue;
+        }
+
+        let permit = in_flight
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("never closed");
+        let (store, frames) = (store.clone(), frames.clone());
+        task::spawn_blocking(move || {
+            let response = handle(&store, request);
+            // The writer only goes away if the client does
+            let _ = frames.blocking_send(response_frame(id, &response));
+            drop(permit);
+        });
+    }
+    // Let the requests still in flight finish, then the writer once they have
+    drop(frames);
+    writing.await.map_err(io::Error::other)?
+}
+
+fn handle(store: &Mutex<KvStore>, request: Request) -> Response {
+    let mut store = store.lock().unwrap();
+    let result = match request {
+        Request::Auth { .. } => unreachable!("tokens are checked by serve"),
+        Request::Apply { command } => match command {
+            Command::Set { ns, key, val } => store.namespace(ns).set(key, val),
+            Command::Rm { ns, key } => store.namespace(ns).remove(key),
+            Command::DropNamespace { ns } => store.drop_namespace(&ns),
+            Command::Merge { ns, key, operand } => store.namespace(ns).merge(key, operand),
+        }
+        .map(|()| Response::Done),
+        Request::Get { ns, key } => store
+            .namespace(ns)
+            .get(key)
+            .map(|value| Response::Value { value }),
+        Request::Scan { ns, prefix } => {
+            scan(&mut store.namespace(ns), &prefix).map(|pairs| Response::Pairs { pairs })
+        }
+    };
+    result.unwrap_or_else(|e| Response::failed(&e))
+}
+
+fn scan(namespace: &mut Namespace, prefix: &str) -> Result<Vec<(String, String)>> {
+    let mut pairs = Vec::new();
+    for key in namespace.keys() {
+        if key.starts_with(prefix) {
+            if let Some(val) = namespace.get(key.clone())? {
+                pairs.push((key, val));
+            }
+        }
+    }
+    Ok(pairs)
+}
+
+// Waiting requests, by id, or `None` once the connection is lost
+type Waiting = Arc<Mutex<Option<HashMap<u64, oneshot::Sender<Result<Response>>>>>>;
+
+/// A client for a [`KvsServer`], for use on tokio.
+///
+/// Clones share one connection, and any number of requests can be in flight on it at once, whether sent by one task
+/// or many. Requests in flight together may be carried out in any order, so wait for one to finish before sending
+/// another that relies on it.
+#[derive(Clone)]
+pub struct KvsClient {
+    addr: String,
+    frames: mpsc::Sender<Vec<u8>>,
+    waiting: Waiting,
+    next_id: Arc<AtomicU64>,
+}
+
+impl KvsClient {
+    pub async fn connect(addr: &str) -> Result<KvsClient> {
+        let stream = TcpStream::connect(addr).await.context(Connect { addr })?;
+        let _ = stream.set_nodelay(true);
+        Ok(KvsClient::start(addr, stream))
+    }
+
+    /// Connect over TLS, expecting the server's certificate to be for `domain` and signed by one of the
+    /// certificates in the PEM file `ca`. A self-signed certificate can be its own `ca`.
+    pub async fn connect_tls(addr: &str, domain: &str, ca: impl AsRef<Path>) -> Result<KvsClient> {
+        let mut roots = RootCertStore::empty();
+        let ca = ca.as_ref();
+        for cert in load_certs(ca)? {
+            roots.add(cert).map_err(|e| bad_tls(ca, e))?;
+        }
+        let config = ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .map_err(|e| bad_tls(ca, e))?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let domain = ServerName::try_from(domain.to_owned())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+            .context(Connect { addr })?;
+
+        let stream = TcpStream::connect(addr).await.context(Connect { addr })?;
+        let _ = stream.set_nodelay(true);
+        let stream = TlsConnector::from(Arc::new(config))
+            .connect(domain, stream)
+            .await
+            .context(Connect { addr })?;
+        Ok(KvsClient::start(addr, stream))
+    }
+
+    fn start(addr: &str, stream: impl AsyncRead + AsyncWrite + Send + 'static) -> KvsClient {
+        let (reader, writer) = tokio::io::split(stream);
+        let (frames, outgoing) = mpsc::channel(MAX_IN_FLIGHT);
+        let waiting: Waiting = Arc::new(Mutex::new(Some(HashMap::new())));
+
+        let (lost, at) = (waiting.clone(), addr.to_owned());
+        tokio::spawn(async move {
+            if let Err(e) = write_frames(writer, outgoing).await {
+                fail_waiting(&lost, &at, e);
+            }
+        });
+        let (lost, at) = (waiting.clone(), addr.to_owned());
+        tokio::spawn(async move {
+            let e = read_responses(BufReader::new(reader), &lost).await;
+            fail_waiting(&lost, &at, e);
+        });
+
+        KvsClient {
+            addr: addr.to_owned(),
+            frames,
+            waiting,
+            next_id: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Authenticate with `token`, for this client and all its clones. Fails with [`ErrorCode::Unauthorized`] if the
+    /// server doesn't accept the token, after which nothing is allowed until another is.
+    pub async fn auth(&self, token: impl Into<String>) -> Result<()> {
+        let token = token.into();
+        match self.request(Request::Auth { token }).await? {
+            Response::Done => Ok(()),
+            found => Err(self.unexpected(found)),
+        }
+    }
+
+    /// Send a request and wait for the server to answer it. Requests that fail are answered with
+    /// [`Response::Failed`], rather than an error.
+    pub async fn request(&self, request: Request) -> Result<Response> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let frame = encode_frame(id, &request)?;
+        let (answer, answered) = oneshot::channel();
+        match self.waiting.lock().unwrap().as_mut() {
+            Some(waiting) => waiting.insert(id, answer),
+            None => return Err(self.lost()),
+        };
+        if self.frames.send(frame).await.is_err() {
+            return Err(self.lost());
+        }
+        answered.await.unwrap_or_else(|_| Err(self.lost()))
+    }
+
+    /// Carry out a command on the server's store.
+    pub async fn apply(&self, command: Command) -> Result<()> {
+        match self.request(Request::Apply { command }).await? {
+            Response::Done => Ok(()),
+            found => Err(self.unexpected(found)),
+        }
+    }
+
+    /// Retrieve the value stored at the specified key in the default namespace.
+    pub async fn get(&self, key: String) -> Result<Option<String>> {
+        self.get_in("", key).await
+    }
+
+    /// Retrieve the value stored at the specified key in the named namespace.
+    pub async fn get_in(&self, ns: &str, key: String) -> Result<Option<String>> {
+        let request = Request::Get {
+            ns: ns.to_owned(),
+            key,
+        };
+        match self.request(request).await? {
+            Response::Value { value } => Ok(value),
+            found => Err(self.unexpected(found)),
+        }
+    }
+
+    pub async fn set(&self, key: String, val: String) -> Result<()> {
+        let ns = String::new();
+        self.apply(Command::Set { ns, key, val }).await
+    }
+
+    pub async fn remove(&self, key: String) -> Result<()> {
+        let ns = String::new();
+        self.apply(Command::Rm { ns, key }).await
+    }
+
+    /// Read out every key in the named namespace starting with `prefix`, along with its value, in order of key.
+    pub async fn scan(&self, ns: &str, prefix: &str) -> Result<Vec<(String, String)>> {
+        let request = Request::Scan {
+            ns: ns.to_owned(),
+            prefix: prefix.to_owned(),
+        };
+        match self.request(request).await? {
+            Response::Pairs { pairs } => Ok(pairs),
+            found => Err(self.unexpected(found)),
+        }
+    }
+
+    fn lost(&self) -> Error {
+        let source = io::Error::from(io::ErrorKind::ConnectionAborted);
+        Error::Disconnected {
+            source,
+            addr: self.addr.clone(),
+        }
+    }
+
+    fn unexpected(&self, found: Response) -> Error {
+        match found {
+            Response::Failed { code, message } => code.into_error(message),
+            found => Error::BadReply {
+                addr: self.addr.clone(),
+                reply: format!("{:?}", found),
+            },
+        }
+    }
+}
+
+/// Hand each response to whoever is waiting on it, until the connection is lost.
+async fn read_responses(mut reader: impl AsyncRead + Unpin, waiting: &Waiting) -> io::Error {
+    loop {
+        let (id, body) = match read_frame(&mut reader, MAX_FRAME).await {
+            Ok(Some(frame)) => frame,
+            Ok(None) => return io::Error::from(io::ErrorKind::UnexpectedEof),
+            Err(e) => return e,
+        };
+        let response = receive::<Response, _>(&mut body.as_slice());
+        let answer = waiting
+            .lock()
+            .unwrap()
+            .as_mut()
+            .and_then(|waiting| waiting.remove(&id));
+        match answer {
+            // Whoever sent the request may have stopped waiting
+            Some(answer) => drop(answer.send(response)),
+            None => warn!("response to unknown request {}", id),
+        }
+    }
+}
+
+/// Fail every request still waiting on a lost connection, and any sent on it from now on.
+fn fail_waiting(waiting: &Waiting, addr: &str, e: io::Error) {
+    let lost = waiting.lock().unwrap().take().unwrap_or_default();
+    for (_, answer) in lost {
+        let source = io::Error::new(e.kind(), e.to_string());
+        let _ = answer.send(Err(source).context(Disconnected { addr }));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn error_codes_round_trip() {
+        for code in 0..14u16 {
+            let known = ErrorCode::from(code);
+            if (1..=11).contains(&code) {
+                assert_eq!(u16::from(known), code);
+            } else {
+                assert_eq!(known, ErrorCode::Failed);
+            }
+        }
+        assert_eq!(
+            Error::NoNamespace {
+                name: "ns".to_owned()
+            }
+            .code(),
+            ErrorCode::NotFound
+        );
+    }
+
+    #[tokio::test]
+    async fn frames_round_trip() {
+        let request = Request::Apply {
+            command: Command::Merge {
+                ns: "counters".to_owned(),
+                key: "hits".to_owned(),
+                operand: "1".to_owned(),
+            },
+        };
+        let mut bytes = encode_frame(7, &request).unwrap();
+        bytes.extend(
+            encode_frame(
+                8,
+                &Request::Get {
+                    ns: String::new(),
+                    key: "k".to_owned(),
+                },
+            )
+            .unwrap(),
+        );
+        let mut reader = bytes.as_slice();
+        let (id, body) = read_frame(&mut reader, MAX_FRAME).await.unwrap().unwrap();
+        assert_eq!(id, 7);
+        match receive::<Request, _>(&mut body.as_slice()).unwrap() {
+            Request::Apply {
+                command: Command::Merge { ns, key, operand },
+            } => {
+                assert_eq!(
+                    (ns.as_str(), key.as_str(), operand.as_str()),
+                    ("counters", "hits", "1")
+                )
+            }
+            other => panic!("decoded {:?}", other),
+        }
+        assert_eq!(
+            read_frame(&mut reader, MAX_FRAME).await.unwrap().unwrap().0,
+            8
+        );
+        assert!(read_frame(&mut reader, MAX_FRAME).await.unwrap().is_none());
+        let mut short: &[u8] = &[0, 0, 0, 4];
+        assert!(read_frame(&mut short, MAX_FRAME).await.is_err());
+        let mut cut_off: &[u8] = &[0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 1, 5, 0, 0];
+        assert_eq!(
+            read_frame(&mut cut_off, MAX_FRAME)
+                .await
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+        let mut too_long: &[u8] = &[0, 0, 1, 0];
+        assert!(read_frame(&mut too_long, 255).await.is_err());
+    }
+}
@@ -0,0 +1,1319 @@
+//! A replicated cluster of stores, kept consistent with Raft.
+//!
+//! Every node keeps a Raft log of commands next to its own `KvStore`, and applies each command to its store once a
+//! majority of nodes have it in their logs. Nodes talk to each other and to clients over TCP, one BSON document per
+//! message. Clients must go through the leader for reads as well as writes, and other nodes point them at it.
+//!
+//! Section numbers refer to the extended Raft paper, <https://raft.github.io/raft.pdf>.
+
+use crate::raftlog::{Entry, RaftLog};
+use crate::wire::{receive, send};
+use crate::{Change, Command, Config, Connect, Error, KvStore, Listen, Result};
+use serde::{Deserialize, Serialize};
+use snafu::{OptionExt, ResultExt};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io;
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(50);
+// Election timeouts are picked at random from this range of milliseconds, so that nodes rarely stand at once
+const ELECTION_TIMEOUT_MS: (u64, u64) = (300, 600);
+const RPC_TIMEOUT: Duration = Duration::from_secs(1);
+// How long a node holds on to a client request while waiting on the rest of the cluster
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+// How long a client keeps looking for a leader before giving up
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+const RETRY_DELAY: Duration = Duration::from_millis(100);
+const MAX_ENTRIES: usize = 100;
+const SNAPSHOT_CHUNK: usize = 1000;
+const DEFAULT_SNAPSHOT_THRESHOLD: u64 = 10_000;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+enum Request {
+    /// §5.2
+    Vote {
+        term: u64,
+        candidate: u64,
+        last_log_index: u64,
+        last_log_term: u64,
+    },
+    /// §5.3, also sent empty as a heartbeat.
+    Append {
+        term: u64,
+        leader: u64,
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: Vec<Entry>,
+        leader_commit: u64,
+    },
+    /// §7, with the store's contents sent as namespace, key and value triples split across several requests.
+    Snapshot {
+        term: u64,
+        leader: u64,
+        last_index: u64,
+        last_term: u64,
+        pairs: Vec<(String, String, String)>,
+        first: bool,
+        done: bool,
+    },
+    Get {
+        key: String,
+    },
+    Set {
+        key: String,
+        val: String,
+    },
+    Remove {
+        key: String,
+    },
+    Status,
+    /// Stream changes to keys with this prefix for as long as the connection lasts.
+    Watch {
+        prefix: String,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type")]
+enum Response {
+    Vote {
+        term: u64,
+        granted: bool,
+    },
+    /// `next_index` is where the leader should carry on from: just past the new entries on success, otherwise its
+    /// best guess at where the logs start to differ.
+    Append {
+        term: u64,
+        success: bool,
+        next_index: u64,
+    },
+    /// Snapshot chunks are only accepted in order, so the leader starts over if one is refused.
+    Snapshot {
+        term: u64,
+        accepted: bool,
+    },
+    Value {
+        val: Option<String>,
+    },
+    Done,
+    NotFound,
+    NotLeader {
+        leader: Option<String>,
+    },
+    /// The leader gave up waiting before it could answer.
+    TimedOut {
+        awaited: Awaited,
+    },
+    Status(NodeStatus),
+    /// The first response to a watch, sent once it has started.
+    Watching,
+    Change(Change),
+}
+
+fn unexpected(found: Response) -> Error {
+    Error::UnexpectedMessage {
+        found: format!("{:?}", found),
+    }
+}
+
+/// What a leader was waiting for when it gave up on a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Awaited {
+    /// A majority of the cluster to accept the entry at `index`.
+    Commit { index: u64 },
+    /// A majority of the cluster to confirm it is still leading, before serving a read.
+    Leadership,
+}
+
+impl fmt::Display for Awaited {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Awaited::Commit { index } => write!(f, "entry {} to commit", index),
+            Awaited::Leadership => write!(f, "leadership to be confirmed"),
+        }
+    }
+}
+
+/// The part a node is playing in the cluster.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+/// A node's view of the cluster.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NodeStatus {
+    pub id: u64,
+    pub role: Role,
+    pub term: u64,
+    /// The address of the node this one believes is leading.
+    pub leader: Option<String>,
+    pub commit_index: u64,
+    pub last_applied: u64,
+    pub snapshot_index: u64,
+}
+
+fn connect(addr: &str, timeout: Duration) -> Result<TcpStream> {
+    let connected = addr
+        .to_socket_addrs()
+        .and_then(|mut addrs| {
+            addrs
+                .next()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address found"))
+        })
+        .and_then(|resolved| TcpStream::connect_timeout(&resolved, timeout))
+        .and_then(|stream| {
+            stream.set_read_timeout(Some(timeout))?;
+            stream.set_write_timeout(Some(timeout))?;
+            stream.set_nodelay(true)?;
+            Ok(stream)
+        });
+    connected.context(Connect { addr })
+}
+
+/// Send a request over a cached connection, connecting first if there isn't one and dropping it on failure.
+fn exchange(
+    conn: &mut Option<TcpStream>,
+    addr: &str,
+    timeout: Duration,
+    request: &Request,
+) -> Result<Response> {
+    if conn.is_none() {
+        *conn = Some(connect(addr, timeout)?);
+    }
+    let stream = conn.as_mut().unwrap();
+    let response = send(stream, request).and_then(|_| receive(stream));
+    if response.is_err() {
+        *conn = None;
+    }
+    response
+}
+
+/// What a snapshot being sent to a lagging follower contains, and how far through it we are.
+struct OutgoingSnapshot {
+    last_index: u64,
+    last_term: u64,
+    pairs: Vec<(String, String, String)>,
+    sent: usize,
+    // Where the chunk waiting on a response ends
+    sending: usize,
+}
+
+/// What the leader knows about another node. Only the vote flag means anything to candidates.
+#[derive(Default)]
+struct Peer {
+    next_index: u64,
+    match_index: u64,
+    // The latest read round the peer has confirmed we are still leading for
+    acked_round: u64,
+    sent_at: Option<Instant>,
+    vote_requested: bool,
+    snapshot: Option<OutgoingSnapshot>,
+}
+
+struct State {
+    id: u64,
+    nodes: Vec<String>,
+    log: RaftLog,
+    role: Role,
+    leader: Option<u64>,
+    commit_index: u64,
+    last_applied: u64,
+    election_deadline: Instant,
+    votes: HashSet<u64>,
+    // Indexed by node id, our own slot is unused
+    peers: Vec<Peer>,
+    // Bumped for every read, which may only be answered once a majority confirm we are still leading
+    round: u64,
+    // Entries that clients are waiting on, and what applying them did
+    waiting: HashSet<u64>,
+    outcomes: HashMap<u64, Response>,
+    // Pairs received so far of a snapshot the leader is sending us
+    incoming: Option<HashMap<(String, String), String>>,
+    // Bumped whenever a snapshot from the leader replaces the store's contents
+    installs: u64,
+    snapshot_threshold: u64,
+    rng: u64,
+}
+
+impl State {
+    fn majority(&self) -> usize {
+        self.nodes.len() / 2 + 1
+    }
+
+    fn random(&mut self) -> u64 {
+        // xorshift64
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        self.rng
+    }
+
+    fn reset_election_timer(&mut self) {
+        let (min, max) = ELECTION_TIMEOUT_MS;
+        let timeout = min + self.random() % (max - min);
+        self.election_deadline = Instant::now() + Duration::from_millis(timeout);
+    }
+
+    fn not_leader(&self) -> Response {
+        Response::NotLeader {
+            leader: self
+                .leader
+                .map(|leader| self.nodes[leader as usize].clone()),
+        }
+    }
+
+    fn status(&self) -> NodeStatus {
+        NodeStatus {
+            id: self.id,
+            role: self.role,
+            term: self.log.term(),
+            leader: self
+                .leader
+                .map(|leader| self.nodes[leader as usize].clone()),
+            commit_index: self.commit_index,
+            last_applied: self.last_applied,
+            snapshot_index: self.log.snapshot_index(),
+        }
+    }
+
+    /// Step down if someone else has seen a later term (§5.1).
+    fn observe_term(&mut self, term: u64) -> Result<()> {
+        if term > self.log.term() {
+            self.log.vote(term, None)?;
+            if self.role != Role::Follower {
+                info!("node {} stepping down in term {}", self.id, term);
+            }
+            self.role = Role::Follower;
+            self.leader = None;
+        }
+        Ok(())
+    }
+
+    fn follow(&mut self, leader: u64) {
+        if self.leader != Some(leader) {
+            info!(
+                "node {} following node {} in term {}",
+                self.id,
+                leader,
+                self.log.term()
+            );
+        }
+        self.role = Role::Follower;
+        self.leader = Some(leader);
+        self.reset_election_timer();
+    }
+
+    fn start_election(&mut self) -> Result<()> {
+        let term = self.log.term() + 1;
+        self.log.vote(term, Some(self.id))?;
+        info!("node {} standing for election in term {}", self.id, term);
+        self.role = Role::Candidate;
+        self.leader = None;
+        self.votes = HashSet::new();
+        self.votes.insert(self.id);
+        for peer in self.peers.iter_mut() {
+            peer.vote_requested = false;
+        }
+        self.reset_election_timer();
+        if self.votes.len() >= self.majority() {
+            self.become_leader()?;
+        }
+        Ok(())
+    }
+
+    fn become_leader(&mut self) -> Result<()> {
+        info!("node {} leading in term {}", self.id, self.log.term());
+        self.role = Role::Leader;
+        self.leader = Some(self.id);
+        let next_index = self.log.last_index() + 1;
+        for peer in self.peers.iter_mut() {
+            *peer = Peer {
+                next_index,
+                ..Peer::default()
+            };
+        }
+        // Nothing from earlier terms counts as committed until something from this one is (§5.4.2), so commit an
+        // empty entry straight away
+        self.log.append(vec![Entry {
+            index: next_index,
+            term: self.log.term(),
+            cmd: None,
+        }])?;
+        self.advance_commit()
+    }
+
+    /// Commit the latest entry from this term that a majority have (§5.3, §5.4).
+    fn advance_commit(&mut self) -> Result<()> {
+        if self.role != Role::Leader {
+            return Ok(());
+        }
+        let term = self.log.term();
+        let mut index = self.log.last_index();
+        while index > self.commit_index && self.log.term_at(index) == Some(term) {
+            let replicated = self
+                .peers
+                .iter()
+                .enumerate()
+                .filter(|(id, peer)| *id as u64 != self.id && peer.match_index >= index)
+                .count()
+                + 1;
+            if replicated >= self.majority() {
+                self.commit_index = index;
+                break;
+            }
+            index -= 1;
+        }
+        Ok(())
+    }
+
+    fn on_vote(
+        &mut self,
+        term: u64,
+        candidate: u64,
+        last_log_index: u64,
+        last_log_term: u64,
+    ) -> Result<Response> {
+        self.observe_term(term)?;
+        let current = self.log.term();
+        // §5.4.1: only vote for candidates whose logs are at least as up to date as ours
+        let up_to_date =
+            (last_log_term, last_log_index) >= (self.log.last_term(), self.log.last_index());
+        let granted = term == current
+            && up_to_date
+            && self.log.voted_for().is_none_or(|voted| voted == candidate);
+        if granted {
+            self.log.vote(current, Some(candidate))?;
+            self.reset_election_timer();
+        }
+        Ok(Response::Vote {
+            term: current,
+            granted,
+        })
+    }
+
+    fn on_append(
+        &mut self,
+        term: u64,
+        leader: u64,
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: Vec<Entry>,
+        leader_commit: u64,
+    ) -> Result<Response> {
+        self.observe_term(term)?;
+        let current = self.log.term();
+        let reject = |next_index| Response::Append {
+            term: current,
+            success: false,
+            next_index,
+        };
+        if term < current {
+            return Ok(reject(0));
+        }
+        self.follow(leader);
+
+        if prev_log_index > self.log.last_index() {
+            return Ok(reject(self.log.last_index() + 1));
+        }
+        // Entries covered by our snapshot are committed, so they match the leader's
+        if prev_log_index >= self.log.snapshot_index() {
+            let found = self.log.term_at(prev_log_index);
+            if found != Some(prev_log_term) {
+                // Skip back over the whole conflicting term rather than one entry at a time
+                let mut first = prev_log_index;
+                while first > self.log.snapshot_index() + 1 && self.log.term_at(first - 1) == found
+                {
+                    first -= 1;
+                }
+                return Ok(reject(first));
+            }
+        }
+
+        let last_new = prev_log_index + entries.len() as u64;
+        let mut new = Vec::new();
+        for entry in entries {
+            if entry.index <= self.log.snapshot_index() || !new.is_empty() {
+                if !new.is_empty() {
+                    new.push(entry);
+                }
+                continue;
+            }
+            match self.log.term_at(entry.index) {
+                Some(term) if term == entry.term => {}
+                Some(_) => {
+                    self.log.truncate(entry.index)?;
+                    new.push(entry);
+                }
+                None => new.push(entry),
+            }
+        }
+        if !new.is_empty() {
+            self.log.append(new)?;
+        }
+
+        if leader_commit > self.commit_index {
+            self.commit_index = self.commit_index.max(leader_commit.min(last_new));
+        }
+        Ok(Response::Append {
+            term: current,
+            success: true,
+            next_index: last_new + 1,
+        })
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn on_snapshot(
+        &mut self,
+        store: &mut KvStore,
+        term: u64,
+        leader: u64,
+        last_index: u64,
+        last_term: u64,
+        pairs: Vec<(String, String, String)>,
+        first: bool,
+        done: bool,
+    ) -> Result<Response> {
+        self.observe_term(term)?;
+        let current = self.log.term();
+        if term < current {
+            return Ok(Response::Snapshot {
+                term: current,
+                accepted: false,
+            });
+        }
+        self.follow(leader);
+
+        if first {
+            self.incoming = Some(HashMap::new());
+        }
+        let incoming = match self.incoming.as_mut() {
+            Some(incoming) => incoming,
+            // We missed the start of this one
+            None => {
+                return Ok(Response::Snapshot {
+                    term: current,
+                    accepted: false,
+                })
+            }
+        };
+        incoming.extend(pairs.into_iter().map(|(ns, key, val)| ((ns, key), val)));
+        if !done {
+            return Ok(Response::Snapshot {
+                term: current,
+                accepted: true,
+            });
+        }
+
+        let pairs = self.incoming.take().unwrap_or_default();
+        if last_index > self.last_applied {
+            info!(
+                "node {} installing snapshot up to entry {}",
+                self.id, last_index
+            );
+            let stale: Vec<(String, String)> = store
+                .keys()
+                .map(|(ns, key)| (ns.clone(), key.clone()))
+                .filter(|pair| !pairs.contains_key(pair))
+                .collect();
+            for (ns, key) in stale {
+                store.remove_in(&ns, key)?;
+            }
+            for ((ns, key), val) in pairs {
+                store.set_in(&ns, key, val)?;
+            }
+            store.compact()?;
+            // As when snapshotting, the log is all that would be left of the entries if the store were lost
+            store.sync_all()?;
+            self.log.compact(last_index, last_term)?;
+            self.last_applied = last_index;
+            self.commit_index = self.commit_index.max(last_index);
+            self.installs += 1;
+        }
+        Ok(Response::Snapshot {
+            term: current,
+            accepted: true,
+        })
+    }
+
+    /// Work out what, if anything, to send a peer next. Returns the request along with the term and read round it
+    /// was sent in.
+    fn next_request(
+        &mut self,
+        store: &Mutex<KvStore>,
+        peer: usize,
+    ) -> Result<Option<(Request, u64, u64)>> {
+        let term = self.log.term();
+        match self.role {
+            Role::Follower => Ok(None),
+            Role::Candidate if self.peers[peer].vote_requested => Ok(None),
+            Role::Candidate => {
+                self.peers[peer].vote_requested = true;
+                let request = Request::Vote {
+                    term,
+                    candidate: self.id,
+                    last_log_index: self.log.last_index(),
+                    last_log_term: self.log.last_term(),
+                };
+                Ok(Some((request, term, self.round)))
+            }
+            Role::Leader => {
+                let now = Instant::now();
+                let state = &self.peers[peer];
+                let behind = state.next_index <= self.log.last_index();
+                let heartbeat = state
+                    .sent_at
+                    .is_none_or(|sent_at| now >= sent_at + HEARTBEAT_INTERVAL);
+                let reading = state.acked_round < self.round;
+                if !(behind || heartbeat || reading) {
+                    return Ok(None);
+                }
+                self.peers[peer].sent_at = Some(now);
+
+                if self.peers[peer].next_index > self.log.snapshot_index() {
+                    let prev_log_index = self.peers[peer].next_index - 1;
+                    let request = Request::Append {
+                        term,
+                        leader: self.id,
+                        prev_log_index,
+                        prev_log_term: self
+                            .log
+                            .term_at(prev_log_index)
+                            .expect("entries after the snapshot are in the log"),
+                        entries: self.log.slice(prev_log_index + 1, MAX_ENTRIES),
+                        leader_commit: self.commit_index,
+                    };
+                    return Ok(Some((request, term, self.round)));
+                }
+
+                // The entries the peer needs are gone, so send everything they led to instead
+                if self.peers[peer].snapshot.is_none() {
+                    // Rather than hold everything else up, try again once entries aren't being applied
+                    let mut store = match store.try_lock() {
+                        Ok(store) => store,
+                        Err(_) => {
+                            self.peers[peer].sent_at = None;
+                            return Ok(None);
+                        }
+                    };
+                    let last_index = self.last_applied;
+                    let last_term = self
+                        .log
+                        .term_at(last_index)
+                        .expect("applied entries are in the log");
+                    let keys: Vec<(String, String)> = store
+                        .keys()
+                        .map(|(ns, key)| (ns.clone(), key.clone()))
+                        .collect();
+                    let mut pairs = Vec::with_capacity(keys.len());
+                    for (ns, key) in keys {
+                        if let Some(val) = store.get_in(&ns, key.clone())? {
+                            pairs.push((ns, key, val));
+                        }
+                    }
+                    self.peers[peer].snapshot = Some(OutgoingSnapshot {
+                        last_index,
+                        last_term,
+                        pairs,
+                        sent: 0,
+                        sending: 0,
+                    });
+                }
+                let snapshot = self.peers[peer].snapshot.as_mut().unwrap();
+                let end = (snapshot.sent + SNAPSHOT_CHUNK).min(snapshot.pairs.len());
+                snapshot.sending = end;
+                let request = Request::Snapshot {
+                    term,
+                    leader: self.id,
+                    last_index: snapshot.last_index,
+                    last_term: snapshot.last_term,
+                    pairs: snapshot.pairs[snapshot.sent..end].to_vec(),
+                    first: snapshot.sent == 0,
+                    done: end == snapshot.pairs.len(),
+                };
+                Ok(Some((request, term, self.round)))
+            }
+        }
+    }
+
+    fn on_response(
+        &mut self,
+        peer: usize,
+        term: u64,
+        round: u64,
+        response: Response,
+    ) -> Result<()> {
+        let replied = match &response {
+            Response::Vote { term, .. }
+            | Response::Append { term, .. }
+            | Response::Snapshot { term, .. } => *term,
+            _ => return Err(unexpected(response)),
+        };
+        self.observe_term(replied)?;
+        // Anything sent in an earlier term is out of date
+        if term != self.log.term() {
+            return Ok(());
+        }
+
+        match response {
+            Response::Vote { granted: true, .. } if self.role == Role::Candidate => {
+                self.votes.insert(peer as u64);
+                if self.votes.len() >= self.majority() {
+                    self.become_leader()?;
+                }
+            }
+            Response::Append {
+                success,
+                next_index,
+                ..
+            } if self.role == Role::Leader => {
+                let state = &mut self.peers[peer];
+                state.acked_round = state.acked_round.max(round);
+                if success {
+                    state.match_index = state.match_index.max(next_index - 1);
+                    state.next_index = next_index;
+                    self.advance_commit()?;
+                } else {
+                    state.next_index = next_index.min(state.next_index - 1).max(1);
+                }
+            }
+            Response::Snapshot { accepted, .. } if self.role == Role::Leader => {
+                let state = &mut self.peers[peer];
+                state.acked_round = state.acked_round.max(round);
+                match state.snapshot.as_mut() {
+                    Some(snapshot) if accepted => {
+                        snapshot.sent = snapshot.sending;
+                        if snapshot.sent == snapshot.pairs.len() {
+                            state.match_index = snapshot.last_index;
+                            state.next_index = snapshot.last_index + 1;
+                            state.snapshot = None;
+                            self.advance_commit()?;
+                        }
+                    }
+                    _ => state.snapshot = None,
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Give a candidate another chance to ask a peer it couldn't reach for its vote.
+    fn on_failure(&mut self, peer: usize, term: u64) {
+        if self.role == Role::Candidate && term == self.log.term() {
+            self.peers[peer].vote_requested = false;
+        }
+    }
+}
+
+struct Shared {
+    state: Mutex<State>,
+    // Only ever locked after the state, if both are needed, so that applying entries never holds up the rest
+    store: Mutex<KvStore>,
+    // Signalled whenever the state changes in a way that others might be waiting on
+    changed: Condvar,
+}
+
+/// Append a command to the leader's log and wait for it to be applied.
+fn propose(shared: &Shared, mut state: MutexGuard<State>, cmd: Command) -> Result<Response> {
+    if state.role != Role::Leader {
+        return Ok(state.not_leader());
+    }
+    let term = state.log.term();
+    let index = state.log.last_index() + 1;
+    state.log.append(vec![Entry {
+        index,
+        term,
+        cmd: Some(cmd),
+    }])?;
+    state.waiting.insert(index);
+    state.advance_commit()?;
+    shared.changed.notify_all();
+
+    let deadline = Instant::now() + REQUEST_TIMEOUT;
+    loop {
+        if let Some(outcome) = state.outcomes.remove(&index) {
+            state.waiting.remove(&index);
+            return Ok(outcome);
+        }
+        // The entry may still be committed by the next leader, but we can't know
+        if state.role != Role::Leader || state.log.term() != term {
+            state.waiting.remove(&index);
+            return Ok(state.not_leader());
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            state.waiting.remove(&index);
+            return Ok(Response::TimedOut {
+                awaited: Awaited::Commit { index },
+            });
+        }
+        state = shared
+            .changed
+            .wait_timeout(state, deadline - now)
+            .unwrap()
+            .0;
+    }
+}
+
+/// Read a key once it's certain that this node is still leading and has applied everything committed before the
+/// read arrived (§8).
+fn read(shared: &Shared, mut state: MutexGuard<State>, key: String) -> Result<Response> {
+    if state.role != Role::Leader {
+        return Ok(state.not_leader());
+    }
+    let term = state.log.term();
+    let deadline = Instant::now() + REQUEST_TIMEOUT;
+    let mut read = None;
+    loop {
+        if state.role != Role::Leader || state.log.term() != term {
+            return Ok(state.not_leader());
+        }
+        // Until something from this term commits we don't know how much of the log is committed
+        if state.log.term_at(state.commit_index) == Some(term) {
+            let (round, read_index) = match read {
+                Some(read) => read,
+                None => {
+                    state.round += 1;
+                    shared.changed.notify_all();
+                    *read.insert((state.round, state.commit_index))
+                }
+            };
+            let confirmed = state
+                .peers
+                .iter()
+                .enumerate()
+                .filter(|(id, peer)| *id as u64 != state.id && peer.acked_round >= round)
+                .count()
+                + 1;
+            if confirmed >= state.majority() && state.last_applied >= read_index {
+                // Entries applied since only make the value newer, which is fine
+                drop(state);
+                let val = shared.store.lock().unwrap().get(key)?;
+                return Ok(Response::Value { val });
+            }
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(Response::TimedOut {
+                awaited: Awaited::Leadership,
+            });
+        }
+        state = shared
+            .changed
+            .wait_timeout(state, deadline - now)
+            .unwrap()
+            .0;
+    }
+}
+
+fn handle(shared: &Shared, request: Request) -> Result<Response> {
+    let mut state = shared.state.lock().unwrap();
+    let response = match request {
+        Request::Vote {
+            term,
+            candidate,
+            last_log_index,
+            last_log_term,
+        } => state.on_vote(term, candidate, last_log_index, last_log_term)?,
+        Request::Append {
+            term,
+            leader,
+            prev_log_index,
+            prev_log_term,
+            entries,
+            leader_commit,
+        } => state.on_append(
+            term,
+            leader,
+            prev_log_index,
+            prev_log_term,
+            entries,
+            leader_commit,
+        )?,
+        Request::Snapshot {
+            term,
+            leader,
+            last_index,
+            last_term,
+            pairs,
+            first,
+            done,
+        } => {
+            let mut store = shared.store.lock().unwrap();
+            state.on_snapshot(
+                &mut store, term, leader, last_index, last_term, pairs, first, done,
+            )?
+        }
+        Request::Status => Response::Status(state.status()),
+        Request::Get { key } => return read(shared, state, key),
+        Request::Set { key, val } => {
+            let cmd = Command::Set {
+                ns: String::new(),
+                key,
+                val,
+            };
+            return propose(shared, state, cmd);
+        }
+        Request::Remove { key } => {
+            let cmd = Command::Rm {
+                ns: String::new(),
+                key,
+            };
+            return propose(shared, state, cmd);
+        }
+        Request::Watch { .. } => unreachable!("watches are streamed by serve"),
+    };
+    shared.changed.notify_all();
+    Ok(response)
+}
+
+/// Answer requests from another node or a client until it hangs up.
+fn serve(shared: Arc<Shared>, mut stream: TcpStream) {
+    let _ = stream.set_nodelay(true);
+    while let Ok(request) = receive::<Request, _>(&mut stream) {
+        if let Request::Watch { prefix } = request {
+            return stream_changes(&shared, stream, prefix);
+        }
+        let response = match handle(&shared, request) {
+            Ok(response) => response,
+            Err(e) => {
+                warn!("failed to handle request: {}", e);
+                return;
+            }
+        };
+        if send(&mut stream, &response).is_err() {
+            return;
+        }
+    }
+}
+
+/// Send a client every change this node applies to keys starting with `prefix`, until it hangs up.
+///
+/// Changes are only applied once committed, so any node can serve a watch.
+fn stream_changes(shared: &Shared, mut stream: TcpStream, prefix: String) {
+    let changes = shared.store.lock().unwrap().watch(prefix);
+    if send(&mut stream, &Response::Watching).is_err() {
+        return;
+    }
+    for change in changes {
+        if send(&mut stream, &Response::Change(change)).is_err() {
+            return;
+        }
+    }
+}
+
+/// Start elections whenever the leader has gone quiet for too long.
+fn tick(shared: Arc<Shared>) {
+    loop {
+        thread::sleep(HEARTBEAT_INTERVAL / 5);
+        let mut state = shared.state.lock().unwrap();
+        if state.role != Role::Leader && Instant::now() >= state.election_deadline {
+            if let Err(e) = state.start_election() {
+                warn!("failed to start election: {}", e);
+            }
+            shared.changed.notify_all();
+        }
+    }
+}
+
+/// Apply committed entries to the store, noting the outcome for any client waiting on them, and snapshot every so
+/// often (§7).
+///
+/// The store is only locked while the rest of the node's state isn't, so however long a write or a compaction
+/// takes, heartbeats and votes carry on meanwhile.
+fn apply(shared: Arc<Shared>) {
+    // Logs before this epoch reached the disk at the last snapshot
+    let mut synced = 0;
+    loop {
+        let (entries, installs) = {
+            let mut state = shared.state.lock().unwrap();
+            while state.last_applied >= state.commit_index {
+                state = shared
+                    .changed
+                    .wait_timeout(state, HEARTBEAT_INTERVAL)
+                    .unwrap()
+                    .0;
+            }
+            let commit_index = state.commit_index;
+            let mut entries = state.log.slice(state.last_applied + 1, MAX_ENTRIES);
+            entries.retain(|entry| entry.index <= commit_index);
+            (entries, state.installs)
+        };
+
+        let mut outcomes = Vec::with_capacity(entries.len());
+        let mut failed = false;
+        {
+            let mut store = shared.store.lock().unwrap();
+            for entry in entries {
+                // Entries may be applied more than once after a restart, but in order that's harmless
+                let outcome = match entry.cmd {
+                    None => Response::Done,
+                    Some(cmd) => match store.apply(cmd) {
+                        Ok(()) => Response::Done,
+                        Err(Error::NotFound) | Err(Error::NoNamespace { .. }) => Response::NotFound,
+                        Err(e) => {
+                            warn!("failed to apply entry {}: {}", entry.index, e);
+                            failed = true;
+                            break;
+                        }
+                    },
+                };
+                outcomes.push((entry.index, outcome));
+            }
+        }
+
+        let mut state = shared.state.lock().unwrap();
+        // A snapshot from the leader installed meanwhile replaced whatever these did
+        if state.installs == installs {
+            for (index, outcome) in outcomes {
+                state.last_applied = index;
+                if state.waiting.contains(&index) {
+                    state.outcomes.insert(index, outcome);
+                }
+            }
+        }
+        shared.changed.notify_all();
+        if failed {
+            drop(state);
+            thread::sleep(RETRY_DELAY);
+            continue;
+        }
+        if state.last_applied - state.log.snapshot_index() < state.snapshot_threshold {
+            continue;
+        }
+
+        // The entries are the only other record of what was applied, so the store must survive a crash without them.
+        // Only what was written since the last snapshot needs to reach the disk.
+        let index = state.last_applied;
+        let term = state
+            .log
+            .term_at(index)
+            .expect("applied entries are in the log");
+        let (id, installs) = (state.id, state.installs);
+        drop(state);
+        let epoch = {
+            let mut store = shared.store.lock().unwrap();
+            match store.sync_from(synced) {
+                Ok(()) => store.epoch,
+                Err(e) => {
+                    warn!("failed to sync the store for a snapshot: {}", e);
+                    thread::sleep(RETRY_DELAY);
+                    continue;
+                }
+            }
+        };
+        synced = epoch;
+        let mut state = shared.state.lock().unwrap();
+        if state.installs != installs || index <= state.log.snapshot_index() {
+            continue;
+        }
+        match state.log.compact(index, term) {
+            Ok(()) => info!("node {} snapshotted up to entry {}", id, index),
+            Err(e) => warn!("failed to snapshot up to entry {}: {}", index, e),
+        }
+    }
+}
+
+/// Carry requests for votes, entries and heartbeats to one peer, one at a time.
+fn replicate(shared: Arc<Shared>, peer: usize) {
+    let addr = shared.state.lock().unwrap().nodes[peer].clone();
+    let mut conn = None;
+    loop {
+        let (request, term, round) = {
+            let mut state = shared.state.lock().unwrap();
+            loop {
+                match state.next_request(&shared.store, peer) {
+                    Ok(Some(next)) => break next,
+                    Ok(None) => {}
+                    Err(e) => warn!("failed to prepare request for {}: {}", addr, e),
+                }
+                state = shared
+                    .changed
+                    .wait_timeout(state, HEARTBEAT_INTERVAL / 5)
+                    .unwrap()
+                    .0;
+            }
+        };
+
+        let response = exchange(&mut conn, &addr, RPC_TIMEOUT, &request);
+        let mut state = shared.state.lock().unwrap();
+        match response {
+            Ok(response) => {
+                if let Err(e) = state.on_response(peer, term, round, response) {
+                    warn!("failed to handle response from {}: {}", addr, e);
+                }
+                shared.changed.notify_all();
+            }
+            Err(e) => {
+                debug!("failed to reach {}: {}", addr, e);
+                state.on_failure(peer, term);
+                drop(state);
+                thread::sleep(HEARTBEAT_INTERVAL);
+            }
+        }
+    }
+}
+
+/// One node of a cluster of stores replicated with Raft.
+///
+/// Its store and Raft log live in the `data` and `raft` directories under the path it is opened with.
+pub struct RaftNode {
+    shared: Arc<Shared>,
+}
+
+impl RaftNode {
+    /// Open node `id` of the cluster whose nodes listen on `nodes`, indexed by id.
+    pub fn open(id: u64, nodes: Vec<String>, path: impl Into<PathBuf>) -> Result<RaftNode> {
+        if id as usize >= nodes.len() {
+            return Err(Error::UnknownNode {
+                id,
+                nodes: nodes.len(),
+            });
+        }
+        let path = path.into();
+        let store = KvStore::open(path.join("data"))?;
+        let log = RaftLog::open(path.join("raft"))?;
+
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_nanos() as u64);
+        let applied = log.snapshot_index();
+        let next_index = log.last_index() + 1;
+        let mut state = State {
+            id,
+            peers: (0..nodes.len())
+                .map(|_| Peer {
+                    next_index,
+                    ..Peer::default()
+                })
+                .collect(),
+            nodes,
+            log,
+            role: Role::Follower,
+            leader: None,
+            // Our store already reflects everything up to the snapshot, and perhaps more
+            commit_index: applied,
+            last_applied: applied,
+            election_deadline: Instant::now(),
+            votes: HashSet::new(),
+            round: 0,
+            waiting: HashSet::new(),
+            outcomes: HashMap::new(),
+            incoming: None,
+            installs: 0,
+            snapshot_threshold: DEFAULT_SNAPSHOT_THRESHOLD,
+            rng: (seed ^ (id + 1).wrapping_mul(0x9e37_79b9_7f4a_7c15)) | 1,
+        };
+        state.reset_election_timer();
+
+        Ok(RaftNode {
+            shared: Arc::new(Shared {
+                state: Mutex::new(state),
+                store: Mutex::new(store),
+                changed: Condvar::new(),
+            }),
+        })
+    }
+
+    /// Make sure the store has reached the disk and drop entries from the Raft log every `threshold` entries applied.
+    /// The store still compacts itself as usual, see [`KvStore::with_compaction_threshold`].
+    pub fn with_snapshot_threshold(self, threshold: u64) -> Self {
+        self.shared.state.lock().unwrap().snapshot_threshold = threshold.max(1);
+        self
+    }
+
+    /// Apply `config` to the store this node replicates. See [`KvStore::with_config`].
+    pub fn with_store_config(self, config: &Config) -> Self {
+        self.shared.store.lock().unwrap().configure(config);
+        self
+    }
+
+    /// Take part in the cluster and serve clients on this node's address. Only returns if it can't listen there.
+    pub fn run(self) -> Result<()> {
+        let (addr, peers) = {
+            let state = self.shared.state.lock().unwrap();
+            (state.nodes[state.id as usize].clone(), state.nodes.len())
+        };
+        let listener = TcpListener::bind(&addr).context(Listen { addr: &addr })?;
+        info!("node listening on {}", addr);
+
+        let shared = self.shared.clone();
+        thread::spawn(move || tick(shared));
+        let shared = self.shared.clone();
+        thread::spawn(move || apply(shared));
+        let id = self.shared.state.lock().unwrap().id as usize;
+        for peer in (0..peers).filter(|peer| *peer != id) {
+            let shared = self.shared.clone();
+            thread::spawn(move || replicate(shared, peer));
+        }
+
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let shared = self.shared.clone();
+                    thread::spawn(move || serve(shared, stream));
+                }
+                Err(e) => warn!("failed to accept connection: {}", e),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A client for a cluster of `RaftNode`s, which finds the leader and follows it as it moves.
+///
+/// Requests are retried against other nodes until one succeeds or there's been no leader for a while. A retried
+/// remove may find its own earlier attempt already took effect, and report the key as not found.
+pub struct ClusterClient {
+    nodes: Vec<String>,
+    leader: Option<String>,
+    // The node we are connected to, if any
+    conn: Option<(String, TcpStream)>,
+    next: usize,
+}
+
+impl ClusterClient {
+    pub fn new(nodes: Vec<String>) -> ClusterClient {
+        ClusterClient {
+            nodes,
+            leader: None,
+            conn: None,
+            next: 0,
+        }
+    }
+
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        match self.request(Request::Get { key })? {
+            Response::Value { val } => Ok(val),
+            found => Err(unexpected(found)),
+        }
+    }
+
+    pub fn set(&mut self, key: String, val: String) -> Result<()> {
+        match self.request(Request::Set { key, val })? {
+            Response::Done => Ok(()),
+            found => Err(unexpected(found)),
+        }
+    }
+
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        match self.request(Request::Remove { key })? {
+            Response::Done => Ok(()),
+            Response::NotFound => Err(Error::NotFound),
+            found => Err(unexpected(found)),
+        }
+    }
+
+    /// Ask a single node for its view of the cluster.
+    pub fn status(addr: &str) -> Result<NodeStatus> {
+        let mut conn = None;
+        match exchange(&mut conn, addr, RPC_TIMEOUT, &Request::Status)? {
+            Response::Status(status) => Ok(status),
+            found => Err(unexpected(found)),
+        }
+    }
+
+    /// Hear about every committed change to keys starting with `prefix`, from whichever node will take the watch.
+    ///
+    /// The receiver disconnects if the connection to that node is lost, after which it's up to the caller to watch
+    /// again. Changes made in between are missed.
+    pub fn watch(&self, prefix: impl Into<String>) -> Result<Receiver<Change>> {
+        let request = Request::Watch {
+            prefix: prefix.into(),
+        };
+        let mut error = Error::NoLeader;
+        for addr in &self.nodes {
+            let mut conn = None;
+            match exchange(&mut conn, addr, RPC_TIMEOUT, &request) {
+                Ok(Response::Watching) => {}
+                Ok(found) => {
+                    error = unexpected(found);
+                    continue;
+                }
+                Err(e) => {
+                    error = e;
+                    continue;
+                }
+            }
+            let mut stream = conn.expect("connected to send the watch");
+            // Changes may be a long time coming
+            stream.set_read_timeout(None).context(Connect { addr })?;
+            let (sender, changes) = channel();
+            thread::spawn(move || {
+                while let Ok(Response::Change(change)) = receive(&mut stream) {
+                    if sender.send(change).is_err() {
+                        return;
+                    }
+                }
+            });
+            return Ok(changes);
+        }
+        Err(error)
+    }
+
+    fn request(&mut self, request: Request) -> Result<Response> {
+        let deadline = Instant::now() + CLIENT_TIMEOUT;
+        loop {
+            let addr = match self.leader.clone() {
+                Some(leader) => leader,
+                None => {
+                    let addr = self.nodes.get(self.next % self.nodes.len().max(1)).cloned();
+                    self.next += 1;
+                    addr.context(crate::NoLeader)?
+                }
+            };
+
+            let error = match self.send(&addr, &request) {
+                Ok(Response::NotLeader {
+                    leader: Some(leader),
+                }) if leader != addr => {
+                    debug!("redirected from {} to {}", addr, leader);
+                    self.leader = Some(leader);
+                    continue;
+                }
+                Ok(Response::NotLeader { .. }) => {
+                    self.leader = None;
+                    Error::NotLeader { addr }
+                }
+                Ok(Response::TimedOut { awaited }) => {
+                    self.leader = None;
+                    Error::Unavailable { addr, awaited }
+                }
+                Ok(response) => {
+                    self.leader = Some(addr);
+                    return Ok(response);
+                }
+                Err(e) => {
+                    self.leader = None;
+                    e
+                }
+            };
+            if Instant::now() >= deadline {
+                return Err(error);
+            }
+            debug!("retrying after {}", error);
+            thread::sleep(RETRY_DELAY);
+        }
+    }
+
+    fn send(&mut self, addr: &str, request: &Request) -> Result<Response> {
+        let mut conn = match self.conn.take() {
+            Some((connected, stream)) if connected == addr => Some(stream),
+            _ => None,
+        };
+        // Nodes hold on to requests while they wait for the rest of the cluster
+        let response = exchange(&mut conn, addr, REQUEST_TIMEOUT + RPC_TIMEOUT, request);
+        self.conn = conn.map(|stream| (addr.to_owned(), stream));
+        response
+    }
+}
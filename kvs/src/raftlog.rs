@@ -0,0 +1,296 @@
+use crate::logfile::{decode_document, encode};
+use crate::{Command, Deser, EncodeMessage, Error, LoadRaftLog, Persist, Result};
+use bson::Bson;
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// One slot in the Raft log. Entries without a command are written by new leaders to commit their term.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Entry {
+    pub(crate) index: u64,
+    pub(crate) term: u64,
+    pub(crate) cmd: Option<Command>,
+}
+
+/// What a node must remember across restarts besides its log.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct HardState {
+    term: u64,
+    voted_for: Option<u64>,
+    // The last entry covered by the store's state, rather than kept in the log
+    snapshot_index: u64,
+    snapshot_term: u64,
+}
+
+/// Write a document to `path` so that it either fully replaces what was there or doesn't happen at all.
+fn replace<T: Serialize>(path: &Path, value: &T) -> Result<()> {
+    let doc = match bson::to_bson(value).context(EncodeMessage)? {
+        Bson::Document(doc) => doc,
+        other => unreachable!("state encoded as {:?}", other),
+    };
+    let staged = path.with_extension("tmp");
+    let written = File::create(&staged).and_then(|mut file| {
+        doc.to_writer(&mut file)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        file.sync_all()
+    });
+    written
+        .and_then(|_| fs::rename(&staged, path))
+        .context(Persist { path })
+}
+
+/// A node's Raft log and the state that goes with it, kept in their own directory.
+///
+/// Entries are checksummed records in the same format as the store's logs, each naming its own index. Everything up
+/// to the snapshot index has been applied to the store and dropped from the log.
+pub(crate) struct RaftLog {
+    path: PathBuf,
+    state: HardState,
+    file: File,
+    entries: Vec<Entry>,
+    // Where each entry starts in the file
+    offsets: Vec<u64>,
+}
+
+impl RaftLog {
+    pub(crate) fn open(path: impl Into<PathBuf>) -> Result<RaftLog> {
+        let path = path.into();
+        fs::create_dir_all(&path).context(Persist { path: &path })?;
+
+        let state_path = path.join("state");
+        let state = match fs::read(&state_path) {
+            Ok(bytes) => {
+                let doc = bson::Document::from_reader(&mut bytes.as_slice())
+                    .context(Deser { offset: 0u64 })
+                    .context(LoadRaftLog { path: &state_path })?;
+                bson::from_bson(Bson::Document(doc))
+                    .context(Deser { offset: 0u64 })
+                    .context(LoadRaftLog { path: &state_path })?
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HardState::default(),
+            Err(e) => return Err(e).context(Persist { path: &state_path }),
+        };
+
+        let log_path = path.join("log");
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&log_path)
+            .context(Persist { path: &log_path })?;
+        let length = file
+            .seek(SeekFrom::End(0))
+            .context(Persist { path: &log_path })?;
+        file.seek(SeekFrom::Start(0))
+            .context(Persist { path: &log_path })?;
+
+        let mut entries = Vec::new();
+        let mut offsets = Vec::new();
+        let mut reader = BufReader::new(&mut file);
+        let mut offset = 0;
+        while offset < length {
+            let start = offset;
+            let doc = match decode_document(&mut reader, start) {
+                Ok(Some(doc)) => doc,
+                Ok(None) => {
+                    return Err(Error::UnexpectedSeal { offset: start })
+                        .context(LoadRaftLog { path: &log_path })
+                }
+                // Cut short by a crash part way through an append, which was never acknowledged
+                Err(Error::Truncated { .. }) => {
+                    warn!("discarding incomplete raft log entry at {}", start);
+                    drop(reader);
+                    file.set_len(start).context(Persist { path: &log_path })?;
+                    break;
+                }
+                Err(e) => return Err(e).context(LoadRaftLog { path: &log_path }),
+            };
+            let entry: Entry = bson::from_bson(Bson::Document(doc))
+                .context(Deser { offset: start })
+                .context(LoadRaftLog { path: &log_path })?;
+            offset = reader
+                .stream_position()
+                .context(Persist { path: &log_path })?;
+
+            // Entries already covered by a snapshot may linger if we stopped while dropping them
+            if entry.index <= state.snapshot_index {
+                continue;
+            }
+            let expected = state.snapshot_index + entries.len() as u64 + 1;
+            if entry.index != expected {
+                return Err(Error::MissingEntry { index: expected })
+                    .context(LoadRaftLog { path: &log_path });
+            }
+            offsets.push(start);
+            entries.push(entry);
+        }
+
+        Ok(RaftLog {
+            path,
+            state,
+            file,
+            entries,
+            offsets,
+        })
+    }
+
+    pub(crate) fn term(&self) -> u64 {
+        self.state.term
+    }
+
+    pub(crate) fn voted_for(&self) -> Option<u64> {
+        self.state.voted_for
+    }
+
+    /// Remember the current term and who we voted for in it.
+    pub(crate) fn vote(&mut self, term: u64, voted_for: Option<u64>) -> Result<()> {
+        if (term, voted_for) == (self.state.term, self.state.voted_for) {
+            return Ok(());
+        }
+        let mut state = self.state.clone();
+        state.term = term;
+        state.voted_for = voted_for;
+        replace(&self.path.join("state"), &state)?;
+        self.state = state;
+        Ok(())
+    }
+
+    pub(crate) fn snapshot_index(&self) -> u64 {
+        self.state.snapshot_index
+    }
+
+    pub(crate) fn last_index(&self) -> u64 {
+        self.state.snapshot_index + self.entries.len() as u64
+    }
+
+    pub(crate) fn last_term(&self) -> u64 {
+        self.entries
+            .last()
+            .map_or(self.state.snapshot_term, |entry| entry.term)
+    }
+
+    /// The term of the entry at `index`, if it is still known.
+    pub(crate) fn term_at(&self, index: u64) -> Option<u64> {
+        if index == self.state.snapshot_index {
+            return Some(self.state.snapshot_term);
+        }
+        self.get(index).map(|entry| entry.term)
+    }
+
+    /// The entry at `index`, unless it is past the end of the log or already dropped into a snapshot.
+    pub(crate) fn get(&self, index: u64) -> Option<&Entry> {
+        if index <= self.state.snapshot_index {
+            return None;
+        }
+        self.entries
+            .get((index - self.state.snapshot_index - 1) as usize)
+    }
+
+    /// Up to `limit` entries starting from `index`.
+    pub(crate) fn slice(&self, index: u64, limit: usize) -> Vec<Entry> {
+        let start =
+            (index.max(self.state.snapshot_index + 1) - self.state.snapshot_index - 1) as usize;
+        self.entries
+            .iter()
+            .skip(start)
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+
+    /// Append entries to the log, making sure they are on disk before returning.
+    pub(crate) fn append(&mut self, entries: Vec<Entry>) -> Result<()> {
+        let log_path = self.path.join("log");
+        let mut offset = self
+            .file
+            .seek(SeekFrom::End(0))
+            .context(Persist { path: &log_path })?;
+        let mut bytes = Vec::new();
+        let mut offsets = Vec::with_capacity(entries.len());
+        for entry in &entries {
+            debug_assert_eq!(entry.index, self.last_index() + offsets.len() as u64 + 1);
+            let doc = match bson::to_bson(entry).context(EncodeMessage)? {
+                Bson::Document(doc) => doc,
+                other => unreachable!("entry encoded as {:?}", other),
+            };
+            let record = encode(&doc, offset)?;
+            offsets.push(offset);
+            offset += record.len() as u64;
+            bytes.extend_from_slice(&record);
+        }
+        let written = self
+            .file
+            .write_all(&bytes)
+            .and_then(|_| self.file.sync_data());
+        if let Err(e) = written {
+            // Don't leave part of an entry behind for the next append to strand
+            if let Some(start) = offsets.first() {
+                let _ = self.file.set_len(*start);
+            }
+            return Err(e).context(Persist { path: log_path });
+        }
+        self.entries.extend(entries);
+        self.offsets.extend(offsets);
+        Ok(())
+    }
+
+    /// Drop every entry from `index` onwards, because they conflict with the leader's log.
+    pub(crate) fn truncate(&mut self, index: u64) -> Result<()> {
+        let keep =
+            (index.max(self.state.snapshot_index + 1) - self.state.snapshot_index - 1) as usize;
+        if keep >= self.entries.len() {
+            return Ok(());
+        }
+        let log_path = self.path.join("log");
+        self.file
+            .set_len(self.offsets[keep])
+            .and_then(|_| self.file.sync_data())
+            .context(Persist { path: log_path })?;
+        self.entries.truncate(keep);
+        self.offsets.truncate(keep);
+        Ok(())
+    }
+
+    /// Record that everything up to `index` now lives in the store, dropping those entries from the log.
+    ///
+    /// If the log doesn't hold the entry at `index` with the same term, it is dropped entirely.
+    pub(crate) fn compact(&mut self, index: u64, term: u64) -> Result<()> {
+        let keep = if self.term_at(index) == Some(term) {
+            self.slice(index + 1, usize::MAX)
+        } else {
+            Vec::new()
+        };
+
+        // Once the state says so the dropped entries are ignored, even if rewriting the log doesn't happen
+        let mut state = self.state.clone();
+        state.snapshot_index = index;
+        state.snapshot_term = term;
+        replace(&self.path.join("state"), &state)?;
+        self.state = state;
+
+        let log_path = self.path.join("log");
+        let staged = self.path.join("log.tmp");
+        let mut bytes = Vec::new();
+        let mut offsets = Vec::with_capacity(keep.len());
+        for entry in &keep {
+            let doc = match bson::to_bson(entry).context(EncodeMessage)? {
+                Bson::Document(doc) => doc,
+                other => unreachable!("entry encoded as {:?}", other),
+            };
+            offsets.push(bytes.len() as u64);
+            bytes.extend_from_slice(&encode(&doc, bytes.len() as u64)?);
+        }
+        let file = File::create(&staged)
+            .and_then(|mut file| file.write_all(&bytes).and_then(|_| file.sync_all()))
+            .and_then(|_| fs::rename(&staged, &log_path))
+            .and_then(|_| OpenOptions::new().read(true).append(true).open(&log_path))
+            .context(Persist { path: &log_path })?;
+        self.file = file;
+        self.entries = keep;
+        self.offsets = offsets;
+        Ok(())
+    }
+}
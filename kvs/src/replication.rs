@@ -0,0 +1,482 @@
+//! Streaming a leader's log records to followers over TCP.
+//!
+//! Every message is a single BSON document, which carries its own length. A follower opens with `Hello`, naming the
+//! last record it applied. The leader replays the records after that one from its logs, or sends `Reset` and
+//! replays everything if that record's log is gone, then sends `CaughtUp`. From then on records are forwarded as
+//! soon as they are appended.
+
+use crate::logfile::LogFile;
+use crate::storage::Storage;
+use crate::wire::{encode, receive, send};
+use crate::{
+    Command, Connect, Deser, Error, KvStore, ListDir, NotConnected, Open, Position, RecordProgress,
+    Result, SendMessage, UnexpectedMessage,
+};
+use bson::{Bson, Document};
+use serde::{Deserialize, Serialize};
+use snafu::{OptionExt, ResultExt};
+use std::collections::HashSet;
+use std::fmt;
+use std::io::Write;
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+// How many records may queue up for a follower before it's dropped as too slow. It catches up from the logs once
+// it reconnects.
+const BACKLOG: usize = 10_000;
+
+/// The name of the file recording the last record a follower applied from its leader.
+const FOLLOWING: &str = "following";
+
+// How many records a follower applies between recording its progress. Any it applied since are sent again after it
+// restarts, which is harmless for all but merges.
+const SAVE_EVERY: usize = 1000;
+
+/// What a follower keeps alongside its store, so that it can pick up where it left off after restarting.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Progress {
+    after: Option<Position>,
+    /// A merge that was about to be applied. Applying it twice would count its operand twice.
+    #[serde(default)]
+    merging: Option<Merging>,
+}
+
+/// A merge from the leader, and the last record in the follower's own logs before it was applied.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct Merging {
+    position: Position,
+    before: Option<Position>,
+}
+
+impl Progress {
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        match bson::to_bson(self) {
+            Ok(Bson::Document(doc)) => doc.to_writer(&mut bytes),
+            other => unreachable!("follower progress encoded as {:?}", other),
+        }
+        .expect("writing to memory can't fail");
+        bytes
+    }
+
+    fn decode(mut bytes: &[u8]) -> Result<Progress> {
+        let doc = Document::from_reader(&mut bytes).context(Deser { offset: 0u64 })?;
+        bson::from_bson(Bson::Document(doc)).context(Deser { offset: 0u64 })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type")]
+enum Message {
+    /// Sent by a follower on connecting, with the last record it applied.
+    Hello {
+        after: Option<Position>,
+    },
+    /// The follower should forget everything it has, as the leader is about to send it all again.
+    Reset,
+    Record {
+        position: Position,
+        cmd: Command,
+    },
+    /// Everything already in the leader's logs has been sent.
+    CaughtUp,
+}
+
+struct Followers {
+    // Where the last record published ends. Everything before it is already in the logs.
+    end: Position,
+    senders: Vec<SyncSender<Arc<Vec<u8>>>>,
+    closed: bool,
+}
+
+/// The leader's side of replication, shared between its store and the threads serving its followers.
+pub(crate) struct Leader {
+    storage: Arc<dyn Storage>,
+    followers: Mutex<Followers>,
+}
+
+impl Leader {
+    pub(crate) fn new(storage: Arc<dyn Storage>, end: Position) -> Leader {
+        Leader {
+            storage,
+            followers: Mutex::new(Followers {
+                end,
+                senders: Vec::new(),
+                closed: false,
+            }),
+        }
+    }
+
+    /// Accept followers on `listener`, serving each on its own thread.
+    pub(crate) fn listen(self: &Arc<Self>, listener: TcpListener) {
+        let leader = self.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if leader.followers.lock().unwrap().closed {
+                    break;
+                }
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        warn!("failed to accept follower: {}", e);
+                        continue;
+                    }
+                };
+                let leader = leader.clone();
+                thread::spawn(move || {
+                    let peer = stream
+                        .peer_addr()
+                        .map(|addr| addr.to_string())
+                        .unwrap_or_default();
+                    match leader.serve(stream) {
+                        Ok(()) => info!("hung up on follower {}", peer),
+                        Err(e) => warn!("stopped serving follower {}: {}", peer, e),
+                    }
+                });
+            }
+        });
+    }
+
+    fn serve(&self, mut stream: TcpStream) -> Result<()> {
+        let after = match receive(&mut stream)? {
+            Message::Hello { after } => after,
+            found => {
+                return UnexpectedMessage {
+                    found: format!("{:?}", found),
+                }
+                .fail()
+            }
+        };
+        info!("serving follower after {:?}", after);
+
+        // Subscribe before reading the logs, so that every record is either already in them or on its way
+        let (sender, records) = mpsc::sync_channel(BACKLOG);
+        let end = {
+            let mut followers = self.followers.lock().unwrap();
+            if followers.closed {
+                return Ok(());
+            }
+            followers.senders.push(sender);
+            followers.end
+        };
+        self.catch_up(&mut stream, after, end)?;
+        send(&mut stream, &Message::CaughtUp)?;
+
+        for record in records {
+            stream.write_all(&record).context(SendMessage)?;
+        }
+        Ok(())
+    }
+
+    /// Send a follower the records in the logs after `after` and before `end`, starting over from the oldest log if
+    /// the one `after` is in has since been compacted away.
+    fn catch_up(
+        &self,
+        stream: &mut TcpStream,
+        after: Option<Position>,
+        end: Position,
+    ) -> Result<()> {
+        let mut epochs: Vec<u64> = self
+            .storage
+            .epochs()
+            .with_context(|| ListDir {
+                path: self.storage.path(),
+            })?
+            .into_iter()
+            .filter(|e| *e <= end.epoch)
+            .collect();
+        epochs.sort_unstable();
+
+        let after = match after {
+            Some(after) if epochs.contains(&after.epoch) => Some(after),
+            _ => {
+                send(stream, &Message::Reset)?;
+                None
+            }
+        };
+        for epoch in epochs {
+            if after.is_some_and(|after| epoch < after.epoch) {
+                continue;
+            }
+            let until = if epoch == end.epoch {
+                end.offset
+            } else {
+                u64::MAX
+            };
+            let mut log = LogFile::open(epoch, self.storage.as_ref()).with_context(|| Open {
+                path: self.storage.locate(epoch),
+            })?;
+            log.read_until(until, |cmd, offset| {
+                let position = Position { epoch, offset };
+                if after.is_none_or(|after| position > after) {
+                    send(stream, &Message::Record { position, cmd })?;
+                }
+                Ok(())
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Forward a newly appended record to every follower, dropping any that have fallen too far behind.
+    pub(crate) fn publish(&self, position: Position, cmd: Command, end: Position) {
+        let mut followers = self.followers.lock().unwrap();
+        followers.end = end;
+        if followers.senders.is_empty() {
+            return;
+        }
+        let record = match encode(&Message::Record { position, cmd }) {
+            Ok(record) => Arc::new(record),
+            Err(e) => {
+                // They'll pick the record up from the logs when they reconnect
+                warn!("dropping followers, failed to encode {}: {}", position, e);
+                followers.senders.clear();
+                return;
+            }
+        };
+        followers
+            .senders
+            .retain(|sender| match sender.try_send(record.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    warn!("dropping follower more than {} records behind", BACKLOG);
+                    false
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            });
+    }
+
+    /// Hang up on every follower and stop accepting new ones.
+    pub(crate) fn shutdown(&self) {
+        let mut followers = self.followers.lock().unwrap();
+        followers.closed = true;
+        followers.senders.clear();
+    }
+}
+
+/// A store kept in sync with a leader's by applying every record the leader appends, as a warm standby.
+///
+/// The follower remembers where in the leader's logs it got to, so after reconnecting it only needs the records it
+/// missed. A new follower, or one that fell behind a compaction on the leader, is sent everything the leader has
+/// instead and drops any keys the leader no longer holds.
+///
+/// ```rust
+/// # use kvs::{Follower, KvStore};
+/// # use std::net::TcpListener;
+/// let mut leader = KvStore::in_memory();
+/// let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+/// let addr = listener.local_addr().unwrap();
+/// leader.serve_followers(listener);
+/// leader.set("my key".to_owned(), "my value".to_owned()).unwrap();
+///
+/// let mut follower = Follower::new(KvStore::in_memory()).unwrap();
+/// follower.connect(addr).unwrap();
+/// while !follower.caught_up() {
+///     follower.step().unwrap();
+/// }
+/// let val = follower.store().get("my key".to_owned()).unwrap();
+/// assert_eq!(val, Some("my value".to_owned()));
+/// ```
+pub struct Follower {
+    store: KvStore,
+    leader: Option<TcpStream>,
+    // The last record applied from the leader's logs
+    position: Option<Position>,
+    // Keys to remove once the leader finishes resending everything, unless it sends them again
+    stale: Option<HashSet<(String, String)>>,
+    caught_up: bool,
+    // Records applied since progress was last recorded
+    unsaved: usize,
+    // The store's logs before this epoch had reached the disk when progress was last recorded
+    synced: u64,
+}
+
+impl Follower {
+    /// Follow a leader into `store`, picking up after the last record it applied from its leader before, if any.
+    pub fn new(store: KvStore) -> Result<Follower> {
+        let storage = store.storage.as_ref();
+        let progress = match storage.read_meta(FOLLOWING).with_context(|| Open {
+            path: storage.path().join(FOLLOWING),
+        })? {
+            Some(bytes) => Progress::decode(&bytes)?,
+            None => Progress::default(),
+        };
+        let mut position = progress.after;
+        if let Some(merging) = progress.merging {
+            // Only records from the leader are written to the store, so if anything was, the merge was
+            if store.last_position() != merging.before {
+                position = Some(merging.position);
+            }
+        }
+        Ok(Follower {
+            store,
+            leader: None,
+            position,
+            stale: None,
+            caught_up: false,
+            unsaved: 0,
+            synced: 0,
+        })
+    }
+
+    /// Record the last record applied next to the store, along with a merge about to be applied after it.
+    ///
+    /// While the leader is resending everything this stays empty, so that a follower restarted part way through
+    /// starts over and still drops the keys the leader no longer has.
+    fn save(&mut self, merging: Option<Merging>) -> Result<()> {
+        // Progress must never get ahead of what the store has on disk, or the records in between are never resent
+        self.store.sync_from(self.synced)?;
+        self.synced = self.store.epoch;
+        let progress = Progress {
+            after: self.stale.is_none().then_some(self.position).flatten(),
+            merging,
+        };
+        self.store
+            .storage
+            .write_meta(FOLLOWING, &progress.encode())
+            .context(RecordProgress)?;
+        self.unsaved = 0;
+        Ok(())
+    }
+
+    /// Connect to a leader, picking up after the last record applied from it.
+    pub fn connect<A: ToSocketAddrs + fmt::Display>(&mut self, addr: A) -> Result<()> {
+        self.leader = None;
+        self.caught_up = false;
+        let mut stream = TcpStream::connect(&addr).with_context(|| Connect {
+            addr: addr.to_string(),
+        })?;
+        // An interrupted reset has to start over, or the keys it would have dropped are never cleaned up
+        let after = match self.stale {
+            Some(_) => None,
+            None => self.position,
+        };
+        send(&mut stream, &Message::Hello { after })?;
+        info!("following {} after {:?}", addr, after);
+        self.leader = Some(stream);
+        Ok(())
+    }
+
+    /// Wait for the next message from the leader and apply it.
+    ///
+    /// The connection is dropped on failure, after which the follower needs to `connect` again.
+    pub fn step(&mut self) -> Result<()> {
+        let stream = self.leader.as_mut().context(NotConnected)?;
+        let applied = receive(stream).and_then(|msg| self.apply(msg));
+        if applied.is_err() {
+            self.leader = None;
+            self.caught_up = false;
+        }
+        applied
+    }
+
+    fn apply(&mut self, msg: Message) -> Result<()> {
+        match msg {
+            Message::Reset => {
+                debug!("leader is resending everything");
+                self.stale = Some(
+                    self.store
+                        .keys()
+                        .map(|(ns, key)| (ns.clone(), key.clone()))
+                        .collect(),
+                );
+                self.position = None;
+                self.save(None)?;
+            }
+            Message::Record { position, cmd } => {
+                if self.position.is_some_and(|applied| position <= applied) {
+                    debug!("skipping {}, which was already applied", position);
+                    return Ok(());
+                }
+                if let Some(stale) = self.stale.as_mut() {
+                    let resent = match &cmd {
+                        Command::Set { ns, key, .. }
+                        | Command::Rm { ns, key }
+                        | Command::Merge { ns, key, .. } => stale
+                            .remove(&(ns.clone(), key.clone()))
+                            .then_some((ns, key)),
+                        Command::DropNamespace { ns } => {
+                            stale.retain(|(stale, _)| stale != ns);
+                            None
+                        }
+                    };
+                    // What the follower had from before the reset mustn't be merged into
+                    if let (Some((ns, key)), Command::Merge { .. }) = (resent, &cmd) {
+                        let cmd = Command::Rm {
+                            ns: ns.clone(),
+                            key: key.clone(),
+                        };
+                        self.apply_record(cmd)?;
+                    }
+                }
+                let before = self.store.last_position();
+                if let (None, Command::Merge { .. }) = (&self.stale, &cmd) {
+                    // A follower restarted part way through a reset starts over, so only merges outside one need this
+                    self.save(Some(Merging { position, before }))?;
+                }
+                let applied = self.apply_record(cmd);
+                // A write can fail after its record is in the logs, such as when compacting afterwards fails
+                if applied.is_ok() || self.store.last_position() != before {
+                    self.position = Some(position);
+                }
+                applied?;
+                self.unsaved += 1;
+                if self.stale.is_none() && self.unsaved >= SAVE_EVERY {
+                    self.save(None)?;
+                }
+            }
+            Message::CaughtUp => {
+                let stale = self.stale.take().unwrap_or_default();
+                for (ns, key) in &stale {
+                    let cmd = Command::Rm {
+                        ns: ns.clone(),
+                        key: key.clone(),
+                    };
+                    if let Err(e) = self.apply_record(cmd) {
+                        self.stale = Some(stale);
+                        return Err(e);
+                    }
+                }
+                self.save(None)?;
+                self.caught_up = true;
+                info!("caught up at {:?}", self.position);
+            }
+            found @ Message::Hello { .. } => {
+                return UnexpectedMessage {
+                    found: format!("{:?}", found),
+                }
+                .fail()
+            }
+        }
+        Ok(())
+    }
+
+    /// Apply a command, ignoring removals of keys or namespaces that are already gone. While the leader resends
+    /// everything, it also resends removals of keys the follower never had.
+    fn apply_record(&mut self, cmd: Command) -> Result<()> {
+        match self.store.apply(cmd) {
+            Err(Error::NotFound) | Err(Error::NoNamespace { .. }) => Ok(()),
+            r => r,
+        }
+    }
+
+    /// Whether everything that was in the leader's logs when the follower connected has been applied.
+    pub fn caught_up(&self) -> bool {
+        self.caught_up
+    }
+
+    /// The last record applied from the leader's logs, if any.
+    pub fn position(&self) -> Option<Position> {
+        self.position
+    }
+
+    /// The follower's own store. Writing to it directly will leave it out of sync with the leader.
+    pub fn store(&mut self) -> &mut KvStore {
+        &mut self.store
+    }
+
+    pub fn into_store(self) -> KvStore {
+        self.store
+    }
+}
@@ -0,0 +1,880 @@
+use crate::{Error, KvStore, Listen, Permission, Result, Tokens};
+use snafu::ResultExt;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The namespace holding when each key set with an expiry is due to expire, in milliseconds since the Unix epoch.
+const EXPIRES: &str = "resp:expires";
+/// How often keys are checked for having expired, besides whenever they are used.
+pub(crate) const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+/// How many keys `SCAN` returns at a time unless asked for more or fewer.
+const DEFAULT_SCAN_COUNT: usize = 10;
+/// How much to read from a connection at once.
+pub(crate) const READ_SIZE: usize = 16 * 1024;
+// Refuse lines and arguments longer than these rather than reading them into memory
+const MAX_LINE: usize = 64 * 1024;
+const MAX_BULK: usize = 512 * 1024 * 1024;
+// Until a client has authenticated, only a token's worth
+const MAX_UNAUTHENTICATED_BULK: usize = 4 * 1024;
+
+/// A command's name followed by its arguments.
+pub(crate) type Args = Vec<Vec<u8>>;
+
+/// Serves a store to Redis clients, speaking RESP2.
+///
+/// Supports `GET`, `SET` (with `EX`, `PX`, `NX`, `XX` and `KEEPTTL`), `DEL`, `EXISTS`, `KEYS`, `SCAN`, `PING`,
+/// `INFO`, `AUTH` and `QUIT` on the default namespace. Expiry times are kept in the store too, so they survive
+/// restarts, but only writes made through this server clear them.
+///
+/// By default anyone who can connect may do anything. See [`with_tokens`](RespServer::with_tokens).
+///
+/// Each connection gets a thread of its own. See [`AsyncRespServer`](crate::AsyncRespServer) for many connections.
+pub struct RespServer {
+    store: Arc<Mutex<KvStore>>,
+    tokens: Option<Arc<Tokens>>,
+}
+
+impl RespServer {
+    pub fn new(store: KvStore) -> RespServer {
+        RespServer {
+            store: Arc::new(Mutex::new(store)),
+            tokens: None,
+        }
+    }
+
+    /// Refuse every command but `AUTH` and `QUIT` until a client authenticates with one of `tokens`, sent as the
+    /// password, and any command its token doesn't permit after that. Until then, arguments may only be a few KiB.
+    pub fn with_tokens(mut self, tokens: Tokens) -> Self {
+        self.tokens = Some(Arc::new(tokens));
+        self
+    }
+
+    /// Bind to `addr` and serve clients there.
+    pub fn run(self, addr: &str) -> Result<()> {
+        let listener = TcpListener::bind(addr).context(Listen { addr })?;
+        info!("serving RESP on {}", addr);
+        self.serve(listener);
+        Ok(())
+    }
+
+    /// Serve clients connecting on `listener`, each on its own thread. Only returns once the listener stops
+    /// accepting connections.
+    pub fn serve(self, listener: TcpListener) {
+        let store = self.store.clone();
+        thread::spawn(move || loop {
+            thread::sleep(SWEEP_INTERVAL);
+            sweep(&store);
+        });
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let store = self.store.clone();
+                    let session = Session::new(self.tokens.clone());
+                    thread::spawn(move || {
+                        if let Err(e) = serve(&store, session, stream) {
+                            debug!("RESP connection closed: {}", e);
+                        }
+                    });
+                }
+                Err(e) => warn!("failed to accept RESP connection: {}", e),
+            }
+        }
+    }
+}
+
+/// Carry out one client's commands until it hangs up or breaks the protocol.
+fn serve(store: &Mutex<KvStore>, mut session: Session, mut stream: TcpStream) -> io::Result<()> {
+    let _ = stream.set_nodelay(true);
+    let (mut buf, mut out) = (Vec::new(), Vec::new());
+    let mut chunk = vec![0; READ_SIZE];
+    loop {
+        let read = stream.read(&mut chunk)?;
+        if read == 0 {
+            return Ok(());
+        }
+        buf.extend_from_slice(&chunk[..read]);
+        let (commands, error) = take_commands(&mut buf, session.max_bulk());
+        let done = answer(store, &mut session, commands, error, &mut out);
+        stream.write_all(&out)?;
+        out.clear();
+        if done {
+            return Ok(());
+        }
+    }
+}
+
+/// Take every whole command off the front of `buf`, leaving any that has only partly arrived. If the client broke
+/// the protocol, or sent an argument longer than `max_bulk`, the commands before that are returned along with what
+/// it did wrong.
+pub(crate) fn take_commands(buf: &mut Vec<u8>, max_bulk: usize) -> (Vec<Args>, Option<io::Error>) {
+    let mut commands = Vec::new();
+    let mut used = 0;
+    let error = loop {
+        match parse_command(&buf[used..], max_bulk) {
+            Ok(Some((args, len))) => {
+                used += len;
+                if !args.is_empty() {
+                    commands.push(args);
+                }
+            }
+            Ok(None) => break None,
+            Err(e) => break Some(e),
+        }
+    };
+    buf.drain(..used);
+    (commands, error)
+}
+
+/// Carry out commands in turn, writing their replies to `out`, then tell the client if it broke the protocol.
+/// Returns whether to hang up, which stops short at `QUIT`.
+///
+/// Pipelined commands are all answered together this way, rather than each in a write of its own.
+pub(crate) fn answer(
+    store: &Mutex<KvStore>,
+    session: &mut Session,
+    commands: Vec<Args>,
+    error: Option<io::Error>,
+    out: &mut Vec<u8>,
+) -> bool {
+    if !commands.is_empty() {
+        let mut store = store.lock().unwrap();
+        for args in commands {
+            if args[0].eq_ignore_ascii_case(b"QUIT") {
+                Frame::ok().write_to(out);
+                return true;
+            }
+            // Nothing reaches the store without a token permitting it
+            if args[0].eq_ignore_ascii_case(b"AUTH") {
+                session.auth(&args[1..]).write_to(out);
+                continue;
+            }
+            if let Some(refusal) = session.refuse(&args[0]) {
+                refusal.write_to(out);
+                continue;
+            }
+            execute(&mut store, &args)
+                .unwrap_or_else(|e| Frame::Error(format!("ERR {}", e)))
+                .write_to(out);
+        }
+    }
+    match error {
+        Some(e) => {
+            Frame::Error(format!("ERR Protocol error: {}", e)).write_to(out);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Remove keys whose time is up, so they don't linger until someone next looks for them.
+pub(crate) fn sweep(store: &Mutex<KvStore>) {
+    let mut store = store.lock().unwrap();
+    let keys = store.namespace(EXPIRES).keys();
+    for key in keys {
+        if let Err(e) = expire(&mut store, &key) {
+            warn!("failed to expire {}: {}", key, e);
+        }
+    }
+}
+
+/// What one client may do, on a server that may require tokens.
+pub(crate) struct Session {
+    tokens: Option<Arc<Tokens>>,
+    permission: Option<Permission>,
+}
+
+impl Session {
+    pub(crate) fn new(tokens: Option<Arc<Tokens>>) -> Session {
+        let permission = match tokens {
+            Some(_) => None,
+            None => Some(Permission::ReadWrite),
+        };
+        Session { tokens, permission }
+    }
+
+    /// The longest argument to take from the client as things stand.
+    pub(crate) fn max_bulk(&self) -> usize {
+        match self.permission {
+            Some(_) => MAX_BULK,
+            None => MAX_UNAUTHENTICATED_BULK,
+        }
+    }
+
+    /// `AUTH [username] password`, where the password is a token and any username is ignored.
+    fn auth(&mut self, args: &[Vec<u8>]) -> Frame {
+        let token = match args {
+            [token] | [_, token] => String::from_utf8_lossy(token),
+            _ => return wrong_arity("AUTH"),
+        };
+        self.permission = match &self.tokens {
+            Some(tokens) => tokens.check(&token),
+            None => Some(Permission::ReadWrite),
+        };
+        match self.permission {
+            Some(_) => Frame::ok(),
+            None => Frame::Error("WRONGPASS invalid token".to_owned()),
+        }
+    }
+
+    /// The reply to a command the client may not run, if it may not.
+    fn refuse(&self, name: &[u8]) -> Option<Frame> {
+        let needed = if name.eq_ignore_ascii_case(b"SET") || name.eq_ignore_ascii_case(b"DEL") {
+            Permission::ReadWrite
+        } else {
+            Permission::Read
+        };
+        match self.permission {
+            None => Some(Frame::Error(format!("NOAUTH {}", Error::Unauthorized))),
+            Some(permission) if permission < needed => {
+                Some(Frame::Error(format!("NOPERM {}", Error::ReadOnly)))
+            }
+            Some(_) => None,
+        }
+    }
+}
+
+/// A command's reply, or, sent as an array of bulk strings, the command itself.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Frame {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<String>),
+    Array(Vec<Frame>),
+}
+
+impl Frame {
+    fn ok() -> Frame {
+        Frame::Simple("OK".to_owned())
+    }
+
+    pub(crate) fn write_to(&self, out: &mut Vec<u8>) {
+        match self {
+            Frame::Simple(s) => out.extend_from_slice(format!("+{}\r\n", s).as_bytes()),
+            // Errors from the store might span lines, which would break the framing
+            Frame::Error(e) => {
+                out.extend_from_slice(format!("-{}\r\n", e.replace(['\r', '\n'], " ")).as_bytes())
+            }
+            Frame::Integer(n) => out.extend_from_slice(format!(":{}\r\n", n).as_bytes()),
+            Frame::Bulk(None) => out.extend_from_slice(b"$-1\r\n"),
+            Frame::Bulk(Some(s)) => {
+                out.extend_from_slice(format!("${}\r\n", s.len()).as_bytes());
+                out.extend_from_slice(s.as_bytes());
+                out.extend_from_slice(b"\r\n");
+            }
+            Frame::Array(frames) => {
+                out.extend_from_slice(format!("*{}\r\n", frames.len()).as_bytes());
+                for frame in frames {
+                    frame.write_to(out);
+                }
+            }
+        }
+    }
+}
+
+fn protocol_error(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_owned())
+}
+
+/// Find the line starting at `start`, returning it without its line ending along with where the next one starts.
+/// `None` if it hasn't all arrived yet.
+fn line_at(buf: &[u8], start: usize) -> io::Result<Option<(&[u8], usize)>> {
+    let rest = &buf[start..];
+    match rest.iter().position(|&b| b == b'\n') {
+        Some(end) if end <= MAX_LINE => {
+            let line = &rest[..end];
+            Ok(Some((
+                line.strip_suffix(b"\r").unwrap_or(line),
+                start + end + 1,
+            )))
+        }
+        None if rest.len() <= MAX_LINE => Ok(None),
+        _ => Err(protocol_error("line too long")),
+    }
+}
+
+fn parse_len(digits: &[u8]) -> io::Result<i64> {
+    std::str::from_utf8(digits)
+        .ok()
+        .and_then(|digits| digits.parse().ok())
+        .ok_or_else(|| protocol_error("invalid length"))
+}
+
+/// Find the bulk string whose length is given in the line ending just before `start`, returning it along with
+/// where the next line starts. `None` if it hasn't all arrived yet.
+fn bulk_at(buf: &[u8], start: usize, len: i64, max: usize) -> io::Result<Option<(&[u8], usize)>> {
+    if len < 0 || len as usize > max {
+        return Err(protocol_error("invalid bulk length"));
+    }
+    let end = start + len as usize;
+    match buf.get(end..end + 2) {
+        Some(b"\r\n") => Ok(Some((&buf[start..end], end + 2))),
+        Some(_) => Err(protocol_error("bulk string longer than its length")),
+        None => Ok(None),
+    }
+}
+
+/// Parse the first command in `buf`, split into its name and arguments, along with how much of `buf` it took up.
+/// `None` if it hasn't all arrived yet.
+///
+/// Takes arrays of bulk strings as sent by client libraries, or whitespace separated inline commands as typed into
+/// a terminal.
+fn parse_command(buf: &[u8], max_bulk: usize) -> io::Result<Option<(Args, usize)>> {
+    let (line, mut pos) = match line_at(buf, 0)? {
+        Some(found) => found,
+        None => return Ok(None),
+    };
+    let count = match line.strip_prefix(b"*") {
+        Some(count) => parse_len(count)?,
+        None => {
+            let words = line
+                .split(u8::is_ascii_whitespace)
+                .filter(|word| !word.is_empty())
+                .map(<[u8]>::to_vec)
+                .collect();
+            return Ok(Some((words, pos)));
+        }
+    };
+
+    let mut args = Vec::new();
+    for _ in 0..count {
+        let (line, start) = match line_at(buf, pos)? {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+        let len = line
+            .strip_prefix(b"$")
+            .ok_or_else(|| protocol_error("expected '$'"))
+            .and_then(parse_len)?;
+        let (arg, next) = match bulk_at(buf, start, len, max_bulk)? {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+        args.push(arg.to_vec());
+        pos = next;
+    }
+    Ok(Some((args, pos)))
+}
+
+/// Parse the reply starting at `start` in `buf`, along with where the next one starts. `None` if it hasn't all
+/// arrived yet.
+pub(crate) fn parse_frame(buf: &[u8], start: usize) -> io::Result<Option<(Frame, usize)>> {
+    let (line, pos) = match line_at(buf, start)? {
+        Some(found) => found,
+        None => return Ok(None),
+    };
+    let (kind, rest) = line
+        .split_first()
+        .ok_or_else(|| protocol_error("empty reply"))?;
+    let text = |bytes: &[u8]| {
+        String::from_utf8(bytes.to_vec()).map_err(|_| protocol_error("replies must be UTF-8"))
+    };
+    let frame = match kind {
+        b'+' => Frame::Simple(text(rest)?),
+        b'-' => Frame::Error(text(rest)?),
+        b':' => Frame::Integer(parse_len(rest)?),
+        b'$' => match parse_len(rest)? {
+            -1 => Frame::Bulk(None),
+            len => {
+                return match bulk_at(buf, pos, len, MAX_BULK)? {
+                    Some((bulk, next)) => Ok(Some((Frame::Bulk(Some(text(bulk)?)), next))),
+                    None => Ok(None),
+                }
+            }
+        },
+        b'*' => {
+            let mut frames = Vec::new();
+            let mut next = pos;
+            for _ in 0..parse_len(rest)?.max(0) {
+                match parse_frame(buf, next)? {
+                    Some((frame, after)) => {
+                        frames.push(frame);
+                        next = after;
+                    }
+                    None => return Ok(None),
+                }
+            }
+            return Ok(Some((Frame::Array(frames), next)));
+        }
+        _ => return Err(protocol_error("unknown reply type")),
+    };
+    Ok(Some((frame, pos)))
+}
+
+fn wrong_arity(name: &str) -> Frame {
+    Frame::Error(format!(
+        "ERR wrong number of arguments for '{}' command",
+        name.to_ascii_lowercase()
+    ))
+}
+
+fn syntax_error() -> Frame {
+    Frame::Error("ERR syntax error".to_owned())
+}
+
+/// Carry out a command. Commands given bad arguments reply with an error, while failures in the store are returned.
+fn execute(store: &mut KvStore, args: &[Vec<u8>]) -> Result<Frame> {
+    let args: Vec<String> = match args
+        .iter()
+        .map(|arg| String::from_utf8(arg.clone()))
+        .collect()
+    {
+        Ok(args) => args,
+        Err(_) => return Ok(Frame::Error("ERR keys and values must be UTF-8".to_owned())),
+    };
+    let (given, args) = args.split_first().expect("commands have a name");
+    let name = given.to_ascii_uppercase();
+
+    let reply = match (name.as_str(), args) {
+        ("PING", []) => Frame::Simple("PONG".to_owned()),
+        ("PING", [message]) => Frame::Bulk(Some(message.clone())),
+        ("GET", [key]) => {
+            if expire(store, key)? {
+                Frame::Bulk(store.get(key.clone())?)
+            } else {
+                Frame::Bulk(None)
+            }
+        }
+        ("SET", [key, value, options @ ..]) => set(store, key, value, options)?,
+        ("DEL", keys) if !keys.is_empty() => {
+            let mut removed = 0;
+            for key in keys {
+                if expire(store, key)? {
+                    store.remove(key.clone())?;
+                    clear_deadline(store, key)?;
+                    removed += 1;
+                }
+            }
+            Frame::Integer(removed)
+        }
+        ("EXISTS", keys) if !keys.is_empty() => {
+            let mut found = 0;
+            for key in keys {
+                if expire(store, key)? {
+                    found += 1;
+                }
+            }
+            Frame::Integer(found)
+        }
+        ("KEYS", [pattern]) => {
+            let mut keys = Vec::new();
+            for key in store.namespace("").keys() {
+                if glob_match(pattern.as_bytes(), key.as_bytes()) && expire(store, &key)? {
+                    keys.push(Frame::Bulk(Some(key)));
+                }
+            }
+            Frame::Array(keys)
+        }
+        ("SCAN", [cursor, options @ ..]) => scan(store, cursor, options)?,
+        ("INFO", []) => info(store, None),
+        ("INFO", [section]) => info(store, Some(section)),
+        ("PING", _)
+        | ("GET", _)
+        | ("SET", _)
+        | ("DEL", _)
+        | ("EXISTS", _)
+        | ("KEYS", _)
+        | ("SCAN", _)
+        | ("INFO", _) => wrong_arity(&name),
+        _ => Frame::Error(format!("ERR unknown command '{}'", given)),
+    };
+    Ok(reply)
+}
+
+/// `SET key value [EX seconds | PX milliseconds | KEEPTTL] [NX | XX]`
+fn set(store: &mut KvStore, key: &str, value: &str, options: &[String]) -> Result<Frame> {
+    let (mut ttl, mut keep_ttl, mut only_if) = (None, false, None);
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        match option.to_ascii_uppercase().as_str() {
+            unit @ ("EX" | "PX") if ttl.is_none() && !keep_ttl => {
+                let amount = match options.next().map(|amount| amount.parse::<i64>()) {
+                    Some(Ok(amount)) => amount,
+                    Some(Err(_)) => {
+                        return Ok(Frame::Error(
+                            "ERR value is not an integer or out of range".to_owned(),
+                        ))
+                    }
+                    None => return Ok(syntax_error()),
+                };
+                let millis = if unit == "EX" {
+                    amount.checked_mul(1000)
+                } else {
+                    Some(amount)
+                };
+                match millis {
+                    Some(millis) if millis > 0 => ttl = Some(millis as u64),
+                    _ => {
+                        return Ok(Frame::Error(
+                            "ERR invalid expire time in 'set' command".to_owned(),
+                        ))
+                    }
+                }
+            }
+            "KEEPTTL" if ttl.is_none() => keep_ttl = true,
+            condition @ ("NX" | "XX") if only_if.is_none() => only_if = Some(condition == "XX"),
+            _ => return Ok(syntax_error()),
+        }
+    }
+
+    if let Some(exists) = only_if {
+        if expire(store, key)? != exists {
+            return Ok(Frame::Bulk(None));
+        }
+    }
+    // The value and its deadline are two writes, ordered so that failing between them never leaves a value that
+    // outlives its expiry: a new deadline goes in before the value, and an old one comes out after. A deadline left
+    // for a key that was never written is cleared by `expire`.
+    if let Some(ttl) = ttl {
+        store
+            .namespace(EXPIRES)
+            .set(key.to_owned(), (now_millis() + ttl).to_string())?;
+    }
+    store.set(key.to_owned(), value.to_owned())?;
+    if ttl.is_none() && !keep_ttl {
+        clear_deadline(store, key)?;
+    }
+    Ok(Frame::ok())
+}
+
+/// `SCAN cursor [MATCH pattern] [COUNT count]`
+///
+/// Keys are visited in order of a hash of each, with the cursor being the hash to carry on from. That way every key
+/// there from start to finish is returned, however many others come and go along the way.
+fn scan(store: &mut KvStore, cursor: &str, options: &[String]) -> Result<Frame> {
+    let cursor: u64 = match cursor.parse() {
+        Ok(cursor) => cursor,
+        Err(_) => return Ok(Frame::Error("ERR invalid cursor".to_owned())),
+    };
+    let (mut pattern, mut count) = (None, DEFAULT_SCAN_COUNT);
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        match (option.to_ascii_uppercase().as_str(), options.next()) {
+            ("MATCH", Some(matching)) => pattern = Some(matching.clone()),
+            ("COUNT", Some(n)) => match n.parse() {
+                Ok(n) if n > 0 => count = n,
+                _ => return Ok(syntax_error()),
+            },
+            _ => return Ok(syntax_error()),
+        }
+    }
+
+    let mut keys: Vec<(u64, String)> = store
+        .namespace("")
+        .keys()
+        .into_iter()
+        .map(|key| (fnv1a(key.as_bytes()), key))
+        .filter(|(hash, _)| *hash >= cursor)
+        .collect();
+    keys.sort_unstable();
+    // Never split keys with the same hash across calls, as the cursor couldn't tell them apart
+    let mut end = count.min(keys.len());
+    while end < keys.len() && end > 0 && keys[end].0 == keys[end - 1].0 {
+        end += 1;
+    }
+    let next = match (end < keys.len(), keys[..end].last()) {
+        (true, Some((hash, _))) => hash + 1,
+        _ => 0,
+    };
+
+    let mut found = Vec::new();
+    for (_, key) in keys.drain(..end) {
+        let matches = pattern
+            .as_ref()
+            .is_none_or(|pattern| glob_match(pattern.as_bytes(), key.as_bytes()));
+        if matches && expire(store, &key)? {
+            found.push(Frame::Bulk(Some(key)));
+        }
+    }
+    Ok(Frame::Array(vec![
+        Frame::Bulk(Some(next.to_string())),
+        Frame::Array(found),
+    ]))
+}
+
+/// `INFO [section]`
+fn info(store: &mut KvStore, section: Option<&String>) -> Frame {
+    let stats = store.stats();
+    let keys = store.namespace("").keys().len();
+    let expires = store.namespace(EXPIRES).keys().len();
+    let sections = [
+        (
+            "server",
+            format!(
+                "# Server\r\nkvs_version:{}\r\nredis_mode:standalone\r\n",
+                env!("CARGO_PKG_VERSION")
+            ),
+        ),
+        (
+            "kvs",
+            format!(
+                "# Kvs\r\nepoch:{}\r\nlive_bytes:{}\r\ndisk_bytes:{}\r\ncache_hits:{}\r\ncache_misses:{}\r\n",
+                stats.epoch, stats.live_bytes, stats.disk_bytes, stats.cache_hits, stats.cache_misses
+            ),
+        ),
+        (
+            "keyspace",
+            format!("# Keyspace\r\ndb0:keys={},expires={}\r\n", keys, expires),
+        ),
+    ];
+    let wanted = section.map(|section| section.to_ascii_lowercase());
+    let text: Vec<&str> = sections
+        .iter()
+        .filter(|(name, _)| {
+            wanted.as_deref().is_none_or(|wanted| {
+                matches!(wanted, "all" | "default" | "everything") || wanted == *name
+            })
+        })
+        .map(|(_, text)| text.as_str())
+        .collect();
+    Frame::Bulk(Some(text.join("\r\n")))
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+/// Remove a key if its time is up, along with any deadline for a key that isn't there. Returns whether the key is
+/// still there.
+fn expire(store: &mut KvStore, key: &str) -> Result<bool> {
+    let deadline = match store.namespace(EXPIRES).get(key.to_owned())? {
+        Some(deadline) => deadline,
+        None => return Ok(exists(store, key)),
+    };
+    let due = deadline
+        .parse()
+        .is_ok_and(|deadline: u64| deadline <= now_millis());
+    if due && exists(store, key) {
+        store.remove(key.to_owned())?;
+    }
+    if due || !exists(store, key) {
+        store.namespace(EXPIRES).remove(key.to_owned())?;
+    }
+    Ok(exists(store, key))
+}
+
+fn exists(store: &KvStore, key: &str) -> bool {
+    store
+        .index
+        .get("")
+        .is_some_and(|keys| keys.contains_key(key))
+}
+
+fn clear_deadline(store: &mut KvStore, key: &str) -> Result<()> {
+    let mut expires = store.namespace(EXPIRES);
+    if expires.get(key.to_owned())?.is_some() {
+        expires.remove(key.to_owned())?;
+    }
+    Ok(())
+}
+
+/// Match `s` against a Redis glob pattern: `*`, `?`, `[abc]`, `[^abc]`, `[a-z]` and `\` to escape.
+///
+/// Only the latest `*` is ever gone back to, to have it swallow one more byte, so matching takes at most
+/// `pattern.len() * s.len()` steps however many stars there are.
+fn glob_match(pattern: &[u8], s: &[u8]) -> bool {
+    let (mut p, mut i) = (0, 0);
+    // Just past the latest `*`, and where in `s` it stopped swallowing bytes
+    let mut star = None;
+    while i < s.len() {
+        if pattern.get(p) == Some(&b'*') {
+            p += 1;
+            star = Some((p, i));
+            continue;
+        }
+        match match_byte(&pattern[p..], s[i]) {
+            Some((true, len)) => {
+                p += len;
+                i += 1;
+            }
+            _ => match star {
+                Some((after, swallowed)) => {
+                    star = Some((after, swallowed + 1));
+                    p = after;
+                    i = swallowed + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// Match one byte against the start of a pattern other than `*`, giving whether it matched and how much of the
+/// pattern that took, or `None` once the pattern has run out.
+fn match_byte(pattern: &[u8], byte: u8) -> Option<(bool, usize)> {
+    match pattern {
+        [] => None,
+        [b'?', ..] => Some((true, 1)),
+        [b'[', rest @ ..] => {
+            let (negated, mut class) = match rest.split_first() {
+                Some((b'^', class)) => (true, class),
+                _ => (false, rest),
+            };
+            let mut matched = false;
+            loop {
+                match class {
+                    // An unclosed class runs to the end of the pattern
+                    [] => break,
+                    [b']', after @ ..] => {
+                        class = after;
+                        break;
+                    }
+                    [b'\\', c, after @ ..] => {
+                        matched |= *c == byte;
+                        class = after;
+                    }
+                    [lo, b'-', hi, after @ ..] if *hi != b']' => {
+                        let (lo, hi) = if lo <= hi { (lo, hi) } else { (hi, lo) };
+                        matched |= (lo..=hi).contains(&&byte);
+                        class = after;
+                    }
+                    [c, after @ ..] => {
+                        matched |= *c == byte;
+                        class = after;
+                    }
+                }
+            }
+            Some((matched != negated, pattern.len() - class.len()))
+        }
+        [b'\\', c, ..] => Some((*c == byte, 2)),
+        [c, ..] => Some((*c == byte, 1)),
+    }
+}
+
+/// 64-bit FNV-1a, which unlike the standard library's hasher gives the same hash every time the server runs, as
+/// cursors handed out by `SCAN` must stay good.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::{Faults, MemStorage};
+
+    #[test]
+    fn glob_patterns() {
+        let cases: &[(&str, &str, bool)] = &[
+            ("*", "anything", true),
+            ("user:*", "user:1", true),
+            ("user:*", "users", false),
+            ("h?llo", "hello", true),
+            ("h?llo", "hllo", false),
+            ("h[ae]llo", "hallo", true),
+            ("h[ae]llo", "hillo", false),
+            ("h[^e]llo", "hallo", true),
+            ("h[^e]llo", "hello", false),
+            ("h[a-c]llo", "hbllo", true),
+            ("h[a-c]llo", "hdllo", false),
+            ("h\\*llo", "h*llo", true),
+            ("h\\*llo", "hello", false),
+            ("*a*b", "xxaxxb", true),
+            ("*a*b", "xxaxxbc", false),
+        ];
+        for (pattern, s, matches) in cases {
+            assert_eq!(
+                glob_match(pattern.as_bytes(), s.as_bytes()),
+                *matches,
+                "{} against {}",
+                pattern,
+                s
+            );
+        }
+    }
+
+    #[test]
+    fn glob_stars_do_not_backtrack_without_end() {
+        let s = "a".repeat(10_000);
+        assert!(!glob_match(b"a*a*a*a*a*a*a*a*a*a*a*a*b", s.as_bytes()));
+        assert!(glob_match(b"a*a*a*a*a*a*a*a*a*a*a*a*", s.as_bytes()));
+    }
+
+    #[test]
+    fn set_with_expiry_never_leaves_a_value_without_its_deadline() {
+        let args = |command: &str| -> Vec<Vec<u8>> {
+            command
+                .split(' ')
+                .map(|arg| arg.as_bytes().to_vec())
+                .collect()
+        };
+        for space in 0..200 {
+            let storage = MemStorage::default();
+            let mut store = KvStore::open_storage(Arc::new(storage.clone())).unwrap();
+            execute(&mut store, &args("SET key old")).unwrap();
+            storage.inject(Faults {
+                space: Some(space),
+                ..Faults::default()
+            });
+            // Whether or not this fails, the new value must not be there without its deadline
+            let _ = execute(&mut store, &args("SET key new EX 100"));
+            storage.inject(Faults::default());
+            if store.get("key".to_owned()).unwrap().as_deref() == Some("new") {
+                assert!(store
+                    .namespace(EXPIRES)
+                    .get("key".to_owned())
+                    .unwrap()
+                    .is_some());
+            }
+
+            // A deadline left for a key that was never written goes once the key is looked at
+            let _ = execute(&mut store, &args("SET fresh value EX 100"));
+            store.remove("fresh".to_owned()).unwrap();
+            assert!(!expire(&mut store, "fresh").unwrap());
+            assert!(store
+                .namespace(EXPIRES)
+                .get("fresh".to_owned())
+                .unwrap()
+                .is_none());
+        }
+    }
+
+    #[test]
+    fn reads_array_and_inline_commands() {
+        let mut buf =
+            b"*2\r\n$3\r\nGET\r\n$5\r\nk\r\ney\r\nPING  hello\r\n\r\n*1\r\n$3\r\nGE".to_vec();
+        let (commands, error) = take_commands(&mut buf, MAX_BULK);
+        assert_eq!(
+            commands,
+            vec![
+                vec![b"GET".to_vec(), b"k\r\ney".to_vec()],
+                vec![b"PING".to_vec(), b"hello".to_vec()],
+            ]
+        );
+        assert!(error.is_none());
+        // The command still arriving is left for later
+        assert_eq!(buf, b"*1\r\n$3\r\nGE");
+        buf.extend_from_slice(b"T\r\n*1\r\n$3\r\nGETTING\r\n");
+        let (commands, error) = take_commands(&mut buf, MAX_BULK);
+        assert_eq!(commands, vec![vec![b"GET".to_vec()]]);
+        assert_eq!(error.unwrap().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn reads_replies() {
+        let buf = b"*3\r\n+OK\r\n$-1\r\n*2\r\n:12\r\n$5\r\nk\r\ney\r\n-ERR no\r\n$3\r\nab";
+        let (frame, next) = parse_frame(buf, 0).unwrap().unwrap();
+        assert_eq!(
+            frame,
+            Frame::Array(vec![
+                Frame::ok(),
+                Frame::Bulk(None),
+                Frame::Array(vec![
+                    Frame::Integer(12),
+                    Frame::Bulk(Some("k\r\ney".to_owned()))
+                ]),
+            ])
+        );
+        let (frame, next) = parse_frame(buf, next).unwrap().unwrap();
+        assert_eq!(frame, Frame::Error("ERR no".to_owned()));
+        assert!(parse_frame(buf, next).unwrap().is_none());
+        assert!(parse_frame(b"$1\r\n\xff\r\n", 0).is_err());
+    }
+}
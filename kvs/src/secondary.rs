@@ -0,0 +1,124 @@
+use crate::{BadIndexPath, Deser, Result};
+use bson::{Bson, Document};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use snafu::{ensure, ResultExt};
+use std::collections::{BTreeSet, HashMap};
+
+/// The name of the file recording which secondary indexes have been declared.
+pub(crate) const INDEXES: &str = "indexes";
+
+/// A declared secondary index: which keys it covers, and where in their values to find what it indexes them by.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Definition {
+    pub(crate) name: String,
+    pub(crate) prefix: String,
+    pub(crate) path: String,
+}
+
+/// Every secondary index declared on a store, as recorded alongside its logs.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct Definitions {
+    pub(crate) indexes: Vec<Definition>,
+}
+
+impl Definitions {
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        match bson::to_bson(self) {
+            Ok(Bson::Document(doc)) => doc.to_writer(&mut bytes),
+            other => unreachable!("index definitions encoded as {:?}", other),
+        }
+        .expect("writing to memory can't fail");
+        bytes
+    }
+
+    pub(crate) fn decode(mut bytes: &[u8]) -> Result<Definitions> {
+        let doc = Document::from_reader(&mut bytes).context(Deser { offset: 0u64 })?;
+        bson::from_bson(Bson::Document(doc)).context(Deser { offset: 0u64 })
+    }
+}
+
+/// Split a path like `$.address.city` into the fields to descend through.
+pub(crate) fn parse_path(path: &str) -> Result<Vec<String>> {
+    let fields: Vec<String> = match path.strip_prefix("$.") {
+        Some(fields) => fields.split('.').map(str::to_owned).collect(),
+        None => Vec::new(),
+    };
+    ensure!(
+        !fields.is_empty() && fields.iter().all(|field| !field.is_empty()),
+        BadIndexPath { path }
+    );
+    Ok(fields)
+}
+
+/// Keys in the default namespace whose values are JSON objects, looked up by one of their fields.
+pub(crate) struct SecondaryIndex {
+    pub(crate) definition: Definition,
+    fields: Vec<String>,
+    // The keys holding each indexed value, and the indexed value of each key
+    keys: HashMap<String, BTreeSet<String>>,
+    values: HashMap<String, String>,
+}
+
+impl SecondaryIndex {
+    pub(crate) fn new(definition: Definition) -> Result<SecondaryIndex> {
+        let fields = parse_path(&definition.path)?;
+        Ok(SecondaryIndex {
+            definition,
+            fields,
+            keys: HashMap::new(),
+            values: HashMap::new(),
+        })
+    }
+
+    pub(crate) fn covers(&self, ns: &str, key: &str) -> bool {
+        ns.is_empty() && key.starts_with(self.definition.prefix.as_str())
+    }
+
+    /// Find what a value is indexed by, if it is a JSON object with a string, number or boolean at the index's path.
+    fn extract(&self, val: &str) -> Option<String> {
+        let mut found = serde_json::from_str::<Value>(val).ok()?;
+        for field in &self.fields {
+            found = found.get_mut(field.as_str())?.take();
+        }
+        match found {
+            Value::String(s) => Some(s),
+            Value::Number(n) => Some(n.to_string()),
+            Value::Bool(b) => Some(b.to_string()),
+            Value::Null | Value::Array(_) | Value::Object(_) => None,
+        }
+    }
+
+    /// Note a covered key's new value, or that it was removed.
+    pub(crate) fn update(&mut self, key: &str, val: Option<&str>) {
+        if let Some(old) = self.values.remove(key) {
+            if let Some(keys) = self.keys.get_mut(&old) {
+                keys.remove(key);
+                if keys.is_empty() {
+                    self.keys.remove(&old);
+                }
+            }
+        }
+        if let Some(indexed) = val.and_then(|val| self.extract(val)) {
+            self.keys
+                .entry(indexed.clone())
+                .or_default()
+                .insert(key.to_owned());
+            self.values.insert(key.to_owned(), indexed);
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.keys.clear();
+        self.values.clear();
+    }
+
+    /// The keys whose values are indexed by `value`, in order.
+    pub(crate) fn find(&self, value: &str) -> Vec<String> {
+        self.keys
+            .get(value)
+            .map(|keys| keys.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+}
@@ -0,0 +1,104 @@
+use crate::logfile::LogFile;
+use crate::merge::resolve;
+use crate::storage::Storage;
+use crate::{Command, KeyDir, KeyEntry, MergeOperator, Open, Position, Result};
+use snafu::ResultExt;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Held by each open snapshot, so that compaction keeps the logs it reads from.
+pub(crate) struct Pin {
+    /// The oldest epoch the snapshot reads from.
+    pub(crate) epoch: u64,
+}
+
+/// A read-only view of the default namespace as it was at some point. See
+/// [`KvStore::snapshot_read`](crate::KvStore::snapshot_read).
+///
+/// Values are read straight from the logs they were recorded in, so the view stays the same whatever is written to
+/// the store afterwards.
+pub struct Snapshot {
+    storage: Arc<dyn Storage>,
+    keys: KeyDir,
+    // Operands merged into keys since they were last set
+    operands: HashMap<String, Vec<KeyEntry>>,
+    merge_operator: Option<Arc<dyn MergeOperator>>,
+    position: Option<Position>,
+    // Logs opened so far, as scans tend to read many values from each
+    logs: HashMap<u64, LogFile>,
+    _pin: Arc<Pin>,
+}
+
+impl Snapshot {
+    pub(crate) fn new(
+        storage: Arc<dyn Storage>,
+        keys: KeyDir,
+        operands: HashMap<String, Vec<KeyEntry>>,
+        merge_operator: Option<Arc<dyn MergeOperator>>,
+        position: Option<Position>,
+        pin: Arc<Pin>,
+    ) -> Snapshot {
+        Snapshot {
+            storage,
+            keys,
+            operands,
+            merge_operator,
+            position,
+            logs: HashMap::new(),
+            _pin: pin,
+        }
+    }
+
+    /// The last record included in the snapshot, if the store had any.
+    pub fn position(&self) -> Option<Position> {
+        self.position
+    }
+
+    /// Retrieve the value a key had when the snapshot was taken.
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        let entry = match self.keys.get(&key) {
+            Some(entry) => *entry,
+            None => return Ok(None),
+        };
+        let operands = self.operands.get(&key).cloned().unwrap_or_default();
+        let mut records = Vec::with_capacity(operands.len() + 1);
+        for entry in Some(entry).into_iter().chain(operands) {
+            records.push((entry.offset, self.retrieve(entry)?));
+        }
+        resolve(self.merge_operator.as_deref(), &key, records).map(Some)
+    }
+
+    /// Read the record an entry points at.
+    fn retrieve(&mut self, entry: KeyEntry) -> Result<Command> {
+        if !self.logs.contains_key(&entry.epoch) {
+            let log = LogFile::open(entry.epoch, self.storage.as_ref()).with_context(|| Open {
+                path: self.storage.locate(entry.epoch),
+            })?;
+            self.logs.insert(entry.epoch, log);
+        }
+        self.logs
+            .get_mut(&entry.epoch)
+            .expect("just opened")
+            .retrieve(entry.offset)
+    }
+
+    /// Read out every key starting with `prefix` along with the value it had when the snapshot was taken, in order
+    /// of key.
+    pub fn scan(&mut self, prefix: &str) -> Result<Vec<(String, String)>> {
+        let mut keys: Vec<String> = self
+            .keys
+            .keys()
+            .filter(|key| key.starts_with(prefix))
+            .cloned()
+            .collect();
+        keys.sort_unstable();
+
+        let mut pairs = Vec::with_capacity(keys.len());
+        for key in keys {
+            if let Some(val) = self.get(key.clone())? {
+                pairs.push((key, val));
+            }
+        }
+        Ok(pairs)
+    }
+}
@@ -0,0 +1,188 @@
+use memmap2::Mmap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+
+/// An open log, readable and appendable.
+pub(crate) trait Handle: Read + Write + Seek + Send {
+    /// Cut the log off at `len` bytes.
+    fn set_len(&mut self, len: u64) -> io::Result<()>;
+
+    /// Wait until everything written so far has reached the disk.
+    fn sync(&mut self) -> io::Result<()>;
+}
+
+/// The contents of a sealed log.
+pub(crate) type Mapped = Box<dyn AsRef<[u8]> + Send + Sync>;
+
+/// Where a store keeps its logs, one per epoch.
+pub(crate) trait Storage: Send + Sync {
+    /// Where the logs live, for error messages.
+    fn path(&self) -> &Path;
+
+    /// List the epochs that have a log, in no particular order.
+    fn epochs(&self) -> io::Result<Vec<u64>>;
+
+    /// Create an empty log for an epoch, truncating it if it already exists.
+    fn create(&self, epoch: u64) -> io::Result<Box<dyn Handle>>;
+
+    /// Open the existing log for an epoch.
+    fn open(&self, epoch: u64) -> io::Result<Box<dyn Handle>>;
+
+    /// Grab the full contents of a sealed log, which will never change again.
+    fn map(&self, epoch: u64) -> io::Result<Mapped>;
+
+    /// Delete the log for an epoch.
+    fn remove(&self, epoch: u64) -> io::Result<()>;
+
+    /// Set an epoch's log aside in the changelog, where it is no longer part of the store but can still be read.
+    fn retire(&self, epoch: u64) -> io::Result<()>;
+
+    /// List the epochs that have a log in the changelog, in no particular order.
+    fn retired(&self) -> io::Result<Vec<u64>>;
+
+    /// Open the log for an epoch in the changelog.
+    fn open_retired(&self, epoch: u64) -> io::Result<Box<dyn Handle>>;
+
+    /// Delete the log for an epoch from the changelog.
+    fn remove_retired(&self, epoch: u64) -> io::Result<()>;
+
+    /// Read a small named file kept alongside the logs, if it exists.
+    fn read_meta(&self, name: &str) -> io::Result<Option<Vec<u8>>>;
+
+    /// Replace a small named file kept alongside the logs, so that it holds either the old contents or the new.
+    fn write_meta(&self, name: &str, contents: &[u8]) -> io::Result<()>;
+
+    /// Wait until logs and files kept alongside them being created, renamed or removed has reached the disk.
+    fn sync_dir(&self) -> io::Result<()>;
+
+    /// The location of an epoch's log, for error messages.
+    fn locate(&self, epoch: u64) -> PathBuf {
+        self.path().join(epoch.to_string())
+    }
+}
+
+impl Handle for File {
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        File::set_len(self, len)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.sync_data()
+    }
+}
+
+/// Logs kept as files in a directory, named for their epoch. Logs in the changelog are named for their epoch with a
+/// `.changes` extension.
+pub(crate) struct DirStorage {
+    path: PathBuf,
+}
+
+impl DirStorage {
+    /// Use the logs in `path`, creating the directory if needed.
+    pub(crate) fn new(path: PathBuf) -> io::Result<DirStorage> {
+        if let Err(e) = fs::create_dir_all(&path) {
+            if e.kind() != io::ErrorKind::AlreadyExists {
+                return Err(e);
+            }
+        };
+        Ok(DirStorage { path })
+    }
+
+    fn locate_retired(&self, epoch: u64) -> PathBuf {
+        self.path.join(format!("{}.changes", epoch))
+    }
+
+    /// List the epochs of the files in the directory whose names, after stripping `suffix`, are an epoch.
+    fn list(&self, suffix: &str) -> io::Result<Vec<u64>> {
+        let mut epochs = Vec::new();
+        for entry in fs::read_dir(&self.path)? {
+            let name = entry?.file_name();
+            let name = name.to_string_lossy();
+            // Anything else in here is the changelog or some other file kept alongside the logs
+            if let Some(Ok(e)) = name.strip_suffix(suffix).map(str::parse) {
+                epochs.push(e);
+            }
+        }
+        Ok(epochs)
+    }
+}
+
+impl Storage for DirStorage {
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn epochs(&self) -> io::Result<Vec<u64>> {
+        self.list("")
+    }
+
+    fn create(&self, epoch: u64) -> io::Result<Box<dyn Handle>> {
+        let handle = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .truncate(true)
+            .create(true)
+            .open(self.locate(epoch))?;
+        Ok(Box::new(handle))
+    }
+
+    fn open(&self, epoch: u64) -> io::Result<Box<dyn Handle>> {
+        let handle = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .open(self.locate(epoch))?;
+        Ok(Box::new(handle))
+    }
+
+    fn map(&self, epoch: u64) -> io::Result<Mapped> {
+        let handle = File::open(self.locate(epoch))?;
+        // Safety: sealed log files are only ever removed by compaction, never modified in place.
+        let map = unsafe { Mmap::map(&handle)? };
+        Ok(Box::new(map))
+    }
+
+    fn remove(&self, epoch: u64) -> io::Result<()> {
+        fs::remove_file(self.locate(epoch))
+    }
+
+    fn retire(&self, epoch: u64) -> io::Result<()> {
+        fs::rename(self.locate(epoch), self.locate_retired(epoch))
+    }
+
+    fn retired(&self) -> io::Result<Vec<u64>> {
+        self.list(".changes")
+    }
+
+    fn open_retired(&self, epoch: u64) -> io::Result<Box<dyn Handle>> {
+        let handle = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .open(self.locate_retired(epoch))?;
+        Ok(Box::new(handle))
+    }
+
+    fn remove_retired(&self, epoch: u64) -> io::Result<()> {
+        fs::remove_file(self.locate_retired(epoch))
+    }
+
+    fn read_meta(&self, name: &str) -> io::Result<Option<Vec<u8>>> {
+        match fs::read(self.path.join(name)) {
+            Ok(contents) => Ok(Some(contents)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn write_meta(&self, name: &str, contents: &[u8]) -> io::Result<()> {
+        let staged = self.path.join(format!("{}.tmp", name));
+        let mut file = File::create(&staged)?;
+        file.write_all(contents)?;
+        file.sync_all()?;
+        fs::rename(&staged, self.path.join(name))
+    }
+
+    fn sync_dir(&self) -> io::Result<()> {
+        File::open(&self.path)?.sync_all()
+    }
+}
@@ -0,0 +1,73 @@
+use crate::{Command, Error, Position};
+use std::fmt;
+
+/// What [`KvStore::verify`](crate::KvStore::verify) checked, and everything it found wrong.
+#[derive(Debug, Default)]
+pub struct Report {
+    /// The number of logs read through.
+    pub logs: usize,
+    /// The number of records decoded from them.
+    pub records: u64,
+    /// The number of keys whose index entries were checked.
+    pub keys: usize,
+    pub problems: Vec<Problem>,
+}
+
+impl Report {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// Something wrong with a store's logs or the index built from them.
+#[derive(Debug)]
+pub enum Problem {
+    /// A log couldn't be read past some point. Nothing after it in that log was checked.
+    Unreadable { epoch: u64, error: Error },
+    /// A log other than the one being written to ends without a seal, so it may have lost its tail.
+    Unsealed { epoch: u64, len: u64 },
+    /// A key's index entry points at something other than its value, or at an operand for another key.
+    Misindexed {
+        ns: String,
+        key: String,
+        at: Position,
+        found: Result<Command, Error>,
+    },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::Unreadable { epoch, error } => {
+                write!(f, "epoch {} is unreadable: {}", epoch, error)
+            }
+            Problem::Unsealed { epoch, len } => {
+                write!(f, "epoch {} ends at {} without being sealed", epoch, len)
+            }
+            Problem::Misindexed { ns, key, at, found } => {
+                if ns.is_empty() {
+                    write!(f, "key {:?} is indexed at {}", key, at)?;
+                } else {
+                    write!(
+                        f,
+                        "key {:?} in namespace {:?} is indexed at {}",
+                        key, ns, at
+                    )?;
+                }
+                match found {
+                    Ok(cmd) => write!(f, ", which holds {:?}", cmd),
+                    Err(e) => write!(f, ", which can't be read: {}", e),
+                }
+            }
+        }
+    }
+}
+
+/// Whether `cmd` is a record of the given key's value: the `Set` it was last given, or an operand merged into it.
+pub(crate) fn belongs_to(cmd: &Command, ns: &str, key: &str, operand: bool) -> bool {
+    match cmd {
+        Command::Set { ns: n, key: k, .. } => !operand && n == ns && k == key,
+        Command::Merge { ns: n, key: k, .. } => n == ns && k == key,
+        Command::Rm { .. } | Command::DropNamespace { .. } => false,
+    }
+}
@@ -0,0 +1,56 @@
+use serde::{Deserialize, Serialize};
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+/// A key that was set or removed in a watched store.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Change {
+    pub key: String,
+    /// The key's new value, or `None` if it was removed.
+    pub val: Option<String>,
+}
+
+/// Someone waiting to hear about changes to keys in a namespace.
+struct Watcher {
+    ns: String,
+    prefix: String,
+    sender: Sender<Change>,
+}
+
+impl Watcher {
+    fn watches(&self, ns: &str, key: &str) -> bool {
+        self.ns == ns && key.starts_with(self.prefix.as_str())
+    }
+}
+
+/// Everyone waiting to hear about changes to a store.
+#[derive(Default)]
+pub(crate) struct Watchers {
+    watching: Vec<Watcher>,
+}
+
+impl Watchers {
+    pub(crate) fn add(&mut self, ns: String, prefix: String) -> Receiver<Change> {
+        let (sender, receiver) = channel();
+        self.watching.push(Watcher { ns, prefix, sender });
+        receiver
+    }
+
+    /// Whether anyone is watching `key`.
+    pub(crate) fn watching(&self, ns: &str, key: &str) -> bool {
+        self.watching.iter().any(|watcher| watcher.watches(ns, key))
+    }
+
+    /// Tell everyone watching `key` about its new value, forgetting watchers that have gone away.
+    pub(crate) fn notify(&mut self, ns: &str, key: &str, val: Option<&str>) {
+        self.watching.retain(|watcher| {
+            !watcher.watches(ns, key)
+                || watcher
+                    .sender
+                    .send(Change {
+                        key: key.to_owned(),
+                        val: val.map(str::to_owned),
+                    })
+                    .is_ok()
+        });
+    }
+}
@@ -0,0 +1,29 @@
+//! Messages sent between processes, each a single BSON document. Documents carry their own length, so they need no
+//! further framing.
+
+use crate::{EncodeMessage, ReceiveMessage, Result, SendMessage};
+use bson::{Bson, Document};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use snafu::ResultExt;
+use std::io::{Read, Write};
+
+pub(crate) fn encode<T: Serialize>(msg: &T) -> Result<Vec<u8>> {
+    let doc = match bson::to_bson(msg).context(EncodeMessage)? {
+        Bson::Document(doc) => doc,
+        other => unreachable!("message encoded as {:?}", other),
+    };
+    let mut bytes = Vec::new();
+    doc.to_writer(&mut bytes).context(EncodeMessage)?;
+    Ok(bytes)
+}
+
+pub(crate) fn send<T: Serialize, W: Write>(writer: &mut W, msg: &T) -> Result<()> {
+    let bytes = encode(msg)?;
+    writer.write_all(&bytes).context(SendMessage)
+}
+
+pub(crate) fn receive<T: DeserializeOwned, R: Read>(reader: &mut R) -> Result<T> {
+    let doc = Document::from_reader(reader).context(ReceiveMessage)?;
+    bson::from_bson(Bson::Document(doc)).context(ReceiveMessage)
+}
@@ -0,0 +1,343 @@
+use assert_cmd::prelude::*;
+use kvs::{Change, ClusterClient, Error, KvStore, NodeStatus, RaftNode, Result, Role};
+use predicates::str::contains;
+use std::io::{BufRead, BufReader};
+use std::net::TcpListener;
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+const KEYS: usize = 100;
+
+fn key(id: usize) -> String {
+    format!("key{}", id)
+}
+
+/// Kills a child process when dropped, so a failing test doesn't leave it running.
+struct Running(Child);
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+/// A cluster of `kvs-server` processes on local ports, each with its own directory.
+struct Cluster {
+    dirs: Vec<TempDir>,
+    addrs: Vec<String>,
+    nodes: Vec<Option<Running>>,
+}
+
+impl Cluster {
+    fn new(size: usize) -> Cluster {
+        let mut cluster = Cluster {
+            dirs: (0..size)
+                .map(|_| TempDir::new().expect("unable to create temporary working directory"))
+                .collect(),
+            addrs: (0..size).map(|_| free_addr()).collect(),
+            nodes: (0..size).map(|_| None).collect(),
+        };
+        for id in 0..size {
+            cluster.start(id, None);
+        }
+        cluster
+    }
+
+    fn start(&mut self, id: usize, snapshot_threshold: Option<u64>) {
+        let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+        cmd.args(["--id", &id.to_string(), "--nodes", &self.addrs.join(",")])
+            .current_dir(&self.dirs[id])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null());
+        if let Some(threshold) = snapshot_threshold {
+            cmd.args(["--snapshot-threshold", &threshold.to_string()]);
+        }
+        self.nodes[id] = Some(Running(cmd.spawn().expect("failed to start node")));
+    }
+
+    fn stop(&mut self, id: usize) {
+        self.nodes[id] = None;
+    }
+
+    fn status(&self, id: usize) -> Option<NodeStatus> {
+        self.nodes[id].as_ref()?;
+        ClusterClient::status(&self.addrs[id]).ok()
+    }
+
+    /// Wait for a running node to win an election, returning its id.
+    fn leader(&self) -> usize {
+        let deadline = Instant::now() + Duration::from_secs(30);
+        loop {
+            let leader = (0..self.addrs.len())
+                .filter_map(|id| self.status(id).map(|status| (id, status)))
+                .filter(|(_, status)| status.role == Role::Leader)
+                .max_by_key(|(_, status)| status.term);
+            if let Some((id, _)) = leader {
+                return id;
+            }
+            assert!(Instant::now() < deadline, "no node was elected");
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+
+    /// Wait until node `id` has applied everything the leader has committed.
+    fn wait_for_applied(&self, id: usize) -> NodeStatus {
+        let deadline = Instant::now() + Duration::from_secs(30);
+        loop {
+            let leader = self.status(self.leader());
+            if let (Some(leader), Some(status)) = (leader, self.status(id)) {
+                if status.last_applied >= leader.commit_index {
+                    return status;
+                }
+            }
+            assert!(Instant::now() < deadline, "node {} never caught up", id);
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+
+    fn client(&self) -> ClusterClient {
+        ClusterClient::new(self.addrs.clone())
+    }
+}
+
+fn free_addr() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("failed to find a free port");
+    listener.local_addr().unwrap().to_string()
+}
+
+fn fill(client: &mut ClusterClient, prefix: &str) -> Result<()> {
+    for k in 0..KEYS {
+        client.set(key(k), format!("{}{}", prefix, k))?;
+    }
+    Ok(())
+}
+
+fn check(client: &mut ClusterClient, prefix: &str) -> Result<()> {
+    for k in 0..KEYS {
+        assert_eq!(client.get(key(k))?, Some(format!("{}{}", prefix, k)));
+    }
+    Ok(())
+}
+
+// Writes acknowledged by the cluster should survive its leader going away, and the old leader should catch up when it
+// comes back.
+#[test]
+fn cluster_fails_over() -> Result<()> {
+    let mut cluster = Cluster::new(3);
+    let mut client = cluster.client();
+    fill(&mut client, "value")?;
+    client.remove(key(0))?;
+
+    let old = cluster.leader();
+    cluster.stop(old);
+    let new = cluster.leader();
+    assert_ne!(new, old);
+    assert_eq!(client.get(key(0))?, None);
+    client.set(key(0), "value0".to_owned())?;
+    check(&mut client, "value")?;
+
+    cluster.start(old, None);
+    let status = cluster.wait_for_applied(old);
+    assert_eq!(status.role, Role::Follower);
+    Ok(())
+}
+
+// A node that falls behind a leader which has since dropped its log should be sent a snapshot instead.
+#[test]
+fn cluster_catches_up_from_snapshot() -> Result<()> {
+    let mut cluster = Cluster::new(3);
+    let leader = cluster.leader();
+    let behind = (leader + 1) % 3;
+    cluster.stop(behind);
+    for id in (0..3).filter(|id| *id != behind) {
+        cluster.stop(id);
+        cluster.start(id, Some(10));
+    }
+
+    let mut client = cluster.client();
+    fill(&mut client, "value")?;
+    client.remove(key(0))?;
+    let leader = cluster.leader();
+    assert!(cluster.status(leader).unwrap().snapshot_index > 0);
+
+    cluster.start(behind, Some(10));
+    let status = cluster.wait_for_applied(behind);
+    assert!(status.snapshot_index > 0);
+
+    // The node's store should hold exactly what the cluster does, including after restarting from it alone
+    for restarted in [false, true] {
+        if restarted {
+            cluster.start(behind, Some(10));
+            let status = cluster.wait_for_applied(behind);
+            assert!(status.snapshot_index > 0);
+        }
+        cluster.stop(behind);
+        let mut store = KvStore::open(cluster.dirs[behind].path().join("data"))?;
+        assert_eq!(store.get(key(0))?, None);
+        for k in 1..KEYS {
+            assert_eq!(store.get(key(k))?, Some(format!("value{}", k)));
+        }
+    }
+    Ok(())
+}
+
+// A client that only knows about a follower should be sent on to the leader.
+#[test]
+fn cluster_redirects_to_leader() -> Result<()> {
+    let cluster = Cluster::new(3);
+    let follower = (cluster.leader() + 1) % 3;
+    let addr = cluster.addrs[follower].clone();
+
+    let mut client = ClusterClient::new(vec![addr.clone()]);
+    client.set(key(1), "value1".to_owned())?;
+    assert_eq!(client.get(key(1))?, Some("value1".to_owned()));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--cluster", &addr, "set", "key2", "value2"])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--cluster", &addr, "get", "key2"])
+        .assert()
+        .success()
+        .stdout("value2");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--cluster", &addr, "rm", "key3"])
+        .assert()
+        .code(2)
+        .stderr(contains("Key not found"));
+    Ok(())
+}
+
+// A watch on any node should stream committed changes under its prefix.
+#[test]
+fn cluster_streams_changes_to_watchers() -> Result<()> {
+    let cluster = Cluster::new(3);
+    let follower = (cluster.leader() + 1) % 3;
+    let changes = ClusterClient::new(vec![cluster.addrs[follower].clone()]).watch("user/")?;
+
+    let mut client = cluster.client();
+    client.set("user/1".to_owned(), "ann".to_owned())?;
+    client.set("group/1".to_owned(), "admins".to_owned())?;
+    client.remove("user/1".to_owned())?;
+
+    let timeout = Duration::from_secs(10);
+    assert_eq!(
+        changes.recv_timeout(timeout).unwrap(),
+        Change {
+            key: "user/1".to_owned(),
+            val: Some("ann".to_owned()),
+        }
+    );
+    assert_eq!(
+        changes.recv_timeout(timeout).unwrap(),
+        Change {
+            key: "user/1".to_owned(),
+            val: None,
+        }
+    );
+
+    let mut watch = Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--cluster", &cluster.addrs.join(","), "watch", "user/"])
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("failed to start watch");
+    let mut lines = BufReader::new(watch.stdout.take().unwrap()).lines();
+    let _watch = Running(watch);
+    // Keep writing until the watch has started and seen one
+    let (seen, stop) = mpsc::channel::<()>();
+    let writer = thread::spawn(move || {
+        for _ in 0..50 {
+            if client.set("user/2".to_owned(), "bob".to_owned()).is_err() {
+                return;
+            }
+            if stop.recv_timeout(Duration::from_millis(100)).is_ok() {
+                return;
+            }
+        }
+    });
+    let line = lines.next().unwrap().unwrap();
+    assert_eq!(line, "set user/2 bob");
+    let _ = seen.send(());
+    writer.join().unwrap();
+    Ok(())
+}
+
+// Five nodes should keep going with any two of them down.
+#[test]
+fn five_node_cluster_survives_two_failures() -> Result<()> {
+    let mut cluster = Cluster::new(5);
+    let mut client = cluster.client();
+    fill(&mut client, "value")?;
+
+    let leader = cluster.leader();
+    cluster.stop(leader);
+    cluster.stop((leader + 1) % 5);
+    check(&mut client, "value")?;
+    fill(&mut client, "again")?;
+    check(&mut client, "again")?;
+    Ok(())
+}
+
+// A node id outside the cluster is a configuration mistake, reported as such before anything is opened.
+#[test]
+fn cluster_rejects_unknown_node() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let nodes = vec![free_addr(), free_addr()];
+    match RaftNode::open(2, nodes, temp_dir.path()) {
+        Err(Error::UnknownNode { id: 2, nodes: 2 }) => {}
+        r => panic!("expected an unknown node error, got {:?}", r.err()),
+    }
+}
+
+// kvs-server should exit with the same codes as kvs, so that scripts can tell a bad node id from a busy port.
+#[test]
+fn cli_server_exit_codes() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args([
+            "--id",
+            "2",
+            "--nodes",
+            &[free_addr(), free_addr()].join(","),
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(9)
+        .stderr(contains("not one of the 2 nodes"));
+
+    let taken = TcpListener::bind("127.0.0.1:0").expect("failed to listen");
+    let addr = taken.local_addr().unwrap().to_string();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--http", &addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(8)
+        .stderr(contains("failed to listen on"));
+
+    // Nodes can't check tokens, so mustn't seem to
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args([
+            "--id",
+            "0",
+            "--nodes",
+            &free_addr(),
+            "--tokens",
+            "tokens.toml",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("--tokens"));
+}
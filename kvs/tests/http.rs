@@ -0,0 +1,197 @@
+use assert_cmd::prelude::*;
+use kvs::{HttpServer, KvStore, Permission, Tokens};
+use serde_json::{json, Value};
+use std::net::TcpListener;
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+/// Serve an in-memory store over HTTP on a local port, returning the URL to reach it at.
+fn serve() -> String {
+    serve_with(HttpServer::new(KvStore::in_memory()))
+}
+
+fn serve_with(server: HttpServer) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("failed to find a free port");
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    thread::spawn(move || server.serve(listener));
+    url
+}
+
+/// Send a request, returning the status and the parsed body, if there was one.
+fn call(method: &str, url: &str, body: Option<Value>) -> (u16, Option<Value>) {
+    send(ureq::request(method, url), body)
+}
+
+fn send(request: ureq::Request, body: Option<Value>) -> (u16, Option<Value>) {
+    let (method, url) = (request.method().to_owned(), request.url().to_owned());
+    let result = match body {
+        Some(body) => request.send_json(body),
+        None => request.call(),
+    };
+    let response = match result {
+        Ok(response) => response,
+        Err(ureq::Error::Status(_, response)) => response,
+        Err(e) => panic!("{} {} failed: {}", method, url, e),
+    };
+    let status = response.status();
+    let text = response.into_string().unwrap();
+    let body = if text.is_empty() {
+        None
+    } else {
+        Some(serde_json::from_str(&text).expect("body should be JSON"))
+    };
+    (status, body)
+}
+
+/// Kills a child process when dropped, so a failing test doesn't leave it running.
+struct Running(Child);
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+// Keys should be set, read, listed and removed through their own URLs.
+#[test]
+fn http_keys() {
+    let url = serve();
+    let put = |key: &str, value: &str| {
+        call(
+            "PUT",
+            &format!("{}/keys/{}", url, key),
+            Some(json!({ "value": value })),
+        )
+    };
+    assert_eq!(put("user:1", "alice"), (204, None));
+    assert_eq!(put("user:2", "bob").0, 204);
+    assert_eq!(put("order:1", "book").0, 204);
+    // Keys with awkward characters are percent-encoded
+    assert_eq!(put("a%2Fb%20c", "slashed").0, 204);
+
+    assert_eq!(
+        call("GET", &format!("{}/keys/user:1", url), None),
+        (200, Some(json!({ "key": "user:1", "value": "alice" })))
+    );
+    assert_eq!(
+        call("GET", &format!("{}/keys/a%2Fb%20c", url), None),
+        (200, Some(json!({ "key": "a/b c", "value": "slashed" })))
+    );
+    let (status, body) = call("GET", &format!("{}/keys/user:3", url), None);
+    assert_eq!(status, 404);
+    assert_eq!(body.unwrap()["error"], "Key not found");
+
+    assert_eq!(
+        call("GET", &format!("{}/keys?prefix=user", url), None),
+        (
+            200,
+            Some(json!({ "keys": [
+                { "key": "user:1", "value": "alice" },
+                { "key": "user:2", "value": "bob" },
+            ] }))
+        )
+    );
+    let (status, body) = call("GET", &format!("{}/keys", url), None);
+    assert_eq!(status, 200);
+    assert_eq!(body.unwrap()["keys"].as_array().unwrap().len(), 4);
+
+    assert_eq!(
+        call("DELETE", &format!("{}/keys/user:1", url), None),
+        (204, None)
+    );
+    assert_eq!(call("DELETE", &format!("{}/keys/user:1", url), None).0, 404);
+    assert_eq!(call("GET", &format!("{}/keys/user:1", url), None).0, 404);
+}
+
+// Requests the server can't make sense of should be turned away with the right status.
+#[test]
+fn http_bad_requests() {
+    let url = serve();
+    let key = format!("{}/keys/k", url);
+    assert_eq!(call("PUT", &key, Some(json!({ "val": "v" }))).0, 400);
+    assert_eq!(call("PUT", &key, Some(json!("v"))).0, 400);
+    assert_eq!(call("POST", &key, Some(json!({ "value": "v" }))).0, 405);
+    assert_eq!(call("GET", &format!("{}/compact", url), None).0, 405);
+    assert_eq!(call("GET", &format!("{}/nothing", url), None).0, 404);
+    assert_eq!(call("GET", &format!("{}/keys/", url), None).0, 404);
+}
+
+// Nothing should reach the store without a bearer token permitting it.
+#[test]
+fn http_tokens() {
+    let tokens = Tokens::default()
+        .with_token("reader", Permission::Read)
+        .with_token("writer", Permission::ReadWrite);
+    let url = serve_with(HttpServer::new(KvStore::in_memory()).with_tokens(tokens));
+    let key = format!("{}/keys/k", url);
+    let body = || Some(json!({ "value": "v" }));
+    let as_token = |method: &str, token: &str| {
+        ureq::request(method, &key).set("Authorization", &format!("Bearer {}", token))
+    };
+
+    assert_eq!(call("GET", &key, None).0, 401);
+    assert_eq!(call("PUT", &key, body()).0, 401);
+    assert_eq!(call("GET", &format!("{}/stats", url), None).0, 401);
+    assert_eq!(send(as_token("GET", "guess"), None).0, 401);
+    let basic = ureq::get(&key).set("Authorization", "Basic d3JpdGVy");
+    assert_eq!(send(basic, None).0, 401);
+
+    assert_eq!(send(as_token("PUT", "reader"), body()).0, 403);
+    assert_eq!(send(as_token("DELETE", "reader"), None).0, 403);
+    assert_eq!(send(as_token("PUT", "writer"), body()).0, 204);
+    let (status, got) = send(as_token("GET", "reader"), None);
+    assert_eq!(status, 200);
+    assert_eq!(got.unwrap()["value"], "v");
+}
+
+// `kvs-server --http` should report on and compact the store it keeps.
+#[test]
+fn http_server_stats_and_compact() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = TcpListener::bind("127.0.0.1:0")
+        .expect("failed to find a free port")
+        .local_addr()
+        .unwrap()
+        .to_string();
+    let _server = Running(
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--http", &addr])
+            .current_dir(&temp_dir)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("failed to start server"),
+    );
+    let url = format!("http://{}", addr);
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while ureq::get(&format!("{}/stats", url)).call().is_err() {
+        assert!(Instant::now() < deadline, "server never came up");
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    for i in 0..100 {
+        let value = json!({ "value": format!("value{}", i) });
+        assert_eq!(
+            call("PUT", &format!("{}/keys/key", url), Some(value)).0,
+            204
+        );
+    }
+    let (status, stats) = call("GET", &format!("{}/stats", url), None);
+    assert_eq!(status, 200);
+    let stats = stats.unwrap();
+    assert_eq!(stats["keys"], 1);
+
+    let (status, compacted) = call("POST", &format!("{}/compact", url), None);
+    assert_eq!(status, 200);
+    let compacted = compacted.unwrap();
+    assert_eq!(compacted["before"], stats["disk_bytes"]);
+    assert!(compacted["after"].as_u64() < compacted["before"].as_u64());
+    assert_eq!(
+        call("GET", &format!("{}/keys/key", url), None),
+        (200, Some(json!({ "key": "key", "value": "value99" })))
+    );
+}
@@ -0,0 +1,199 @@
+use kvs::{Error, KvStore};
+use proptest::prelude::*;
+use proptest::sample::Index;
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use tempfile::TempDir;
+
+// A small keyspace and log size so that sequences overwrite keys and rotate epochs often
+const KEYS: usize = 8;
+const MAX_LOG_SIZE: u64 = 256;
+
+#[derive(Debug, Clone)]
+enum Op {
+    Set(usize, String),
+    Get(usize),
+    Remove(usize),
+    Compact,
+    Reopen,
+}
+
+#[derive(Debug, Clone)]
+enum Damage {
+    Truncate,
+    Flip(u8),
+}
+
+fn key(id: usize) -> String {
+    format!("key{}", id)
+}
+
+fn open(dir: &TempDir, cache: bool) -> KvStore {
+    let store = KvStore::open(dir.path())
+        .expect("failed to open store")
+        .with_max_size(MAX_LOG_SIZE);
+    if cache {
+        store.with_cache_capacity(64)
+    } else {
+        store
+    }
+}
+
+fn op() -> impl Strategy<Value = Op> {
+    prop_oneof![
+        4 => (0..KEYS, "[a-z]{0,16}").prop_map(|(k, v)| Op::Set(k, v)),
+        3 => (0..KEYS).prop_map(Op::Get),
+        2 => (0..KEYS).prop_map(Op::Remove),
+        1 => Just(Op::Compact),
+        1 => Just(Op::Reopen),
+    ]
+}
+
+// Operations that change what is on disk
+fn write_op() -> impl Strategy<Value = Op> {
+    prop_oneof![
+        6 => (0..KEYS, "[a-z]{0,16}").prop_map(|(k, v)| Op::Set(k, v)),
+        2 => (0..KEYS).prop_map(Op::Remove),
+        1 => Just(Op::Compact),
+    ]
+}
+
+fn damage() -> impl Strategy<Value = Damage> {
+    prop_oneof![Just(Damage::Truncate), (1..=255u8).prop_map(Damage::Flip)]
+}
+
+/// Apply a mutation to both the store and the reference model.
+fn apply(store: &mut KvStore, model: &mut HashMap<String, String>, op: Op) {
+    match op {
+        Op::Set(k, v) => {
+            store.set(key(k), v.clone()).expect("failed to set");
+            model.insert(key(k), v);
+        }
+        Op::Remove(k) => {
+            let removed = store.remove(key(k));
+            match model.remove(&key(k)) {
+                Some(_) => removed.expect("failed to remove"),
+                None => assert!(matches!(removed, Err(Error::NotFound))),
+            }
+        }
+        Op::Compact => store.compact().expect("failed to compact"),
+        op => panic!("{:?} is not a mutation", op),
+    }
+}
+
+/// Log files in the store's directory with their epochs, oldest first. Other files kept alongside them are skipped.
+fn logs(dir: &TempDir) -> Vec<(u64, PathBuf)> {
+    let mut logs: Vec<(u64, PathBuf)> = fs::read_dir(dir.path())
+        .expect("failed to list store")
+        .filter_map(|entry| {
+            let path = entry.expect("failed to list store").path();
+            let epoch = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.parse().ok())?;
+            Some((epoch, path))
+        })
+        .collect();
+    logs.sort();
+    logs
+}
+
+fn damage_log(path: &PathBuf, offset: u64, damage: Damage) {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .expect("failed to open log");
+    match damage {
+        Damage::Truncate => file.set_len(offset).expect("failed to truncate log"),
+        Damage::Flip(mask) => {
+            let mut byte = [0u8];
+            file.seek(SeekFrom::Start(offset)).unwrap();
+            file.read_exact(&mut byte).unwrap();
+            byte[0] ^= mask;
+            file.seek(SeekFrom::Start(offset)).unwrap();
+            file.write_all(&byte).unwrap();
+        }
+    }
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    // Random sequences of operations should behave exactly like a `HashMap`, across reopens and compactions.
+    #[test]
+    fn store_matches_model(ops in prop::collection::vec(op(), 1..200), cache in any::<bool>()) {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = open(&temp_dir, cache);
+        let mut model = HashMap::new();
+
+        for op in ops {
+            match op {
+                Op::Get(k) => prop_assert_eq!(store.get(key(k)).unwrap(), model.get(&key(k)).cloned()),
+                Op::Reopen => {
+                    drop(store);
+                    store = open(&temp_dir, cache);
+                }
+                op => apply(&mut store, &mut model, op),
+            }
+        }
+        for k in 0..KEYS {
+            prop_assert_eq!(store.get(key(k)).unwrap(), model.get(&key(k)).cloned());
+        }
+    }
+
+    // Truncating or corrupting a log at any byte must either lose only the most recent writes or be reported as a
+    // replay failure pointing at the damaged epoch, at or before the damaged offset.
+    #[test]
+    fn damaged_logs_recover_a_prefix(
+        ops in prop::collection::vec(write_op(), 1..100),
+        victim in any::<Index>(),
+        at in any::<Index>(),
+        damage in damage(),
+    ) {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = open(&temp_dir, false);
+        let mut model = HashMap::new();
+        let mut history = vec![model.clone()];
+        for op in ops {
+            apply(&mut store, &mut model, op);
+            history.push(model.clone());
+        }
+        drop(store);
+
+        let logs: Vec<(u64, PathBuf)> = logs(&temp_dir)
+            .into_iter()
+            .filter(|(_, path)| fs::metadata(path).unwrap().len() > 0)
+            .collect();
+        prop_assume!(!logs.is_empty());
+        let (epoch, path) = victim.get(&logs);
+        let offset = at.index(fs::metadata(path).unwrap().len() as usize) as u64;
+        damage_log(path, offset, damage);
+
+        match KvStore::open(temp_dir.path()) {
+            Ok(mut store) => {
+                let mut recovered = HashMap::new();
+                for k in 0..KEYS {
+                    if let Some(val) = store.get(key(k)).unwrap() {
+                        recovered.insert(key(k), val);
+                    }
+                }
+                prop_assert!(history.contains(&recovered), "recovered {:?} is not a prefix", recovered);
+            }
+            Err(Error::Replay { epoch: failed, source }) => {
+                prop_assert_eq!(failed, *epoch);
+                match *source {
+                    Error::Truncated { offset: at } | Error::Checksum { offset: at, .. } => {
+                        prop_assert!(at <= offset, "error at {} after damage at {}", at, offset)
+                    }
+                    // The header comes before everything else, so any damage to it is at or after it
+                    Error::BadHeader { .. } => {}
+                    e => prop_assert!(false, "unexpected replay error: {}", e),
+                }
+            }
+            Err(e) => prop_assert!(false, "unexpected error: {}", e),
+        }
+    }
+}
@@ -0,0 +1,455 @@
+use assert_cmd::prelude::*;
+use kvs::{
+    Add, Command, Error, ErrorCode, KvStore, KvsClient, KvsServer, Permission, Request, Response,
+    Tokens,
+};
+use std::fs;
+use std::path::Path;
+use std::process::{Child, Stdio};
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Serve `store` on a local port, returning the address it's on.
+async fn serve(store: KvStore) -> String {
+    serve_with(KvsServer::new(store)).await
+}
+
+async fn serve_with(server: KvsServer) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("failed to find a free port");
+    let addr = listener.local_addr().unwrap().to_string();
+    tokio::spawn(server.serve(listener));
+    addr
+}
+
+/// Write a self-signed certificate for localhost and its key into `dir`, returning their paths.
+fn self_signed(dir: &Path) -> (String, String) {
+    let signed = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()])
+        .expect("failed to generate a certificate");
+    let (cert, key) = (dir.join("cert.pem"), dir.join("key.pem"));
+    fs::write(&cert, signed.cert.pem()).unwrap();
+    fs::write(&key, signed.key_pair.serialize_pem()).unwrap();
+    (
+        cert.to_string_lossy().into_owned(),
+        key.to_string_lossy().into_owned(),
+    )
+}
+
+fn remote_code<T: std::fmt::Debug>(result: kvs::Result<T>) -> ErrorCode {
+    match result {
+        Err(Error::Remote { code, .. }) => code,
+        other => panic!("expected a remote failure, got {:?}", other),
+    }
+}
+
+fn set(ns: &str, key: &str, val: &str) -> Command {
+    Command::Set {
+        ns: ns.to_owned(),
+        key: key.to_owned(),
+        val: val.to_owned(),
+    }
+}
+
+/// Kills a child process when dropped, so a failing test doesn't leave it running.
+struct Running(Child);
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+// Every command should be carried out, with gets and scans reading back what they did.
+#[tokio::test]
+async fn protocol_commands() -> kvs::Result<()> {
+    let addr = serve(KvStore::in_memory().with_merge_operator(Add)).await;
+    let client = KvsClient::connect(&addr).await?;
+
+    client.set("key".to_owned(), "value".to_owned()).await?;
+    client.apply(set("users", "user:1", "alice")).await?;
+    client.apply(set("users", "user:2", "bob")).await?;
+    client.apply(set("users", "admin", "carol")).await?;
+    for _ in 0..3 {
+        client
+            .apply(Command::Merge {
+                ns: "counters".to_owned(),
+                key: "hits".to_owned(),
+                operand: "2".to_owned(),
+            })
+            .await?;
+    }
+
+    assert_eq!(
+        client.get("key".to_owned()).await?,
+        Some("value".to_owned())
+    );
+    assert_eq!(client.get("user:1".to_owned()).await?, None);
+    assert_eq!(
+        client.get_in("counters", "hits".to_owned()).await?,
+        Some("6".to_owned())
+    );
+    assert_eq!(
+        client.scan("users", "user:").await?,
+        vec![
+            ("user:1".to_owned(), "alice".to_owned()),
+            ("user:2".to_owned(), "bob".to_owned()),
+        ]
+    );
+
+    client
+        .apply(Command::Rm {
+            ns: "users".to_owned(),
+            key: "user:1".to_owned(),
+        })
+        .await?;
+    assert_eq!(client.scan("users", "").await?.len(), 2);
+    client
+        .apply(Command::DropNamespace {
+            ns: "users".to_owned(),
+        })
+        .await?;
+    assert_eq!(client.scan("users", "").await?, vec![]);
+    client.remove("key".to_owned()).await?;
+    assert_eq!(client.get("key".to_owned()).await?, None);
+    Ok(())
+}
+
+// Failures should come back with the code for what went wrong.
+#[tokio::test]
+async fn protocol_error_codes() -> kvs::Result<()> {
+    let addr = serve(KvStore::in_memory().with_max_key_size(8)).await;
+    let client = KvsClient::connect(&addr).await?;
+
+    match client.remove("missing".to_owned()).await {
+        Err(Error::NotFound) => {}
+        other => panic!("expected NotFound, got {:?}", other),
+    }
+    let dropped = client
+        .apply(Command::DropNamespace {
+            ns: "missing".to_owned(),
+        })
+        .await;
+    assert!(matches!(dropped, Err(Error::NotFound)), "{:?}", dropped);
+
+    let long_key = client.set("a long key".to_owned(), "v".to_owned()).await;
+    match long_key {
+        Err(Error::Remote { code, message }) => {
+            assert_eq!(code, ErrorCode::KeyTooLarge);
+            assert!(message.contains("key"), "{}", message);
+        }
+        other => panic!("expected KeyTooLarge, got {:?}", other),
+    }
+    let merged = client
+        .request(Request::Apply {
+            command: Command::Merge {
+                ns: String::new(),
+                key: "k".to_owned(),
+                operand: "1".to_owned(),
+            },
+        })
+        .await?;
+    assert!(
+        matches!(
+            merged,
+            Response::Failed {
+                code: ErrorCode::Invalid,
+                ..
+            }
+        ),
+        "{:?}",
+        merged
+    );
+    Ok(())
+}
+
+// Requests sent together on one connection should each get their own response.
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn protocol_pipelines_requests() -> kvs::Result<()> {
+    let addr = serve(KvStore::in_memory()).await;
+    let client = KvsClient::connect(&addr).await?;
+
+    let sets: Vec<_> = (0..1000)
+        .map(|i| {
+            let client = client.clone();
+            tokio::spawn(async move { client.set(format!("key{}", i), i.to_string()).await })
+        })
+        .collect();
+    for set in sets {
+        set.await.expect("set panicked")?;
+    }
+    let gets: Vec<_> = (0..1000)
+        .map(|i| {
+            let client = client.clone();
+            tokio::spawn(async move { (i, client.get(format!("key{}", i)).await) })
+        })
+        .collect();
+    for get in gets {
+        let (i, got) = get.await.expect("get panicked");
+        assert_eq!(got?, Some(i.to_string()));
+    }
+    Ok(())
+}
+
+// Frames should be laid out as documented: length, then request id, then a BSON document.
+#[tokio::test]
+async fn protocol_framing() -> kvs::Result<()> {
+    let addr = serve(KvStore::in_memory()).await;
+    let mut stream = TcpStream::connect(&addr).await.unwrap();
+
+    let mut body = Vec::new();
+    bson::doc! { "type": "Get", "ns": "", "key": "k" }
+        .to_writer(&mut body)
+        .unwrap();
+    let mut garbage = b"not bson".to_vec();
+    let mut frames = Vec::new();
+    for (id, body) in [(42u64, &mut body), (43, &mut garbage)].iter_mut() {
+        frames.extend_from_slice(&(body.len() as u32 + 8).to_be_bytes());
+        frames.extend_from_slice(&id.to_be_bytes());
+        frames.append(body);
+    }
+    stream.write_all(&frames).await.unwrap();
+
+    let mut answered = Vec::new();
+    for _ in 0..2 {
+        let len = stream.read_u32().await.unwrap();
+        let id = stream.read_u64().await.unwrap();
+        let mut body = vec![0; len as usize - 8];
+        stream.read_exact(&mut body).await.unwrap();
+        let doc = bson::Document::from_reader(&mut body.as_slice()).unwrap();
+        answered.push((id, doc));
+    }
+    answered.sort_by_key(|(id, _)| *id);
+    assert_eq!(answered[0].0, 42);
+    assert_eq!(answered[0].1.get_str("type").unwrap(), "Value");
+    assert_eq!(answered[1].0, 43);
+    assert_eq!(answered[1].1.get_str("type").unwrap(), "Failed");
+    assert_eq!(
+        answered[1].1.get("code").and_then(|code| code.as_i32()),
+        Some(ErrorCode::Invalid as i32)
+    );
+    Ok(())
+}
+
+// Nothing should reach the store until a client authenticates, and then only what its token permits.
+#[tokio::test]
+async fn protocol_tokens() -> kvs::Result<()> {
+    let mut store = KvStore::in_memory();
+    store.set("key".to_owned(), "value".to_owned())?;
+    let tokens = Tokens::default()
+        .with_token("reader", Permission::Read)
+        .with_token("writer", Permission::ReadWrite);
+    let addr = serve_with(KvsServer::new(store).with_tokens(tokens)).await;
+
+    let client = KvsClient::connect(&addr).await?;
+    let code = remote_code(client.get("key".to_owned()).await);
+    assert_eq!(code, ErrorCode::Unauthorized);
+    let code = remote_code(client.auth("guess").await);
+    assert_eq!(code, ErrorCode::Unauthorized);
+    let code = remote_code(client.scan("", "").await);
+    assert_eq!(code, ErrorCode::Unauthorized);
+
+    client.auth("reader").await?;
+    assert_eq!(
+        client.get("key".to_owned()).await?,
+        Some("value".to_owned())
+    );
+    let code = remote_code(client.set("key".to_owned(), "changed".to_owned()).await);
+    assert_eq!(code, ErrorCode::Forbidden);
+    let code = remote_code(client.remove("key".to_owned()).await);
+    assert_eq!(code, ErrorCode::Forbidden);
+
+    // Each connection authenticates on its own
+    let writer = KvsClient::connect(&addr).await?;
+    writer.auth("writer").await?;
+    writer.set("key".to_owned(), "changed".to_owned()).await?;
+    assert_eq!(
+        client.get("key".to_owned()).await?,
+        Some("changed".to_owned())
+    );
+    Ok(())
+}
+
+// Until a client authenticates it may only send frames big enough to carry a token, and nobody may send frames
+// bigger than the largest BSON document.
+#[tokio::test]
+async fn protocol_frame_limits() -> kvs::Result<()> {
+    let tokens = Tokens::default().with_token("writer", Permission::ReadWrite);
+    let addr = serve_with(KvsServer::new(KvStore::in_memory()).with_tokens(tokens)).await;
+    let big = "v".repeat(64 * 1024);
+
+    let client = KvsClient::connect(&addr).await?;
+    match client.set("key".to_owned(), big.clone()).await {
+        Err(Error::Disconnected { .. }) => {}
+        other => panic!("expected the connection to be dropped, got {:?}", other),
+    }
+
+    let client = KvsClient::connect(&addr).await?;
+    client.auth("writer").await?;
+    client.set("key".to_owned(), big.clone()).await?;
+    assert_eq!(client.get("key".to_owned()).await?, Some(big));
+    match client
+        .set("key".to_owned(), "v".repeat(17 * 1024 * 1024))
+        .await
+    {
+        Err(e @ Error::FrameTooLarge { .. }) => assert_eq!(e.code(), ErrorCode::Invalid),
+        other => panic!("expected the frame to be refused, got {:?}", other),
+    }
+
+    // Claiming a long frame is enough to be dropped, without sending it
+    let mut stream = TcpStream::connect(&addr).await.unwrap();
+    stream.write_u32(u32::MAX).await.unwrap();
+    let mut rest = Vec::new();
+    assert_eq!(stream.read_to_end(&mut rest).await.unwrap(), 0);
+    Ok(())
+}
+
+// A TLS server should only talk to clients that trust its certificate and speak TLS.
+#[tokio::test]
+async fn protocol_tls() -> kvs::Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (cert, key) = self_signed(temp_dir.path());
+    let addr = serve_with(KvsServer::new(KvStore::in_memory()).with_tls(&cert, &key)?).await;
+
+    let client = KvsClient::connect_tls(&addr, "localhost", &cert).await?;
+    client.set("key".to_owned(), "value".to_owned()).await?;
+    assert_eq!(
+        client.get("key".to_owned()).await?,
+        Some("value".to_owned())
+    );
+
+    // The certificate is for localhost, not this name
+    let wrong_name = KvsClient::connect_tls(&addr, "example.com", &cert).await;
+    assert!(matches!(wrong_name, Err(Error::Connect { .. })));
+    // Another certificate doesn't vouch for the server's
+    let other = TempDir::new().unwrap();
+    let (other_cert, _) = self_signed(other.path());
+    let untrusted = KvsClient::connect_tls(&addr, "localhost", &other_cert).await;
+    assert!(matches!(untrusted, Err(Error::Connect { .. })));
+
+    let plain = KvsClient::connect(&addr).await?;
+    assert!(plain.get("key".to_owned()).await.is_err());
+
+    let bad_key = KvsServer::new(KvStore::in_memory()).with_tls(&cert, &cert);
+    assert!(matches!(bad_key, Err(Error::BadTls { .. })));
+    Ok(())
+}
+
+// `kvs-server --listen` should serve kvs clients from the store on disk.
+#[tokio::test]
+async fn protocol_server() -> kvs::Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("kept".to_owned(), "from before".to_owned())?;
+    drop(store);
+
+    let addr = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .to_string();
+    let _server = Running(
+        std::process::Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--listen", &addr])
+            .current_dir(&temp_dir)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("failed to start server"),
+    );
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let client = loop {
+        match KvsClient::connect(&addr).await {
+            Ok(client) => break client,
+            Err(e) if Instant::now() > deadline => panic!("server never came up: {}", e),
+            Err(_) => tokio::time::sleep(Duration::from_millis(50)).await,
+        }
+    };
+    assert_eq!(
+        client.get("kept".to_owned()).await?,
+        Some("from before".to_owned())
+    );
+    client.set("new".to_owned(), "value".to_owned()).await?;
+    assert_eq!(
+        client.get("new".to_owned()).await?,
+        Some("value".to_owned())
+    );
+    Ok(())
+}
+
+// `kvs-server` should take its certificate, key and tokens from files.
+#[tokio::test]
+async fn protocol_server_tls_tokens() -> kvs::Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (cert, key) = self_signed(temp_dir.path());
+    let tokens = temp_dir.path().join("tokens.toml");
+    fs::write(&tokens, "[tokens]\nsecret = \"read-write\"\n").unwrap();
+
+    let addr = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .to_string();
+    let _server = Running(
+        std::process::Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--listen", &addr, "--tls-cert", &cert, "--tls-key", &key])
+            .arg("--tokens")
+            .arg(&tokens)
+            .current_dir(&temp_dir)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("failed to start server"),
+    );
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let client = loop {
+        match KvsClient::connect_tls(&addr, "localhost", &cert).await {
+            Ok(client) => break client,
+            Err(e) if Instant::now() > deadline => panic!("server never came up: {}", e),
+            Err(_) => tokio::time::sleep(Duration::from_millis(50)).await,
+        }
+    };
+    let code = remote_code(client.set("key".to_owned(), "value".to_owned()).await);
+    assert_eq!(code, ErrorCode::Unauthorized);
+    client.auth("secret").await?;
+    client.set("key".to_owned(), "value".to_owned()).await?;
+    assert_eq!(
+        client.get("key".to_owned()).await?,
+        Some("value".to_owned())
+    );
+    Ok(())
+}
+
+// `kvs-server` should refuse to serve clients that other hosts can reach unless they must authenticate over TLS, or
+// it's told to trust the network.
+#[test]
+fn protocol_server_refuses_exposed_addresses() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let tokens = temp_dir.path().join("tokens.toml");
+    fs::write(&tokens, "[tokens]\nsecret = \"read-write\"\n").unwrap();
+    let refused = |args: &[&str]| {
+        std::process::Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(args)
+            .current_dir(&temp_dir)
+            .assert()
+            .code(9)
+            .stderr(predicates::str::contains("refusing to serve 0.0.0.0:0"));
+    };
+    refused(&["--resp", "0.0.0.0:0"]);
+    refused(&["--resp", "0.0.0.0:0", "--async"]);
+    refused(&["--http", "0.0.0.0:0"]);
+    refused(&["--id", "0", "--nodes", "0.0.0.0:0,127.0.0.1:1"]);
+    // Tokens are no use without TLS to keep them secret
+    refused(&[
+        "--listen",
+        "0.0.0.0:0",
+        "--tokens",
+        tokens.to_str().unwrap(),
+    ]);
+}
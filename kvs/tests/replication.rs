@@ -0,0 +1,355 @@
+extern crate env_logger;
+
+use assert_cmd::prelude::*;
+use kvs::{Add, Follower, KvStore, Result};
+use predicates::str::contains;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+const KEYS: usize = 100;
+// Small enough that the leader spans several epochs
+const MAX_LOG_SIZE: u64 = 1000;
+
+fn init() {
+    let _ = env_logger::builder().is_test(true).try_init();
+}
+
+fn key(id: usize) -> String {
+    format!("key{}", id)
+}
+
+/// Open a leader in `dir`, serving followers on a fresh local port.
+fn lead(dir: &TempDir) -> Result<(KvStore, SocketAddr)> {
+    let mut store = KvStore::open(dir.path())?.with_max_size(MAX_LOG_SIZE);
+    let listener = TcpListener::bind("127.0.0.1:0").expect("failed to listen");
+    let addr = listener.local_addr().unwrap();
+    store.serve_followers(listener);
+    Ok((store, addr))
+}
+
+/// Like `lead`, but merging by adding.
+fn lead_adding(dir: &TempDir) -> Result<(KvStore, SocketAddr)> {
+    let mut store = KvStore::open(dir.path())?
+        .with_max_size(MAX_LOG_SIZE)
+        .with_merge_operator(Add);
+    let listener = TcpListener::bind("127.0.0.1:0").expect("failed to listen");
+    let addr = listener.local_addr().unwrap();
+    store.serve_followers(listener);
+    Ok((store, addr))
+}
+
+/// Step until the follower has applied everything the leader had when it connected, returning how many steps that
+/// took.
+fn catch_up(follower: &mut Follower) -> Result<usize> {
+    let mut steps = 0;
+    while !follower.caught_up() {
+        follower.step()?;
+        steps += 1;
+    }
+    Ok(steps)
+}
+
+fn assert_same(leader: &mut KvStore, follower: &mut KvStore) -> Result<()> {
+    for k in 0..KEYS {
+        assert_eq!(follower.get(key(k))?, leader.get(key(k))?, "{}", key(k));
+    }
+    assert_eq!(follower.stats().keys, leader.stats().keys);
+    Ok(())
+}
+
+// A new follower should be sent everything the leader has, then each record as it is appended.
+#[test]
+fn lib_follower_catches_up_and_streams() -> Result<()> {
+    init();
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (mut leader, addr) = lead(&temp_dir)?;
+    for k in 0..KEYS {
+        leader.set(key(k), format!("value{}", k))?;
+    }
+
+    let mut follower = Follower::new(KvStore::in_memory())?;
+    follower.connect(addr)?;
+    catch_up(&mut follower)?;
+    assert_same(&mut leader, follower.store())?;
+
+    leader.set(key(0), "changed".to_owned())?;
+    leader.remove(key(1))?;
+    for _ in 0..2 {
+        follower.step()?;
+    }
+    assert_same(&mut leader, follower.store())?;
+    Ok(())
+}
+
+// A follower that reconnects should only be sent the records it missed.
+#[test]
+fn lib_follower_resumes_after_reconnect() -> Result<()> {
+    init();
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (mut leader, addr) = lead(&temp_dir)?;
+    for k in 0..KEYS {
+        leader.set(key(k), format!("value{}", k))?;
+    }
+    let mut follower = Follower::new(KvStore::in_memory())?;
+    follower.connect(addr)?;
+    catch_up(&mut follower)?;
+
+    // The leader hangs up when it goes away
+    drop(leader);
+    assert!(follower.step().is_err());
+
+    let (mut leader, addr) = lead(&temp_dir)?;
+    leader.set(key(2), "changed".to_owned())?;
+    leader.remove(key(3))?;
+    follower.connect(addr)?;
+    // Two records, then word that it's caught up
+    assert_eq!(catch_up(&mut follower)?, 3);
+    assert_same(&mut leader, follower.store())?;
+    Ok(())
+}
+
+// A follower restarted on the same store should pick up where it left off, rather than be sent everything again.
+#[test]
+fn lib_follower_resumes_after_restart() -> Result<()> {
+    init();
+    let leader_dir = TempDir::new().expect("unable to create temporary working directory");
+    let follower_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (mut leader, addr) = lead(&leader_dir)?;
+    for k in 0..KEYS {
+        leader.set(key(k), format!("value{}", k))?;
+    }
+    let mut follower = Follower::new(KvStore::open(follower_dir.path())?)?;
+    follower.connect(addr)?;
+    catch_up(&mut follower)?;
+    let position = follower.position();
+    assert!(position.is_some());
+    drop(follower);
+
+    leader.set(key(2), "changed".to_owned())?;
+    leader.remove(key(3))?;
+    let mut follower = Follower::new(KvStore::open(follower_dir.path())?)?;
+    assert_eq!(follower.position(), position);
+    follower.connect(addr)?;
+    // Two records, then word that it's caught up
+    assert_eq!(catch_up(&mut follower)?, 3);
+    assert_same(&mut leader, follower.store())?;
+    Ok(())
+}
+
+// Merges add up differently if applied twice, so a follower cut off part way through a stream of them, by
+// reconnecting or by restarting, must pick up with exactly the next one.
+#[test]
+fn lib_follower_applies_merges_once() -> Result<()> {
+    init();
+    let leader_dir = TempDir::new().expect("unable to create temporary working directory");
+    let follower_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (mut leader, addr) = lead_adding(&leader_dir)?;
+    let follow = || -> Result<Follower> {
+        Follower::new(KvStore::open(follower_dir.path())?.with_merge_operator(Add))
+    };
+    let mut follower = follow()?;
+    follower.connect(addr)?;
+    catch_up(&mut follower)?;
+
+    for k in 0..KEYS {
+        leader.merge("total".to_owned(), "1".to_owned())?;
+        leader.merge(key(k % 10), k.to_string())?;
+    }
+    for _ in 0..KEYS / 2 {
+        follower.step()?;
+    }
+    // Abandons whatever the leader had already sent
+    follower.connect(addr)?;
+    for _ in 0..KEYS / 2 {
+        follower.step()?;
+    }
+
+    drop(follower);
+    let mut follower = follow()?;
+    follower.connect(addr)?;
+    catch_up(&mut follower)?;
+    assert_eq!(
+        follower.store().get("total".to_owned())?,
+        Some(KEYS.to_string())
+    );
+    assert_same(&mut leader, follower.store())?;
+    Ok(())
+}
+
+// A follower whose place in the leader's logs was compacted away should start over, dropping keys the leader removed
+// in the meantime.
+#[test]
+fn lib_follower_resets_after_compaction() -> Result<()> {
+    init();
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (mut leader, addr) = lead(&temp_dir)?;
+    for k in 0..KEYS {
+        leader.set(key(k), format!("value{}", k))?;
+    }
+    let mut follower = Follower::new(KvStore::in_memory())?;
+    follower.connect(addr)?;
+    catch_up(&mut follower)?;
+    drop(leader);
+
+    let (mut leader, addr) = lead(&temp_dir)?;
+    leader.remove(key(4))?;
+    leader.compact()?;
+    follower.connect(addr)?;
+    catch_up(&mut follower)?;
+    assert_eq!(follower.store().get(key(4))?, None);
+    assert_same(&mut leader, follower.store())?;
+    Ok(())
+}
+
+/// Kills a child process when dropped, so a failing test doesn't leave it running.
+struct Running(Child);
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn free_addr() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("failed to find a free port");
+    listener.local_addr().unwrap()
+}
+
+fn spawn_leader(dir: &TempDir, addr: SocketAddr) -> Running {
+    let child = Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["leader", "--listen", &addr.to_string()])
+        .current_dir(dir)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()
+        .expect("failed to start leader");
+    Running(child)
+}
+
+fn send(leader: &mut Running, commands: &str) {
+    let stdin = leader.0.stdin.as_mut().unwrap();
+    stdin.write_all(commands.as_bytes()).unwrap();
+    stdin.flush().unwrap();
+}
+
+/// Read a copy of a live store, so that the process using it can carry on undisturbed.
+fn snapshot(dir: &Path) -> Option<HashMap<String, String>> {
+    let copy = TempDir::new().expect("unable to create temporary working directory");
+    for entry in fs::read_dir(dir).ok()? {
+        let path = entry.ok()?.path();
+        fs::copy(&path, copy.path().join(path.file_name()?)).ok()?;
+    }
+    let mut store = KvStore::open(copy.path()).ok()?;
+    let mut contents = HashMap::new();
+    for k in 0..KEYS {
+        if let Some(val) = store.get(key(k)).ok()? {
+            contents.insert(key(k), val);
+        }
+    }
+    Some(contents)
+}
+
+fn wait_for(dir: &Path, expected: &HashMap<String, String>) {
+    let deadline = Instant::now() + Duration::from_secs(30);
+    while snapshot(dir).as_ref() != Some(expected) {
+        assert!(Instant::now() < deadline, "follower never caught up");
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+// `kvs follow` should keep a store in sync with a `kvs leader` in another process, including after the leader
+// restarts.
+#[test]
+fn cli_leader_and_follower() {
+    let leader_dir = TempDir::new().expect("unable to create temporary working directory");
+    let follower_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = free_addr();
+
+    let mut leader = spawn_leader(&leader_dir, addr);
+    let mut expected = HashMap::new();
+    let mut commands = String::new();
+    for k in 0..KEYS {
+        commands.push_str(&format!("set {} value {}\n", key(k), k));
+        expected.insert(key(k), format!("value {}", k));
+    }
+    send(&mut leader, &commands);
+
+    let follower = Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["follow", &addr.to_string()])
+        .current_dir(&follower_dir)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("failed to start follower");
+    let follower = Running(follower);
+    wait_for(follower_dir.path(), &expected);
+
+    send(&mut leader, "set key0 changed\nrm key1\n");
+    expected.insert(key(0), "changed".to_owned());
+    expected.remove(&key(1));
+    wait_for(follower_dir.path(), &expected);
+
+    // The follower reconnects once the leader is back
+    drop(leader);
+    let mut leader = spawn_leader(&leader_dir, addr);
+    send(&mut leader, "set key2 restarted\n");
+    expected.insert(key(2), "restarted".to_owned());
+    wait_for(follower_dir.path(), &expected);
+
+    drop(follower);
+    assert_eq!(snapshot(follower_dir.path()), Some(expected));
+}
+
+// `kvs leader` should keep its stdout for values, reporting commands it can't carry out on stderr.
+#[test]
+fn cli_leader_reports_errors_on_stderr() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let child = Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["leader", "--listen", &free_addr().to_string()])
+        .current_dir(&temp_dir)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("failed to start leader");
+    let mut leader = Running(child);
+    let mut stdout = BufReader::new(leader.0.stdout.take().unwrap());
+    let mut stderr = BufReader::new(leader.0.stderr.take().unwrap());
+    send(&mut leader, "bogus\nget key1\n");
+
+    let mut line = String::new();
+    stderr.read_line(&mut line).unwrap();
+    assert_eq!(line, "unknown command: bogus\n");
+    line.clear();
+    stdout.read_line(&mut line).unwrap();
+    assert_eq!(line, "Key not found\n");
+}
+
+// `kvs leader` should report an address it can't listen on like any other network failure, rather than panic.
+#[test]
+fn cli_leader_address_in_use() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let taken = TcpListener::bind("127.0.0.1:0").expect("failed to listen");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args([
+            "leader",
+            "--listen",
+            &taken.local_addr().unwrap().to_string(),
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(8)
+        .stderr(contains("failed to listen on"));
+}
@@ -0,0 +1,381 @@
+use assert_cmd::prelude::*;
+use kvs::{AsyncClient, AsyncRespServer, Error, KvStore, Permission, RespServer, Tokens};
+use redis::{Commands, Connection, RedisResult, Value};
+use std::collections::HashSet;
+use std::fs;
+use std::net::TcpListener;
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+/// Serve an in-memory store to Redis clients on a local port, returning a connection to it.
+fn serve() -> Connection {
+    connect(&serve_with(RespServer::new(KvStore::in_memory())))
+}
+
+/// Run `server` on a local port, returning the address it's on.
+fn serve_with(server: RespServer) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("failed to find a free port");
+    let addr = listener.local_addr().unwrap().to_string();
+    thread::spawn(move || server.serve(listener));
+    addr
+}
+
+/// Serve an in-memory store from the tokio server on a local port, returning the address it's on.
+fn serve_async() -> String {
+    serve_async_with(AsyncRespServer::new(KvStore::in_memory()))
+}
+
+fn serve_async_with(server: AsyncRespServer) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("failed to find a free port");
+    let addr = listener.local_addr().unwrap().to_string();
+    thread::spawn(move || {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            listener.set_nonblocking(true).unwrap();
+            let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+            server.serve(listener).await
+        })
+    });
+    addr
+}
+
+fn connect(addr: &str) -> Connection {
+    let client = redis::Client::open(format!("redis://{}/", addr)).unwrap();
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        match client.get_connection() {
+            Ok(con) => return con,
+            Err(e) if Instant::now() > deadline => panic!("failed to connect to {}: {}", addr, e),
+            Err(_) => thread::sleep(Duration::from_millis(50)),
+        }
+    }
+}
+
+/// Kills a child process when dropped, so a failing test doesn't leave it running.
+struct Running(Child);
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+// The basic commands should behave as they do against Redis.
+#[test]
+fn resp_commands() -> RedisResult<()> {
+    let mut con = serve();
+    assert_eq!(redis::cmd("PING").query::<String>(&mut con)?, "PONG");
+    assert_eq!(
+        redis::cmd("PING").arg("hi").query::<String>(&mut con)?,
+        "hi"
+    );
+
+    con.set::<_, _, ()>("user:1", "alice")?;
+    con.set::<_, _, ()>("user:2", "bob")?;
+    con.set::<_, _, ()>("order:1", "book")?;
+    assert_eq!(
+        con.get::<_, Option<String>>("user:1")?,
+        Some("alice".to_owned())
+    );
+    assert_eq!(con.get::<_, Option<String>>("user:3")?, None);
+    assert_eq!(con.exists::<_, usize>(&["user:1", "user:3", "user:1"])?, 2);
+
+    let mut keys: Vec<String> = con.keys("user:*")?;
+    keys.sort();
+    assert_eq!(keys, ["user:1", "user:2"]);
+
+    assert_eq!(con.del::<_, usize>(&["user:1", "user:3"])?, 1);
+    assert_eq!(con.get::<_, Option<String>>("user:1")?, None);
+
+    let info: String = redis::cmd("INFO").arg("keyspace").query(&mut con)?;
+    assert!(info.contains("db0:keys=2,expires=0"), "{}", info);
+
+    let unknown = redis::cmd("FLUSHALL").query::<Value>(&mut con);
+    assert!(unknown.unwrap_err().to_string().contains("unknown command"));
+    let arity = redis::cmd("GET").query::<Value>(&mut con);
+    assert!(arity
+        .unwrap_err()
+        .to_string()
+        .contains("wrong number of arguments"));
+    Ok(())
+}
+
+// `SET` should honour NX and XX, and keys set with EX or PX should vanish once their time is up.
+#[test]
+fn resp_set_options() -> RedisResult<()> {
+    let mut con = serve();
+    let set = |con: &mut Connection, args: &[&str]| -> RedisResult<Option<String>> {
+        redis::cmd("SET").arg(args).query(con)
+    };
+    assert_eq!(set(&mut con, &["a", "1", "XX"])?, None);
+    assert_eq!(set(&mut con, &["a", "1", "NX"])?, Some("OK".to_owned()));
+    assert_eq!(set(&mut con, &["a", "2", "NX"])?, None);
+    assert_eq!(set(&mut con, &["a", "3", "XX"])?, Some("OK".to_owned()));
+    assert_eq!(con.get::<_, String>("a")?, "3");
+    assert!(set(&mut con, &["a", "4", "NX", "XX"]).is_err());
+    assert!(set(&mut con, &["a", "4", "EX", "0"]).is_err());
+
+    set(&mut con, &["short", "1", "PX", "100"])?;
+    set(&mut con, &["long", "1", "EX", "100"])?;
+    set(&mut con, &["kept", "1", "PX", "100"])?;
+    set(&mut con, &["kept", "2", "KEEPTTL"])?;
+    set(&mut con, &["cleared", "1", "PX", "100"])?;
+    set(&mut con, &["cleared", "2"])?;
+    thread::sleep(Duration::from_millis(200));
+
+    assert_eq!(con.get::<_, Option<String>>("short")?, None);
+    assert_eq!(con.get::<_, Option<String>>("kept")?, None);
+    assert_eq!(con.get::<_, Option<String>>("long")?, Some("1".to_owned()));
+    assert_eq!(
+        con.get::<_, Option<String>>("cleared")?,
+        Some("2".to_owned())
+    );
+    let mut keys: Vec<String> = con.keys("*")?;
+    keys.sort();
+    assert_eq!(keys, ["a", "cleared", "long"]);
+    // NX sees through keys that have expired but haven't been cleared out yet
+    set(&mut con, &["expiring", "1", "PX", "1"])?;
+    thread::sleep(Duration::from_millis(10));
+    assert_eq!(
+        set(&mut con, &["expiring", "2", "NX"])?,
+        Some("OK".to_owned())
+    );
+    Ok(())
+}
+
+// A full `SCAN` should return every key that was there throughout exactly once, however the store changed meanwhile.
+#[test]
+fn resp_scan() -> RedisResult<()> {
+    let mut con = serve();
+    for i in 0..100 {
+        con.set::<_, _, ()>(format!("key{}", i), i)?;
+    }
+
+    let mut seen = Vec::new();
+    let mut cursor = 0;
+    let mut rounds = 0;
+    loop {
+        let (next, keys): (u64, Vec<String>) = redis::cmd("SCAN")
+            .arg(cursor)
+            .arg("COUNT")
+            .arg(7)
+            .query(&mut con)?;
+        seen.extend(keys);
+        // Churn the keys the scan hasn't got to yet, and some it has
+        rounds += 1;
+        con.set::<_, _, ()>(format!("new{}", rounds), rounds)?;
+        con.del::<_, ()>(format!("key{}", 90 + rounds % 10))?;
+        if next == 0 {
+            break;
+        }
+        cursor = next;
+    }
+    let survivors: HashSet<String> = (0..90).map(|i| format!("key{}", i)).collect();
+    let unique: HashSet<&String> = seen.iter().collect();
+    assert_eq!(unique.len(), seen.len(), "some keys were returned twice");
+    assert!(survivors.iter().all(|key| unique.contains(key)));
+
+    let matched: Vec<String> = con.scan_match("key1*")?.collect();
+    let mut matched: Vec<&str> = matched.iter().map(String::as_str).collect();
+    matched.sort_unstable();
+    assert_eq!(
+        matched,
+        [
+            "key1", "key10", "key11", "key12", "key13", "key14", "key15", "key16", "key17",
+            "key18", "key19"
+        ]
+    );
+    Ok(())
+}
+
+// `kvs-server --resp` should keep what Redis clients write, expiry times included, across restarts, whichever
+// server it runs, and require the tokens it's given.
+#[test]
+fn resp_server_keeps_keys() -> RedisResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = TcpListener::bind("127.0.0.1:0")
+        .expect("failed to find a free port")
+        .local_addr()
+        .unwrap()
+        .to_string();
+    let start = |extra: &[&str]| {
+        Running(
+            Command::cargo_bin("kvs-server")
+                .unwrap()
+                .args(["--resp", &addr])
+                .args(extra)
+                .current_dir(&temp_dir)
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .spawn()
+                .expect("failed to start server"),
+        )
+    };
+
+    let server = start(&[]);
+    let mut con = connect(&addr);
+    con.set::<_, _, ()>("key1", "value1")?;
+    redis::cmd("SET")
+        .arg(&["key2", "value2", "EX", "1"])
+        .query::<()>(&mut con)?;
+    drop(con);
+    drop(server);
+    thread::sleep(Duration::from_millis(1100));
+
+    let server = start(&["--async"]);
+    let mut con = connect(&addr);
+    assert_eq!(
+        con.get::<_, Option<String>>("key1")?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(con.get::<_, Option<String>>("key2")?, None);
+    drop(con);
+    drop(server);
+
+    let tokens = temp_dir.path().join("tokens.toml");
+    fs::write(&tokens, "[tokens]\nsecret = \"read-write\"\n").unwrap();
+    let _server = start(&["--tokens", tokens.to_str().unwrap()]);
+    let mut con = connect(&addr);
+    let get = || redis::cmd("GET").arg("key1").clone();
+    assert!(refusal(get().query(&mut con)).contains("NOAUTH"));
+    redis::cmd("AUTH").arg("secret").query::<()>(&mut con)?;
+    assert_eq!(
+        get().query::<Option<String>>(&mut con)?,
+        Some("value1".to_owned())
+    );
+    Ok(())
+}
+
+// The tokio server should answer commands just like the threaded one, pipelined ones included.
+#[test]
+fn async_resp_commands() -> RedisResult<()> {
+    let mut con = connect(&serve_async());
+    assert_eq!(redis::cmd("PING").query::<String>(&mut con)?, "PONG");
+    let (set, got, removed, gone): (String, String, usize, Option<String>) = redis::pipe()
+        .set("key", "value")
+        .get("key")
+        .del("key")
+        .get("key")
+        .query(&mut con)?;
+    assert_eq!(
+        (set.as_str(), got.as_str(), removed, gone),
+        ("OK", "value", 1, None)
+    );
+
+    redis::cmd("SET")
+        .arg(&["expiring", "1", "PX", "50"])
+        .query::<()>(&mut con)?;
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(con.get::<_, Option<String>>("expiring")?, None);
+    let unknown = redis::cmd("FLUSHALL").query::<Value>(&mut con);
+    assert!(unknown.unwrap_err().to_string().contains("unknown command"));
+    Ok(())
+}
+
+fn tokens() -> Tokens {
+    Tokens::default()
+        .with_token("reader", Permission::Read)
+        .with_token("writer", Permission::ReadWrite)
+}
+
+fn refusal(result: RedisResult<Value>) -> String {
+    result.expect_err("should be refused").to_string()
+}
+
+// Nothing should reach the store until a client authenticates, and then only what its token permits, from either
+// server.
+#[test]
+fn resp_tokens() -> RedisResult<()> {
+    let addrs = [
+        serve_with(RespServer::new(KvStore::in_memory()).with_tokens(tokens())),
+        serve_async_with(AsyncRespServer::new(KvStore::in_memory()).with_tokens(tokens())),
+    ];
+    for addr in &addrs {
+        let mut con = connect(addr);
+        let get = || redis::cmd("GET").arg("key").clone();
+        assert!(refusal(get().query(&mut con)).contains("NOAUTH"));
+        assert!(refusal(redis::cmd("PING").query(&mut con)).contains("NOAUTH"));
+        assert!(refusal(redis::cmd("AUTH").arg("guess").query(&mut con)).contains("WRONGPASS"));
+
+        redis::cmd("AUTH").arg("reader").query::<()>(&mut con)?;
+        assert_eq!(get().query::<Option<String>>(&mut con)?, None);
+        let set = redis::cmd("SET").arg(&["key", "value"]).query(&mut con);
+        assert!(refusal(set).contains("NOPERM"));
+
+        // Big arguments only once authenticated
+        let big = "v".repeat(64 * 1024);
+        let mut con = connect(addr);
+        let set = redis::cmd("SET").arg("key").arg(&big).query(&mut con);
+        assert!(refusal(set).contains("Protocol error"));
+        let mut con = connect(addr);
+        redis::cmd("AUTH")
+            .arg(&["default", "writer"])
+            .query::<()>(&mut con)?;
+        con.set::<_, _, ()>("key", &big)?;
+        assert_eq!(con.get::<_, Option<String>>("key")?, Some(big));
+    }
+    Ok(())
+}
+
+// Many clients, most of them idle, should be able to hold connections to the tokio server at once.
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn async_client_many_connections() -> kvs::Result<()> {
+    let addr = serve_async();
+    let mut idle = Vec::new();
+    for _ in 0..500 {
+        idle.push(AsyncClient::connect(&addr).await?);
+    }
+
+    let tasks: Vec<_> = (0..50)
+        .map(|id| {
+            let addr = addr.clone();
+            tokio::spawn(async move {
+                let mut client = AsyncClient::connect(&addr).await?;
+                for i in 0..10 {
+                    let key = format!("client{}-key{}", id, i);
+                    client.set(key.clone(), i.to_string()).await?;
+                    assert_eq!(client.get(key.clone()).await?, Some(i.to_string()));
+                }
+                client.remove(format!("client{}-key0", id)).await
+            })
+        })
+        .collect();
+    for task in tasks {
+        task.await.expect("client panicked")?;
+    }
+
+    // Every idle connection is still being served
+    for (id, client) in idle.iter_mut().enumerate().step_by(50) {
+        assert_eq!(client.get(format!("client{}-key0", id % 50)).await?, None);
+        assert_eq!(
+            client.get(format!("client{}-key9", id % 50)).await?,
+            Some("9".to_owned())
+        );
+    }
+    match idle[0].remove("client0-key0".to_owned()).await {
+        Err(Error::NotFound) => {}
+        other => panic!("expected NotFound, got {:?}", other),
+    }
+    Ok(())
+}
+
+// The async client should work against the threaded server too, since both speak RESP.
+#[tokio::test]
+async fn async_client_threaded_server() -> kvs::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("failed to find a free port");
+    let addr = listener.local_addr().unwrap().to_string();
+    thread::spawn(move || RespServer::new(KvStore::in_memory()).serve(listener));
+
+    let mut client = AsyncClient::connect(&addr).await?;
+    client.set("key".to_owned(), "value".to_owned()).await?;
+    assert_eq!(
+        client.get("key".to_owned()).await?,
+        Some("value".to_owned())
+    );
+    client.remove("key".to_owned()).await?;
+    assert_eq!(client.get("key".to_owned()).await?, None);
+    Ok(())
+}
@@ -1,9 +1,10 @@
 extern crate env_logger;
 
 use assert_cmd::prelude::*;
-use kvs::{KvStore, Result};
+use kvs::{Change, Config, Durability, KvStore, Position, Result};
 use predicates::ord::eq;
 use predicates::str::{contains, is_empty, PredicateStrExt};
+use std::fs;
 use std::process::Command;
 use tempfile::TempDir;
 use walkdir::WalkDir;
@@ -22,7 +23,7 @@ fn cli_no_args() {
 fn cli_version() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["-V"])
+        .args(["-V"])
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
 }
@@ -33,24 +34,25 @@ fn cli_get_non_existent_key() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key1"])
+        .args(["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout(eq("Key not found").trim());
 }
 
-// `kvs rm <KEY>` should print "Key not found" for an empty database and exit with non-zero code.
+// `kvs rm <KEY>` should report "Key not found" on stderr for an empty database and exit with code 2.
 #[test]
 fn cli_rm_non_existent_key() {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "key1"])
+        .args(["rm", "key1"])
         .current_dir(&temp_dir)
         .assert()
-        .failure()
-        .stdout(eq("Key not found").trim());
+        .code(2)
+        .stdout(is_empty())
+        .stderr(eq("Key not found").trim());
 }
 
 // `kvs set <KEY> <VALUE>` should print nothing and exit with zero.
@@ -59,7 +61,7 @@ fn cli_set() {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "key1", "value1"])
+        .args(["set", "key1", "value1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -77,7 +79,7 @@ fn cli_get_stored() -> Result<()> {
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key1"])
+        .args(["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -85,7 +87,7 @@ fn cli_get_stored() -> Result<()> {
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key2"])
+        .args(["get", "key2"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -105,7 +107,7 @@ fn cli_rm_stored() -> Result<()> {
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "key1"])
+        .args(["rm", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -113,7 +115,7 @@ fn cli_rm_stored() -> Result<()> {
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key1"])
+        .args(["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -126,13 +128,13 @@ fn cli_rm_stored() -> Result<()> {
 fn cli_invalid_get() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get"])
+        .args(["get"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "extra", "field"])
+        .args(["get", "extra", "field"])
         .assert()
         .failure();
 }
@@ -141,19 +143,19 @@ fn cli_invalid_get() {
 fn cli_invalid_set() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set"])
+        .args(["set"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "missing_field"])
+        .args(["set", "missing_field"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "extra", "extra", "field"])
+        .args(["set", "extra", "extra", "field"])
         .assert()
         .failure();
 }
@@ -162,13 +164,13 @@ fn cli_invalid_set() {
 fn cli_invalid_rm() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm"])
+        .args(["rm"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "extra", "field"])
+        .args(["rm", "extra", "field"])
         .assert()
         .failure();
 }
@@ -177,7 +179,7 @@ fn cli_invalid_rm() {
 fn cli_invalid_subcommand() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["unknown", "subcommand"])
+        .args(["unknown", "subcommand"])
         .assert()
         .failure();
 }
@@ -310,3 +312,1159 @@ fn lib_compaction() -> Result<()> {
 
     panic!("No compaction detected");
 }
+
+// Values in sealed epochs should read the same whether or not they are memory-mapped.
+#[test]
+fn lib_sealed_epoch_reads() -> Result<()> {
+    init();
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?.with_max_size(1000);
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    drop(store);
+
+    for &mmap in &[true, false] {
+        let mut store = KvStore::open(temp_dir.path())?.with_mmap(mmap);
+        for key_id in 0..100 {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some(format!("value{}", key_id))
+            );
+        }
+    }
+    Ok(())
+}
+
+// The value cache should serve repeated reads and forget removed keys.
+#[test]
+fn lib_cache_hits_and_misses() -> Result<()> {
+    init();
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?.with_cache_capacity(1024);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.stats().cache_hits, 1);
+    assert_eq!(store.stats().cache_misses, 0);
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?.with_cache_capacity(1024);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    let stats = store.stats();
+    assert_eq!((stats.cache_hits, stats.cache_misses), (1, 1));
+    assert_eq!(stats.cache_size, "key1value1".len());
+
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.stats().cache_size, 0);
+    assert_eq!(store.stats().keys, 0);
+    Ok(())
+}
+
+// Cached values should stay correct across compaction, even when the cache is too small to hold every key.
+#[test]
+fn lib_cache_compaction() -> Result<()> {
+    init();
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?
+        .with_max_size(1000)
+        .with_cache_capacity(256);
+
+    for iter in 0..20 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+        for key_id in 0..100 {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some(format!("{}", iter))
+            );
+        }
+    }
+    assert!(store.stats().cache_size <= 256);
+    Ok(())
+}
+
+// An in-memory store should behave just like one on disk.
+#[test]
+fn lib_in_memory_store() -> Result<()> {
+    init();
+    let mut store = KvStore::in_memory();
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    store.remove("key2".to_owned())?;
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert!(matches!(
+        store.remove("key2".to_owned()),
+        Err(kvs::Error::NotFound)
+    ));
+    assert_eq!(store.stats().keys, 1);
+    Ok(())
+}
+
+// In-memory stores rotate epochs and compact like stores on disk do.
+#[test]
+fn lib_in_memory_compaction() -> Result<()> {
+    init();
+    let mut store = KvStore::in_memory().with_max_size(1000);
+    for iter in 0..20 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    assert!(store.stats().epoch > 0);
+
+    store.compact()?;
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("19".to_owned()));
+    }
+    assert_eq!(store.stats().keys, 100);
+    Ok(())
+}
+
+// Watchers should hear about every set and remove under their prefix, in order, and nothing else.
+#[test]
+fn lib_watch() -> Result<()> {
+    init();
+    let mut store = KvStore::in_memory();
+    let users = store.watch("user/");
+    let everything = store.watch("");
+    let dropped = store.watch("user/");
+    drop(dropped);
+
+    store.set("user/1".to_owned(), "ann".to_owned())?;
+    store.set("group/1".to_owned(), "admins".to_owned())?;
+    store.set("user/1".to_owned(), "bob".to_owned())?;
+    store.remove("user/1".to_owned())?;
+    assert!(store.remove("user/1".to_owned()).is_err());
+    store.compact()?;
+
+    let change = |key: &str, val: Option<&str>| Change {
+        key: key.to_owned(),
+        val: val.map(str::to_owned),
+    };
+    assert_eq!(
+        users.try_iter().collect::<Vec<_>>(),
+        vec![
+            change("user/1", Some("ann")),
+            change("user/1", Some("bob")),
+            change("user/1", None),
+        ]
+    );
+    assert_eq!(everything.try_iter().count(), 4);
+    Ok(())
+}
+
+/// Read every change after `after`.
+fn changes(store: &KvStore, after: Option<Position>) -> Result<Vec<(Position, kvs::Command)>> {
+    store.changes_since(after)?.collect()
+}
+
+fn describe(changes: &[(Position, kvs::Command)]) -> Vec<String> {
+    changes
+        .iter()
+        .map(|(_, cmd)| match cmd {
+            kvs::Command::Set { key, val, .. } => format!("set {} {}", key, val),
+            kvs::Command::Rm { key, .. } => format!("rm {}", key),
+            kvs::Command::DropNamespace { ns } => format!("drop {}", ns),
+            kvs::Command::Merge { key, operand, .. } => format!("merge {} {}", key, operand),
+        })
+        .collect()
+}
+
+// Changes should be read back in order, and resuming from any of them should read the rest.
+#[test]
+fn lib_changes_since() -> Result<()> {
+    init();
+    let mut store = KvStore::in_memory().with_max_size(100);
+    let mut expected = Vec::new();
+    for id in 0..20 {
+        store.set(format!("key{}", id), format!("value{}", id))?;
+        expected.push(format!("set key{} value{}", id, id));
+        if id % 3 == 0 {
+            store.remove(format!("key{}", id))?;
+            expected.push(format!("rm key{}", id));
+        }
+    }
+    assert!(store.stats().epoch > 0);
+
+    let all = changes(&store, None)?;
+    assert_eq!(describe(&all), expected);
+    assert!(all.windows(2).all(|pair| pair[0].0 < pair[1].0));
+    assert_eq!(
+        store.last_position(),
+        all.last().map(|(position, _)| *position)
+    );
+    for (i, (position, _)) in all.iter().enumerate() {
+        assert_eq!(
+            describe(&changes(&store, Some(*position))?),
+            expected[i + 1..]
+        );
+    }
+    Ok(())
+}
+
+// With a changelog, readers should be able to resume from before a compaction without seeing the values it copied.
+#[test]
+fn lib_changes_survive_compaction() -> Result<()> {
+    init();
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?
+        .with_max_size(100)
+        .with_changelog_retention(100);
+    for id in 0..10 {
+        store.set(format!("key{}", id), "old".to_owned())?;
+    }
+    let seen = store.last_position();
+    store.set("key0".to_owned(), "new".to_owned())?;
+    store.remove("key1".to_owned())?;
+    store.compact()?;
+    store.set("key2".to_owned(), "newer".to_owned())?;
+    store.compact()?;
+    store.remove("key3".to_owned())?;
+
+    let expected = vec!["set key0 new", "rm key1", "set key2 newer", "rm key3"];
+    assert_eq!(describe(&changes(&store, seen)?), expected);
+    assert_eq!(changes(&store, None)?.len(), 14);
+
+    // The changelog is kept on disk
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(describe(&changes(&store, seen)?), expected);
+    Ok(())
+}
+
+// Without a changelog, changes from before a compaction are gone, but those after it can still be read.
+#[test]
+fn lib_changes_expire_after_compaction() -> Result<()> {
+    init();
+    let mut store = KvStore::in_memory().with_max_size(100);
+    for id in 0..10 {
+        store.set(format!("key{}", id), "old".to_owned())?;
+    }
+    let seen = store.last_position();
+    store.compact()?;
+    assert!(matches!(
+        store.changes_since(seen),
+        Err(kvs::Error::ChangesExpired { .. })
+    ));
+
+    let compacted = store.last_position();
+    store.set("key0".to_owned(), "new".to_owned())?;
+    assert_eq!(describe(&changes(&store, compacted)?), vec!["set key0 new"]);
+    Ok(())
+}
+
+// Reading an earlier record back out of the log being written to shouldn't change where the store thinks it ends.
+#[test]
+fn lib_reads_leave_the_current_log_alone() -> Result<()> {
+    init();
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let disk_bytes = store.stats().disk_bytes;
+
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.stats().disk_bytes, disk_bytes);
+    assert_eq!(store.changes_since(None)?.count(), 2);
+    Ok(())
+}
+
+// Keys in different namespaces shouldn't see each other, and the default namespace is the store itself.
+#[test]
+fn lib_namespaces_are_separate() -> Result<()> {
+    init();
+    let mut store = KvStore::in_memory();
+    store.set("key1".to_owned(), "default".to_owned())?;
+    store
+        .namespace("users")
+        .set("key1".to_owned(), "users".to_owned())?;
+    store
+        .namespace("groups")
+        .set("key2".to_owned(), "groups".to_owned())?;
+
+    assert_eq!(store.get("key1".to_owned())?, Some("default".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    let mut users = store.namespace("users");
+    assert_eq!(users.name(), "users");
+    assert_eq!(users.get("key1".to_owned())?, Some("users".to_owned()));
+    assert_eq!(users.get("key2".to_owned())?, None);
+    assert!(matches!(
+        users.remove("key2".to_owned()),
+        Err(kvs::Error::NotFound)
+    ));
+    assert_eq!(store.namespaces(), vec!["groups", "users"]);
+
+    // A namespace is gone once its last key is
+    store.namespace("groups").remove("key2".to_owned())?;
+    assert_eq!(store.namespaces(), vec!["users"]);
+    Ok(())
+}
+
+// Dropping a namespace should remove all of its keys, and stay dropped across compaction and reopening.
+#[test]
+fn lib_drop_namespace() -> Result<()> {
+    init();
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let removed = store.namespace("users").watch("");
+    for id in 0..10 {
+        let mut users = store.namespace("users");
+        users.set(format!("key{}", id), format!("value{}", id))?;
+        let mut groups = store.namespace("groups");
+        groups.set(format!("key{}", id), format!("value{}", id))?;
+    }
+    store.drop_namespace("users")?;
+    assert!(matches!(
+        store.drop_namespace("users"),
+        Err(kvs::Error::NoNamespace { .. })
+    ));
+    assert_eq!(store.namespaces(), vec!["groups"]);
+    assert_eq!(store.namespace("users").get("key0".to_owned())?, None);
+    assert_eq!(
+        removed
+            .try_iter()
+            .filter(|change| change.val.is_none())
+            .count(),
+        10
+    );
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.namespaces(), vec!["groups"]);
+    store.compact()?;
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.namespaces(), vec!["groups"]);
+    assert!(store.namespace("users").keys().is_empty());
+    assert_eq!(store.namespace("groups").keys().len(), 10);
+    Ok(())
+}
+
+// Exporting a namespace should read out exactly its keys, in order.
+#[test]
+fn lib_export_namespace() -> Result<()> {
+    init();
+    let mut store = KvStore::in_memory().with_max_size(100);
+    for id in (0..5).rev() {
+        store.set(format!("key{}", id), "default".to_owned())?;
+        store
+            .namespace("users")
+            .set(format!("key{}", id), format!("value{}", id))?;
+    }
+    store.namespace("users").remove("key3".to_owned())?;
+
+    let expected: Vec<(String, String)> = [0, 1, 2, 4]
+        .iter()
+        .map(|id| (format!("key{}", id), format!("value{}", id)))
+        .collect();
+    assert_eq!(store.namespace("users").export()?, expected);
+    assert!(store.namespace("nobody").export()?.is_empty());
+    Ok(())
+}
+
+/// Write a log the way stores did before logs had a header: bare BSON documents, with no checksums or seal.
+fn write_bare_log(dir: &TempDir, epoch: u64, docs: &[bson::Document]) {
+    let mut log = Vec::new();
+    for doc in docs {
+        doc.to_writer(&mut log).unwrap();
+    }
+    fs::write(dir.path().join(epoch.to_string()), log).unwrap();
+}
+
+// Logs written before there were namespaces should still open, with everything in the default namespace.
+#[test]
+fn lib_reads_logs_without_namespaces() -> Result<()> {
+    init();
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    write_bare_log(
+        &temp_dir,
+        0,
+        &[
+            bson::doc! { "Set": { "key": "key1", "val": "value1" } },
+            bson::doc! { "Set": { "key": "key2", "val": "value2" } },
+            bson::doc! { "Rm": "key1" },
+        ],
+    );
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert!(store.namespaces().is_empty());
+    Ok(())
+}
+
+// A store written before logs had a header should open, take new writes in the current format and compact.
+#[test]
+fn lib_opens_stores_without_log_headers() -> Result<()> {
+    init();
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    write_bare_log(
+        &temp_dir,
+        0,
+        &[
+            bson::doc! { "Set": { "key": "key1", "val": "value1" } },
+            bson::doc! { "Set": { "key": "key2", "val": "value2" } },
+        ],
+    );
+    write_bare_log(
+        &temp_dir,
+        1,
+        &[
+            bson::doc! { "Rm": "key1" },
+            bson::doc! { "Set": { "key": "key3", "val": "value3" } },
+        ],
+    );
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    let report = store.verify()?;
+    assert!(report.is_ok(), "{:?}", report.problems);
+    drop(store);
+
+    // The old logs are left as they were, with new writes going to a log of their own
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        fs::read(temp_dir.path().join("2")).unwrap()[..4],
+        [0xff, b'k', b'v', b's']
+    );
+    for (key, val) in &[("key2", "value2"), ("key3", "value3"), ("key4", "value4")] {
+        assert_eq!(store.get(key.to_string())?, Some(val.to_string()));
+    }
+
+    store.compact()?;
+    assert!(!temp_dir.path().join("0").exists());
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// A snapshot should keep reading values as they were when it was taken, whatever is written afterwards.
+#[test]
+fn lib_snapshot_read() -> Result<()> {
+    init();
+    let mut store = KvStore::in_memory().with_max_size(100);
+    for id in 0..10 {
+        store.set(format!("key{}", id), format!("value{}", id))?;
+    }
+    let mut snapshot = store.snapshot_read();
+    assert_eq!(snapshot.position(), store.last_position());
+
+    store.set("key0".to_owned(), "new".to_owned())?;
+    store.remove("key1".to_owned())?;
+    store.set("key10".to_owned(), "value10".to_owned())?;
+    assert_eq!(snapshot.get("key0".to_owned())?, Some("value0".to_owned()));
+    assert_eq!(snapshot.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(snapshot.get("key10".to_owned())?, None);
+
+    let expected: Vec<(String, String)> = (0..10)
+        .map(|id| (format!("key{}", id), format!("value{}", id)))
+        .collect();
+    assert_eq!(snapshot.scan("key")?, expected);
+    assert_eq!(
+        snapshot.scan("key1")?,
+        vec![("key1".to_owned(), "value1".to_owned())]
+    );
+    Ok(())
+}
+
+// Compaction should keep the logs open snapshots read from, and remove them once the snapshots are gone.
+#[test]
+fn lib_snapshot_survives_compaction() -> Result<()> {
+    init();
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?.with_max_size(100);
+    for id in 0..10 {
+        store.set(format!("key{}", id), format!("value{}", id))?;
+    }
+    let mut snapshot = store.snapshot_read();
+    for id in 0..10 {
+        store.set(format!("key{}", id), "new".to_owned())?;
+    }
+    store.compact()?;
+    store.compact()?;
+    for id in 0..10 {
+        assert_eq!(
+            snapshot.get(format!("key{}", id))?,
+            Some(format!("value{}", id))
+        );
+        assert_eq!(store.get(format!("key{}", id))?, Some("new".to_owned()));
+    }
+
+    // The kept logs still replay to the latest values
+    let logs = |dir: &TempDir| fs::read_dir(dir.path()).unwrap().count();
+    let pinned = logs(&temp_dir);
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("new".to_owned()));
+
+    drop(snapshot);
+    store.compact()?;
+    assert!(logs(&temp_dir) < pinned);
+    Ok(())
+}
+
+// Secondary indexes should follow sets and removes, and be rebuilt from the logs when the store is reopened.
+#[test]
+fn lib_secondary_index() -> Result<()> {
+    init();
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set(
+        "user/1".to_owned(),
+        r#"{"email": "ann@example.com"}"#.to_owned(),
+    )?;
+    store.set(
+        "user/2".to_owned(),
+        r#"{"email": "bob@example.com"}"#.to_owned(),
+    )?;
+    store.set(
+        "admin/1".to_owned(),
+        r#"{"email": "ann@example.com"}"#.to_owned(),
+    )?;
+    store.set("user/3".to_owned(), "not json".to_owned())?;
+    store.create_index("email", "user/", "$.email")?;
+    store.create_index("city", "user/", "$.address.city")?;
+    assert!(matches!(
+        store.create_index("email", "user/", "$.email"),
+        Err(kvs::Error::IndexExists { .. })
+    ));
+    assert!(matches!(
+        store.create_index("bad", "user/", "email"),
+        Err(kvs::Error::BadIndexPath { .. })
+    ));
+
+    assert_eq!(
+        store.find_by_index("email", "ann@example.com")?,
+        vec!["user/1"]
+    );
+    store.set(
+        "user/3".to_owned(),
+        r#"{"email": "ann@example.com", "address": {"city": "Oslo"}}"#.to_owned(),
+    )?;
+    store.set(
+        "user/2".to_owned(),
+        r#"{"email": "bea@example.com"}"#.to_owned(),
+    )?;
+    store.remove("user/1".to_owned())?;
+    assert_eq!(
+        store.find_by_index("email", "ann@example.com")?,
+        vec!["user/3"]
+    );
+    assert!(store.find_by_index("email", "bob@example.com")?.is_empty());
+    assert_eq!(store.find_by_index("city", "Oslo")?, vec!["user/3"]);
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.find_by_index("email", "bea@example.com")?,
+        vec!["user/2"]
+    );
+    assert_eq!(store.find_by_index("city", "Oslo")?, vec!["user/3"]);
+    store.drop_index("city")?;
+    assert!(matches!(
+        store.find_by_index("city", "Oslo"),
+        Err(kvs::Error::NoIndex { .. })
+    ));
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.find_by_index("city", "Oslo").is_err());
+    assert_eq!(
+        store.find_by_index("email", "ann@example.com")?,
+        vec!["user/3"]
+    );
+    Ok(())
+}
+
+// `kvs find <INDEX> <VALUE>` should print every matching key on its own line.
+#[test]
+fn cli_find() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("user/1".to_owned(), r#"{"age": 30}"#.to_owned())?;
+    store.set("user/2".to_owned(), r#"{"age": 41}"#.to_owned())?;
+    store.set("user/3".to_owned(), r#"{"age": 30}"#.to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["index", "age", "user/", "$.age"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["find", "age", "30"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("user/1\nuser/3\n");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["find", "height", "30"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(2)
+        .stderr(contains("Index height not found"));
+    Ok(())
+}
+
+// Writes past any of the store's limits should be refused, leaving the store as it was.
+#[test]
+fn lib_size_limits() -> Result<()> {
+    init();
+    let mut store = KvStore::in_memory()
+        .with_max_key_size(8)
+        .with_max_value_size(16)
+        .with_max_live_bytes(40);
+    assert!(matches!(
+        store.set("a long key".to_owned(), "value".to_owned()),
+        Err(kvs::Error::KeyTooLarge { size: 10, max: 8 })
+    ));
+    assert!(matches!(
+        store.set("key".to_owned(), "a very long value".to_owned()),
+        Err(kvs::Error::ValueTooLarge { size: 17, max: 16 })
+    ));
+    assert_eq!(store.get("key".to_owned())?, None);
+
+    // Overwriting only counts the difference, and removing makes room
+    store.set("key1".to_owned(), "0123456789".to_owned())?;
+    store.set("key2".to_owned(), "0123456789".to_owned())?;
+    store.set("key2".to_owned(), "0123456789abcdef".to_owned())?;
+    assert_eq!(store.stats().live_bytes, 34);
+    assert!(matches!(
+        store.set("key3".to_owned(), "value".to_owned()),
+        Err(kvs::Error::QuotaExceeded {
+            quota: kvs::Quota::LiveData,
+            max: 40
+        })
+    ));
+    store.remove("key2".to_owned())?;
+    store.set("key3".to_owned(), "value".to_owned())?;
+    assert_eq!(store.stats().live_bytes, 23);
+    Ok(())
+}
+
+// The disk quota should count every log, and compacting should make room under it again.
+#[test]
+fn lib_disk_quota() -> Result<()> {
+    init();
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?
+        .with_max_size(200)
+        .with_max_disk_bytes(1000);
+    let mut refused = false;
+    for id in 0..100 {
+        match store.set("key".to_owned(), format!("value{}", id)) {
+            Ok(()) => {}
+            Err(kvs::Error::QuotaExceeded {
+                quota: kvs::Quota::Disk,
+                ..
+            }) => {
+                refused = true;
+                break;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    assert!(refused);
+    assert!(store.stats().disk_bytes <= 1000);
+
+    store.compact()?;
+    store.set("key".to_owned(), "again".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    let on_disk: u64 = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_name().to_string_lossy().parse::<u64>().is_ok())
+        .map(|entry| entry.metadata().unwrap().len())
+        .sum();
+    assert_eq!(store.stats().disk_bytes, on_disk);
+    Ok(())
+}
+
+// `verify` should find index entries that point at the wrong record, and carry on to report every one of them.
+#[test]
+fn lib_verify() -> Result<()> {
+    init();
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("a".to_owned(), "1".to_owned())?;
+    store.set("b".to_owned(), "2".to_owned())?;
+    let report = store.verify()?;
+    assert!(report.is_ok(), "{:?}", report.problems);
+    assert_eq!((report.logs, report.records, report.keys), (1, 2, 2));
+
+    // Both records are the same size, so swapping them leaves each key's entry pointing at the other's value
+    let log = temp_dir.path().join("0");
+    let bytes = fs::read(&log).unwrap();
+    let (header, records) = bytes.split_at(8);
+    let (first, second) = records.split_at(records.len() / 2);
+    fs::write(&log, [header, second, first].concat()).unwrap();
+
+    let report = store.verify()?;
+    assert_eq!(report.records, 2);
+    let mut misindexed: Vec<&str> = report
+        .problems
+        .iter()
+        .map(|problem| match problem {
+            kvs::Problem::Misindexed { key, .. } => key.as_str(),
+            other => panic!("unexpected problem {}", other),
+        })
+        .collect();
+    misindexed.sort_unstable();
+    assert_eq!(misindexed, ["a", "b"]);
+    Ok(())
+}
+
+// A config file should set up everything it mentions, leaving the rest to flags or the store's defaults.
+#[test]
+fn lib_config() -> Result<()> {
+    init();
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("kvs.toml");
+    fs::write(
+        &path,
+        "data_dir = \"/var/lib/kvs\"\ncompaction_threshold = 0\ndurability = \"sync\"\n",
+    )
+    .unwrap();
+    let config = Config::load(&path)?;
+    assert_eq!(
+        config,
+        Config {
+            data_dir: Some("/var/lib/kvs".into()),
+            compaction_threshold: Some(0),
+            durability: Some(Durability::Sync),
+            ..Config::default()
+        }
+    );
+    let overridden = config.clone().overridden_by(Config {
+        compaction_threshold: Some(5),
+        ..Config::default()
+    });
+    assert_eq!(overridden.compaction_threshold, Some(5));
+    assert_eq!(overridden.durability, Some(Durability::Sync));
+
+    // A threshold of zero leaves compaction to whoever opened the store
+    let mut store = KvStore::open(temp_dir.path().join("never"))?.with_config(&config);
+    for i in 0..1100 {
+        store.set("key".to_owned(), i.to_string())?;
+    }
+    assert_eq!(store.stats().epoch, 0);
+    let mut store = KvStore::open(temp_dir.path().join("often"))?.with_config(&overridden);
+    for i in 0..10 {
+        store.set("key".to_owned(), i.to_string())?;
+    }
+    assert!(store.stats().epoch > 0);
+    assert_eq!(store.get("key".to_owned())?, Some("9".to_owned()));
+
+    fs::write(&path, "max_log_size = \"big\"\n").unwrap();
+    assert!(matches!(
+        Config::load(&path),
+        Err(kvs::Error::BadConfig { .. })
+    ));
+    fs::write(&path, "max_logsize = 10\n").unwrap();
+    assert!(matches!(
+        Config::load(&path),
+        Err(kvs::Error::BadConfig { .. })
+    ));
+    Ok(())
+}
+
+// `kvs set` should exit with a distinct code for each limit it runs into.
+#[test]
+fn cli_size_limits() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let set = |args: &[&str]| {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(args)
+            .current_dir(&temp_dir)
+            .assert()
+    };
+    set(&["--max-key-size", "3", "set", "key1", "value1"])
+        .code(3)
+        .stderr(contains("key is 4 bytes"));
+    set(&["--max-value-size", "3", "set", "key1", "value1"])
+        .code(4)
+        .stderr(contains("value is 6 bytes"));
+    set(&["set", "key1", "value1"]).success();
+    set(&["--max-live-bytes", "20", "set", "key2", "value2"]).success();
+    set(&["--max-live-bytes", "20", "set", "key3", "value3"])
+        .code(5)
+        .stderr(contains("live data quota of 20 bytes exceeded"));
+}
+
+// Each built-in merge operator should combine operands with whatever value a key has, or start from nothing.
+#[test]
+fn lib_merge_operators() -> Result<()> {
+    init();
+    let merged = |mut store: KvStore, set: Option<&str>, operands: &[&str]| -> Result<String> {
+        if let Some(val) = set {
+            store.set("key".to_owned(), val.to_owned())?;
+        }
+        for operand in operands {
+            store.merge("key".to_owned(), (*operand).to_owned())?;
+        }
+        Ok(store.get("key".to_owned())?.unwrap())
+    };
+
+    let append = || KvStore::in_memory().with_merge_operator(kvs::Append);
+    assert_eq!(merged(append(), None, &["a", "b"])?, "ab");
+    assert_eq!(merged(append(), Some("x"), &["a", "b"])?, "xab");
+    let add = || KvStore::in_memory().with_merge_operator(kvs::Add);
+    assert_eq!(merged(add(), None, &["1", "2", "-5"])?, "-2");
+    assert_eq!(merged(add(), Some("10"), &["1"])?, "11");
+    assert!(matches!(
+        merged(add(), Some("ten"), &["1"]),
+        Err(kvs::Error::MergeFailed { .. })
+    ));
+    let max = || KvStore::in_memory().with_merge_operator(kvs::Max);
+    assert_eq!(merged(max(), None, &["3", "10", "2.5"])?, "10");
+    assert_eq!(merged(max(), Some("1.5e2"), &["99"])?, "1.5e2");
+    let patch = || KvStore::in_memory().with_merge_operator(kvs::JsonMergePatch);
+    let patched = merged(
+        patch(),
+        Some(r#"{"name": "ann", "tags": {"a": 1, "b": 2}}"#),
+        &[r#"{"tags": {"a": null, "c": 3}}"#, r#"{"age": 30}"#],
+    )?;
+    assert_eq!(
+        serde_json::from_str::<serde_json::Value>(&patched).unwrap(),
+        serde_json::json!({"age": 30, "name": "ann", "tags": {"b": 2, "c": 3}})
+    );
+
+    let mut store = KvStore::in_memory();
+    assert!(matches!(
+        store.merge("key".to_owned(), "1".to_owned()),
+        Err(kvs::Error::NoMergeOperator)
+    ));
+    Ok(())
+}
+
+// Operands should be recorded in the log, and worked out the same way after reopening and after compaction folds them.
+#[test]
+fn lib_merge_survives_reopen_and_compaction() -> Result<()> {
+    init();
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = || -> Result<KvStore> {
+        Ok(KvStore::open(temp_dir.path())?
+            .with_max_size(200)
+            .with_merge_operator(kvs::Add))
+    };
+    let mut store = open()?;
+    let seen = store.last_position();
+    for _ in 0..10 {
+        store.merge("counter".to_owned(), "1".to_owned())?;
+    }
+    store.set("reset".to_owned(), "5".to_owned())?;
+    store.merge("reset".to_owned(), "1".to_owned())?;
+    store.set("reset".to_owned(), "0".to_owned())?;
+    store.merge("gone".to_owned(), "1".to_owned())?;
+    store.remove("gone".to_owned())?;
+    assert_eq!(
+        describe(&changes(&store, seen)?)[..2],
+        ["merge counter 1", "merge counter 1"]
+    );
+
+    drop(store);
+    let mut store = open()?;
+    assert_eq!(store.get("counter".to_owned())?, Some("10".to_owned()));
+    assert_eq!(store.get("reset".to_owned())?, Some("0".to_owned()));
+    assert_eq!(store.get("gone".to_owned())?, None);
+
+    let mut snapshot = store.snapshot_read();
+    store.compact()?;
+    store.merge("counter".to_owned(), "5".to_owned())?;
+    assert_eq!(store.get("counter".to_owned())?, Some("15".to_owned()));
+    assert_eq!(snapshot.get("counter".to_owned())?, Some("10".to_owned()));
+    assert_eq!(store.stats().live_bytes, 17 + 6);
+
+    drop(store);
+    let mut store = open()?;
+    assert_eq!(store.get("counter".to_owned())?, Some("15".to_owned()));
+    Ok(())
+}
+
+// Compaction should keep operands it can't merge rather than failing or dropping them.
+#[test]
+fn lib_compaction_keeps_unmergeable_operands() -> Result<()> {
+    init();
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?.with_merge_operator(kvs::Add);
+    store.set("bad".to_owned(), "ten".to_owned())?;
+    store.merge("bad".to_owned(), "1".to_owned())?;
+    store.merge("good".to_owned(), "1".to_owned())?;
+    store.compact()?;
+    assert!(store.get("bad".to_owned()).is_err());
+    assert_eq!(store.get("good".to_owned())?, Some("1".to_owned()));
+
+    // Without an operator nothing can be merged, but everything is still kept
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.compact()?;
+    assert!(matches!(
+        store.get("bad".to_owned()),
+        Err(kvs::Error::NoMergeOperator)
+    ));
+    assert_eq!(store.get("good".to_owned())?, Some("1".to_owned()));
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?.with_merge_operator(kvs::Append);
+    assert_eq!(store.get("bad".to_owned())?, Some("ten1".to_owned()));
+    store.set("bad".to_owned(), "fixed".to_owned())?;
+    store.compact()?;
+    assert_eq!(store.get("bad".to_owned())?, Some("fixed".to_owned()));
+    Ok(())
+}
+
+// `--output json` should print each result as a JSON object, and errors as JSON on stderr.
+#[test]
+fn cli_json_output() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let kvs = |args: &[&str]| {
+        let output = Command::cargo_bin("kvs")
+            .unwrap()
+            .arg("--output")
+            .arg("json")
+            .args(args)
+            .current_dir(&temp_dir)
+            .output()
+            .unwrap();
+        let parse = |bytes: &[u8]| {
+            let text = String::from_utf8(bytes.to_vec()).unwrap();
+            (!text.is_empty()).then(|| serde_json::from_str::<serde_json::Value>(&text).unwrap())
+        };
+        (
+            output.status.code(),
+            parse(&output.stdout),
+            parse(&output.stderr),
+        )
+    };
+
+    assert_eq!(
+        kvs(&["set", "key1", "value1"]),
+        (
+            Some(0),
+            Some(serde_json::json!({"ok": true, "key": "key1"})),
+            None
+        )
+    );
+    assert_eq!(
+        kvs(&["get", "key1"]),
+        (
+            Some(0),
+            Some(serde_json::json!({"ok": true, "key": "key1", "value": "value1"})),
+            None
+        )
+    );
+    assert_eq!(
+        kvs(&["get", "key2"]),
+        (
+            Some(0),
+            Some(serde_json::json!({"ok": true, "key": "key2", "value": null})),
+            None
+        )
+    );
+    assert_eq!(
+        kvs(&["rm", "key1"]),
+        (
+            Some(0),
+            Some(serde_json::json!({"ok": true, "key": "key1"})),
+            None
+        )
+    );
+    assert_eq!(
+        kvs(&["rm", "key1"]),
+        (
+            Some(2),
+            None,
+            Some(serde_json::json!({
+                "ok": false,
+                "error": {"kind": "not_found", "code": 2, "message": "Key not found"},
+            }))
+        )
+    );
+
+    let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let (code, stdout, stderr) = kvs(&[
+        "leader",
+        "--listen",
+        &taken.local_addr().unwrap().to_string(),
+    ]);
+    assert_eq!((code, stdout), (Some(8), None));
+    assert_eq!(stderr.unwrap()["error"]["kind"], "network");
+}
+
+// A corrupt log should be reported with its own exit code.
+#[test]
+fn cli_corrupt_log_exit_code() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    let log = temp_dir.path().join("0");
+    let mut bytes = fs::read(&log).unwrap();
+    let last = bytes.len() - 1;
+    bytes[last] ^= 0xff;
+    fs::write(&log, bytes).unwrap();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(6)
+        .stdout(is_empty());
+}
+
+// `kvs batch` should apply each line from stdin to one store, carrying on past lines that fail.
+#[test]
+fn cli_batch() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["batch"])
+        .current_dir(&temp_dir)
+        .with_stdin()
+        .buffer("set key1 value1\n# a comment\n\nget key1\nrm key2\nget key2\nfrobnicate\nset key2 value 2\n")
+        .assert()
+        .code(2)
+        .stdout("ok\nvalue1\nKey not found\nok\n")
+        .stderr("line 5: Key not found\nline 7: unknown command: frobnicate\n");
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value 2");
+}
+
+// `kvs --output json batch FILE` should take JSON lines and report on each line as JSON.
+#[test]
+fn cli_batch_json() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let file = temp_dir.path().join("batch");
+    fs::write(
+        &file,
+        "{\"op\": \"set\", \"key\": \"key1\", \"value\": \"value1\"}\nget key1\n{\"op\": \"rm\"}\n",
+    )
+    .unwrap();
+    let output = Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--output", "json", "batch"])
+        .arg(&file)
+        .current_dir(&temp_dir)
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(9));
+
+    let results: Vec<serde_json::Value> = String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(results.len(), 3);
+    assert_eq!(
+        results[0],
+        serde_json::json!({"ok": true, "key": "key1", "line": 1})
+    );
+    assert_eq!(
+        results[1],
+        serde_json::json!({"ok": true, "key": "key1", "value": "value1", "line": 2})
+    );
+    assert_eq!(results[2]["ok"], false);
+    assert_eq!(results[2]["error"]["kind"], "invalid");
+    assert_eq!(results[2]["line"], 3);
+}
+
+// `kvs compact` should report how much the logs shrank, and `kvs verify` should pass on a healthy store.
+#[test]
+fn cli_compact_and_verify() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let kvs = |args: &[&str]| {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(args)
+            .current_dir(&temp_dir)
+            .output()
+            .unwrap()
+    };
+    for i in 0..10 {
+        assert!(kvs(&["set", "key1", &format!("value{}", i)])
+            .status
+            .success());
+    }
+
+    let compacted = kvs(&["--output", "json", "compact"]);
+    assert!(compacted.status.success());
+    let compacted: serde_json::Value = serde_json::from_slice(&compacted.stdout).unwrap();
+    assert!(compacted["after_bytes"].as_u64() < compacted["before_bytes"].as_u64());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["verify"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("1 keys: 0 problems"));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value9");
+}
+
+// `kvs` should keep its logs where the config file says, unless told otherwise, and find the config file from `.env`.
+#[test]
+fn cli_config() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let data = temp_dir.path().join("data");
+    fs::write(
+        temp_dir.path().join("kvs.toml"),
+        format!("data_dir = {:?}\n", data.to_str().unwrap()),
+    )
+    .unwrap();
+    fs::write(temp_dir.path().join(".env"), "KVS_CONFIG=kvs.toml\n").unwrap();
+    let kvs = |args: &[&str]| {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .env_remove("LOG_DIR")
+            .env_remove("KVS_CONFIG")
+            .args(args)
+            .current_dir(&temp_dir)
+            .assert()
+    };
+
+    kvs(&["set", "key1", "value1"]).success();
+    assert!(data.join("0").exists());
+    kvs(&["-f", "elsewhere", "get", "key1"])
+        .success()
+        .stdout("Key not found");
+    kvs(&["--durability", "sync", "get", "key1"])
+        .success()
+        .stdout("value1");
+    kvs(&["--durability", "sometimes", "get", "key1"]).failure();
+
+    fs::write(temp_dir.path().join("kvs.toml"), "data_dir = 3\n").unwrap();
+    kvs(&["get", "key1"])
+        .code(9)
+        .stderr(contains("invalid config file"));
+}